@@ -0,0 +1,22 @@
+use std::process::Command;
+
+// Bake the git commit into the binary, for the version banner and run
+// metadata. Builds outside a git checkout can set PROSEG_GIT_HASH instead.
+fn main() {
+    println!("cargo:rerun-if-env-changed=PROSEG_GIT_HASH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+
+    let hash = std::env::var("PROSEG_GIT_HASH").ok().or_else(|| {
+        Command::new("git")
+            .args(["rev-parse", "--short=12", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    });
+
+    if let Some(hash) = hash {
+        println!("cargo:rustc-env=PROSEG_GIT_HASH={}", hash);
+    }
+}
@@ -0,0 +1,9 @@
+#![allow(confusable_idents)]
+
+// The sampler, readers, and output writers are exposed as a library so proseg
+// can be driven from other tools without going through the command line
+// interface in main.rs.
+
+pub mod output;
+pub mod sampler;
+pub mod schemas;
@@ -1,29 +1,63 @@
 #![allow(confusable_idents)]
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 
-mod output;
-mod sampler;
-mod schemas;
+use proseg::{output, sampler, schemas};
 
 use indicatif::{ProgressBar, ProgressStyle};
 use itertools::Itertools;
+use arrow::array::{Array, BooleanArray, Float32Array, RecordBatch, StringArray, UInt32Array, UInt64Array};
+use petgraph::unionfind::UnionFind;
+use rand::Rng;
 use rayon::current_num_threads;
 use sampler::hull::compute_cell_areas;
 use sampler::transcripts::{
-    coordinate_span, estimate_full_area, filter_cellfree_transcripts, read_transcripts_csv, Transcript
+    apply_affine, check_coordinate_units, check_z_span, coordinate_span, decompressed_reader, estimate_full_area, filter_absent_genes, filter_cellfree_transcripts,
+    normalize_gene_names, read_prior_transcript_assignments, read_transcripts_csv,
+    read_transcripts_header, read_transcripts_sample, Compartment, CompartmentValues, MappedTranscripts, Transcript, TranscriptDataset, BACKGROUND_CELL,
 };
-use sampler::voxelsampler::{filter_sparse_cells, VoxelSampler};
-use sampler::{ModelParams, ModelPriors, ProposalStats, Sampler, UncertaintyTracker};
+use sampler::voxelsampler::{
+    check_layer_counts, discrete_z_levels, doubles_z_layers, edge_cells, final_voxel_layers, filter_sparse_cells, tissue_boundary, VoxelSampler,
+};
+use sampler::{counts_from_assignments, ModelParams, ModelPriors, ProposalStats, Sampler, UncertaintyTracker};
 use core::f32;
+use ndarray::{Array1, Array2, Axis};
+use std::borrow::Cow;
 use std::cell::RefCell;
-use std::collections::HashSet;
-use schemas::OutputFormat;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::sync::Arc;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use schemas::{transcript_metadata_schema, with_source_transcript_id_column, DuplicateGenes, GeometryFormat, NucleusDistanceMetric, OutputCompression, OutputFormat, RatesLayout};
 
 use output::*;
 
-#[derive(Parser)]
+// What to do with cells that end up with effectively no volume.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum DegenerateCells {
+    // Keep them as is, only marking them in cell metadata.
+    Flag,
+    // Reassign their transcripts to the nearest non-degenerate cell with voxels.
+    Merge,
+    // Reassign their transcripts to background.
+    Drop,
+}
+
+// How expected counts are normalized for --output-normalized-counts.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum Normalization {
+    // Divide by cell volume, giving transcripts per cubic micron.
+    Volume,
+    // Scale each cell to sum to --normalization-target-sum.
+    Total,
+    // Leave counts as they are.
+    None,
+}
+
+#[derive(Parser, Debug, Clone)]
 #[command(version)]
+#[command(args_override_self = true)]
 #[command(name = "proseg")]
 #[command(author = "Daniel C. Jones")]
 #[command(
@@ -64,10 +98,36 @@ struct Args {
     #[arg(long, default_value = None)]
     gene_column: Option<String>,
 
+    /// Trim whitespace from gene names, merging genes that then have the same name
+    #[arg(long, default_value_t = false)]
+    normalize_gene_names: bool,
+
+    /// Like --normalize-gene-names, but also convert gene names to uppercase
+    #[arg(long, default_value_t = false)]
+    uppercase_gene_names: bool,
+
+    /// How to handle distinct genes whose names coincide after
+    /// --normalize-gene-names or --uppercase-gene-names
+    #[arg(long, value_enum, default_value_t = DuplicateGenes::Merge)]
+    duplicate_genes: DuplicateGenes,
+
     /// Name of column containing the transcript ID
     #[arg(long, default_value = None)]
     transcript_id_column: Option<String>,
 
+    /// Name of column giving the number of identical transcripts each row
+    /// represents, for pre-aggregated data. Expanded transcripts get their
+    /// own ids, with the row's id kept as source_transcript_id.
+    #[arg(long, default_value = None)]
+    count_column: Option<String>,
+
+    /// Name of column giving each transcript's probability of belonging to
+    /// its prior segmentation cell, from a probabilistic segmenter. Weights
+    /// the --prior-seg-reassignment-prob penalty, and transcripts outside
+    /// nuclei start in their prior cell with this probability.
+    #[arg(long, default_value = None)]
+    prior_cell_prob_column: Option<String>,
+
     /// Name of column containing the x coordinate
     #[arg(short, long, default_value = None)]
     x_column: Option<String>,
@@ -88,6 +148,26 @@ struct Args {
     #[arg(long, default_value = None)]
     compartment_nuclear: Option<String>,
 
+    /// Value in the cellular compartment column indicating the cytoplasm
+    #[arg(long, default_value = None)]
+    compartment_cytoplasm: Option<String>,
+
+    /// Value in the cellular compartment column indicating the membrane
+    #[arg(long, default_value = None)]
+    compartment_membrane: Option<String>,
+
+    /// Value in the cellular compartment column indicating transcripts outside
+    /// any cell
+    #[arg(long, default_value = None)]
+    compartment_extracellular: Option<String>,
+
+    /// Weight of the prior segmentation for transcripts in the membrane
+    /// compartment, which sit on cell boundaries: at 1 they're penalized by
+    /// --prior-seg-reassignment-prob like any other transcript, at 0 the prior
+    /// segmentation has no say.
+    #[arg(long, default_value_t = 0.5_f32)]
+    membrane_prior_weight: f32,
+
     /// Name of column containing the field of view
     #[arg(long, default_value = None)]
     fov_column: Option<String>,
@@ -126,14 +206,58 @@ struct Args {
     #[arg(long, default_value_t = 100)]
     cells_per_chunk: usize,
 
+    /// Instead of a uniform grid, chunk using a quadtree over cell centroids
+    /// with about cells-per-chunk cells in each leaf. Improves load balancing
+    /// on sections with very uneven cell density.
+    #[arg(long, default_value_t = false)]
+    adaptive_chunks: bool,
+
     /// Number of components in the mixture model of cellular gene expression
     #[arg(long, default_value_t = 10)]
     ncomponents: usize,
 
+    /// Use a truncated stick-breaking (Dirichlet process) prior over mixture
+    /// components so the number used is learned, ignoring --ncomponents
+    #[arg(long, default_value_t = false)]
+    components_auto: bool,
+
+    /// Truncation level used by --components-auto
+    #[arg(long, default_value_t = 30)]
+    max_components: usize,
+
+    /// Concentration of the --components-auto stick-breaking prior. Larger
+    /// values favor using more components.
+    #[arg(long, default_value_t = 1.0)]
+    components_concentration: f32,
+
     /// Number of z-axis layers used to model background expression
     #[arg(long, default_value_t = 4)]
     nbglayers: usize,
 
+    /// Known ambient background rates (transcripts per cubic micron) per
+    /// gene, e.g. from a blank region, as a prior on each gene's background
+    /// rate. A table in the format of --output-background-rates, with a
+    /// column per gene, averaged over its rows.
+    #[arg(long, default_value = None)]
+    background_prior: Option<String>,
+
+    /// Prior confusion rate per cubic micron of cell volume, for each gene:
+    /// the rate at which misread or mislocalized probes appear inside cells
+    /// regardless of cell type. Lower values discount fewer transcripts as
+    /// confusion. Without this, the rate is estimated from the data alone.
+    #[arg(long, default_value = None)]
+    confusion_prior_rate: Option<f32>,
+
+    /// Weight of --confusion-prior-rate, in multiples of the total cell
+    /// volume. At 1 the prior rate counts as much as the data.
+    #[arg(long, default_value_t = 1.0_f32)]
+    confusion_prior_weight: f32,
+
+    /// Weight of --background-prior, in z-layers' worth of observed volume.
+    /// At 1 the known rate counts as much as the data in each layer.
+    #[arg(long, default_value_t = 1.0_f32)]
+    background_prior_weight: f32,
+
     /// Detect the number of z-layers from the data when it's discrete
     #[arg(long, default_value_t = false)]
     detect_layers: bool,
@@ -142,7 +266,32 @@ struct Args {
     #[arg(long, default_value_t = 1)]
     voxel_layers: usize,
 
-    /// Sampler schedule, indicating the number of iterations between doubling resolution.
+    /// Collapse z into this many equally thick layers, whatever the native z
+    /// resolution. Sets the background layers in place of --nbglayers, and
+    /// caps the voxel layers: z-layers stop doubling with resolution before
+    /// exceeding it.
+    #[arg(long, default_value = None, conflicts_with = "detect_layers")]
+    target_z_layers: Option<usize>,
+
+    /// Lower and upper quantiles that transcript z-coordinates are clamped to.
+    /// Clamping keeps a few extreme outliers from stretching the z-layers so
+    /// thin that most layers end up nearly empty.
+    #[arg(long, num_args=1.., value_delimiter=',', default_values_t=[0.01, 0.99])]
+    z_clamp_quantiles: Vec<f32>,
+
+    /// Don't clamp transcript z-coordinates.
+    #[arg(long, default_value_t = false)]
+    no_z_clamp: bool,
+
+    /// Don't automatically rescale z-coordinates whose span is implausibly
+    /// large for microns. Proseg still warns.
+    #[arg(long, default_value_t = false)]
+    no_auto_z_scale: bool,
+
+    /// Sampler schedule, indicating the number of iterations between doubling
+    /// resolution. Each entry is a phase, and resolution doubles between
+    /// consecutive phases, so n entries give n-1 doublings (at most
+    /// --max-resolution-level).
     #[arg(long, num_args=1.., value_delimiter=',', default_values_t=[150, 150, 300])]
     schedule: Vec<usize>,
 
@@ -150,15 +299,30 @@ struct Args {
     #[arg(long, default_value_t = true)]
     double_z_layers: bool,
 
+    /// Stop doubling resolution after this many doublings, running any
+    /// remaining schedule phases at the capped resolution
+    #[arg(long, default_value = None)]
+    max_resolution_level: Option<usize>,
+
     /// Number of samples at the end of the schedule used to compute
     /// expectations and uncertainty
     #[arg(long, default_value_t = 100)]
     recorded_samples: usize,
 
+    /// Record only every nth iteration when computing expectations and
+    /// uncertainty, extending the final phase so that `recorded-samples`
+    /// samples are still recorded. Reduces autocorrelation between samples.
+    #[arg(long, default_value_t = 1)]
+    recorded_thinning: usize,
+
     /// Number of CPU threads (by default, all cores are used)
     #[arg(short = 't', long, default_value=None)]
     nthreads: Option<usize>,
 
+    /// Don't report sampling progress
+    #[arg(long, default_value_t = false)]
+    quiet: bool,
+
     /// Number of sub-iterations sampling cell morphology per overall iteration
     #[arg(short, long, default_value_t = 1000)]
     morphology_steps_per_iter: usize,
@@ -166,34 +330,139 @@ struct Args {
     #[arg(long, default_value_t = 0.1)]
     count_pr_cutoff: f32,
 
+    /// Attribute max posterior counts and transcript assignments to the cell
+    /// containing each transcript's observed position, rather than its
+    /// position after diffusion. Cell boundaries are still inferred with
+    /// diffusion. Expected counts are unaffected.
+    #[arg(long, default_value_t = false)]
+    count_at_observed_position: bool,
+
     #[arg(long, default_value_t = 0.9)]
     foreground_pr_cutoff: f32,
 
+    /// Bound on cell perimeter, as a multiple of the perimeter of a circle
+    /// of the same area.
     #[arg(long, default_value_t = 1.3_f32)]
     perimeter_bound: f32,
 
+    /// Scaling factor between the number of mismatching voxel neighbors and
+    /// perimeter. Smaller values make the perimeter bound more restrictive,
+    /// giving smoother cells.
+    #[arg(long, default_value_t = 5.3_f32)]
+    perimeter_eta: f32,
+
     #[arg(long, default_value_t = 2e-1_f32)]
     nuclear_reassignment_prob: f32,
 
+    /// File listing genes, one per line, known to be localized to the nucleus.
+    /// Transcripts of these genes that start out in a nucleus are less likely
+    /// to be reassigned away from it.
+    #[arg(long, default_value = None)]
+    nuclear_marker_genes: Option<String>,
+
+    /// Used in place of --nuclear-reassignment-prob for transcripts of
+    /// --nuclear-marker-genes.
+    #[arg(long, default_value_t = 5e-2_f32)]
+    nuclear_marker_reassignment_prob: f32,
+
     #[arg(long, default_value_t = 5e-1_f32)]
     prior_seg_reassignment_prob: f32,
 
+    /// Skip sampling, and write max posterior counts and cell correspondence
+    /// from the cell assignments in a prior run's transcript metadata (csv,
+    /// csv.gz, or parquet), read with the same input options. The tissue
+    /// boundary and density are written too, but nothing that needs the
+    /// segmentation itself (voxels, polygons, cell metadata, ...).
+    #[arg(long, default_value = None, conflicts_with = "init_from_transcript_metadata")]
+    counts_from_transcript_metadata: Option<String>,
+
+    /// Write only the max posterior count matrix, to --output-maxpost-counts
+    /// (maxpost-counts.csv.gz by default). Every other output is turned off,
+    /// along with the post-sampling work only they need.
+    #[arg(long, default_value_t = false, conflicts_with_all = ["counts_from_transcript_metadata", "by_fov", "restarts"])]
+    counts_only: bool,
+
+    /// Segment each FOV separately, along with transcripts within
+    /// `--fov-overlap` of it, then stitch the results, merging cells that
+    /// match across FOV seams. Bounds memory use on very large datasets.
+    /// Requires csv input with a FOV column, and writes only transcript
+    /// metadata and max posterior counts.
+    #[arg(long, default_value_t = false, conflicts_with_all = ["counts_from_transcript_metadata", "dry_run"])]
+    by_fov: bool,
+
+    /// Margin in microns around each FOV included when segmenting it with --by-fov
+    #[arg(long, default_value_t = 20.0)]
+    fov_overlap: f32,
+
+    /// Run this many independent chains, each a full run of proseg, and keep
+    /// the outputs of the one with the highest final log likelihood. The
+    /// likelihood of every chain is reported to help judge multimodality.
+    #[arg(long, default_value_t = 1, conflicts_with_all = ["by_fov", "counts_from_transcript_metadata", "dry_run"])]
+    restarts: usize,
+
+    /// Initialize cell assignments from a prior run's transcript metadata
+    /// (csv, csv.gz, or parquet), rather than from nuclei alone. Useful to
+    /// warm start parameter sweeps on the same data.
+    #[arg(long, default_value = None)]
+    init_from_transcript_metadata: Option<String>,
+
     /// Scale transcript coordinates by this factor to arrive at microns
     #[arg(long, default_value=None)]
     coordinate_scale: Option<f32>,
 
+    /// Scale z coordinates by this factor, independently of x and y, for data
+    /// where z is in different units (e.g. slices). Applied before z-clamping.
+    #[arg(long, default_value_t = 1.0_f32)]
+    z_scale: f32,
+
+    /// Affine transform a,b,c,d,e,f applied to x and y after
+    /// --coordinate-scale, giving x' = a*x + b*y + c and y' = d*x + e*y + f,
+    /// to segment in the coordinate frame of an external image.
+    #[arg(long, num_args=1.., value_delimiter=',', default_values_t=[1.0, 0.0, 0.0, 0.0, 1.0, 0.0])]
+    coord_affine: Vec<f32>,
+
+    /// Store the parsed transcripts in a memory-mapped scratch file at this
+    /// path during sampling, so the OS can page them out on datasets that
+    /// don't fit in memory. Slower when memory is actually short.
+    #[arg(long, default_value = None)]
+    mmap_transcripts: Option<String>,
+
     /// Initial size x/y size of voxels.
     #[arg(long, default_value_t = 4.0_f32)]
     initial_voxel_size: f32,
 
+    /// Choose the initial voxel size from the median nucleus area, overriding
+    /// --initial-voxel-size.
+    #[arg(long, default_value_t = false)]
+    calibrate_voxel_size: bool,
+
+    /// Number of initial voxels the median nucleus should span when using
+    /// --calibrate-voxel-size.
+    #[arg(long, default_value_t = 4.0_f32)]
+    calibrate_voxel_size_target: f32,
+
     /// Exclude transcripts that are more than this distance from any nucleus
     #[arg(long, default_value_t = 60_f32)]
     max_transcript_nucleus_distance: f32,
 
+    /// Measure nucleus distance in xy only, or in xyz to also exclude
+    /// transcripts far from any nucleus along z
+    #[arg(long, value_enum, default_value_t = NucleusDistanceMetric::Xy)]
+    nucleus_distance_metric: NucleusDistanceMetric,
+
     /// Disable transcript diffusion model
     #[arg(long, default_value_t = false)]
     no_diffusion: bool,
 
+    /// File listing genes, one per line, whose transcripts may diffuse even
+    /// with --no-diffusion
+    #[arg(long, default_value = None)]
+    diffusion_genes: Option<String>,
+
+    /// File listing genes, one per line, whose transcripts never diffuse
+    #[arg(long, default_value = None)]
+    no_diffusion_genes: Option<String>,
+
     /// Probability of transcript diffusion
     #[arg(long, default_value_t = 0.2)]
     diffusion_probability: f32,
@@ -210,6 +479,16 @@ struct Args {
     #[arg(long, default_value_t = 4.0)]
     diffusion_sigma_far: f32,
 
+    /// Stddev, in microns, of transcript repositioning on the z-axis
+    /// (default: 0.2 times the z span)
+    #[arg(long, default_value = None)]
+    z_diffusion_sigma: Option<f32>,
+
+    /// Stddev, in microns, of the proposal distribution for transcript
+    /// repositioning on the z-axis (default: 0.2 times the z span)
+    #[arg(long, default_value = None)]
+    z_diffusion_proposal_sigma: Option<f32>,
+
     /// Allow dispersion parameter to vary during burn-in
     #[arg(long, default_value_t = false)]
     variable_burnin_dispersion: bool,
@@ -222,10 +501,44 @@ struct Args {
     #[arg(long, default_value = None)]
     dispersion: Option<f32>,
 
+    /// Learn one dispersion parameter per gene shared by every component,
+    /// rather than a separate dispersion for each gene and component.
+    #[arg(long, default_value_t = false)]
+    per_gene_dispersion: bool,
+
     /// Run time consuming checks to make sure data structures are in a consistent state
     #[arg(long, default_value_t = false)]
     check_consistency: bool,
 
+    /// Decimal places floating point values are rounded to in csv output.
+    /// Values smaller than one keep at least this many significant digits.
+    #[arg(long, default_value_t = 4)]
+    output_precision: usize,
+
+    /// Compression level, from 0 (none, fastest) to 9 (smallest), for
+    /// gzipped outputs.
+    #[arg(long, default_value_t = 6, value_parser = clap::value_parser!(u32).range(0..=9))]
+    gzip_level: u32,
+
+    /// Compression for outputs that are gzipped by default. Their .gz suffix
+    /// becomes .zst with zstd, and is dropped with none.
+    #[arg(long, value_enum, default_value_t = OutputCompression::Gzip)]
+    compression: OutputCompression,
+
+    /// Prefix for cell ids in every output, to keep cells from separate runs
+    /// distinct when combining them. Cell ids become strings, e.g. `slideA_0`.
+    #[arg(long, default_value = "")]
+    cell_id_prefix: String,
+
+    /// Number cells in outputs starting from this, rather than 0
+    #[arg(long, default_value_t = 0)]
+    cell_id_start: u32,
+
+    /// Write outputs into this directory (created if needed). Relative output
+    /// paths, including the defaults, are taken to be relative to it.
+    #[arg(long, default_value = None)]
+    out_dir: Option<String>,
+
     /// Output a point estimate of transcript counts per cell
     #[arg(long, default_value = None)]
     output_maxpost_counts: Option<String>,
@@ -244,6 +557,17 @@ struct Args {
     #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
     output_rates_fmt: OutputFormat,
 
+    /// Output the per-gene background (ambient) expression rate for each z-layer
+    #[arg(long, default_value = None)]
+    output_background_rates: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_background_rates_fmt: OutputFormat,
+
+    /// Write rates as a cell-by-gene matrix (wide) or one row per cell and gene (tidy)
+    #[arg(long, value_enum, default_value_t = RatesLayout::Wide)]
+    output_rates_layout: RatesLayout,
+
     /// Output per-component parameter values
     #[arg(long, default_value = None)]
     output_component_params: Option<String>,
@@ -251,13 +575,176 @@ struct Args {
     #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
     output_component_params_fmt: OutputFormat,
 
+    /// Output the spatial extent of the cells assigned to each component
+    #[arg(long, default_value = None)]
+    output_component_extent: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_component_extent_fmt: OutputFormat,
+
+    /// Append a row for the background, the transcripts not assigned to any
+    /// cell, to the count matrices and cell metadata. In cell metadata it's
+    /// marked by a `background` column.
+    #[arg(long, default_value_t = false)]
+    output_background_cell: bool,
+
+    /// Drop genes with fewer than this many transcripts assigned to cells,
+    /// summed over all cells, from the count matrices and gene metadata. Only
+    /// the outputs are filtered; sampling still uses every gene.
+    #[arg(long, default_value_t = 0)]
+    min_gene_total: u32,
+
+    /// Output the number of transcripts each proseg cell shares with each cell
+    /// in the input's segmentation
+    #[arg(long, default_value = None)]
+    output_cell_correspondence: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_cell_correspondence_fmt: OutputFormat,
+
     #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
     output_expected_counts_fmt: OutputFormat,
 
+    /// Output a matrix of normalized expected transcript counts per cell
+    #[arg(long, default_value = None)]
+    output_normalized_counts: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_normalized_counts_fmt: OutputFormat,
+
+    /// How to normalize counts for `output-normalized-counts`
+    #[arg(long, value_enum, default_value_t = Normalization::Volume)]
+    normalization: Normalization,
+
+    /// Sum each cell is scaled to with `--normalization total`
+    #[arg(long, default_value_t = 1e6)]
+    normalization_target_sum: f32,
+
     /// Output cell convex hulls
     #[arg(long, default_value = None)]
     output_cell_hulls: Option<String>,
 
+    #[arg(long, value_enum, default_value_t = GeometryFormat::Infer)]
+    output_cell_hulls_fmt: GeometryFormat,
+
+    /// Use concave rather than convex cell hulls, with this concavity. Smaller
+    /// values follow the transcripts more closely, while large values
+    /// approach the convex hull.
+    #[arg(long, default_value = None)]
+    cell_hull_alpha: Option<f32>,
+
+    /// Output a polygon outlining the region covered by transcripts
+    #[arg(long, default_value = None)]
+    output_tissue_boundary: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = GeometryFormat::Infer)]
+    output_tissue_boundary_fmt: GeometryFormat,
+
+    /// Resolution used to trace the tissue boundary. Defaults to twice the
+    /// square root of the mean nucleus area.
+    #[arg(long, default_value = None)]
+    tissue_boundary_voxel_size: Option<f32>,
+
+    /// Output a table of transcript density (transcripts per square micron)
+    /// on a grid over the xy plane
+    #[arg(long, default_value = None)]
+    output_density: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_density_fmt: OutputFormat,
+
+    /// Side length of the bins used for --output-density
+    #[arg(long, default_value_t = 10.0)]
+    density_bin_size: f32,
+
+    /// Include a density column for each gene in --output-density
+    #[arg(long, default_value_t = false)]
+    density_per_gene: bool,
+
+    /// Output per-iteration proposal acceptance statistics
+    #[arg(long, default_value = None)]
+    output_proposal_stats: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_proposal_stats_fmt: OutputFormat,
+
+    /// Output a JSON file summarizing the run, including how many seeded cells
+    /// ended up with no assigned transcripts.
+    #[arg(long, default_value = None)]
+    output_run_metadata: Option<String>,
+
+    /// Output a JSON file summarizing common QC statistics: the distributions
+    /// of transcripts per cell, genes per cell, and cell volume, and the
+    /// fraction of transcripts assigned, background, and confusion.
+    #[arg(long, default_value = None)]
+    output_qc_summary: Option<String>,
+
+    /// Allow birth and death moves that add cells from dense clusters of
+    /// background transcripts, and remove them again. Reserved cells that are
+    /// never born are output as empty cells.
+    #[arg(long, default_value_t = false)]
+    allow_cell_birth: bool,
+
+    /// Number of cells reserved for --allow-cell-birth (default: a tenth of
+    /// the number of nuclei, at least 10)
+    #[arg(long, default_value = None)]
+    max_born_cells: Option<usize>,
+
+    /// Check that the summaries computed from the final sampler state
+    /// (likelihood, counts, expected counts, centroids) are identical when
+    /// recomputed on a single thread. This only checks the final summaries:
+    /// sampling isn't seeded, so separate runs, at any thread count, differ.
+    #[arg(long, default_value_t = false)]
+    check_final_summaries: bool,
+
+    /// Flag neighboring cells of the same component as likely over-split when
+    /// their shared boundary is at least this fraction of the smaller cell's
+    /// boundary. Reported in the `oversplit_partner` cell metadata column.
+    #[arg(long, default_value_t = 0.3)]
+    oversplit_contact_fraction: f32,
+
+    /// Merge the likely over-split cell pairs, keeping the merged-away cell as
+    /// an empty row.
+    #[arg(long, default_value_t = false)]
+    merge_oversplit: bool,
+
+    /// Floor on cell volume in cubic microns (or whatever unit coordinates
+    /// are in). Cells are clamped to this and treated as degenerate at it.
+    #[arg(long, default_value = None, conflicts_with = "min_cell_volume_factor")]
+    min_cell_volume: Option<f32>,
+
+    /// Set the cell volume floor to this factor times the mean nucleus area
+    /// times the z span.
+    #[arg(long, default_value_t = 1e-6)]
+    min_cell_volume_factor: f32,
+
+    /// Soft cap on cell volume, in the same units as --min-cell-volume.
+    /// Growing past it is increasingly penalized, and cells that end up near
+    /// or over it are reported.
+    #[arg(long, default_value = None)]
+    max_cell_volume: Option<f32>,
+
+    /// How to handle cells whose volume has collapsed to the minimum cell
+    /// volume. Merged and dropped cells are kept as empty rows so cell indexes
+    /// stay consistent across outputs.
+    #[arg(long, value_enum, default_value_t = DegenerateCells::Flag)]
+    degenerate_cells: DegenerateCells,
+
+    /// Print the input's column names with types and a few sample values,
+    /// to help with setting --x-column etc., then exit.
+    #[arg(long, default_value_t = false)]
+    list_columns: bool,
+
+    /// Read and filter the data, report dataset statistics and resolved
+    /// arguments, then exit without sampling.
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+
+    /// Write polygons for the initial nucleus segmentation, at the initial
+    /// voxel resolution, and exit without sampling.
+    #[arg(long, default_value_t = false)]
+    nucleus_polygons_only: bool,
+
     /// Output cell metadata
     #[arg(long, default_value = "cell-metadata.csv.gz")]
     output_cell_metadata: Option<String>,
@@ -286,11 +773,28 @@ struct Args {
     #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
     output_cell_voxels_fmt: OutputFormat,
 
+    /// Output the voxel table after each resolution level of the schedule,
+    /// inserting the level number into the filename (e.g. cell-voxels.csv.gz
+    /// becomes cell-voxels-level0.csv.gz, cell-voxels-level1.csv.gz, ...)
+    #[arg(long, default_value=None)]
+    output_cell_voxels_each_level: Option<String>,
+
+    /// Output a labeled voxel volume as a (z, y, x) NPY array, with an
+    /// accompanying JSON file giving the voxel-to-micron affine
+    #[arg(long, default_value = None)]
+    output_label_volume: Option<String>,
+
     /// Output consensus non-overlapping 2D polygons, formed by taking the
     /// dominant cell at each x/y location.
     #[arg(long, default_value = "cell-polygons.geojson.gz")]
     output_cell_polygons: Option<String>,
 
+    /// Format for --output-cell-polygons: GeoJSON, or a CSV table of cell ids
+    /// and WKT multipolygons for tools that don't read GeoJSON. Inferred from
+    /// the file name by default.
+    #[arg(long, value_enum, default_value_t = GeometryFormat::Infer)]
+    output_cell_polygons_fmt: GeometryFormat,
+
     /// Output cell polygons flattened (unioned) to 2D
     #[arg(long, default_value = "union-cell-polygons.geojson.gz")]
     output_union_cell_polygons: Option<String>,
@@ -299,6 +803,11 @@ struct Args {
     #[arg(long, default_value = "cell-polygons-layers.geojson.gz")]
     output_cell_polygon_layers: Option<String>,
 
+    /// Output consensus cell polygons split into one file per mixture
+    /// component, named by appending the component to this basename
+    #[arg(long, default_value = None)]
+    output_cell_polygons_by_component: Option<String>,
+
     /// Output cell polygons repeatedly during sampling
     #[arg(long, default_value = None)]
     monitor_cell_polygons: Option<String>,
@@ -307,6 +816,11 @@ struct Args {
     #[arg(long, default_value_t = 10)]
     monitor_cell_polygons_freq: usize,
 
+    /// How frequently (in iterations) to recompute the log-likelihood shown in
+    /// the progress bar. Set to 1 to compute it every iteration.
+    #[arg(long, default_value_t = 10)]
+    loglik_every: usize,
+
     /// Use connectivity checks to prevent cells from having any disconnected voxels
     #[arg(long, default_value_t = true)]
     enforce_connectivity: bool,
@@ -342,6 +856,12 @@ fn set_cosmx_presets(args: &mut Args) {
         .get_or_insert(String::from("CellComp"));
     args.compartment_nuclear
         .get_or_insert(String::from("Nuclear"));
+    args.compartment_cytoplasm
+        .get_or_insert(String::from("Cytoplasm"));
+    args.compartment_membrane
+        .get_or_insert(String::from("Membrane"));
+    args.compartment_extracellular
+        .get_or_insert(String::from("None"));
     args.fov_column.get_or_insert(String::from("fov"));
     args.cell_id_column.get_or_insert(String::from("cell"));
     args.cell_id_unassigned.get_or_insert(String::from(""));
@@ -363,6 +883,12 @@ fn set_cosmx_micron_presets(args: &mut Args) {
         .get_or_insert(String::from("CellComp"));
     args.compartment_nuclear
         .get_or_insert(String::from("Nuclear"));
+    args.compartment_cytoplasm
+        .get_or_insert(String::from("Cytoplasm"));
+    args.compartment_membrane
+        .get_or_insert(String::from("Membrane"));
+    args.compartment_extracellular
+        .get_or_insert(String::from("None"));
     args.fov_column.get_or_insert(String::from("fov"));
     args.cell_id_column.get_or_insert(String::from("cell_ID"));
     args.cell_id_unassigned.get_or_insert(String::from("0"));
@@ -392,6 +918,93 @@ fn set_merscope_presets(args: &mut Args) {
     args.initial_voxel_size = 4.0;
 }
 
+// --list-columns: print each column's name, type, and first few values.
+fn list_columns(path: &str) {
+    const NROWS: usize = 5;
+    let columns = read_transcripts_sample(path, NROWS);
+    let name_width = columns.iter().map(|(name, _, _)| name.len()).max().unwrap_or(0);
+    let type_width = columns.iter().map(|(_, t, _)| t.len()).max().unwrap_or(0);
+    println!("{} columns in {}:", columns.len(), path);
+    for (name, column_type, values) in &columns {
+        println!(
+            "  {:name_width$}  {:type_width$}  {}",
+            name,
+            column_type,
+            values.iter().join(", "),
+        );
+    }
+}
+
+// Guess which preset, or failing that which individual columns, to use by
+// looking at the column names of the transcript table. Only used when no preset
+// is given, and never overrides columns that were set explicitly.
+fn autodetect_columns(args: &mut Args) {
+    let headers = read_transcripts_header(&args.transcript_csv);
+    let has_columns = |columns: &[&str]| columns.iter().all(|c| headers.iter().any(|h| h == c));
+
+    // Preset name, the columns it requires, and the function to apply it.
+    type Preset = (&'static str, &'static [&'static str], fn(&mut Args));
+    let presets: [Preset; 5] = [
+        ("xenium", &["feature_name", "x_location", "y_location", "z_location", "cell_id"], set_xenium_presets),
+        ("cosmx", &["target", "x_global_px", "y_global_px", "z", "cell", "cell_ID"], set_cosmx_presets),
+        ("cosmx-micron", &["target", "x", "y", "z", "cell_ID", "CellComp"], set_cosmx_micron_presets),
+        ("merscope", &["gene", "global_x", "global_y", "global_z", "cell_id"], set_merscope_presets),
+        ("merfish", &["gene", "x", "y", "z", "cell"], set_merfish_presets),
+    ];
+
+    let matching_presets = presets
+        .iter()
+        .filter(|(_, columns, _)| has_columns(columns))
+        .collect::<Vec<_>>();
+
+    if matching_presets.len() == 1 {
+        let (name, _, set_presets) = matching_presets[0];
+        println!("Columns look like {} data. Using --{} presets.", name, name);
+        set_presets(args);
+        return;
+    } else if matching_presets.len() > 1 {
+        panic!(
+            "Columns are consistent with multiple presets ({}). Specify one explicitly.",
+            matching_presets.iter().map(|(name, _, _)| format!("--{}", name)).join(", ")
+        );
+    }
+
+    fn detect_column(headers: &[String], column: &mut Option<String>, argname: &str, candidates: &[&str]) {
+        if column.is_some() {
+            return;
+        }
+
+        let found = candidates
+            .iter()
+            .filter(|c| headers.iter().any(|h| h == *c))
+            .collect::<Vec<_>>();
+
+        match found.len() {
+            0 => {}
+            1 => {
+                println!("Using column '{}' for --{}", found[0], argname);
+                column.replace(found[0].to_string());
+            }
+            _ => panic!(
+                "Ambiguous columns for --{}: {}. Specify one explicitly.",
+                argname,
+                found.iter().join(", ")
+            ),
+        }
+    }
+
+    detect_column(&headers, &mut args.gene_column, "gene-column",
+        &["feature_name", "target", "gene", "gene_name"]);
+    detect_column(&headers, &mut args.x_column, "x-column",
+        &["x_location", "x_global_px", "global_x", "x"]);
+    detect_column(&headers, &mut args.y_column, "y-column",
+        &["y_location", "y_global_px", "global_y", "y"]);
+    detect_column(&headers, &mut args.z_column, "z-column",
+        &["z_location", "global_z", "z"]);
+    detect_column(&headers, &mut args.cell_id_column, "cell-id-column",
+        &["cell_id", "cell_ID", "cell"]);
+}
+
 fn main() {
     // // TODO: Just testing PG sampling
     // {
@@ -412,6 +1025,37 @@ fn main() {
 
     let mut args = Args::parse();
 
+    println!("{}", version_banner());
+    println!("Command line: {}", command_line());
+
+    set_output_precision(args.output_precision);
+    set_gzip_level(args.gzip_level);
+    set_output_compression(args.compression);
+    set_cell_ids(&args.cell_id_prefix, args.cell_id_start);
+
+    if args.list_columns {
+        list_columns(&args.transcript_csv);
+        return;
+    }
+
+    drop_empty_outputs(&mut args);
+    if args.counts_only {
+        keep_only_counts_output(&mut args);
+    }
+    rename_wkt_polygons_output(&mut args);
+    rename_compressed_outputs(&mut args);
+    if let Some(out_dir) = args.out_dir.clone() {
+        place_outputs_in_dir(&mut args, &out_dir);
+    }
+
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, AtomicOrdering::Relaxed) {
+            std::process::exit(130);
+        }
+        eprintln!("\nInterrupted. Finishing the current iteration and writing outputs (Ctrl-C again to exit immediately).");
+    })
+    .expect("Unable to install Ctrl-C handler");
+
     if let Some(nthreads) = args.nthreads {
         rayon::ThreadPoolBuilder::new()
             .num_threads(nthreads)
@@ -454,21 +1098,49 @@ fn main() {
         set_merscope_presets(&mut args);
     }
 
-    if args.recorded_samples > *args.schedule.last().unwrap() {
-        panic!("recorded-samples must be <= the last entry in the schedule");
+    if !(args.xenium || args.cosmx || args.cosmx_micron || args.merfish || args.merscope) {
+        autodetect_columns(&mut args);
+    }
+
+    validate_args(&args);
+
+    if args.dry_run {
+        println!("Resolved arguments: {:#?}", args);
+    }
+
+    if args.loglik_every == 0 {
+        panic!("loglik-every must be at least 1");
     }
 
     if args.use_cell_initialization {
         args.compartment_column = None;
         args.compartment_nuclear = None;
+        args.compartment_cytoplasm = None;
+        args.compartment_membrane = None;
+        args.compartment_extracellular = None;
     }
 
     assert!(args.ncomponents > 0);
+    assert!(args.max_components > 0);
 
     fn expect_arg<T>(arg: Option<T>, argname: &str) -> T {
         arg.unwrap_or_else(|| panic!("Missing required argument: --{}", argname))
     }
 
+    if args.coord_affine.len() != 6 {
+        panic!("--coord-affine must be given exactly six values");
+    }
+
+    if args.by_fov {
+        run_by_fov(&args);
+        return;
+    }
+
+    if args.restarts > 1 {
+        run_restarts(&args);
+        return;
+    }
+
     /* let (transcript_names,
     mut transcripts,
     mut nucleus_assignments,
@@ -481,20 +1153,43 @@ fn main() {
         args.transcript_id_column,
         args.compartment_column,
         args.compartment_nuclear,
+        CompartmentValues {
+            cytoplasm: args.compartment_cytoplasm,
+            membrane: args.compartment_membrane,
+            extracellular: args.compartment_extracellular,
+        },
         args.fov_column,
         args.cell_assignment_column,
         args.cell_assignment_unassigned,
         &expect_arg(args.cell_id_column, "cell-id-column"),
         &expect_arg(args.cell_id_unassigned, "cell-id-unassigned"),
         args.qv_column,
+        args.count_column,
+        args.prior_cell_prob_column,
         &expect_arg(args.x_column, "x-column"),
         &expect_arg(args.y_column, "y-column"),
         &expect_arg(args.z_column, "z-column"),
         args.min_qv,
         args.ignore_z_coord,
         args.coordinate_scale.unwrap_or(1.0),
+        args.z_scale,
+        args.coord_affine.clone().try_into().unwrap(),
     );
 
+    if args.normalize_gene_names || args.uppercase_gene_names {
+        let duplicated = normalize_gene_names(&mut dataset, args.uppercase_gene_names, args.duplicate_genes);
+        for (name, original_names) in &duplicated {
+            let original_names = original_names.iter().map(|n| format!("'{}'", n)).join(", ");
+            if args.duplicate_genes == DuplicateGenes::Suffix {
+                println!("Suffixed genes {} with the same name {}", original_names, name);
+            } else {
+                println!("Merged genes {} into {}", original_names, name);
+            }
+        }
+    }
+
+    check_coordinate_units(&dataset.transcripts);
+
     // Warn if any nucleus has extremely high population, which is likely
     // an error interpreting the file.
     dataset.nucleus_population.iter().for_each(|&p| {
@@ -512,26 +1207,126 @@ fn main() {
     // Clamp transcript depth
     // This is we get some reasonable depth slices when we step up to
     // 3d sampling.
-    let zs: Vec<f32> = dataset
-        .transcripts
-        .iter()
-        .map(|t| t.z)
-        .sorted_by(|a, b| a.partial_cmp(b).unwrap())
-        .collect();
+    let (zmin, zmax) = if args.no_z_clamp {
+        dataset
+            .transcripts
+            .iter()
+            .fold((f32::MAX, f32::MIN), |(zmin, zmax), t| {
+                (zmin.min(t.z), zmax.max(t.z))
+            })
+    } else {
+        if args.z_clamp_quantiles.len() != 2 {
+            panic!("--z-clamp-quantiles must be given exactly two values");
+        }
+        let (q0, q1) = (args.z_clamp_quantiles[0], args.z_clamp_quantiles[1]);
+        if !(0.0..=1.0).contains(&q0) || !(0.0..=1.0).contains(&q1) || q0 > q1 {
+            panic!("--z-clamp-quantiles must satisfy 0 <= lo <= hi <= 1");
+        }
 
-    let (q0, q1) = (0.01, 0.99);
-    let zmin = zs[(q0 * (zs.len() as f32)) as usize];
-    let zmax = zs[(q1 * (zs.len() as f32)) as usize];
-    for t in &mut dataset.transcripts {
-        t.z = t.z.max(zmin).min(zmax);
-    }
+        let zs: Vec<f32> = dataset
+            .transcripts
+            .iter()
+            .map(|t| t.z)
+            .sorted_by(|a, b| a.partial_cmp(b).unwrap())
+            .collect();
+
+        let quantile = |q: f32| zs[((q * (zs.len() as f32)) as usize).min(zs.len() - 1)];
+        let zmin = quantile(q0);
+        let zmax = quantile(q1);
+        for t in &mut dataset.transcripts {
+            t.z = t.z.max(zmin).min(zmax);
+        }
+        (zmin, zmax)
+    };
+
+    // A huge z span, e.g. from nanometers, would make layers and z-diffusion
+    // meaningless and clamping doesn't help, so rescale it.
+    let (zmin, zmax) = match check_z_span(&dataset.transcripts, zmin, zmax) {
+        Some(factor) if !args.no_auto_z_scale => {
+            eprintln!("Rescaling z-coordinates by {} (disable with --no-auto-z-scale).", factor);
+            for t in &mut dataset.transcripts {
+                t.z *= factor;
+            }
+            (zmin * factor, zmax * factor)
+        }
+        _ => (zmin, zmax),
+    };
 
     let mut ncells = dataset.nucleus_population.len();
-    filter_cellfree_transcripts(&mut dataset, ncells, args.max_transcript_nucleus_distance);
+    let mut filtering = FilteringReport {
+        transcripts_read: dataset.transcripts.len() + dataset.nfiltered_qv,
+        low_qv_transcripts: dataset.nfiltered_qv,
+        initial_cells: ncells,
+        ..Default::default()
+    };
+    let ntranscripts_unfiltered = dataset.transcripts.len();
+    let nucleus_distances =
+        filter_cellfree_transcripts(
+            &mut dataset,
+            ncells,
+            args.max_transcript_nucleus_distance,
+            args.nucleus_distance_metric,
+        );
+    filtering.distant_transcripts = ntranscripts_unfiltered - dataset.transcripts.len();
+
+    if args.calibrate_voxel_size {
+        args.initial_voxel_size = calibrate_voxel_size(
+            ncells,
+            &dataset.transcripts,
+            &dataset.nucleus_assignments,
+            args.calibrate_voxel_size_target,
+        );
+    }
+
+    const MAX_ZLAYERS: usize = 30;
+    let zlevels = discrete_z_levels(&dataset.transcripts, MAX_ZLAYERS);
+    if args.detect_layers {
+        if let Some(zlevels) = zlevels {
+            args.nbglayers = zlevels;
+            println!("Detected {} z-layers", args.nbglayers);
+        }
+    }
+    if let Some(target_z_layers) = args.target_z_layers {
+        if target_z_layers == 0 {
+            panic!("--target-z-layers must be at least 1");
+        }
+        args.nbglayers = target_z_layers;
+    }
+    let ndoublings = args
+        .schedule
+        .len()
+        .saturating_sub(1)
+        .min(args.max_resolution_level.unwrap_or(usize::MAX));
+    println!(
+        "{} schedule phases: {} resolution doublings, to a final voxel size of {} with {} voxel layers",
+        args.schedule.len(),
+        ndoublings,
+        args.initial_voxel_size / 2.0_f32.powi(ndoublings as i32),
+        final_voxel_layers(args.voxel_layers, ndoublings, args.double_z_layers, args.target_z_layers)
+    );
+    check_layer_counts(
+        args.voxel_layers,
+        args.nbglayers,
+        ndoublings,
+        args.double_z_layers,
+        zlevels,
+        args.target_z_layers,
+    );
 
     // keep removing cells until we can initialize with every cell having at least one voxel
+    const MAX_SPARSE_CELL_PASSES: usize = 50;
     loop {
         let prev_ncells = ncells;
+        if filtering.sparse_cells_removed.len() == MAX_SPARSE_CELL_PASSES {
+            panic!(
+                "Removing cells with no voxels of their own didn't settle after {} passes ({} cells removed, {} left). \
+                This usually means the voxel grid doesn't suit the data's scale: check --coordinate-scale, \
+                or try a smaller --initial-voxel-size or fewer --voxel-layers.",
+                MAX_SPARSE_CELL_PASSES,
+                filtering.sparse_cells_removed.iter().sum::<usize>(),
+                ncells
+            );
+        }
 
         filter_sparse_cells(
             args.initial_voxel_size,
@@ -545,40 +1340,50 @@ fn main() {
         if ncells == prev_ncells {
             break;
         }
+        filtering.sparse_cells_removed.push(prev_ncells - ncells);
+        println!(
+            "Sparse cell filter pass {}: removed {} cells with no voxels of their own",
+            filtering.sparse_cells_removed.len(),
+            prev_ncells - ncells
+        );
+    }
+
+    let removed_genes = filter_absent_genes(&mut dataset);
+    filtering.absent_genes = removed_genes.len();
+    filtering.print();
+    if !removed_genes.is_empty() {
+        println!(
+            "Removed {} genes with no remaining transcripts: {}",
+            removed_genes.len(),
+            removed_genes.join(", ")
+        );
     }
 
     let ngenes = dataset.transcript_names.len();
     let ncells = dataset.nucleus_population.len();
     let ntranscripts = dataset.transcripts.len();
 
+    // k-means initialization needs at least as many cells as components
+    if args.components_auto {
+        args.ncomponents = args.max_components.min(ncells);
+    }
+
     let nucleus_areas =
         compute_cell_areas(ncells, &dataset.transcripts, &dataset.nucleus_assignments);
     let mean_nucleus_area = nucleus_areas.iter().sum::<f32>()
         / nucleus_areas.iter().filter(|a| **a > 0.0).count() as f32;
 
-    if args.detect_layers {
-        const MAX_ZLAYERS: usize = 30;
-        let mut undetectable = false;
-        let mut zlayers = HashSet::new();
-        for t in &dataset.transcripts {
-            if t.z.round() == t.z {
-                zlayers.insert(t.z as i32);
-            } else {
-                undetectable = true;
-                break;
-            }
-        }
-
-        if !undetectable && zlayers.len() <= MAX_ZLAYERS {
-            args.nbglayers = zlayers.len();
-            println!("Detected {} z-layers", args.nbglayers);
-        }
-    }
-
-    let mut layer_depth = 1.01 * (zmax - zmin) / (args.nbglayers as f32);
+    let mut layer_depth = if args.target_z_layers.is_some() {
+        (zmax - zmin) / (args.nbglayers as f32)
+    } else {
+        1.01 * (zmax - zmin) / (args.nbglayers as f32)
+    };
     if layer_depth == 0.0 {
         layer_depth = 1.0;
     }
+    if args.target_z_layers.is_some() {
+        println!("Using {} z-layers {} deep", args.nbglayers, layer_depth);
+    }
 
     println!("Read {} transcripts", ntranscripts);
     println!("     {} cells", ncells);
@@ -590,9 +1395,55 @@ fn main() {
         zspan = 1.0;
     }
 
-    let full_area = estimate_full_area(&dataset.transcripts, mean_nucleus_area);
-    println!("Estimated full area: {}", full_area);
-    let full_volume = full_area * zspan;
+    let tissue_boundary_voxel_size = args
+        .tissue_boundary_voxel_size
+        .unwrap_or(2.0 * mean_nucleus_area.sqrt());
+    if args.output_tissue_boundary.is_some() {
+        write_tissue_boundary(
+            &args.output_tissue_boundary,
+            args.output_tissue_boundary_fmt,
+            tissue_boundary(&dataset.transcripts, tissue_boundary_voxel_size),
+        );
+    }
+
+    write_transcript_density(
+        &args.output_density,
+        args.output_density_fmt,
+        &dataset.transcripts,
+        &dataset.transcript_names,
+        args.density_bin_size,
+        args.density_per_gene,
+    );
+
+    if let Some(path) = &args.counts_from_transcript_metadata {
+        counts_from_prior_assignments(
+            path,
+            &dataset,
+            args.count_pr_cutoff,
+            args.output_background_cell,
+            args.min_gene_total,
+            &args.output_maxpost_counts,
+            args.output_maxpost_counts_fmt,
+            &args.output_cell_correspondence,
+            args.output_cell_correspondence_fmt,
+        );
+        return;
+    }
+
+    // Everything from here on reads transcripts through this slice, so with
+    // --mmap-transcripts the parsed transcripts are moved out to disk.
+    let mapped_transcripts = args
+        .mmap_transcripts
+        .as_ref()
+        .map(|path| MappedTranscripts::new(path, std::mem::take(&mut dataset.transcripts)));
+    let transcripts = match &mapped_transcripts {
+        Some(mapped_transcripts) => mapped_transcripts.as_slice(),
+        None => &dataset.transcripts,
+    };
+
+    let full_area = estimate_full_area(transcripts, mean_nucleus_area);
+    println!("Estimated full area: {}", full_area);
+    let full_volume = full_area * zspan;
 
     let full_layer_volume = full_volume / (args.nbglayers as f32);
     println!("Full volume: {}", full_volume);
@@ -613,7 +1464,48 @@ fn main() {
         nchunks(chunk_size, xspan, yspan)
     );
 
-    let min_cell_volume = 1e-6 * mean_nucleus_area * zspan;
+    if args.dry_run {
+        println!("Coordinate span: x: [{}, {}], y: [{}, {}], z: [{}, {}]", xmin, xmax, ymin, ymax, zmin, zmax);
+        println!("Mean nucleus area: {}", mean_nucleus_area);
+        println!("Initial voxel size: {}", args.initial_voxel_size);
+        let memory = estimate_memory_usage(
+            ntranscripts,
+            ncells,
+            ngenes,
+            args.ncomponents,
+            full_volume,
+            zspan,
+            args.initial_voxel_size,
+            final_voxel_layers(args.voxel_layers, ndoublings, args.double_z_layers, args.target_z_layers),
+            ndoublings + 1,
+        );
+        println!("Estimated memory use: {:.1} MB", memory as f64 / 1e6);
+        return;
+    }
+
+    let min_cell_volume = args
+        .min_cell_volume
+        .unwrap_or(args.min_cell_volume_factor * mean_nucleus_area * zspan);
+    println!("Minimum cell volume: {}", min_cell_volume);
+
+    let mut gene_diffusion = vec![!args.no_diffusion; ngenes];
+    if let Some(path) = &args.diffusion_genes {
+        for (d, listed) in gene_diffusion.iter_mut().zip(read_gene_set(path, &dataset.transcript_names)) {
+            *d |= listed;
+        }
+    }
+    if let Some(path) = &args.no_diffusion_genes {
+        for (d, listed) in gene_diffusion.iter_mut().zip(read_gene_set(path, &dataset.transcript_names)) {
+            *d &= !listed;
+        }
+    }
+    if args.diffusion_genes.is_some() || args.no_diffusion_genes.is_some() {
+        println!(
+            "Diffusion enabled for {} of {} genes",
+            gene_diffusion.iter().filter(|&&d| d).count(),
+            ngenes
+        );
+    }
 
     let priors = ModelPriors {
         dispersion: args.dispersion,
@@ -622,8 +1514,11 @@ fn main() {
         } else {
             Some(args.burnin_dispersion)
         },
+        per_gene_dispersion: args.per_gene_dispersion,
+        component_concentration: if args.components_auto { Some(args.components_concentration) } else { None },
 
         min_cell_volume,
+        max_cell_volume: args.max_cell_volume,
 
         μ_μ_volume: (2.0 * mean_nucleus_area * zspan).ln(),
         σ_μ_volume: 3.0_f32,
@@ -643,36 +1538,59 @@ fn main() {
         α_c: 1.0,
         β_c: 1.0,
 
-        perimeter_eta: 5.3,
+        confusion_prior_rate: args.confusion_prior_rate,
+        confusion_prior_weight: args.confusion_prior_weight,
+
+        perimeter_eta: args.perimeter_eta,
         perimeter_bound: args.perimeter_bound,
 
         nuclear_reassignment_log_prob: args.nuclear_reassignment_prob.ln(),
         nuclear_reassignment_1mlog_prob: (1.0 - args.nuclear_reassignment_prob).ln(),
 
+        nuclear_marker_reassignment_log_prob: args.nuclear_marker_reassignment_prob.ln(),
+        nuclear_marker_reassignment_1mlog_prob: (1.0 - args.nuclear_marker_reassignment_prob).ln(),
+
         prior_seg_reassignment_log_prob: args.prior_seg_reassignment_prob.ln(),
         prior_seg_reassignment_1mlog_prob: (1.0 - args.prior_seg_reassignment_prob).ln(),
 
-        use_diffusion_model: !args.no_diffusion,
+        use_diffusion_model: gene_diffusion.iter().any(|&d| d),
         σ_diffusion_proposal: args.diffusion_proposal_sigma,
         p_diffusion: args.diffusion_probability,
         σ_diffusion_near: args.diffusion_sigma_near,
         σ_diffusion_far: args.diffusion_sigma_far,
 
-        σ_z_diffusion_proposal: 0.2 * zspan,
-        σ_z_diffusion: 0.2 * zspan,
+        σ_z_diffusion_proposal: args.z_diffusion_proposal_sigma.unwrap_or(0.2 * zspan),
+        σ_z_diffusion: args.z_diffusion_sigma.unwrap_or(0.2 * zspan),
 
         zmin,
         zmax,
 
         enforce_connectivity: args.enforce_connectivity,
+
+        allow_cell_birth: args.allow_cell_birth,
     };
 
+    // Seed voxels from nuclei alone, ignoring any prior cell segmentation.
+    if args.nucleus_polygons_only {
+        dataset.cell_assignments = dataset.nucleus_assignments.clone();
+    }
+
+    // Cells that births can draw from are allocated up front, as empty cells
+    // after the nucleus-seeded ones.
+    let nseeded_cells = ncells;
+    if args.allow_cell_birth {
+        let npool = args.max_born_cells.unwrap_or((ncells / 10).max(10));
+        dataset.nucleus_population.resize(ncells + npool, 0);
+        println!("Reserved {} cells for births", npool);
+    }
+    let ncells = dataset.nucleus_population.len();
+
     let mut params = ModelParams::new(
         &priors,
         full_layer_volume,
         zmin,
         layer_depth,
-        &dataset.transcripts,
+        transcripts,
         &dataset.nucleus_assignments,
         &dataset.nucleus_population,
         &dataset.cell_assignments,
@@ -682,20 +1600,52 @@ fn main() {
         ngenes,
     );
 
-    let total_iterations = args.schedule.iter().sum::<usize>();
-    let mut prog = ProgressBar::new(total_iterations as u64);
-    prog.set_style(
-        ProgressStyle::with_template("{eta_precise} {bar:60} | {msg}")
-            .unwrap()
-            .progress_chars("##-"),
-    );
+    if let Some(path) = &args.nuclear_marker_genes {
+        params.nuclear_marker = read_nuclear_marker_genes(path, &dataset.transcript_names, transcripts);
+    }
+    params.gene_diffusion = gene_diffusion;
+    params.reserve_birth_pool(nseeded_cells);
+
+    if let Some(path) = &args.init_from_transcript_metadata {
+        let prior = read_prior_transcript_assignments(path);
+        let (cell_assignments, cell_population) = warm_start_cell_assignments(
+            transcripts,
+            &dataset.nucleus_assignments,
+            ncells,
+            &prior,
+        );
+        params.cell_assignments = cell_assignments;
+        params.cell_population = cell_population;
+    }
+
+    let weigh_membrane = args.membrane_prior_weight < 1.0
+        && dataset.compartments.contains(&Compartment::Membrane);
+    if !dataset.prior_cell_probs.is_empty() || weigh_membrane {
+        params.set_prior_cell_probs(&priors, &prior_seg_weights(&dataset, args.membrane_prior_weight));
+    }
+    if !dataset.prior_cell_probs.is_empty() && args.init_from_transcript_metadata.is_none() {
+        init_from_prior_cell_probs(&mut params, &dataset);
+    }
+    if let Some(path) = &args.background_prior {
+        let rates = read_background_prior(path, &dataset.transcript_names);
+        println!(
+            "Using a background rate prior for {} of {} genes",
+            rates.iter().filter(|r| r.is_some()).count(),
+            ngenes
+        );
+        params.set_background_rate_priors(&priors, &rates, args.background_prior_weight);
+    }
+
+    let total_iterations = args.schedule.iter().sum::<usize>()
+        + args.recorded_samples * (args.recorded_thinning - 1);
+    let mut prog = Progress::new(total_iterations, args.quiet);
 
     let mut uncertainty = UncertaintyTracker::new();
 
     let mut sampler = RefCell::new(VoxelSampler::new(
         &priors,
         &mut params,
-        &dataset.transcripts,
+        transcripts,
         ngenes,
         args.voxel_layers,
         args.nbglayers,
@@ -703,10 +1653,32 @@ fn main() {
         layer_depth,
         args.initial_voxel_size,
         chunk_size,
+        if args.adaptive_chunks {
+            Some(args.cells_per_chunk)
+        } else {
+            None
+        },
     ));
     sampler.borrow_mut().initialize(&priors, &mut params);
+    if args.adaptive_chunks {
+        println!("Adaptive chunks: {}", sampler.borrow().nchunks());
+    }
+
+    if args.nucleus_polygons_only {
+        write_polygons(
+            &sampler.borrow(),
+            &params,
+            &args.output_cell_polygons,
+            args.output_cell_polygons_fmt,
+            &args.output_cell_polygon_layers,
+            &args.output_union_cell_polygons,
+            &args.output_cell_polygons_by_component,
+        );
+        return;
+    }
 
     let mut total_steps = 0;
+    let mut proposal_stats_trace = Vec::new();
 
     if args.schedule.len() > 1 {
         run_hexbin_sampler(
@@ -714,11 +1686,14 @@ fn main() {
             sampler.get_mut(),
             &priors,
             &mut params,
-            &dataset.transcripts,
+            transcripts,
             args.schedule[0],
             args.morphology_steps_per_iter,
             None,
+            1,
             &mut total_steps,
+            &mut proposal_stats_trace,
+            args.loglik_every,
             &args.monitor_cell_polygons,
             args.monitor_cell_polygons_freq,
             true,
@@ -726,34 +1701,59 @@ fn main() {
             false,
         );
 
-        for &niter in args.schedule[1..args.schedule.len() - 1].iter() {
+        write_level_voxels(
+            &args.output_cell_voxels_each_level,
+            args.output_cell_voxels_fmt,
+            &sampler.borrow(),
+            0,
+        );
+
+        for (level, &niter) in args.schedule[1..args.schedule.len() - 1].iter().enumerate() {
+            if interrupted() {
+                break;
+            }
             if args.check_consistency {
                 sampler.borrow_mut().check_consistency(&priors, &mut params);
             }
 
-            sampler
-                .replace_with(|sampler| sampler.double_resolution(&params, args.double_z_layers));
+            if level < ndoublings {
+                let double_z = doubles_z_layers(sampler.borrow().voxel_layers(), args.double_z_layers, args.target_z_layers);
+                sampler.replace_with(|sampler| sampler.double_resolution(&params, double_z));
+            }
             run_hexbin_sampler(
                 &mut prog,
                 sampler.get_mut(),
                 &priors,
                 &mut params,
-                &dataset.transcripts,
+                transcripts,
                 niter,
                 args.morphology_steps_per_iter,
                 None,
+                1,
                 &mut total_steps,
+                &mut proposal_stats_trace,
+                args.loglik_every,
                 &args.monitor_cell_polygons,
                 args.monitor_cell_polygons_freq,
                 true,
                 true,
                 false,
             );
+
+            write_level_voxels(
+                &args.output_cell_voxels_each_level,
+                args.output_cell_voxels_fmt,
+                &sampler.borrow(),
+                level + 1,
+            );
         }
         if args.check_consistency {
             sampler.borrow_mut().check_consistency(&priors, &mut params);
         }
-        sampler.replace_with(|sampler| sampler.double_resolution(&params, args.double_z_layers));
+        if !interrupted() && args.schedule.len() - 1 <= ndoublings {
+            let double_z = doubles_z_layers(sampler.borrow().voxel_layers(), args.double_z_layers, args.target_z_layers);
+            sampler.replace_with(|sampler| sampler.double_resolution(&params, double_z));
+        }
     }
 
     run_hexbin_sampler(
@@ -761,11 +1761,14 @@ fn main() {
         sampler.get_mut(),
         &priors,
         &mut params,
-        &dataset.transcripts,
+        transcripts,
         *args.schedule.last().unwrap() - args.recorded_samples,
         args.morphology_steps_per_iter,
         None,
+        1,
         &mut total_steps,
+        &mut proposal_stats_trace,
+        args.loglik_every,
         &args.monitor_cell_polygons,
         args.monitor_cell_polygons_freq,
         true,
@@ -778,11 +1781,14 @@ fn main() {
         sampler.get_mut(),
         &priors,
         &mut params,
-        &dataset.transcripts,
-        args.recorded_samples,
+        transcripts,
+        args.recorded_samples * args.recorded_thinning,
         args.morphology_steps_per_iter,
         Some(&mut uncertainty),
+        args.recorded_thinning,
         &mut total_steps,
+        &mut proposal_stats_trace,
+        args.loglik_every,
         &args.monitor_cell_polygons,
         args.monitor_cell_polygons_freq,
         true,
@@ -795,168 +1801,2210 @@ fn main() {
     }
     prog.finish();
 
-    uncertainty.finish(&params);
-    let (counts, cell_assignments) = uncertainty.max_posterior_transcript_counts_assignments(
+    // If interrupted before any samples were recorded, fall back on the
+    // current assignments.
+    if uncertainty.nrecorded_samples() > 0 {
+        uncertainty.finish(&params);
+    } else {
+        uncertainty.finish_unrecorded(&priors, &params);
+    }
+    let (mut counts, mut cell_assignments) = uncertainty.max_posterior_transcript_counts_assignments(
         &params,
-        &dataset.transcripts,
+        transcripts,
         args.count_pr_cutoff,
         args.foreground_pr_cutoff,
     );
 
-    let ecounts = uncertainty.expected_counts(&params, &dataset.transcripts);
-    let cell_centroids = sampler.borrow().cell_centroids();
+    if args.count_at_observed_position {
+        let nmoved = attribute_at_observed_positions(
+            &sampler.borrow(),
+            transcripts,
+            &mut cell_assignments,
+        );
+        let assignments = cell_assignments
+            .iter()
+            .map(|&(cell, pr)| if pr > args.count_pr_cutoff { cell } else { BACKGROUND_CELL })
+            .collect::<Vec<_>>();
+        counts = counts_from_assignments(&assignments, transcripts, ngenes, ncells);
+        println!(
+            "Counting transcripts at observed positions moved {} of {} assignments",
+            nmoved,
+            cell_assignments.len()
+        );
+    }
 
-    write_expected_counts(
-        &args.output_expected_counts,
-        args.output_expected_counts_fmt,
-        &dataset.transcript_names,
-        &ecounts,
-    );
-    write_counts(
-        &args.output_maxpost_counts,
-        args.output_maxpost_counts_fmt,
-        &dataset.transcript_names,
-        &counts,
-    );
-    write_rates(
-        &args.output_rates,
-        args.output_rates_fmt,
-        &params,
-        &dataset.transcript_names,
+    // Expected counts are a full pass over the recorded assignments, so they're
+    // only computed for the outputs that use them.
+    let need_ecounts = args.output_expected_counts.is_some()
+        || args.output_normalized_counts.is_some()
+        || args.output_gene_metadata.is_some()
+        || args.check_final_summaries;
+    let mut ecounts = need_ecounts.then(|| uncertainty.expected_counts(&params, transcripts));
+    let mut cell_centroids = sampler.borrow().cell_centroids();
+    let mut cell_voxel_counts = sampler.borrow().cell_voxel_counts();
+
+    if args.check_final_summaries {
+        check_final_summaries(
+            &priors,
+            &params,
+            &uncertainty,
+            &sampler.borrow(),
+            transcripts,
+            args.count_pr_cutoff,
+            args.foreground_pr_cutoff,
+            &counts,
+            ecounts.as_ref(),
+            &cell_centroids,
+        );
+    }
+    // Perimeters and over-split pairs are only reported, except when merging
+    // over-split cells, so --counts-only skips them.
+    let mut cell_perimeters = if args.counts_only {
+        Vec::new()
+    } else {
+        sampler.borrow().cell_perimeters(&priors)
+    };
+
+    if !args.counts_only {
+        let nbound = cell_perimeters
+            .iter()
+            .filter(|(perimeter, bound)| *perimeter >= *bound)
+            .count();
+        println!("{} cells are at their perimeter bound", nbound);
+    }
+
+    if let Some(max_cell_volume) = args.max_cell_volume {
+        let (nnear, nover) = params
+            .cell_volume
+            .iter()
+            .fold((0, 0), |(nnear, nover), &v| {
+                (nnear + (v >= 0.9 * max_cell_volume) as usize, nover + (v > max_cell_volume) as usize)
+            });
+        println!(
+            "{} cells are within 10% of the maximum cell volume, {} of them over it",
+            nnear, nover
+        );
+    }
+
+    if !params.nuclear_marker.is_empty() {
+        let (nnuclear, nkept) = params
+            .nuclear_marker
+            .iter()
+            .zip(&dataset.nucleus_assignments)
+            .zip(&cell_assignments)
+            .filter(|((&marker, &nucleus), _)| marker && nucleus != BACKGROUND_CELL)
+            .fold((0, 0), |(nnuclear, nkept), ((_, &nucleus), &(cell, _))| {
+                (nnuclear + 1, nkept + (cell == nucleus) as usize)
+            });
+        println!(
+            "{} of {} nuclear marker transcripts in nuclei remained assigned to their nucleus",
+            nkept, nnuclear
+        );
+    }
+
+    if args.components_auto {
+        println!(
+            "Effective components: {} of {}",
+            params.ncomponents_used(),
+            params.ncomponents()
+        );
+    }
+
+    let nempty = (0..nseeded_cells).filter(|&i| counts.column(i).sum() == 0).count();
+    println!(
+        "Seeded cells: {}, assigned cells: {}, empty cells: {}",
+        nseeded_cells,
+        nseeded_cells - nempty,
+        nempty
     );
-    write_component_params(
-        &args.output_component_params,
-        args.output_component_params_fmt,
-        &params,
-        &dataset.transcript_names,
+    println!(
+        "Fitted median cell volume by component: {}",
+        params
+            .volume_distribution()
+            .iter()
+            .filter(|(ncells, _, _)| *ncells > 0)
+            .map(|(_, μ, _)| format!("{:.1}", μ.exp()))
+            .join(", ")
     );
-    write_cell_metadata(
-        &args.output_cell_metadata,
-        args.output_cell_metadata_fmt,
-        &params,
+
+    report_confusion(&params, transcripts, &dataset.transcript_names);
+
+    if let (Some(first), Some(last)) = (proposal_stats_trace.first(), proposal_stats_trace.last()) {
+        println!(
+            "Non-empty cells: {} after the first iteration, {} after the last",
+            first.nonempty_cells, last.nonempty_cells
+        );
+    }
+
+    if args.allow_cell_birth {
+        let (nborn, ndied) = proposal_stats_trace
+            .iter()
+            .fold((0, 0), |(nborn, ndied), s| (nborn + s.cell_birth_accept, ndied + s.cell_death_accept));
+        println!(
+            "Cells born: {}, died: {}, alive at the end: {} of {} reserved",
+            nborn,
+            ndied,
+            params.nborn_cells(),
+            ncells - nseeded_cells
+        );
+    }
+
+    let degenerate = handle_degenerate_cells(
+        args.degenerate_cells,
+        &priors,
+        &mut params,
         &cell_centroids,
-        &cell_assignments,
-        &dataset.fovs,
-        &dataset.fov_names,
-    );
-    write_transcript_metadata(
-        &args.output_transcript_metadata,
-        args.output_transcript_metadata_fmt,
-        &dataset.transcripts,
-        &params.transcript_positions,
-        &dataset.transcript_names,
-        &cell_assignments,
-        &params.transcript_state,
-        &dataset.qvs,
-        &dataset.fovs,
-        &dataset.fov_names,
-    );
-    write_gene_metadata(
-        &args.output_gene_metadata,
-        args.output_gene_metadata_fmt,
-        &params,
-        &dataset.transcript_names,
-        &ecounts,
+        &cell_voxel_counts,
+        &mut cell_assignments,
+        &mut counts,
+        &mut ecounts,
     );
-    write_voxels(
-        &args.output_cell_voxels,
-        args.output_cell_voxels_fmt,
-        &sampler.borrow(),
+
+    let oversplit_partners = if args.counts_only && !args.merge_oversplit {
+        Vec::new()
+    } else {
+        find_oversplit_cells(
+            &params,
+            &sampler.borrow(),
+            args.oversplit_contact_fraction,
+            &degenerate,
+        )
+    };
+    if args.merge_oversplit {
+        merge_oversplit_cells(
+            &oversplit_partners,
+            &priors,
+            &mut params,
+            &mut sampler.borrow_mut(),
+            &mut cell_assignments,
+            &mut counts,
+            &mut ecounts,
+        );
+        let sampler = sampler.borrow();
+        cell_centroids = sampler.cell_centroids();
+        cell_voxel_counts = sampler.cell_voxel_counts();
+        if !args.counts_only {
+            cell_perimeters = sampler.cell_perimeters(&priors);
+        }
+    }
+
+    let (background_counts, background_ecounts) = if args.output_background_cell {
+        let total = gene_totals(transcripts, ngenes);
+        let background_ecounts = ecounts
+            .as_ref()
+            .map(|ecounts| (total.mapv(|c| c as f32) - ecounts.sum_axis(Axis(1))).mapv(|c| c.max(0.0)));
+        (Some(&total - &counts.sum_axis(Axis(1))), background_ecounts)
+    } else {
+        (None, None)
+    };
+
+    let kept = kept_genes(args.min_gene_total, &counts, &dataset.transcript_names);
+    let kept_names = select_gene_names(&dataset.transcript_names, &kept);
+    // The writers only read the final state, and most are independent, so run
+    // them concurrently. A panic in any of them is re-raised once the rest finish.
+    let sampler = &*sampler.borrow();
+    let cell_existence = uncertainty.cell_existence_probs(params.ncells());
+    rayon::scope(|s| {
+        if let Some(ecounts) = &ecounts {
+            s.spawn(|_| {
+                write_expected_counts(
+                    &args.output_expected_counts,
+                    args.output_expected_counts_fmt,
+                    &kept_names,
+                    &select_genes(with_background_column(ecounts, background_ecounts), &kept),
+                );
+            });
+        }
+        s.spawn(|_| {
+            write_counts(
+                &args.output_maxpost_counts,
+                args.output_maxpost_counts_fmt,
+                &kept_names,
+                &select_genes(with_background_column(&counts, background_counts.clone()), &kept),
+            );
+        });
+        if let (Some(_), Some(ecounts)) = (&args.output_normalized_counts, &ecounts) {
+            s.spawn(|_| {
+                write_expected_counts(
+                    &args.output_normalized_counts,
+                    args.output_normalized_counts_fmt,
+                    &kept_names,
+                    &select_genes(
+                        Cow::Owned(normalize_counts(
+                            ecounts,
+                            &params.cell_volume,
+                            args.normalization,
+                            args.normalization_target_sum,
+                        )),
+                        &kept,
+                    ),
+                );
+            });
+        }
+        s.spawn(|_| {
+            write_rates(
+                &args.output_rates,
+                args.output_rates_fmt,
+                args.output_rates_layout,
+                &params,
+                &dataset.transcript_names,
+            );
+            write_background_rates(
+                &args.output_background_rates,
+                args.output_background_rates_fmt,
+                &params,
+                &dataset.transcript_names,
+            );
+            write_component_params(
+                &args.output_component_params,
+                args.output_component_params_fmt,
+                &params,
+                &dataset.transcript_names,
+            );
+            write_component_extent(
+                &args.output_component_extent,
+                args.output_component_extent_fmt,
+                &params,
+                &cell_centroids,
+            );
+        });
+        s.spawn(|_| {
+            write_cell_correspondence(
+                &args.output_cell_correspondence,
+                args.output_cell_correspondence_fmt,
+                &cell_assignments,
+                &dataset.original_cell_assignments,
+                &dataset.original_cell_ids,
+                &dataset.fov_names,
+            );
+        });
+        if args.output_cell_metadata.is_some() {
+            s.spawn(|_| {
+                let edge = edge_cells(sampler, transcripts, tissue_boundary_voxel_size, params.ncells());
+                write_cell_metadata(
+                    &args.output_cell_metadata,
+                    args.output_cell_metadata_fmt,
+                    &params,
+                    &cell_centroids,
+                    &cell_voxel_counts,
+                    &cell_assignments,
+                    &cell_perimeters,
+                    &degenerate,
+                    &edge,
+                    &cell_existence,
+                    &oversplit_partners,
+                    &counts,
+                    background_counts.as_ref(),
+                    transcripts,
+                    &dataset.fovs,
+                    &dataset.fov_names,
+                );
+            });
+        }
+        s.spawn(|_| {
+            write_transcript_metadata(
+                &args.output_transcript_metadata,
+                args.output_transcript_metadata_fmt,
+                transcripts,
+                &params.transcript_positions,
+                &dataset.transcript_names,
+                &cell_assignments,
+                args.count_pr_cutoff,
+                &params.transcript_state,
+                &dataset.qvs,
+                &dataset.fovs,
+                &dataset.fov_names,
+                &dataset.compartments,
+                &nucleus_distances,
+                &dataset.source_transcript_ids,
+            );
+        });
+        if let Some(ecounts) = &ecounts {
+            s.spawn(|_| {
+                write_gene_metadata(
+                    &args.output_gene_metadata,
+                    args.output_gene_metadata_fmt,
+                    &params,
+                    &dataset.transcript_names,
+                    ecounts,
+                    transcripts,
+                    &cell_assignments,
+                    args.count_pr_cutoff,
+                    &kept,
+                );
+            });
+        }
+        s.spawn(|_| {
+            write_voxels(
+                &args.output_cell_voxels,
+                args.output_cell_voxels_fmt,
+                sampler,
+            );
+            write_label_volume(&args.output_label_volume, sampler);
+            write_level_voxels(
+                &args.output_cell_voxels_each_level,
+                args.output_cell_voxels_fmt,
+                sampler,
+                args.schedule.len() - 1,
+            );
+        });
+        s.spawn(|_| {
+            write_qc_summary(&args.output_qc_summary, &params, &counts);
+            // the log likelihood is a full pass over the data
+            if args.output_run_metadata.is_some() {
+                write_run_metadata(
+                    &args.output_run_metadata,
+                    transcripts.len(),
+                    ngenes,
+                    &counts,
+                    &filtering,
+                    priors.min_cell_volume,
+                    &params,
+                    params.log_likelihood(&priors),
+                );
+            }
+            write_proposal_stats(
+                &args.output_proposal_stats,
+                args.output_proposal_stats_fmt,
+                &proposal_stats_trace,
+            );
+        });
+        s.spawn(|_| {
+            write_polygons(
+                sampler,
+                &params,
+                &args.output_cell_polygons,
+                args.output_cell_polygons_fmt,
+                &args.output_cell_polygon_layers,
+                &args.output_union_cell_polygons,
+                &args.output_cell_polygons_by_component,
+            );
+        });
+        s.spawn(|_| {
+            write_cell_hulls(
+                &args.output_cell_hulls,
+                args.output_cell_hulls_fmt,
+                args.cell_hull_alpha,
+                &params,
+                transcripts,
+                &counts,
+            );
+        });
+    });
+}
+
+// Print how many transcripts the final sample puts in the confusion state, and
+// the genes most of them are from.
+fn report_confusion(params: &ModelParams, transcripts: &[Transcript], transcript_names: &[String]) {
+    let confusion_counts = gene_confusion_counts(params, transcripts, transcript_names.len());
+    let nconfusion = confusion_counts.iter().sum::<usize>();
+    println!(
+        "Confusion transcripts: {} of {} ({:.2}%){}",
+        nconfusion,
+        transcripts.len(),
+        100.0 * nconfusion as f32 / transcripts.len().max(1) as f32,
+        if nconfusion > 0 {
+            format!(
+                ", most from {}",
+                confusion_counts
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &n)| n > 0)
+                    .sorted_by_key(|(_, &n)| std::cmp::Reverse(n))
+                    .take(5)
+                    .map(|(gene, n)| format!("{} ({})", transcript_names[gene], n))
+                    .join(", ")
+            )
+        } else {
+            String::new()
+        }
     );
+}
+
+// Read a file of gene names, one per line, marking which genes are listed.
+fn read_gene_set(path: &str, transcript_names: &[String]) -> Vec<bool> {
+    let content = std::fs::read_to_string(path)
+        .unwrap_or_else(|_| panic!("Unable to read genes from '{}'", path));
 
-    if args.output_cell_polygon_layers.is_some() || args.output_union_cell_polygons.is_some() {
-        let (cell_polygons, cell_flattened_polygons) = sampler.borrow().cell_polygons();
-        write_cell_multipolygons(&args.output_union_cell_polygons, cell_flattened_polygons);
-        write_cell_layered_multipolygons(&args.output_cell_polygon_layers, cell_polygons);
+    let mut genes = vec![false; transcript_names.len()];
+    for name in content.lines().map(|line| line.trim()).filter(|line| !line.is_empty()) {
+        match transcript_names.iter().position(|n| n == name) {
+            Some(gene) => genes[gene] = true,
+            None => eprintln!("Warning: gene '{}' listed in '{}' not found in transcripts", name, path),
+        }
     }
+    genes
+}
 
-    if args.output_cell_polygons.is_some() {
-        let consensus_cell_polygons = sampler.borrow().consensus_cell_polygons();
-        write_cell_multipolygons(
-            &args.output_cell_polygons,
-            consensus_cell_polygons,
-        );
+// Read per-gene ambient rates from a table with a column per gene, as written
+// by --output-background-rates, averaging any rows (e.g. z-layers).
+fn read_background_prior(path: &str, transcript_names: &[String]) -> Vec<Option<f32>> {
+    let mut rdr = open_transcripts_csv(path);
+    let headers = rdr.headers().unwrap().clone();
+    let mut sums = vec![0.0_f64; headers.len()];
+    let mut nrows = 0;
+    for row in rdr.records() {
+        let row = row.unwrap();
+        for (sum, value) in sums.iter_mut().zip(row.iter()) {
+            let rate = value
+                .parse::<f64>()
+                .unwrap_or_else(|_| panic!("Background rate must be a number: {}", value));
+            if rate.is_nan() || rate < 0.0 {
+                panic!("Background rates must be non-negative: {}", value);
+            }
+            *sum += rate;
+        }
+        nrows += 1;
+    }
+    if nrows == 0 {
+        panic!("No background rates in '{}'", path);
     }
 
-    if let Some(output_cell_hulls) = args.output_cell_hulls {
-        params.write_cell_hulls(&dataset.transcripts, &counts, &output_cell_hulls);
+    let mut rates = vec![None; transcript_names.len()];
+    for (name, sum) in headers.iter().zip(&sums) {
+        match transcript_names.iter().position(|n| n == name) {
+            Some(gene) => rates[gene] = Some((sum / nrows as f64) as f32),
+            None => eprintln!("Warning: gene '{}' listed in '{}' not found in transcripts", name, path),
+        }
     }
+    rates
 }
 
-#[allow(clippy::too_many_arguments)]
-fn run_hexbin_sampler(
-    prog: &mut ProgressBar,
-    sampler: &mut VoxelSampler,
-    priors: &ModelPriors,
-    params: &mut ModelParams,
-    transcripts: &Vec<Transcript>,
-    niter: usize,
-    local_steps_per_iter: usize,
-    mut uncertainty: Option<&mut UncertaintyTracker>,
-    total_steps: &mut usize,
-    monitor_cell_polygons: &Option<String>,
-    monitor_cell_polygons_freq: usize,
-    sample_cell_regions: bool,
-    burnin: bool,
-    hillclimb: bool,
-) {
-    sampler.sample_global_params(priors, params, transcripts, &mut uncertainty, burnin);
-    let mut proposal_stats = ProposalStats::new();
+// Read a list of nuclear marker genes, returning which transcripts belong to them.
+fn read_nuclear_marker_genes(path: &str, transcript_names: &[String], transcripts: &[Transcript]) -> Vec<bool> {
+    let marker_genes = read_gene_set(path, transcript_names);
+    println!(
+        "Using {} nuclear marker genes",
+        marker_genes.iter().filter(|&&m| m).count()
+    );
 
-    for _ in 0..niter {
-        // sampler.check_perimeter_bounds(priors);
+    transcripts.iter().map(|t| marker_genes[t.gene as usize]).collect()
+}
 
-        if sample_cell_regions {
-            // let t0 = std::time::Instant::now();
-            for _ in 0..local_steps_per_iter {
-                sampler.sample_cell_regions(
-                    priors,
-                    params,
-                    &mut proposal_stats,
-                    transcripts,
-                    hillclimb,
-                    &mut uncertainty,
-                );
+// Per-transcript weight of the prior segmentation: the prior cell probability
+// if there is one, scaled down for membrane transcripts.
+fn prior_seg_weights(dataset: &TranscriptDataset, membrane_prior_weight: f32) -> Vec<f32> {
+    dataset
+        .compartments
+        .iter()
+        .enumerate()
+        .map(|(i, &compartment)| {
+            let prob = dataset.prior_cell_probs.get(i).cloned().unwrap_or(1.0);
+            if compartment == Compartment::Membrane {
+                prob * membrane_prior_weight
+            } else {
+                prob
             }
-            // println!("Sample cell regions: {:?}", t0.elapsed());
+        })
+        .collect()
+}
+
+// Start each transcript outside a nucleus in its prior segmentation cell with
+// its prior cell probability, rather than in background.
+fn init_from_prior_cell_probs(params: &mut ModelParams, dataset: &TranscriptDataset) {
+    let mut rng = rand::thread_rng();
+    let mut nassigned = 0;
+    for (((cell, &nucleus), &prior_cell), &prob) in params
+        .cell_assignments
+        .iter_mut()
+        .zip(&dataset.nucleus_assignments)
+        .zip(&dataset.cell_assignments)
+        .zip(&dataset.prior_cell_probs)
+    {
+        if nucleus == BACKGROUND_CELL && prior_cell != BACKGROUND_CELL && rng.gen::<f32>() < prob {
+            *cell = prior_cell;
+            params.cell_population[prior_cell as usize] += 1;
+            nassigned += 1;
         }
-        // let t0 = std::time::Instant::now();
-        sampler.sample_global_params(priors, params, transcripts, &mut uncertainty, burnin);
-        // println!("Sample parameters: {:?}", t0.elapsed());
+    }
+    println!(
+        "Initialized {} transcripts outside nuclei from prior cell probabilities",
+        nassigned
+    );
+}
 
-        let nassigned = params.nassigned();
-        let nforeground = params.nforeground();
-        prog.inc(1);
-        prog.set_message(format!(
-            "log-likelihood: {ll} | assigned: {nassigned} / {n} ({perc_assigned:.2}%) | non-background: ({perc_foreground:.2}%)",
-            ll = params.log_likelihood(priors),
-            nassigned = nassigned,
-            n = transcripts.len(),
-            perc_assigned = 100.0 * (nassigned as f32) / (transcripts.len() as f32),
-            perc_foreground = 100.0 * (nforeground as f32) / (transcripts.len() as f32),
-        ));
+// Write the outputs of --counts-from-transcript-metadata, taking cell
+// assignments as they are in a prior run's transcript metadata. Transcripts
+// missing from it (filtered in that run) are background, and as in a full run
+// only assignments with probability above `count_pr_cutoff` are counted. Cells
+// keep the prior run's numbering, up to the highest one with a transcript.
+#[allow(clippy::too_many_arguments)]
+fn counts_from_prior_assignments(
+    path: &str,
+    dataset: &TranscriptDataset,
+    count_pr_cutoff: f32,
+    output_background_cell: bool,
+    min_gene_total: u32,
+    output_maxpost_counts: &Option<String>,
+    output_maxpost_counts_fmt: OutputFormat,
+    output_cell_correspondence: &Option<String>,
+    output_cell_correspondence_fmt: OutputFormat,
+) {
+    let prior = read_prior_transcript_assignments(path);
+    let mut nmatched = 0;
+    let cell_assignments = dataset
+        .transcripts
+        .iter()
+        .map(|t| match prior.get(&t.transcript_id) {
+            Some(&(cell, pr, x, y)) => {
+                check_prior_position(t, x, y);
+                nmatched += 1;
+                (cell, pr)
+            }
+            None => (BACKGROUND_CELL, 0.0),
+        })
+        .collect::<Vec<_>>();
+    if nmatched == 0 {
+        panic!("No transcript ids matched the prior run's transcript metadata.");
+    }
 
-        // println!("Log likelihood: {}", params.log_likelihood());
+    let ncells = cell_assignments
+        .iter()
+        .filter(|(cell, _)| *cell != BACKGROUND_CELL)
+        .map(|(cell, _)| *cell as usize + 1)
+        .max()
+        .unwrap_or(0);
+    println!(
+        "Counting from prior run: matched {} of {} transcripts, {} cells",
+        nmatched,
+        dataset.transcripts.len(),
+        ncells
+    );
 
-        // let empty_cell_count = params.cell_population.iter().filter(|p| **p == 0).count();
-        // println!("Empty cells: {}", empty_cell_count);
+    let ngenes = dataset.transcript_names.len();
+    let assignments = cell_assignments
+        .iter()
+        .map(|&(cell, pr)| if pr > count_pr_cutoff { cell } else { BACKGROUND_CELL })
+        .collect::<Vec<_>>();
+    let counts = counts_from_assignments(&assignments, &dataset.transcripts, ngenes, ncells);
+    let background_counts = output_background_cell
+        .then(|| &gene_totals(&dataset.transcripts, ngenes) - &counts.sum_axis(Axis(1)));
 
-        // dbg!(&proposal_stats);
-        // dbg!(sampler.mismatch_edge_stats());
-        proposal_stats.reset();
+    let kept = kept_genes(min_gene_total, &counts, &dataset.transcript_names);
+    write_counts(
+        output_maxpost_counts,
+        output_maxpost_counts_fmt,
+        &select_gene_names(&dataset.transcript_names, &kept),
+        &select_genes(with_background_column(&counts, background_counts), &kept),
+    );
+    write_cell_correspondence(
+        output_cell_correspondence,
+        output_cell_correspondence_fmt,
+        &cell_assignments,
+        &dataset.original_cell_assignments,
+        &dataset.original_cell_ids,
+        &dataset.fov_names,
+    );
+}
 
-        // if i % 100 == 0 {
-        //     println!("Iteration {} ({} unassigned transcripts)", i, params.nunassigned());
-        // }
+// Panic if a transcript matched by id to a prior run isn't where it was then.
+fn check_prior_position(t: &Transcript, x: f32, y: f32) {
+    if (x - t.x).abs() > 1e-2 * t.x.abs().max(1.0) || (y - t.y).abs() > 1e-2 * t.y.abs().max(1.0) {
+        panic!(
+            "Transcript {} is at ({}, {}) but at ({}, {}) in the prior run. Was it run with different coordinate options?",
+            t.transcript_id, t.x, t.y, x, y
+        );
+    }
+}
 
-        if *total_steps % monitor_cell_polygons_freq == 0 {
-            if let Some(basename) = monitor_cell_polygons {
-                let filename = format!("{}-{:04}.geojson.gz", basename, *total_steps);
-                let (cell_polygons, _cell_flattened_polygons) = sampler.cell_polygons();
-                write_cell_layered_multipolygons(&Some(filename), cell_polygons);
+// Initial cell assignments taken from a prior run's transcript metadata.
+// Prior cells are matched to current cells by the nucleus most of their
+// transcripts belong to, and nuclear transcripts always start in their own
+// nucleus so every cell keeps at least that.
+fn warm_start_cell_assignments(
+    transcripts: &[Transcript],
+    nucleus_assignments: &[u32],
+    ncells: usize,
+    prior: &HashMap<u64, (u32, f32, f32, f32)>,
+) -> (Vec<u32>, Vec<usize>) {
+    let mut nmatched = 0;
+    let mut nucleus_votes: HashMap<u32, HashMap<u32, usize>> = HashMap::new();
+    for (t, &nucleus) in transcripts.iter().zip(nucleus_assignments) {
+        if let Some(&(prior_cell, _, x, y)) = prior.get(&t.transcript_id) {
+            check_prior_position(t, x, y);
+            nmatched += 1;
+            if prior_cell != BACKGROUND_CELL && nucleus != BACKGROUND_CELL {
+                *nucleus_votes.entry(prior_cell).or_default().entry(nucleus).or_default() += 1;
             }
         }
+    }
 
-        *total_steps += 1;
+    if nmatched == 0 {
+        panic!("No transcript ids matched the prior run's transcript metadata.");
+    }
+    println!(
+        "Initializing from prior run: matched {} of {} transcripts",
+        nmatched,
+        transcripts.len()
+    );
+
+    let cell_map: HashMap<u32, u32> = nucleus_votes
+        .iter()
+        .map(|(&prior_cell, votes)| {
+            let (&nucleus, _) = votes.iter().max_by_key(|(&nucleus, &count)| (count, nucleus)).unwrap();
+            (prior_cell, nucleus)
+        })
+        .collect();
+
+    let mut cell_population = vec![0; ncells];
+    let cell_assignments = transcripts
+        .iter()
+        .zip(nucleus_assignments)
+        .map(|(t, &nucleus)| {
+            let cell = if nucleus != BACKGROUND_CELL {
+                nucleus
+            } else {
+                prior
+                    .get(&t.transcript_id)
+                    .and_then(|(prior_cell, _, _, _)| cell_map.get(prior_cell))
+                    .cloned()
+                    .unwrap_or(BACKGROUND_CELL)
+            };
+            if cell != BACKGROUND_CELL {
+                cell_population[cell as usize] += 1;
+            }
+            cell
+        })
+        .collect();
+
+    (cell_assignments, cell_population)
+}
+
+// Number of FOVs whose transcripts are split out per pass over the input with
+// --by-fov, which bounds the number of files open at once.
+const BY_FOV_BATCH_SIZE: usize = 64;
+
+// Transcript id column added to per-FOV inputs when the data has none, or when
+// --count-column rows are expanded into one row per transcript, so results
+// from overlapping FOVs can be matched up.
+const BY_FOV_ID_COLUMN: &str = "proseg_by_fov_id";
+
+// Cells from neighboring FOVs are merged if each is the other's best match by
+// shared overlap transcripts, and they share at least this many.
+const BY_FOV_MIN_SHARED_TRANSCRIPTS: u32 = 3;
+
+fn open_transcripts_csv(path: &str) -> csv::Reader<Box<dyn std::io::Read>> {
+    match infer_format_from_filename(path) {
+        fmt @ (OutputFormat::Csv | OutputFormat::CsvGz | OutputFormat::CsvZst) => {
+            csv::Reader::from_reader(decompressed_reader(path, fmt))
+        }
+        _ => panic!("Expected csv, csv.gz, or csv.zst input: '{}'", path),
+    }
+}
+
+// Segment each FOV in turn by running proseg on its transcripts, plus those
+// within `fov_overlap` of it, then stitch the results. Every transcript keeps
+// its assignment from its own FOV's run. Where runs overlap, a cell from one
+// FOV is merged with a cell from the other if they're each other's best match
+// by transcripts assigned to both, standing in for matching overlapping voxels.
+fn run_by_fov(args: &Args) {
+    let path = &args.transcript_csv;
+    let fov_column = args.fov_column.as_ref().expect("--by-fov requires a FOV column (--fov-column)");
+    let x_column = args.x_column.as_ref().expect("Missing required argument: --x-column");
+    let y_column = args.y_column.as_ref().expect("Missing required argument: --y-column");
+    let scale = args.coordinate_scale.unwrap_or(1.0);
+    let affine: [f32; 6] = args.coord_affine.clone().try_into().unwrap();
+
+    let mut rdr = open_transcripts_csv(path);
+    let mut headers = rdr.headers().unwrap().clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|h| h == name)
+            .unwrap_or_else(|| panic!("Column '{}' not found in CSV file", name))
+    };
+    let (x_col, y_col, fov_col) = (column(x_column), column(y_column), column(fov_column));
+    let id_col = args.transcript_id_column.as_deref().map(column);
+    let count_col = args.count_column.as_deref().map(column);
+    let row_count = |row: &csv::StringRecord| {
+        count_col.map_or(1, |count_col| {
+            row[count_col]
+                .parse::<usize>()
+                .unwrap_or_else(|_| panic!("Transcript count must be a non-negative integer: {}", &row[count_col]))
+        })
+    };
+
+    // FOV bounding boxes as (xmin, xmax, ymin, ymax)
+    let mut fov_names: Vec<String> = Vec::new();
+    let mut fov_index: HashMap<String, usize> = HashMap::new();
+    let mut bounds: Vec<(f32, f32, f32, f32)> = Vec::new();
+    // input row id (or row number) of each expanded transcript, with --count-column
+    let mut source_transcript_ids: Vec<u64> = Vec::new();
+    let position = |row: &csv::StringRecord| {
+        apply_affine(
+            &affine,
+            scale * row[x_col].parse::<f32>().unwrap(),
+            scale * row[y_col].parse::<f32>().unwrap(),
+        )
+    };
+    for (i, row) in rdr.records().enumerate() {
+        let row = row.unwrap();
+        let (x, y) = position(&row);
+        if count_col.is_some() {
+            let source_transcript_id = id_col.map_or(i as u64, |id_col| {
+                row[id_col]
+                    .parse::<u64>()
+                    .unwrap_or_else(|_| panic!("Transcript ID must be an integer: {}", &row[id_col]))
+            });
+            source_transcript_ids.extend(std::iter::repeat_n(source_transcript_id, row_count(&row)));
+        }
+        let k = *fov_index.entry(row[fov_col].to_string()).or_insert_with(|| {
+            fov_names.push(row[fov_col].to_string());
+            bounds.push((f32::INFINITY, f32::NEG_INFINITY, f32::INFINITY, f32::NEG_INFINITY));
+            fov_names.len() - 1
+        });
+        let b = &mut bounds[k];
+        *b = (b.0.min(x), b.1.max(x), b.2.min(y), b.3.max(y));
+    }
+    let nfovs = fov_names.len();
+    println!("Segmenting {} FOVs separately", nfovs);
+
+    let add_id_column = args.transcript_id_column.is_none() || count_col.is_some();
+    if add_id_column {
+        headers.push_field(BY_FOV_ID_COLUMN);
+    }
+
+    let work_dir = std::path::Path::new(args.out_dir.as_deref().unwrap_or("."))
+        .join(format!("proseg-by-fov-{}", std::process::id()));
+    let fov_dir = |k: usize| work_dir.join(format!("fov{}", k));
+
+    // (fov, cell) pairs from every run, merged across seams
+    let mut nodes: Vec<(usize, u32)> = Vec::new();
+    let mut node_index: HashMap<(usize, u32), usize> = HashMap::new();
+    let mut node = |k: usize, cell: u32| {
+        *node_index.entry((k, cell)).or_insert_with(|| {
+            nodes.push((k, cell));
+            nodes.len() - 1
+        })
+    };
+
+    // workers number cells from zero, and ids are assigned after stitching
+    let schema = Arc::new(transcript_metadata_schema(OutputFormat::Csv));
+    let stitched_schema = Arc::new(with_cell_id_column(&schema, "assignment"));
+    let col = |name: &str| schema.index_of(name).unwrap();
+    let mut home_cells: HashMap<u64, usize> = HashMap::new();
+    let mut margin_cells: Vec<(u64, usize)> = Vec::new();
+    let mut stitched: Vec<(Vec<Option<usize>>, RecordBatch)> = Vec::new();
+    let mut fov_cell_counts = vec![0; nfovs];
+
+    let batches = (0..nfovs).collect::<Vec<_>>();
+    'fovs: for batch in batches.chunks(BY_FOV_BATCH_SIZE) {
+        let mut writers = batch
+            .iter()
+            .map(|&k| {
+                std::fs::create_dir_all(fov_dir(k)).unwrap();
+                let mut writer = csv::Writer::from_path(fov_dir(k).join("transcripts.csv")).unwrap();
+                writer.write_record(&headers).unwrap();
+                writer
+            })
+            .collect::<Vec<_>>();
+
+        let mut rdr = open_transcripts_csv(path);
+        let mut next_id = 0;
+        for row in rdr.records() {
+            let row = row.unwrap();
+            let (x, y) = position(&row);
+            let copies = if let Some(count_col) = count_col {
+                let single = row
+                    .iter()
+                    .enumerate()
+                    .map(|(j, field)| if j == count_col { "1" } else { field })
+                    .collect::<csv::StringRecord>();
+                vec![single; row_count(&row)]
+            } else {
+                vec![row]
+            };
+            for mut row in copies {
+                if add_id_column {
+                    row.push_field(&next_id.to_string());
+                    next_id += 1;
+                }
+                for (writer, &k) in writers.iter_mut().zip(batch) {
+                    let b = bounds[k];
+                    let margin = args.fov_overlap;
+                    if x >= b.0 - margin && x <= b.1 + margin && y >= b.2 - margin && y <= b.3 + margin {
+                        writer.write_record(&row).unwrap();
+                    }
+                }
+            }
+        }
+        for writer in &mut writers {
+            writer.flush().unwrap();
+        }
+        drop(writers);
+
+        for &k in batch {
+            println!("Segmenting FOV {} ({}/{})", fov_names[k], k + 1, nfovs);
+            let status = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(by_fov_worker_args(args, &fov_dir(k), add_id_column, count_col.is_some()))
+                .status()
+                .unwrap();
+            if !status.success() {
+                eprintln!("Warning: segmenting FOV {} failed, leaving its transcripts out", fov_names[k]);
+                continue;
+            }
+
+            let metadata = fov_dir(k).join("transcript-metadata.csv");
+            let rdr = arrow::csv::ReaderBuilder::new(schema.clone())
+                .with_header(true)
+                .build(File::open(&metadata).unwrap())
+                .unwrap_or_else(|_| panic!("Unable to construct CSV reader for '{}'", metadata.display()));
+            let mut cells = HashSet::new();
+            for rec_batch in rdr {
+                let rec_batch = rec_batch.expect("Unable to read record batch.");
+                let ids = rec_batch.column(col("transcript_id")).as_any().downcast_ref::<UInt64Array>().unwrap();
+                let fovs = rec_batch.column(col("fov")).as_any().downcast_ref::<StringArray>().unwrap();
+                let assignments = rec_batch.column(col("assignment")).as_any().downcast_ref::<UInt32Array>().unwrap();
+
+                let mut home = Vec::with_capacity(rec_batch.num_rows());
+                let mut home_nodes = Vec::new();
+                for i in 0..rec_batch.num_rows() {
+                    let is_home = fovs.value(i) == fov_names[k];
+                    let cell = assignments.value(i);
+                    let cell_node = if cell == BACKGROUND_CELL {
+                        None
+                    } else {
+                        cells.insert(cell);
+                        Some(node(k, cell))
+                    };
+                    if is_home {
+                        if let Some(cell_node) = cell_node {
+                            home_cells.insert(ids.value(i), cell_node);
+                        }
+                        home_nodes.push(cell_node);
+                    } else if let Some(cell_node) = cell_node {
+                        margin_cells.push((ids.value(i), cell_node));
+                    }
+                    home.push(is_home);
+                }
+                let home = BooleanArray::from(home);
+                stitched.push((home_nodes, arrow::compute::filter_record_batch(&rec_batch, &home).unwrap()));
+            }
+            fov_cell_counts[k] = cells.len();
+            std::fs::remove_dir_all(fov_dir(k)).unwrap();
+
+            if interrupted() {
+                eprintln!("Interrupted. Stitching the FOVs segmented so far.");
+                break 'fovs;
+            }
+        }
+    }
+    std::fs::remove_dir_all(&work_dir).ok();
+
+    let roots = stitch_fov_cells(&nodes, &home_cells, &margin_cells);
+
+    // Number stitched cells in order of appearance.
+    let mut cell_index: HashMap<usize, u32> = HashMap::new();
+    let mut nhome_cells = HashSet::new();
+    let mut batches = Vec::new();
+    let mut assignments = Vec::new();
+    for (home_nodes, rec_batch) in &stitched {
+        nhome_cells.extend(home_nodes.iter().flatten().copied());
+        let batch_assignments = number_stitched_cells(home_nodes, &roots, &mut cell_index);
+        let mut columns = rec_batch.columns().to_vec();
+        columns[col("assignment")] = cell_id_array(batch_assignments.iter().map(|&cell| Some(cell)));
+        batches.push(RecordBatch::try_new(stitched_schema.clone(), columns).unwrap());
+        assignments.extend(batch_assignments);
+    }
+    let ncells = cell_index.len();
+
+    for (name, count) in fov_names.iter().zip(&fov_cell_counts) {
+        println!("  FOV {}: {} cells", name, count);
+    }
+    println!(
+        "Stitched {} cells ({} merged across FOV seams)",
+        ncells,
+        nhome_cells.len() - ncells
+    );
+
+    let mut metadata = arrow::compute::concat_batches(&stitched_schema, &batches).unwrap();
+    if count_col.is_some() {
+        let ids = metadata.column(col("transcript_id")).as_any().downcast_ref::<UInt64Array>().unwrap();
+        let sources = ids.values().iter().map(|&id| source_transcript_ids[id as usize]).collect::<UInt64Array>();
+        let mut columns = metadata.columns().to_vec();
+        columns.push(Arc::new(sources));
+        let schema = Arc::new(with_source_transcript_id_column(&stitched_schema, false));
+        metadata = RecordBatch::try_new(schema, columns).unwrap();
+    }
+    if let Some(output_transcript_metadata) = &args.output_transcript_metadata {
+        write_table(output_transcript_metadata, args.output_transcript_metadata_fmt, &metadata);
+    }
+
+    let genes = metadata.column(col("gene")).as_any().downcast_ref::<StringArray>().unwrap();
+    let probabilities = metadata.column(col("probability")).as_any().downcast_ref::<Float32Array>().unwrap();
+    let mut transcript_names: Vec<String> = Vec::new();
+    let mut gene_index: HashMap<&str, usize> = HashMap::new();
+    for gene in genes.iter().flatten() {
+        gene_index.entry(gene).or_insert_with(|| {
+            transcript_names.push(gene.to_string());
+            transcript_names.len() - 1
+        });
+    }
+    let mut counts = Array2::<u32>::zeros((transcript_names.len(), ncells));
+    for (i, &cell) in assignments.iter().enumerate() {
+        if cell != BACKGROUND_CELL && probabilities.value(i) > args.count_pr_cutoff {
+            counts[[gene_index[genes.value(i)], cell as usize]] += 1;
+        }
+    }
+    let kept = kept_genes(args.min_gene_total, &counts, &transcript_names);
+    write_counts(
+        &args.output_maxpost_counts,
+        args.output_maxpost_counts_fmt,
+        &select_gene_names(&transcript_names, &kept),
+        &select_genes(Cow::Borrowed(&counts), &kept),
+    );
+}
+
+// Group the (fov, cell) `nodes` of every --by-fov run into stitched cells,
+// returning a representative node for each. `home_cells` gives the node each
+// transcript is assigned to in its own FOV's run, and `margin_cells` the nodes
+// transcripts are assigned to in neighboring runs that overlap them.
+fn stitch_fov_cells(
+    nodes: &[(usize, u32)],
+    home_cells: &HashMap<u64, usize>,
+    margin_cells: &[(u64, usize)],
+) -> Vec<usize> {
+    // Count transcripts in each FOV's margin assigned to a cell there and to a
+    // cell in the transcript's own FOV.
+    let mut shared: HashMap<(usize, usize), u32> = HashMap::new();
+    for (id, margin_node) in margin_cells {
+        if let Some(&home_node) = home_cells.get(id) {
+            let pair = (*margin_node.min(&home_node), *margin_node.max(&home_node));
+            *shared.entry(pair).or_insert(0) += 1;
+        }
+    }
+    let mut best: HashMap<(usize, usize), (u32, usize)> = HashMap::new();
+    for (&(a, b), &count) in &shared {
+        for (u, v) in [(a, b), (b, a)] {
+            let entry = best.entry((u, nodes[v].0)).or_insert((0, v));
+            if count > entry.0 || (count == entry.0 && v < entry.1) {
+                *entry = (count, v);
+            }
+        }
+    }
+    let mut components = UnionFind::<usize>::new(nodes.len());
+    for (&(a, b), &count) in &shared {
+        if count >= BY_FOV_MIN_SHARED_TRANSCRIPTS
+            && best[&(a, nodes[b].0)].1 == b
+            && best[&(b, nodes[a].0)].1 == a
+        {
+            components.union(a, b);
+        }
+    }
+
+    (0..nodes.len()).map(|node| components.find_mut(node)).collect()
+}
+
+// Stitched cell of each transcript assigned to `home_nodes`, numbering
+// stitched cells from zero as they're first seen in `cell_index`.
+fn number_stitched_cells(
+    home_nodes: &[Option<usize>],
+    roots: &[usize],
+    cell_index: &mut HashMap<usize, u32>,
+) -> Vec<u32> {
+    home_nodes
+        .iter()
+        .map(|cell_node| {
+            cell_node.map_or(BACKGROUND_CELL, |cell_node| {
+                let next = cell_index.len() as u32;
+                *cell_index.entry(roots[cell_node]).or_insert(next)
+            })
+        })
+        .collect()
+}
+
+// Command line for segmenting one FOV with --by-fov: the original arguments
+// minus --by-fov, reading the FOV's transcripts and writing only transcript
+// metadata into its directory. With `expanded_counts` the FOV's rows are one
+// per transcript, so the count and id columns are dropped in favor of the
+// added id column.
+fn by_fov_worker_args(args: &Args, dir: &std::path::Path, add_id_column: bool, expanded_counts: bool) -> Vec<String> {
+    let dropped = |flag: &str| {
+        flag == "--fov-overlap"
+            || (expanded_counts && (flag == "--count-column" || flag == "--transcript-id-column"))
+    };
+    let mut worker_args = Vec::new();
+    let mut input_replaced = false;
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        if arg == "--by-fov" || arg.split_once('=').is_some_and(|(flag, _)| dropped(flag)) {
+            continue;
+        } else if dropped(&arg) {
+            argv.next();
+        } else if arg == args.transcript_csv && !input_replaced {
+            worker_args.push(dir.join("transcripts.csv").to_string_lossy().into_owned());
+            input_replaced = true;
+        } else {
+            worker_args.push(arg);
+        }
+    }
+
+    worker_args.extend(["--cell-id-prefix", "", "--cell-id-start", "0"].map(String::from));
+    worker_args.push("--out-dir".to_string());
+    worker_args.push(dir.to_string_lossy().into_owned());
+    for (name, _) in output_paths(&mut args.clone()) {
+        worker_args.push(format!("--{}", name));
+        worker_args.push(String::new());
+    }
+    worker_args.extend(
+        [
+            "--output-transcript-metadata", "transcript-metadata.csv",
+            "--output-transcript-metadata-fmt", "csv",
+        ]
+        .map(String::from),
+    );
+    if add_id_column {
+        worker_args.push("--transcript-id-column".to_string());
+        worker_args.push(BY_FOV_ID_COLUMN.to_string());
+    }
+    worker_args
+}
+
+// Run --restarts independent chains, each as a separate proseg process
+// writing into its own directory, then move the outputs of the chain with the
+// highest final log likelihood into place. Sampling isn't seeded, so chains
+// differ only by their own randomness.
+fn run_restarts(args: &Args) {
+    let work_dir = std::path::Path::new(args.out_dir.as_deref().unwrap_or("."))
+        .join(format!("proseg-restarts-{}", std::process::id()));
+    let outputs = output_paths(&mut args.clone())
+        .into_iter()
+        .map(|(name, path)| (name, path.clone()))
+        .collect::<Vec<_>>();
+
+    // each output is written as "{i}-{file name}" so files derived from its
+    // name (per-component polygons, per-level voxels) can be traced back to it
+    let worker_name = |i: usize, path: &str| {
+        let file_name = std::path::Path::new(path).file_name().unwrap().to_string_lossy();
+        format!("{}-{}", i, file_name)
+    };
+
+    let mut log_likelihoods = Vec::new();
+    for k in 0..args.restarts {
+        if interrupted() {
+            eprintln!("Interrupted. Keeping the best of the chains finished so far.");
+            break;
+        }
+        let dir = work_dir.join(format!("restart{}", k));
+        let mut worker_args = Vec::new();
+        let mut argv = std::env::args().skip(1);
+        while let Some(arg) = argv.next() {
+            if arg == "--restarts" {
+                argv.next();
+            } else if !arg.starts_with("--restarts=") {
+                worker_args.push(arg);
+            }
+        }
+        worker_args.push("--out-dir".to_string());
+        worker_args.push(dir.to_string_lossy().into_owned());
+        for (i, (name, path)) in outputs.iter().enumerate() {
+            worker_args.push(format!("--{}", name));
+            worker_args.push(path.as_deref().map(|path| worker_name(i, path)).unwrap_or_default());
+        }
+        let run_metadata = match &outputs.iter().position(|(name, _)| *name == "output-run-metadata") {
+            Some(i) if outputs[*i].1.is_some() => worker_name(*i, outputs[*i].1.as_ref().unwrap()),
+            _ => {
+                worker_args.push("--output-run-metadata".to_string());
+                worker_args.push("restart-run-metadata.json".to_string());
+                "restart-run-metadata.json".to_string()
+            }
+        };
+
+        println!("Running chain {} of {}", k + 1, args.restarts);
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(&worker_args)
+            .status()
+            .unwrap();
+        if !status.success() {
+            eprintln!("Warning: chain {} failed", k + 1);
+            continue;
+        }
+        // Ctrl-C reaches the chain too, which stops early; it's only kept if
+        // no chain got to finish.
+        if interrupted() && !log_likelihoods.is_empty() {
+            continue;
+        }
+
+        let metadata = std::fs::read_to_string(dir.join(&run_metadata)).unwrap();
+        let log_likelihood = json::parse(&metadata).unwrap()["log_likelihood"].as_f64().unwrap();
+        log_likelihoods.push((k, log_likelihood));
+    }
+
+    if log_likelihoods.is_empty() {
+        std::fs::remove_dir_all(&work_dir).ok();
+        panic!("Every chain failed");
+    }
+    let &(best, best_log_likelihood) = log_likelihoods
+        .iter()
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+        .unwrap();
+    for (k, log_likelihood) in &log_likelihoods {
+        println!(
+            "Chain {}: log likelihood {}{}",
+            k + 1,
+            log_likelihood,
+            if *k == best { " (kept)" } else { "" }
+        );
+    }
+    let worst = log_likelihoods.iter().map(|(_, ll)| *ll).fold(f64::INFINITY, f64::min);
+    println!("Log likelihoods span {} across {} chains", best_log_likelihood - worst, log_likelihoods.len());
+
+    let best_dir = work_dir.join(format!("restart{}", best));
+    for entry in std::fs::read_dir(&best_dir).unwrap() {
+        let entry = entry.unwrap();
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let Some((i, rest)) = file_name.split_once('-') else { continue };
+        let Some((_, Some(path))) = i.parse::<usize>().ok().and_then(|i| outputs.get(i)) else { continue };
+        let target = std::path::Path::new(path).with_file_name(rest);
+        if std::fs::rename(entry.path(), &target).is_err() {
+            std::fs::copy(entry.path(), &target)
+                .unwrap_or_else(|err| panic!("Unable to write {}: {}", target.display(), err));
+        }
+    }
+    std::fs::remove_dir_all(&work_dir).unwrap();
+}
+
+// Set by the Ctrl-C handler, so sampling stops early and outputs still get
+// written.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+fn interrupted() -> bool {
+    INTERRUPTED.load(AtomicOrdering::Relaxed)
+}
+
+// Sampling progress. The animated bar is only drawn on a terminal; when
+// stderr is redirected to a log, plain lines are printed about a hundred
+// times over the run instead.
+enum Progress {
+    Bar(ProgressBar),
+    Plain { total: usize, pos: usize, every: usize },
+    Quiet,
+}
+
+impl Progress {
+    fn new(total: usize, quiet: bool) -> Progress {
+        if quiet {
+            Progress::Quiet
+        } else if std::io::stderr().is_terminal() {
+            let bar = ProgressBar::new(total as u64);
+            bar.set_style(
+                ProgressStyle::with_template("{eta_precise} {bar:60} | {msg}")
+                    .unwrap()
+                    .progress_chars("##-"),
+            );
+            Progress::Bar(bar)
+        } else {
+            Progress::Plain { total, pos: 0, every: total.div_ceil(100).max(1) }
+        }
+    }
+
+    fn inc(&mut self, log_likelihood: f32, nassigned: usize, nforeground: usize, n: usize) {
+        let perc_assigned = 100.0 * (nassigned as f32) / (n as f32);
+        match self {
+            Progress::Bar(bar) => {
+                bar.inc(1);
+                bar.set_message(format!(
+                    "log-likelihood: {ll} | assigned: {nassigned} / {n} ({perc_assigned:.2}%) | non-background: ({perc_foreground:.2}%)",
+                    ll = log_likelihood,
+                    perc_foreground = 100.0 * (nforeground as f32) / (n as f32),
+                ));
+            }
+            Progress::Plain { total, pos, every } => {
+                *pos += 1;
+                if (*pos).is_multiple_of(*every) || *pos == *total {
+                    println!(
+                        "iter {}/{}, ll={}, assigned={}/{} ({:.2}%)",
+                        pos, total, log_likelihood, nassigned, n, perc_assigned
+                    );
+                }
+            }
+            Progress::Quiet => {}
+        }
+    }
+
+    fn finish(&self) {
+        if let Progress::Bar(bar) = self {
+            bar.finish();
+        }
+    }
+}
+
+// Write the voxel table for one level of the schedule, naming the file by
+// inserting the level before the extension.
+fn write_level_voxels(
+    basename: &Option<String>,
+    fmt: OutputFormat,
+    sampler: &VoxelSampler,
+    level: usize,
+) {
+    if let Some(basename) = basename {
+        let stem_start = basename.rfind('/').map(|i| i + 1).unwrap_or(0);
+        let ext_start = basename[stem_start..]
+            .find('.')
+            .map(|i| stem_start + i)
+            .unwrap_or(basename.len());
+        let filename = format!(
+            "{}-level{}{}",
+            &basename[..ext_start],
+            level,
+            &basename[ext_start..]
+        );
+        write_voxels(&Some(filename), fmt, sampler);
+    }
+}
+
+fn write_polygons(
+    sampler: &VoxelSampler,
+    params: &ModelParams,
+    output_cell_polygons: &Option<String>,
+    output_cell_polygons_fmt: GeometryFormat,
+    output_cell_polygon_layers: &Option<String>,
+    output_union_cell_polygons: &Option<String>,
+    output_cell_polygons_by_component: &Option<String>,
+) {
+    if output_cell_polygon_layers.is_some() || output_union_cell_polygons.is_some() {
+        let (cell_polygons, cell_flattened_polygons) = sampler.cell_polygons();
+        write_cell_multipolygons(
+            output_union_cell_polygons,
+            GeometryFormat::GeoJsonGz,
+            cell_flattened_polygons,
+        );
+        write_cell_layered_multipolygons(output_cell_polygon_layers, cell_polygons);
+    }
+
+    if output_cell_polygons.is_some() || output_cell_polygons_by_component.is_some() {
+        let consensus_cell_polygons = sampler.consensus_cell_polygons();
+        write_cell_multipolygons_by_component(
+            output_cell_polygons_by_component,
+            &consensus_cell_polygons,
+            &params.z,
+        );
+        write_cell_multipolygons(output_cell_polygons, output_cell_polygons_fmt, consensus_cell_polygons);
+    }
+}
+
+// Reassign each transcript assigned to a cell to the cell whose voxels contain
+// its observed position, keeping its probability. Transcripts observed outside
+// every cell become background. Returns the number whose assignment changed.
+fn attribute_at_observed_positions(
+    sampler: &VoxelSampler,
+    transcripts: &[Transcript],
+    cell_assignments: &mut [(u32, f32)],
+) -> usize {
+    let mut nmoved = 0;
+    for (t, (cell, _)) in transcripts.iter().zip(cell_assignments.iter_mut()) {
+        if *cell != BACKGROUND_CELL {
+            let observed_cell = sampler.cell_at_position((t.x, t.y, t.z));
+            if observed_cell != *cell {
+                *cell = observed_cell;
+                nmoved += 1;
+            }
+        }
+    }
+    nmoved
+}
+
+// Very rough estimate of peak memory use in bytes. This is dominated by
+// per-transcript state, the cell-by-gene matrices, and the voxels at the final
+// (highest) resolution, so that's all we count.
+#[allow(clippy::too_many_arguments)]
+fn estimate_memory_usage(
+    ntranscripts: usize,
+    ncells: usize,
+    ngenes: usize,
+    ncomponents: usize,
+    full_volume: f32,
+    zspan: f32,
+    initial_voxel_size: f32,
+    nlayers: usize,
+    nresolutions: usize,
+) -> usize {
+    // Positions, proposals, assignments, and the uncertainty tracker's entries
+    const BYTES_PER_TRANSCRIPT: usize = 128;
+    // Voxel to cell map entries, mismatch edges, etc.
+    const BYTES_PER_VOXEL: usize = 64;
+    // Counts, rates, and related matrices
+    const CELL_GENE_MATRICES: usize = 8;
+
+    let ndoublings = nresolutions.saturating_sub(1) as i32;
+    let voxel_size = initial_voxel_size / 2.0_f32.powi(ndoublings);
+    let voxel_volume = voxel_size * voxel_size * zspan / nlayers as f32;
+    let nvoxels = (full_volume / voxel_volume) as usize;
+
+    ntranscripts * BYTES_PER_TRANSCRIPT
+        + nvoxels * BYTES_PER_VOXEL
+        + CELL_GENE_MATRICES * ncells * ngenes * 4
+        + ncomponents * ngenes * 8
+}
+
+// Normalize a gene-by-cell count matrix per cell. Empty cells stay zero
+// rather than becoming NaN.
+fn normalize_counts(
+    counts: &Array2<f32>,
+    cell_volume: &Array1<f32>,
+    normalization: Normalization,
+    target_sum: f32,
+) -> Array2<f32> {
+    let mut normalized = counts.clone();
+    for (j, mut column) in normalized.columns_mut().into_iter().enumerate() {
+        let denom = match normalization {
+            Normalization::Volume => cell_volume[j],
+            Normalization::Total => column.sum() / target_sum,
+            Normalization::None => 1.0,
+        };
+        if denom > 0.0 {
+            column /= denom;
+        } else {
+            column.fill(0.0);
+        }
+    }
+    normalized
+}
+
+// Number of transcripts of each gene. Subtracting what's counted in cells
+// gives the background, so cells and background add up to the total.
+fn gene_totals(transcripts: &[Transcript], ngenes: usize) -> Array1<u32> {
+    let mut total = Array1::<u32>::zeros(ngenes);
+    for t in transcripts {
+        total[t.gene as usize] += 1;
+    }
+    total
+}
+
+// Append the background as one more cell, after the others, if given.
+fn with_background_column<T: Clone>(counts: &Array2<T>, background: Option<Array1<T>>) -> Cow<'_, Array2<T>> {
+    match background {
+        Some(background) => Cow::Owned(
+            ndarray::concatenate(Axis(1), &[counts.view(), background.view().insert_axis(Axis(1))]).unwrap(),
+        ),
+        None => Cow::Borrowed(counts),
+    }
+}
+
+// Indices of the genes with at least `min_total` transcripts counted in cells,
+// for --min-gene-total, reporting any that are dropped.
+fn kept_genes(min_total: u32, counts: &Array2<u32>, transcript_names: &[String]) -> Vec<usize> {
+    let totals = counts.sum_axis(Axis(1));
+    let (kept, dropped): (Vec<usize>, Vec<usize>) = (0..transcript_names.len()).partition(|&g| totals[g] >= min_total);
+    if !dropped.is_empty() {
+        println!(
+            "Dropped {} of {} genes with fewer than {} assigned transcripts from the outputs: {}",
+            dropped.len(),
+            transcript_names.len(),
+            min_total,
+            dropped.iter().map(|&g| format!("{} ({})", transcript_names[g], totals[g])).join(", ")
+        );
+    }
+    kept
+}
+
+// Keep just the rows of the genes in `kept`, in order.
+fn select_genes<'a, T: Clone>(counts: Cow<'a, Array2<T>>, kept: &[usize]) -> Cow<'a, Array2<T>> {
+    if kept.len() == counts.nrows() {
+        counts
+    } else {
+        Cow::Owned(counts.select(Axis(0), kept))
+    }
+}
+
+fn select_gene_names(transcript_names: &[String], kept: &[usize]) -> Vec<String> {
+    kept.iter().map(|&g| transcript_names[g].clone()).collect()
+}
+
+// Recompute the summaries derived from the final sampler state on a single
+// thread and check they match the ones computed with the full thread pool,
+// which could otherwise differ if a reduction's order depends on scheduling.
+#[allow(clippy::too_many_arguments)]
+fn check_final_summaries(
+    priors: &ModelPriors,
+    params: &ModelParams,
+    uncertainty: &UncertaintyTracker,
+    sampler: &VoxelSampler,
+    transcripts: &[Transcript],
+    count_pr_cutoff: f32,
+    foreground_pr_cutoff: f32,
+    counts: &Array2<u32>,
+    ecounts: Option<&Array2<f32>>,
+    cell_centroids: &[(f32, f32, f32)],
+) {
+    let ll = params.log_likelihood(priors);
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+    let (ll_1, counts_1, ecounts_1, cell_centroids_1) = pool.install(|| {
+        (
+            params.log_likelihood(priors),
+            uncertainty
+                .max_posterior_transcript_counts_assignments(
+                    params,
+                    transcripts,
+                    count_pr_cutoff,
+                    foreground_pr_cutoff,
+                )
+                .0,
+            ecounts.map(|_| uncertainty.expected_counts(params, transcripts)),
+            sampler.cell_centroids(),
+        )
+    });
+
+    let mut mismatches = Vec::new();
+    if ll.to_bits() != ll_1.to_bits() {
+        mismatches.push("log likelihood");
+    }
+    if *counts != counts_1 {
+        mismatches.push("counts");
+    }
+    if ecounts != ecounts_1.as_ref() {
+        mismatches.push("expected counts");
+    }
+    if cell_centroids != cell_centroids_1 {
+        mismatches.push("cell centroids");
+    }
+
+    if !mismatches.is_empty() {
+        panic!(
+            "Final summaries differ between 1 and {} threads: {}",
+            current_num_threads(),
+            mismatches.join(", ")
+        );
+    }
+    println!("Final summaries match between 1 and {} threads", current_num_threads());
+}
+
+// Find pairs of neighboring cells that are likely one cell split in two: they're
+// assigned the same component, and the boundary they share is at least
+// `min_contact_fraction` of the smaller of their boundaries. Returns, for each
+// cell, the candidate partner it shares the most boundary with.
+fn find_oversplit_cells(
+    params: &ModelParams,
+    sampler: &VoxelSampler,
+    min_contact_fraction: f32,
+    degenerate: &[bool],
+) -> Vec<Option<u32>> {
+    let (boundary, contacts) = sampler.cell_contacts();
+
+    let mut partners: Vec<Option<(u32, f32)>> = vec![None; boundary.len()];
+    let mut npairs = 0;
+    for (&(a, b), &contact) in contacts.iter().sorted() {
+        let (a, b) = (a as usize, b as usize);
+        if degenerate[a] || degenerate[b] || params.z[a] != params.z[b] {
+            continue;
+        }
+
+        let fraction = contact as f32 / boundary[a].min(boundary[b]) as f32;
+        if fraction < min_contact_fraction {
+            continue;
+        }
+        npairs += 1;
+
+        for (cell, partner) in [(a, b), (b, a)] {
+            if partners[cell].is_none_or(|(_, f)| fraction > f) {
+                partners[cell] = Some((partner as u32, fraction));
+            }
+        }
+    }
+
+    println!("Found {} likely over-split cell pairs", npairs);
+
+    partners.iter().map(|p| p.map(|(partner, _)| partner)).collect()
+}
+
+// Merge each cell into its over-split partner, the smaller into the larger,
+// starting from the pairs with the most transcripts. A cell takes part in at
+// most one merge. Merged cells are kept as empty rows, with their voxels given
+// to the cell they were merged into.
+fn merge_oversplit_cells(
+    partners: &[Option<u32>],
+    priors: &ModelPriors,
+    params: &mut ModelParams,
+    sampler: &mut VoxelSampler,
+    cell_assignments: &mut [(u32, f32)],
+    counts: &mut Array2<u32>,
+    ecounts: &mut Option<Array2<f32>>,
+) {
+    let destination = oversplit_merge_destinations(partners, &params.cell_population);
+    move_cells(&destination, &mut params.cell_population, cell_assignments, counts, ecounts);
+    sampler.merge_cells(priors, params, &destination);
+
+    println!(
+        "Merged {} over-split cells",
+        destination.iter().enumerate().filter(|(i, &d)| *i as u32 != d).count()
+    );
+}
+
+// Cell each cell is merged into, itself if it isn't: mutual over-split
+// partners are merged in order of their combined population, the smaller into
+// the larger, skipping pairs with a cell that's already been merged.
+fn oversplit_merge_destinations(partners: &[Option<u32>], cell_population: &[usize]) -> Vec<u32> {
+    let pairs = partners
+        .iter()
+        .enumerate()
+        .filter_map(|(a, p)| p.map(|b| (a, b as usize)))
+        .filter(|&(a, b)| a < b && partners[b] == Some(a as u32))
+        .sorted_by_key(|&(a, b)| std::cmp::Reverse(cell_population[a] + cell_population[b]))
+        .collect::<Vec<_>>();
+
+    let ncells = partners.len();
+    let mut destination = (0..ncells as u32).collect::<Vec<_>>();
+    let mut merged = vec![false; ncells];
+    for (a, b) in pairs {
+        if merged[a] || merged[b] {
+            continue;
+        }
+        let (src, dest) = if cell_population[a] < cell_population[b] {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        destination[src] = dest as u32;
+        merged[src] = true;
+        merged[dest] = true;
+    }
+
+    destination
+}
+
+// Move every cell's transcripts, counts, and population to its entry in
+// `destination`, or to the background where that's BACKGROUND_CELL, leaving
+// the cells that moved empty.
+fn move_cells(
+    destination: &[u32],
+    cell_population: &mut [usize],
+    cell_assignments: &mut [(u32, f32)],
+    counts: &mut Array2<u32>,
+    ecounts: &mut Option<Array2<f32>>,
+) {
+    for (cell, _) in cell_assignments.iter_mut() {
+        if *cell != BACKGROUND_CELL {
+            *cell = destination[*cell as usize];
+        }
+    }
+
+    for (src, &dest) in destination.iter().enumerate() {
+        if dest == src as u32 {
+            continue;
+        }
+
+        if dest != BACKGROUND_CELL {
+            let dest = dest as usize;
+            let (src_counts, dest_counts) = (counts.column(src).to_owned(), counts.column(dest).to_owned());
+            counts.column_mut(dest).assign(&(src_counts + dest_counts));
+            if let Some(ecounts) = ecounts.as_mut() {
+                let (src_ecounts, dest_ecounts) = (ecounts.column(src).to_owned(), ecounts.column(dest).to_owned());
+                ecounts.column_mut(dest).assign(&(src_ecounts + dest_ecounts));
+            }
+            cell_population[dest] += cell_population[src];
+        }
+        counts.column_mut(src).fill(0);
+        if let Some(ecounts) = ecounts.as_mut() {
+            ecounts.column_mut(src).fill(0.0);
+        }
+        cell_population[src] = 0;
+    }
+}
+
+// Find cells whose volume has collapsed to the minimum, which would otherwise
+// produce absurd volume normalized values downstream, and either just report
+// them, or merge or drop their transcripts. Returns a mask of degenerate cells.
+#[allow(clippy::too_many_arguments)]
+fn handle_degenerate_cells(
+    mode: DegenerateCells,
+    priors: &ModelPriors,
+    params: &mut ModelParams,
+    cell_centroids: &[(f32, f32, f32)],
+    cell_voxel_counts: &[u32],
+    cell_assignments: &mut [(u32, f32)],
+    counts: &mut Array2<u32>,
+    ecounts: &mut Option<Array2<f32>>,
+) -> Vec<bool> {
+    let degenerate: Vec<bool> = params
+        .cell_volume
+        .iter()
+        .zip(&params.cell_dormant)
+        .map(|(&v, &dormant)| !dormant && v <= priors.min_cell_volume)
+        .collect();
+
+    let ndegenerate = degenerate.iter().filter(|&&d| d).count();
+    if ndegenerate == 0 {
+        return degenerate;
+    }
+
+    println!(
+        "Found {} degenerate cells with no volume: {}",
+        ndegenerate,
+        degenerate
+            .iter()
+            .enumerate()
+            .filter(|(_, &d)| d)
+            .map(|(i, _)| i)
+            .join(", ")
+    );
+
+    if mode == DegenerateCells::Flag {
+        return degenerate;
+    }
+
+    let destination = degenerate_cell_destinations(
+        mode,
+        &degenerate,
+        params.cell_dormant.as_slice().unwrap(),
+        cell_centroids,
+        cell_voxel_counts,
+        cell_assignments,
+        &params.transcript_positions,
+    );
+
+    move_cells(&destination, &mut params.cell_population, cell_assignments, counts, ecounts);
+
+    degenerate
+}
+
+// Cell each degenerate cell's transcripts are merged into: the nearest cell,
+// by centroid, to the centroid of its transcripts, or BACKGROUND_CELL when
+// dropping or when it has none. Other cells stay put. Unborn pool cells and other cells without
+// voxels sit at a placeholder centroid and are never picked.
+fn degenerate_cell_destinations(
+    mode: DegenerateCells,
+    degenerate: &[bool],
+    cell_dormant: &[bool],
+    cell_centroids: &[(f32, f32, f32)],
+    cell_voxel_counts: &[u32],
+    cell_assignments: &[(u32, f32)],
+    transcript_positions: &[(f32, f32, f32)],
+) -> Vec<u32> {
+    // Degenerate cells have no voxels to take a centroid from, so locate them
+    // by their assigned transcripts instead.
+    let ncells = degenerate.len();
+    let mut transcript_centroids = vec![(0.0, 0.0, 0.0); ncells];
+    let mut transcript_counts = vec![0; ncells];
+    for ((cell, _), position) in cell_assignments.iter().zip(transcript_positions) {
+        if *cell != BACKGROUND_CELL && degenerate[*cell as usize] {
+            let centroid = &mut transcript_centroids[*cell as usize];
+            centroid.0 += position.0;
+            centroid.1 += position.1;
+            centroid.2 += position.2;
+            transcript_counts[*cell as usize] += 1;
+        }
+    }
+
+    let mut destination = (0..ncells as u32).collect::<Vec<_>>();
+    for (i, dest) in destination.iter_mut().enumerate() {
+        if !degenerate[i] {
+            continue;
+        }
+        *dest = BACKGROUND_CELL;
+        if mode == DegenerateCells::Drop || transcript_counts[i] == 0 {
+            continue;
+        }
+
+        let n = transcript_counts[i] as f32;
+        let (x, y, z) = transcript_centroids[i];
+        let (x, y, z) = (x / n, y / n, z / n);
+
+        if let Some((j, _)) = cell_centroids
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| !degenerate[*j] && !cell_dormant[*j] && cell_voxel_counts[*j] > 0)
+            .map(|(j, c)| (j, (c.0 - x).powi(2) + (c.1 - y).powi(2) + (c.2 - z).powi(2)))
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+        {
+            *dest = j as u32;
+        }
+    }
+
+    destination
+}
+
+// Pick an initial voxel size so that the median nucleus covers roughly
+// `target_voxels` voxels. The median is used rather than the mean so a handful
+// of huge or mis-segmented nuclei don't blow up the voxel size.
+fn calibrate_voxel_size(
+    ncells: usize,
+    transcripts: &[Transcript],
+    nucleus_assignments: &[u32],
+    target_voxels: f32,
+) -> f32 {
+    const MIN_VOXEL_SIZE: f32 = 0.5;
+    const MAX_VOXEL_SIZE: f32 = 20.0;
+    const FALLBACK_VOXEL_SIZE: f32 = 4.0;
+
+    if target_voxels <= 0.0 {
+        panic!("--calibrate-voxel-size-target must be positive");
+    }
+
+    let nucleus_areas: Vec<f32> = compute_cell_areas(ncells, transcripts, nucleus_assignments)
+        .into_iter()
+        .filter(|a| *a > 0.0)
+        .sorted_by(|a, b| a.partial_cmp(b).unwrap())
+        .collect();
+
+    if nucleus_areas.is_empty() {
+        eprintln!(
+            "Warning: no nuclei with measurable area. Using voxel size {}",
+            FALLBACK_VOXEL_SIZE
+        );
+        return FALLBACK_VOXEL_SIZE;
+    }
+
+    let median_nucleus_area = nucleus_areas[nucleus_areas.len() / 2];
+    let voxel_size = (median_nucleus_area / target_voxels).sqrt();
+    let clamped_voxel_size = voxel_size.clamp(MIN_VOXEL_SIZE, MAX_VOXEL_SIZE);
+
+    if clamped_voxel_size != voxel_size {
+        eprintln!(
+            "Warning: calibrated voxel size {} is out of range. Clamping to {}",
+            voxel_size, clamped_voxel_size
+        );
+    }
+    println!(
+        "Median nucleus area: {}. Using initial voxel size {}",
+        median_nucleus_area, clamped_voxel_size
+    );
+
+    clamped_voxel_size
+}
+
+// Iterations of the final phase that should run at the final resolution before
+// samples start being recorded.
+const MIN_FINAL_BURNIN: usize = 50;
+
+// Outputs written with --counts-from-transcript-metadata and --by-fov, respectively.
+const COUNTS_FROM_METADATA_OUTPUTS: &[&str] = &[
+    "output-maxpost-counts",
+    "output-cell-correspondence",
+    "output-tissue-boundary",
+    "output-density",
+];
+const BY_FOV_OUTPUTS: &[&str] = &["output-maxpost-counts", "output-transcript-metadata"];
+
+// Check arguments before anything is read: panic on an invalid schedule,
+// recording, or out of range numeric option, and warn about settings that
+// won't do what they look like (empty or unreachable schedule phases, outputs
+// the run mode can't write, too short a burn-in before recording).
+fn validate_args(args: &Args) {
+    if args.schedule.is_empty() {
+        panic!("schedule must have at least one entry");
+    }
+
+    let last = *args.schedule.last().unwrap();
+    if args.recorded_samples > last {
+        panic!("recorded-samples must be <= the last entry in the schedule");
+    }
+
+    if args.recorded_thinning == 0 {
+        panic!("recorded-thinning must be at least 1");
+    }
+
+    if args.components_concentration.is_nan() || args.components_concentration <= 0.0 {
+        panic!("components-concentration must be positive");
+    }
+
+    // A phase of no iterations doubles resolution twice in a row, going
+    // straight through a resolution without sampling at it.
+    for (phase, &niter) in args.schedule[..args.schedule.len() - 1].iter().enumerate() {
+        if niter == 0 {
+            eprintln!(
+                "Warning: schedule phase {} has no iterations, so resolution is doubled again right after the previous one",
+                phase + 1
+            );
+        }
+    }
+
+    if let Some(max_resolution_level) = args.max_resolution_level {
+        if max_resolution_level >= args.schedule.len() - 1 {
+            eprintln!(
+                "Warning: --max-resolution-level {} has no effect, since a schedule of {} phases only doubles resolution {} times",
+                max_resolution_level,
+                args.schedule.len(),
+                args.schedule.len() - 1
+            );
+        }
+    }
+
+    if args.min_cell_volume.is_some_and(|v| v.is_nan() || v < 0.0) {
+        panic!("min-cell-volume must be non-negative");
+    }
+
+    if args.min_cell_volume_factor.is_nan() || args.min_cell_volume_factor < 0.0 {
+        panic!("min-cell-volume-factor must be non-negative");
+    }
+
+    if args.max_cell_volume.is_some_and(|v| v.is_nan() || v <= 0.0) {
+        panic!("max-cell-volume must be positive");
+    }
+
+    if args.normalization_target_sum.is_nan() || args.normalization_target_sum <= 0.0 {
+        panic!("normalization-target-sum must be positive");
+    }
+
+    if args.z_diffusion_sigma.is_some_and(|v| v.is_nan() || v <= 0.0) {
+        panic!("z-diffusion-sigma must be positive");
+    }
+
+    if args.z_diffusion_proposal_sigma.is_some_and(|v| v.is_nan() || v <= 0.0) {
+        panic!("z-diffusion-proposal-sigma must be positive");
+    }
+
+    if !(0.0..=1.0).contains(&args.membrane_prior_weight) {
+        panic!("membrane-prior-weight must be between 0 and 1");
+    }
+
+    if args.fov_overlap.is_nan() || args.fov_overlap < 0.0 {
+        panic!("fov-overlap must be non-negative");
+    }
+
+    if args.background_prior_weight.is_nan() || args.background_prior_weight < 0.0 {
+        panic!("background-prior-weight must be non-negative");
+    }
+
+    if args.confusion_prior_rate.is_some_and(|v| v.is_nan() || v < 0.0) {
+        panic!("confusion-prior-rate must be non-negative");
+    }
+
+    if args.confusion_prior_weight.is_nan() || args.confusion_prior_weight < 0.0 {
+        panic!("confusion-prior-weight must be non-negative");
+    }
+
+    // --counts-from-transcript-metadata has no sampler state, which most
+    // outputs need, and --by-fov only stitches per-transcript results.
+    let mode = if args.counts_from_transcript_metadata.is_some() {
+        Some(("--counts-from-transcript-metadata", COUNTS_FROM_METADATA_OUTPUTS))
+    } else if args.by_fov {
+        Some(("--by-fov", BY_FOV_OUTPUTS))
+    } else {
+        None
+    };
+    if let Some((mode, supported)) = mode {
+        let mut args = args.clone();
+        let unsupported = output_paths(&mut args)
+            .into_iter()
+            .filter(|(name, path)| path.is_some() && !supported.contains(name))
+            .map(|(name, _)| format!("--{}", name))
+            .collect::<Vec<_>>();
+        if !unsupported.is_empty() {
+            eprintln!(
+                "Warning: not writing {}, which can't be written with {}",
+                unsupported.join(", "),
+                mode
+            );
+        }
+    }
+
+    // Recording right after the last doubling averages over samples that
+    // haven't yet settled at the new resolution, inflating the uncertainty.
+    let burnin = last - args.recorded_samples;
+    if burnin < MIN_FINAL_BURNIN {
+        eprintln!(
+            "Warning: only {} iteration(s) of the final schedule phase run before the {} recorded samples. \
+            Uncertainty estimates will be noisy. Make the last --schedule entry at least {} more than --recorded-samples.",
+            burnin, args.recorded_samples, MIN_FINAL_BURNIN
+        );
+    }
+}
+
+// Every output path argument. New outputs need to be listed here too.
+fn output_paths(args: &mut Args) -> Vec<(&'static str, &mut Option<String>)> {
+    vec![
+        ("output-maxpost-counts", &mut args.output_maxpost_counts),
+        ("output-expected-counts", &mut args.output_expected_counts),
+        ("output-normalized-counts", &mut args.output_normalized_counts),
+        ("output-rates", &mut args.output_rates),
+        ("output-background-rates", &mut args.output_background_rates),
+        ("output-component-params", &mut args.output_component_params),
+        ("output-component-extent", &mut args.output_component_extent),
+        ("output-cell-correspondence", &mut args.output_cell_correspondence),
+        ("output-cell-hulls", &mut args.output_cell_hulls),
+        ("output-tissue-boundary", &mut args.output_tissue_boundary),
+        ("output-density", &mut args.output_density),
+        ("output-proposal-stats", &mut args.output_proposal_stats),
+        ("output-run-metadata", &mut args.output_run_metadata),
+        ("output-qc-summary", &mut args.output_qc_summary),
+        ("output-cell-metadata", &mut args.output_cell_metadata),
+        ("output-transcript-metadata", &mut args.output_transcript_metadata),
+        ("output-gene-metadata", &mut args.output_gene_metadata),
+        ("output-cell-voxels", &mut args.output_cell_voxels),
+        ("output-cell-voxels-each-level", &mut args.output_cell_voxels_each_level),
+        ("output-label-volume", &mut args.output_label_volume),
+        ("output-cell-polygons", &mut args.output_cell_polygons),
+        ("output-cell-polygons-by-component", &mut args.output_cell_polygons_by_component),
+        ("output-union-cell-polygons", &mut args.output_union_cell_polygons),
+        ("output-cell-polygon-layers", &mut args.output_cell_polygon_layers),
+    ]
+}
+
+// An empty path turns an output off, which is the only way to skip one that's
+// written by default.
+fn drop_empty_outputs(args: &mut Args) {
+    for (_, path) in output_paths(args) {
+        if path.as_deref() == Some("") {
+            *path = None;
+        }
+    }
+}
+
+// Turn off every output but the max posterior counts, for --counts-only.
+fn keep_only_counts_output(args: &mut Args) {
+    let counts = args
+        .output_maxpost_counts
+        .take()
+        .unwrap_or_else(|| String::from("maxpost-counts.csv.gz"));
+    for (_, path) in output_paths(args) {
+        *path = None;
+    }
+    args.output_maxpost_counts = Some(counts);
+}
+
+// With a WKT --output-cell-polygons-fmt, give a GeoJSON file name, like the
+// default, the csv suffix for the format instead.
+fn rename_wkt_polygons_output(args: &mut Args) {
+    let suffix = match args.output_cell_polygons_fmt {
+        GeometryFormat::Wkt => ".csv",
+        GeometryFormat::WktGz => ".csv.gz",
+        GeometryFormat::WktZst => ".csv.zst",
+        _ => return,
+    };
+    if let Some(path) = &mut args.output_cell_polygons {
+        for geojson_suffix in [".geojson.gz", ".geojson.zst", ".geojson"] {
+            if let Some(stem) = path.strip_suffix(geojson_suffix) {
+                *path = format!("{}{}", stem, suffix);
+                break;
+            }
+        }
+    }
+}
+
+// Give gzipped output paths the suffix for --compression.
+fn rename_compressed_outputs(args: &mut Args) {
+    for path in output_paths(args).into_iter().filter_map(|(_, path)| path.as_mut()) {
+        *path = with_output_compression(path);
+    }
+}
+
+// Move every relative output path under `dir`.
+fn place_outputs_in_dir(args: &mut Args, dir: &str) {
+    std::fs::create_dir_all(dir)
+        .unwrap_or_else(|err| panic!("Unable to create output directory '{}': {}", dir, err));
+
+    for path in output_paths(args).into_iter().filter_map(|(_, path)| path.as_mut()) {
+        if std::path::Path::new(path.as_str()).is_relative() {
+            *path = std::path::Path::new(dir).join(&path).to_string_lossy().into_owned();
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_hexbin_sampler(
+    prog: &mut Progress,
+    sampler: &mut VoxelSampler,
+    priors: &ModelPriors,
+    params: &mut ModelParams,
+    transcripts: &[Transcript],
+    niter: usize,
+    local_steps_per_iter: usize,
+    mut uncertainty: Option<&mut UncertaintyTracker>,
+    record_every: usize,
+    total_steps: &mut usize,
+    proposal_stats_trace: &mut Vec<ProposalStats>,
+    loglik_every: usize,
+    monitor_cell_polygons: &Option<String>,
+    monitor_cell_polygons_freq: usize,
+    sample_cell_regions: bool,
+    burnin: bool,
+    hillclimb: bool,
+) {
+    if interrupted() {
+        return;
+    }
+
+    let mut proposal_stats = ProposalStats::new();
+    let mut log_likelihood = None;
+    let mut init_uncertainty = if record_every > 1 { None } else { uncertainty.as_deref_mut() };
+    sampler.sample_global_params(
+        priors,
+        params,
+        &mut proposal_stats,
+        transcripts,
+        &mut init_uncertainty,
+        burnin,
+    );
+
+    for i in 0..niter {
+        if interrupted() {
+            break;
+        }
+
+        // With thinning, only every `record_every`th iteration contributes to
+        // the uncertainty tracker.
+        let recording = (i + 1) % record_every == 0;
+        let mut iter_uncertainty = if recording { uncertainty.as_deref_mut() } else { None };
+        if record_every > 1 {
+            if let Some(uncertainty) = iter_uncertainty.as_mut() {
+                uncertainty.start_recording(params);
+            }
+        }
+
+        if sample_cell_regions {
+            // let t0 = std::time::Instant::now();
+            for _ in 0..local_steps_per_iter {
+                sampler.sample_cell_regions(
+                    priors,
+                    params,
+                    &mut proposal_stats,
+                    transcripts,
+                    hillclimb,
+                    &mut iter_uncertainty,
+                );
+            }
+            // println!("Sample cell regions: {:?}", t0.elapsed());
+        }
+        // let t0 = std::time::Instant::now();
+        sampler.sample_global_params(
+            priors,
+            params,
+            &mut proposal_stats,
+            transcripts,
+            &mut iter_uncertainty,
+            burnin,
+        );
+
+        if record_every > 1 {
+            if let Some(uncertainty) = iter_uncertainty.as_mut() {
+                uncertainty.stop_recording(params);
+            }
+        }
+        if let Some(uncertainty) = iter_uncertainty.as_mut() {
+            uncertainty.record_cell_existence(priors, params);
+        }
+        // println!("Sample parameters: {:?}", t0.elapsed());
+
+        // Computing the log-likelihood is a full pass over the data, so by
+        // default it's only done periodically just to update the progress bar.
+        if log_likelihood.is_none() || total_steps.is_multiple_of(loglik_every) {
+            log_likelihood = Some(params.log_likelihood(priors));
+        }
+
+        let nassigned = params.nassigned();
+        let nforeground = params.nforeground();
+        prog.inc(log_likelihood.unwrap(), nassigned, nforeground, transcripts.len());
+
+        // println!("Log likelihood: {}", params.log_likelihood());
+
+        proposal_stats.nonempty_cells = params.cell_population.iter().filter(|p| **p > 0).count();
+
+        // dbg!(&proposal_stats);
+        // dbg!(sampler.mismatch_edge_stats());
+        proposal_stats_trace.push(proposal_stats.clone());
+        proposal_stats.reset();
+
+        // if i % 100 == 0 {
+        //     println!("Iteration {} ({} unassigned transcripts)", i, params.nunassigned());
+        // }
+
+        if total_steps.is_multiple_of(monitor_cell_polygons_freq) {
+            if let Some(basename) = monitor_cell_polygons {
+                let filename = format!("{}-{:04}.geojson{}", basename, *total_steps, compressed_suffix());
+                let (cell_polygons, _cell_flattened_polygons) = sampler.cell_polygons();
+                write_cell_layered_multipolygons(&Some(filename), cell_polygons);
+            }
+        }
+
+        *total_steps += 1;
+    }
+}
+
+#[test]
+fn degenerate_cells_skip_unborn_destinations() {
+    // cell 0 is degenerate near the origin, 1 is a live cell further away,
+    // 2 is an unborn pool cell, and 3 a cell left without voxels, both at
+    // the placeholder centroid.
+    let degenerate = [true, false, false, false];
+    let dormant = [false, false, true, false];
+    let centroids = [(0.0, 0.0, 0.0), (10.0, 10.0, 0.0), (0.0, 0.0, 0.0), (0.0, 0.0, 0.0)];
+    let voxel_counts = [0, 20, 0, 0];
+    let assignments = [(0, 1.0), (0, 1.0), (1, 1.0)];
+    let positions = [(1.0, 1.0, 0.0), (2.0, 0.0, 0.0), (10.0, 10.0, 0.0)];
+
+    let destinations = |mode| {
+        degenerate_cell_destinations(mode, &degenerate, &dormant, &centroids, &voxel_counts, &assignments, &positions)
+    };
+    assert_eq!(destinations(DegenerateCells::Merge), vec![1, 1, 2, 3]);
+    assert_eq!(destinations(DegenerateCells::Drop), vec![BACKGROUND_CELL, 1, 2, 3]);
+}
+
+#[test]
+fn by_fov_stitching_merges_mutual_best_matches() {
+    // FOV 0 has cells A and B, FOV 1 has C, D, and E. C and E both overlap A,
+    // but C shares more of its transcripts, and D shares too few with B.
+    let nodes = [(0, 0), (0, 1), (1, 0), (1, 1), (1, 2)];
+    let (a, b, c, d, e) = (0, 1, 2, 3, 4);
+    let home_cells: HashMap<u64, usize> = (1..=7).map(|id| (id, a)).chain([(10, b), (11, b)]).collect();
+    let margin_cells = [(1, c), (2, c), (3, c), (4, c), (5, e), (6, e), (7, e), (10, d), (11, d)];
+
+    let roots = stitch_fov_cells(&nodes, &home_cells, &margin_cells);
+    assert_eq!(roots[a], roots[c]);
+    assert_eq!(roots.iter().unique().count(), 4);
+
+    let mut cell_index = HashMap::new();
+    assert_eq!(number_stitched_cells(&[Some(a), Some(b), None], &roots, &mut cell_index), vec![0, 1, BACKGROUND_CELL]);
+    assert_eq!(number_stitched_cells(&[Some(c), Some(d), Some(e)], &roots, &mut cell_index), vec![0, 2, 3]);
+}
+
+#[test]
+fn oversplit_cells_merge_smaller_into_larger() {
+    // 0 and 1 are mutual partners, as are 3 and 4, but 2's partner prefers 0.
+    let partners = [Some(1), Some(0), Some(1), Some(4), Some(3), None];
+    let mut population = vec![5, 10, 3, 8, 2, 4];
+    let destination = oversplit_merge_destinations(&partners, &population);
+    assert_eq!(destination, vec![1, 1, 2, 3, 3, 5]);
+
+    let mut assignments = [(0, 1.0), (1, 1.0), (4, 1.0), (BACKGROUND_CELL, 1.0), (5, 1.0)];
+    let mut counts = Array2::from_shape_fn((2, 6), |(g, c)| (10 * g + c) as u32);
+    let mut ecounts = Some(counts.mapv(|c| c as f32));
+    move_cells(&destination, &mut population, &mut assignments, &mut counts, &mut ecounts);
+
+    assert_eq!(population, vec![0, 15, 3, 10, 0, 4]);
+    assert_eq!(
+        assignments.iter().map(|(cell, _)| *cell).collect::<Vec<_>>(),
+        vec![1, 1, 3, BACKGROUND_CELL, 5]
+    );
+    assert_eq!(counts.row(0).to_vec(), vec![0, 1, 2, 7, 0, 5]);
+    assert_eq!(counts.row(1).to_vec(), vec![0, 21, 12, 27, 0, 15]);
+    assert_eq!(ecounts.unwrap(), counts.mapv(|c| c as f32));
+}
+
+#[test]
+fn degenerate_cells_are_merged_or_dropped() {
+    // Cell 1 is degenerate, with transcripts nearest cell 2.
+    let degenerate = [false, true, false];
+    let dormant = [false; 3];
+    let centroids = [(0.0, 0.0, 0.0), (0.0, 0.0, 0.0), (10.0, 0.0, 0.0)];
+    let voxel_counts = [20, 0, 20];
+    let positions = [(0.0, 0.0, 0.0), (9.0, 0.0, 0.0), (8.0, 0.0, 0.0), (10.0, 0.0, 0.0)];
+    let initial_assignments = [(0, 1.0), (1, 1.0), (1, 1.0), (2, 1.0)];
+
+    for (mode, expected_assignments, expected_population, expected_counts) in [
+        (DegenerateCells::Merge, vec![0, 2, 2, 2], vec![1, 0, 3], vec![1, 0, 3]),
+        (
+            DegenerateCells::Drop,
+            vec![0, BACKGROUND_CELL, BACKGROUND_CELL, 2],
+            vec![1, 0, 1],
+            vec![1, 0, 1],
+        ),
+    ] {
+        let destination = degenerate_cell_destinations(
+            mode,
+            &degenerate,
+            &dormant,
+            &centroids,
+            &voxel_counts,
+            &initial_assignments,
+            &positions,
+        );
+        let mut assignments = initial_assignments;
+        let mut population = vec![1, 2, 1];
+        let mut counts = Array2::from_shape_vec((1, 3), vec![1, 2, 1]).unwrap();
+        move_cells(&destination, &mut population, &mut assignments, &mut counts, &mut None);
+
+        assert_eq!(assignments.iter().map(|(cell, _)| *cell).collect::<Vec<_>>(), expected_assignments);
+        assert_eq!(population, expected_population);
+        assert_eq!(counts.row(0).to_vec(), expected_counts);
     }
 }
@@ -8,17 +8,177 @@ use parquet::file::properties::WriterProperties;
 use parquet::basic::{Compression::ZSTD, ZstdLevel};
 use flate2::write::GzEncoder;
 use flate2::Compression;
-use geo::MultiPolygon;
+use geo::{Area, Geometry, LineString, MultiPolygon, Polygon};
 use ndarray::{Array1, Array2, Axis, Zip};
+use itertools::Itertools;
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::Write;
-use std::sync::Arc;
+use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
 
-use crate::schemas::{transcript_metadata_schema, OutputFormat};
-use super::sampler::transcripts::Transcript;
+use crate::schemas::{transcript_metadata_schema, with_source_transcript_id_column, GeometryFormat, OutputCompression, OutputFormat, RatesLayout};
+use super::sampler::hull::{concave_hull_area, convex_hull_area};
+use super::sampler::transcripts::{CellIndex, Compartment, Transcript};
 use super::sampler::transcripts::BACKGROUND_CELL;
-use super::sampler::voxelsampler::VoxelSampler;
-use super::sampler::{ModelParams, TranscriptState};
+use super::sampler::voxelsampler::{Voxel, VoxelSampler};
+use super::sampler::{acceptance_rate, ModelParams, ProposalStats, TranscriptState};
+
+// Number of decimal places floating point values are rounded to in CSV output.
+static OUTPUT_PRECISION: AtomicUsize = AtomicUsize::new(4);
+
+pub fn set_output_precision(precision: usize) {
+    OUTPUT_PRECISION.store(precision, Ordering::Relaxed);
+}
+
+// Compression level for gzipped output, flate2's default unless set.
+static GZIP_LEVEL: AtomicU32 = AtomicU32::new(6);
+
+pub fn set_gzip_level(level: u32) {
+    GZIP_LEVEL.store(level, Ordering::Relaxed);
+}
+
+fn gzip_compression() -> Compression {
+    Compression::new(GZIP_LEVEL.load(Ordering::Relaxed))
+}
+
+// What the gzipped formats are actually compressed with, from --compression.
+// Outputs named .zst are zstd regardless.
+static OUTPUT_COMPRESSION: RwLock<OutputCompression> = RwLock::new(OutputCompression::Gzip);
+
+pub fn set_output_compression(compression: OutputCompression) {
+    *OUTPUT_COMPRESSION.write().unwrap() = compression;
+}
+
+fn output_compression() -> OutputCompression {
+    *OUTPUT_COMPRESSION.read().unwrap()
+}
+
+// Suffix for compressed output file names, matching --compression.
+pub fn compressed_suffix() -> &'static str {
+    match output_compression() {
+        OutputCompression::Gzip => ".gz",
+        OutputCompression::Zstd => ".zst",
+        OutputCompression::None => "",
+    }
+}
+
+// Rename a gzipped output path to match --compression.
+pub fn with_output_compression(path: &str) -> String {
+    match path.strip_suffix(".gz") {
+        Some(stem) => format!("{}{}", stem, compressed_suffix()),
+        None => path.to_string(),
+    }
+}
+
+// Wrap a file in an encoder for the given compression. Encoders finish when
+// dropped.
+fn compressed_writer(file: File, compression: OutputCompression) -> Box<dyn Write + Send> {
+    match compression {
+        OutputCompression::Gzip => Box::new(GzEncoder::new(file, gzip_compression())),
+        OutputCompression::Zstd => Box::new(zstd::Encoder::new(file, 0).unwrap().auto_finish()),
+        OutputCompression::None => Box::new(BufWriter::new(file)),
+    }
+}
+
+// Prefix and first number for cell ids in outputs, so cells from separate runs
+// stay distinct when combined. Cells are numbered from zero internally.
+static CELL_ID_PREFIX: RwLock<String> = RwLock::new(String::new());
+static CELL_ID_START: AtomicU32 = AtomicU32::new(0);
+
+pub fn set_cell_ids(prefix: &str, start: u32) {
+    *CELL_ID_PREFIX.write().unwrap() = prefix.to_string();
+    CELL_ID_START.store(start, Ordering::Relaxed);
+}
+
+fn cell_id_number(cell: u32) -> u32 {
+    cell.checked_add(CELL_ID_START.load(Ordering::Relaxed))
+        .filter(|&id| id != BACKGROUND_CELL)
+        .expect("cell-id-start is too large for the number of cells")
+}
+
+// Cell id columns are strings when there's a prefix, and integers otherwise.
+pub fn cell_id_field(name: &str, nullable: bool) -> Field {
+    if CELL_ID_PREFIX.read().unwrap().is_empty() {
+        Field::new(name, DataType::UInt32, nullable)
+    } else {
+        Field::new(name, DataType::Utf8, true)
+    }
+}
+
+// Output ids for cell indexes. Integer ids keep BACKGROUND_CELL as is, while
+// string ids leave background null.
+pub fn cell_id_array(cells: impl Iterator<Item = Option<u32>>) -> Arc<dyn arrow::array::Array> {
+    let prefix = CELL_ID_PREFIX.read().unwrap();
+    if prefix.is_empty() {
+        Arc::new(
+            cells
+                .map(|cell| cell.map(|cell| if cell == BACKGROUND_CELL { cell } else { cell_id_number(cell) }))
+                .collect::<arrow::array::UInt32Array>(),
+        )
+    } else {
+        Arc::new(
+            cells
+                .map(|cell| {
+                    cell.filter(|&cell| cell != BACKGROUND_CELL)
+                        .map(|cell| format!("{}{}", prefix, cell_id_number(cell)))
+                })
+                .collect::<arrow::array::StringArray>(),
+        )
+    }
+}
+
+// A cell id formatted as a GeoJSON property value.
+fn cell_id_property(cell: usize) -> String {
+    let prefix = CELL_ID_PREFIX.read().unwrap();
+    let id = cell_id_number(cell as u32);
+    if prefix.is_empty() {
+        id.to_string()
+    } else {
+        json::stringify(format!("{}{}", prefix, id))
+    }
+}
+
+// Round a value to the output precision, keeping at least that many
+// significant digits for values smaller than one.
+fn round_to_output_precision(x: f64, precision: usize) -> f64 {
+    if x == 0.0 || !x.is_finite() {
+        return x;
+    }
+    let magnitude = x.abs().log10().floor() as i32;
+    let decimals = (precision as i32).max(precision as i32 - magnitude - 1).min(15);
+    let scale = 10.0_f64.powi(decimals);
+    (x * scale).round() / scale
+}
+
+// Round every float column in the batch, so CSV output doesn't carry a long
+// tail of meaningless digits.
+fn round_float_columns(batch: &RecordBatch) -> RecordBatch {
+    let precision = OUTPUT_PRECISION.load(Ordering::Relaxed);
+    let columns = batch
+        .columns()
+        .iter()
+        .map(|column| -> Arc<dyn arrow::array::Array> {
+            match column.data_type() {
+                DataType::Float32 => {
+                    let column = column.as_any().downcast_ref::<arrow::array::Float32Array>().unwrap();
+                    Arc::new(column.unary::<_, arrow::datatypes::Float32Type>(|x| {
+                        round_to_output_precision(x as f64, precision) as f32
+                    }))
+                }
+                DataType::Float64 => {
+                    let column = column.as_any().downcast_ref::<arrow::array::Float64Array>().unwrap();
+                    Arc::new(column.unary::<_, arrow::datatypes::Float64Type>(|x| {
+                        round_to_output_precision(x, precision)
+                    }))
+                }
+                _ => column.clone(),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    RecordBatch::try_new(batch.schema(), columns).unwrap()
+}
 
 pub fn write_table(
     filename: &str,
@@ -32,16 +192,18 @@ pub fn write_table(
 
     let mut file = File::create(filename).unwrap();
 
+    let csv_compression = match fmt {
+        OutputFormat::Csv => Some(OutputCompression::None),
+        OutputFormat::CsvGz => Some(output_compression()),
+        OutputFormat::CsvZst => Some(OutputCompression::Zstd),
+        _ => None,
+    };
+
     match fmt {
-        OutputFormat::Csv => {
-            if write_table_csv(&mut file, batch).is_err() {
-                panic!("Error writing csv file: {}", filename);
-            }
-        }
-        OutputFormat::CsvGz => {
-            let mut encoder = GzEncoder::new(file, Compression::default());
+        OutputFormat::Csv | OutputFormat::CsvGz | OutputFormat::CsvZst => {
+            let mut encoder = compressed_writer(file, csv_compression.unwrap());
             if write_table_csv(&mut encoder, batch).is_err() {
-                panic!("Error writing csv.gz file: {}", filename);
+                panic!("Error writing csv file: {}", filename);
             }
         }
         OutputFormat::Parquet => {
@@ -65,7 +227,7 @@ where
     let mut writer = csv::WriterBuilder::new()
         .with_header(true)
         .build(output);
-    writer.write(batch)
+    writer.write(&round_float_columns(batch))
 }
 
 fn write_table_parquet<W>(
@@ -90,6 +252,8 @@ where
 pub fn infer_format_from_filename(filename: &str) -> OutputFormat {
     if filename.ends_with(".csv.gz") {
         OutputFormat::CsvGz
+    } else if filename.ends_with(".csv.zst") {
+        OutputFormat::CsvZst
     } else if filename.ends_with(".csv") {
         OutputFormat::Csv
     } else if filename.ends_with(".parquet") {
@@ -168,10 +332,16 @@ pub fn write_expected_counts(
 pub fn write_rates(
     output_rates: &Option<String>,
     output_rates_fmt: OutputFormat,
+    output_rates_layout: RatesLayout,
     params: &ModelParams,
     transcript_names: &[String],
 ) {
     if let Some(output_rates) = output_rates {
+        if output_rates_layout == RatesLayout::Tidy {
+            write_rates_tidy(output_rates, output_rates_fmt, params, transcript_names);
+            return;
+        }
+
         let schema = Schema::new(
             transcript_names
                 .iter()
@@ -196,6 +366,76 @@ pub fn write_rates(
     }
 }
 
+// Background rates as a layer-by-gene matrix, in the same layout as write_rates.
+pub fn write_background_rates(
+    output_background_rates: &Option<String>,
+    output_background_rates_fmt: OutputFormat,
+    params: &ModelParams,
+    transcript_names: &[String],
+) {
+    if let Some(output_background_rates) = output_background_rates {
+        let schema = Schema::new(
+            transcript_names
+                .iter()
+                .map(|name| {
+                    Field::new(name, DataType::Float32, false)
+                }).collect::<Vec<Field>>()
+        );
+
+        let mut columns: Vec<Arc<dyn arrow::array::Array>> = Vec::new();
+        for row in params.λ_bg.rows() {
+            columns.push(Arc::new(
+                row.iter().cloned().collect::<arrow::array::Float32Array>(),
+            ));
+        }
+
+        let batch = RecordBatch::try_new(
+            Arc::new(schema),
+            columns
+        ).unwrap();
+
+        write_table(output_background_rates, output_background_rates_fmt, &batch);
+    }
+}
+
+// Long format rates, with the cell's component and that component's mixing
+// weight, so rates can be grouped or weighted by component.
+fn write_rates_tidy(
+    output_rates: &str,
+    output_rates_fmt: OutputFormat,
+    params: &ModelParams,
+    transcript_names: &[String],
+) {
+    let schema = Schema::new(vec![
+        cell_id_field("cell", false),
+        Field::new("gene", DataType::LargeUtf8, false),
+        Field::new("rate", DataType::Float32, false),
+        Field::new("component", DataType::UInt32, false),
+        Field::new("component_weight", DataType::Float32, false),
+    ]);
+
+    let (ngenes, ncells) = params.λ.dim();
+    let cells = (0..ncells as u32).flat_map(|i| std::iter::repeat_n(Some(i), ngenes));
+    let genes = (0..ncells).flat_map(|_| transcript_names.iter().map(Some));
+    let rates = params.λ.t().iter().cloned().collect::<arrow::array::Float32Array>();
+    let components = params.z.iter().flat_map(|&z| std::iter::repeat_n(z, ngenes));
+    let weights = params
+        .z
+        .iter()
+        .flat_map(|&z| std::iter::repeat_n(params.π[z as usize], ngenes));
+
+    let columns: Vec<Arc<dyn arrow::array::Array>> = vec![
+        cell_id_array(cells),
+        Arc::new(genes.collect::<arrow::array::LargeStringArray>()),
+        Arc::new(rates),
+        Arc::new(components.collect::<arrow::array::UInt32Array>()),
+        Arc::new(weights.collect::<arrow::array::Float32Array>()),
+    ];
+
+    let batch = RecordBatch::try_new(Arc::new(schema), columns).unwrap();
+    write_table(output_rates, output_rates_fmt, &batch);
+}
+
 pub fn write_component_params(
     output_component_params: &Option<String>,
     output_component_params_fmt: OutputFormat,
@@ -213,13 +453,13 @@ pub fn write_component_params(
         let mut fields = Vec::new();
         fields.push(Field::new("gene", DataType::Utf8, false));
         for i in 0..ncomponents {
-            fields.push(Field::new(&format!("α_{}", i), DataType::Float32, false));
-            fields.push(Field::new(&format!("β_{}", i), DataType::Float32, false));
+            fields.push(Field::new(format!("α_{}", i), DataType::Float32, false));
+            fields.push(Field::new(format!("β_{}", i), DataType::Float32, false));
         }
         let schema = Schema::new(fields);
 
         let mut columns: Vec<Arc<dyn arrow::array::Array>> = Vec::new();
-        columns.push(Arc::new(arrow::array::StringArray::from(transcript_names.iter().cloned().collect::<Vec<String>>())));
+        columns.push(Arc::new(arrow::array::StringArray::from(transcript_names.to_vec())));
 
         Zip::from(α.rows()).and(β.rows()).for_each(|α, β| {
             columns.push(Arc::new(α.iter().cloned().collect::<arrow::array::Float32Array>()));
@@ -239,6 +479,159 @@ pub fn write_component_params(
     }
 }
 
+// Spatial extent of the cells assigned to each component: the mean and RMS
+// spread of their centroids, and their bounding box. Localized components
+// tend to be tissue regions, dispersed ones cell types.
+pub fn write_component_extent(
+    output_component_extent: &Option<String>,
+    output_component_extent_fmt: OutputFormat,
+    params: &ModelParams,
+    cell_centroids: &[(f32, f32, f32)],
+) {
+    if let Some(output_component_extent) = output_component_extent {
+        let ncomponents = params.ncomponents();
+        let mut component_centroids = vec![Vec::new(); ncomponents];
+        for (&z, &(x, y, _)) in params.z.iter().zip(cell_centroids) {
+            if x.is_finite() && y.is_finite() {
+                component_centroids[z as usize].push((x, y));
+            }
+        }
+
+        let mut ncells = Vec::with_capacity(ncomponents);
+        let mut mean_x = Vec::with_capacity(ncomponents);
+        let mut mean_y = Vec::with_capacity(ncomponents);
+        let mut spread = Vec::with_capacity(ncomponents);
+        let mut min_x = Vec::with_capacity(ncomponents);
+        let mut max_x = Vec::with_capacity(ncomponents);
+        let mut min_y = Vec::with_capacity(ncomponents);
+        let mut max_y = Vec::with_capacity(ncomponents);
+        for centroids in &component_centroids {
+            ncells.push(centroids.len() as u32);
+            if centroids.is_empty() {
+                for column in [&mut mean_x, &mut mean_y, &mut spread, &mut min_x, &mut max_x, &mut min_y, &mut max_y] {
+                    column.push(None);
+                }
+                continue;
+            }
+
+            let n = centroids.len() as f32;
+            let μx = centroids.iter().map(|(x, _)| x).sum::<f32>() / n;
+            let μy = centroids.iter().map(|(_, y)| y).sum::<f32>() / n;
+            let msd = centroids
+                .iter()
+                .map(|(x, y)| (x - μx).powi(2) + (y - μy).powi(2))
+                .sum::<f32>()
+                / n;
+            mean_x.push(Some(μx));
+            mean_y.push(Some(μy));
+            spread.push(Some(msd.sqrt()));
+            min_x.push(centroids.iter().map(|(x, _)| *x).reduce(f32::min));
+            max_x.push(centroids.iter().map(|(x, _)| *x).reduce(f32::max));
+            min_y.push(centroids.iter().map(|(_, y)| *y).reduce(f32::min));
+            max_y.push(centroids.iter().map(|(_, y)| *y).reduce(f32::max));
+        }
+
+        let schema = Schema::new(vec![
+            Field::new("component", DataType::UInt32, false),
+            Field::new("cells", DataType::UInt32, false),
+            Field::new("centroid_x", DataType::Float32, true),
+            Field::new("centroid_y", DataType::Float32, true),
+            Field::new("spread", DataType::Float32, true),
+            Field::new("min_x", DataType::Float32, true),
+            Field::new("max_x", DataType::Float32, true),
+            Field::new("min_y", DataType::Float32, true),
+            Field::new("max_y", DataType::Float32, true),
+        ]);
+
+        let mut columns: Vec<Arc<dyn arrow::array::Array>> = vec![
+            Arc::new((0..ncomponents as u32).collect::<arrow::array::UInt32Array>()),
+            Arc::new(arrow::array::UInt32Array::from(ncells)),
+        ];
+        for column in [mean_x, mean_y, spread, min_x, max_x, min_y, max_y] {
+            columns.push(Arc::new(arrow::array::Float32Array::from(column)));
+        }
+
+        let batch = RecordBatch::try_new(Arc::new(schema), columns).unwrap();
+
+        write_table(
+            output_component_extent,
+            output_component_extent_fmt,
+            &batch,
+        );
+    }
+}
+
+// Contingency table of transcripts between proseg cells and the cells in the
+// input, with a row for each pair sharing at least one transcript. Either side
+// is null for transcripts that were unassigned there.
+pub fn write_cell_correspondence(
+    output_cell_correspondence: &Option<String>,
+    output_cell_correspondence_fmt: OutputFormat,
+    cell_assignments: &[(u32, f32)],
+    original_cell_assignments: &[CellIndex],
+    original_cell_ids: &[(u32, String)],
+    fov_names: &[String],
+) {
+    if let Some(output_cell_correspondence) = output_cell_correspondence {
+        let mut pair_counts: HashMap<(CellIndex, CellIndex), u32> = HashMap::new();
+        for (&(cell, _), &original_cell) in cell_assignments.iter().zip(original_cell_assignments) {
+            *pair_counts.entry((cell, original_cell)).or_insert(0) += 1;
+        }
+        let pair_counts = pair_counts.into_iter().sorted().collect::<Vec<_>>();
+
+        let original = |cell: CellIndex| {
+            if cell == BACKGROUND_CELL {
+                None
+            } else {
+                Some(&original_cell_ids[cell as usize])
+            }
+        };
+
+        let schema = Schema::new(vec![
+            cell_id_field("cell", true),
+            Field::new("original_fov", DataType::Utf8, true),
+            Field::new("original_cell_id", DataType::Utf8, true),
+            Field::new("count", DataType::UInt32, false),
+        ]);
+
+        let columns: Vec<Arc<dyn arrow::array::Array>> = vec![
+            cell_id_array(
+                pair_counts
+                    .iter()
+                    .map(|((cell, _), _)| if *cell == BACKGROUND_CELL { None } else { Some(*cell) }),
+            ),
+            Arc::new(
+                pair_counts
+                    .iter()
+                    .map(|((_, original_cell), _)| {
+                        original(*original_cell).map(|(fov, _)| fov_names[*fov as usize].clone())
+                    })
+                    .collect::<arrow::array::StringArray>(),
+            ),
+            Arc::new(
+                pair_counts
+                    .iter()
+                    .map(|((_, original_cell), _)| original(*original_cell).map(|(_, id)| id.clone()))
+                    .collect::<arrow::array::StringArray>(),
+            ),
+            Arc::new(
+                pair_counts
+                    .iter()
+                    .map(|(_, count)| *count)
+                    .collect::<arrow::array::UInt32Array>(),
+            ),
+        ];
+
+        let batch = RecordBatch::try_new(Arc::new(schema), columns).unwrap();
+
+        write_table(
+            output_cell_correspondence,
+            output_cell_correspondence_fmt,
+            &batch,
+        );
+    }
+}
+
 // Assign cells to fovs by finding the most common transcript fov of the
 // assigned transcripts.
 fn cell_fov_vote(
@@ -270,12 +663,81 @@ fn cell_fov_vote(
         .collect::<Vec<u32>>()
 }
 
+// Append a row for the background to the cell metadata, marked by a
+// `background` column. Only its transcript counts are filled in; every other
+// column is null.
+fn with_background_row(
+    schema: Schema,
+    columns: Vec<Arc<dyn arrow::array::Array>>,
+    background_counts: &Array1<u32>,
+) -> (Schema, Vec<Arc<dyn arrow::array::Array>>) {
+    let nrows = columns.first().map(|c| c.len()).unwrap_or(0);
+
+    let mut fields = Vec::new();
+    let mut extended_columns: Vec<Arc<dyn arrow::array::Array>> = Vec::new();
+    for (field, column) in schema.fields().iter().zip(columns) {
+        let row: Arc<dyn arrow::array::Array> = match field.name().as_str() {
+            "population" => Arc::new(arrow::array::UInt64Array::from(vec![background_counts.sum() as u64])),
+            "genes_detected" => Arc::new(arrow::array::UInt32Array::from(vec![
+                background_counts.iter().filter(|&&c| c > 0).count() as u32,
+            ])),
+            "entropy" => Arc::new(arrow::array::Float32Array::from(vec![count_entropy(
+                background_counts.iter().cloned(),
+            )])),
+            _ => arrow::array::new_null_array(field.data_type(), 1),
+        };
+        fields.push(field.as_ref().clone().with_nullable(true));
+        extended_columns.push(arrow::compute::concat(&[column.as_ref(), row.as_ref()]).unwrap());
+    }
+
+    fields.push(Field::new("background", DataType::Boolean, false));
+    extended_columns.push(Arc::new(
+        (0..=nrows).map(|i| Some(i == nrows)).collect::<arrow::array::BooleanArray>(),
+    ));
+
+    (Schema::new(fields), extended_columns)
+}
+
+// Shannon entropy (in nats) of the distribution of a cell's transcripts over genes.
+fn count_entropy(counts: impl Iterator<Item = u32> + Clone) -> f32 {
+    let total = counts.clone().sum::<u32>() as f64;
+    if total == 0.0 {
+        return 0.0;
+    }
+    -counts
+        .filter(|&c| c > 0)
+        .map(|c| {
+            let p = c as f64 / total;
+            p * p.ln()
+        })
+        .sum::<f64>() as f32
+}
+
+// Distance each transcript's sampled position is from where it was observed.
+fn transcript_displacements(transcripts: &[Transcript], transcript_positions: &[(f32, f32, f32)]) -> Vec<f32> {
+    transcripts
+        .iter()
+        .zip(transcript_positions)
+        .map(|(t, (x, y, z))| ((t.x - x).powi(2) + (t.y - y).powi(2) + (t.z - z).powi(2)).sqrt())
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn write_cell_metadata(
     output_cell_metadata: &Option<String>,
     output_cell_metadata_fmt: OutputFormat,
     params: &ModelParams,
     cell_centroids: &[(f32, f32, f32)],
+    cell_voxel_counts: &[u32],
     cell_assignments: &[(u32, f32)],
+    cell_perimeters: &[(f32, f32)],
+    degenerate: &[bool],
+    edge: &[bool],
+    existence: &[f32],
+    oversplit_partners: &[Option<u32>],
+    counts: &Array2<u32>,
+    background_counts: Option<&Array1<u32>>,
+    transcripts: &[Transcript],
     fovs: &[u32],
     fov_names: &[String],
 ) {
@@ -284,20 +746,42 @@ pub fn write_cell_metadata(
     let cell_fovs = cell_fov_vote(ncells, nfovs, cell_assignments, fovs);
 
     if let Some(output_cell_metadata) = output_cell_metadata {
+        // mean displacement of the transcripts assigned to each cell
+        let mut cell_displacements = vec![(0.0_f32, 0_u32); ncells];
+        for (d, (cell, _)) in transcript_displacements(transcripts, &params.transcript_positions)
+            .iter()
+            .zip(cell_assignments)
+        {
+            if *cell != BACKGROUND_CELL {
+                cell_displacements[*cell as usize].0 += d;
+                cell_displacements[*cell as usize].1 += 1;
+            }
+        }
+
         let schema = Schema::new(vec![
-            Field::new("cell", DataType::UInt32, false),
+            cell_id_field("cell", false),
             Field::new("centroid_x", DataType::Float32, false),
             Field::new("centroid_y", DataType::Float32, false),
             Field::new("centroid_z", DataType::Float32, false),
             Field::new("fov", DataType::Utf8, true),
             Field::new("cluster", DataType::UInt16, false),
             Field::new("volume", DataType::Float32, false),
+            Field::new("voxels", DataType::UInt32, false),
             Field::new("population", DataType::UInt64, false),
+            Field::new("perimeter", DataType::Float32, false),
+            Field::new("perimeter_bound", DataType::Float32, false),
+            Field::new("degenerate", DataType::Boolean, false),
+            Field::new("edge_cell", DataType::Boolean, false),
+            Field::new("existence_probability", DataType::Float32, false),
+            cell_id_field("oversplit_partner", true),
+            Field::new("mean_displacement", DataType::Float32, false),
+            Field::new("genes_detected", DataType::UInt32, false),
+            Field::new("entropy", DataType::Float32, false),
         ]);
 
         let columns: Vec<Arc<dyn arrow::array::Array>> = vec![
 
-            Arc::new((0..params.ncells() as u32).collect::<arrow::array::UInt32Array>()),
+            cell_id_array((0..params.ncells() as u32).map(Some)),
             Arc::new(cell_centroids.iter().map(|(x, _, _)| *x).collect::<arrow::array::Float32Array>()),
             Arc::new(cell_centroids.iter().map(|(_, y, _)| *y).collect::<arrow::array::Float32Array>()),
             Arc::new(cell_centroids.iter().map(|(_, _, z)| *z).collect::<arrow::array::Float32Array>()),
@@ -313,9 +797,41 @@ pub fn write_cell_metadata(
                 ).collect::<arrow::array::StringArray>()),
             Arc::new(params.z.iter().map(|&z| z as u16).collect::<arrow::array::UInt16Array>()),
             Arc::new(params.cell_volume.iter().cloned().collect::<arrow::array::Float32Array>()),
-            Arc::new(params.cell_population.iter().map(|&p| p as u64).collect::<arrow::array::UInt64Array>())
+            Arc::new(arrow::array::UInt32Array::from(cell_voxel_counts.to_vec())),
+            Arc::new(params.cell_population.iter().map(|&p| p as u64).collect::<arrow::array::UInt64Array>()),
+            Arc::new(cell_perimeters.iter().map(|(p, _)| *p).collect::<arrow::array::Float32Array>()),
+            Arc::new(cell_perimeters.iter().map(|(_, b)| *b).collect::<arrow::array::Float32Array>()),
+            Arc::new(degenerate.iter().map(|&d| Some(d)).collect::<arrow::array::BooleanArray>()),
+            Arc::new(edge.iter().map(|&e| Some(e)).collect::<arrow::array::BooleanArray>()),
+            Arc::new(arrow::array::Float32Array::from(existence.to_vec())),
+            cell_id_array(oversplit_partners.iter().cloned()),
+            Arc::new(
+                cell_displacements
+                    .iter()
+                    .map(|(d, n)| if *n > 0 { d / *n as f32 } else { 0.0 })
+                    .collect::<arrow::array::Float32Array>()
+            ),
+            Arc::new(
+                counts
+                    .columns()
+                    .into_iter()
+                    .map(|cell_counts| cell_counts.iter().filter(|&&c| c > 0).count() as u32)
+                    .collect::<arrow::array::UInt32Array>()
+            ),
+            Arc::new(
+                counts
+                    .columns()
+                    .into_iter()
+                    .map(|cell_counts| count_entropy(cell_counts.iter().cloned()))
+                    .collect::<arrow::array::Float32Array>()
+            ),
         ];
 
+        let (schema, columns) = match background_counts {
+            Some(background_counts) => with_background_row(schema, columns, background_counts),
+            None => (schema, columns),
+        };
+
         let batch = RecordBatch::try_new(
             Arc::new(schema),
             columns
@@ -330,6 +846,22 @@ pub fn write_cell_metadata(
 }
 
 
+// Give a column the type of cell ids.
+pub fn with_cell_id_column(schema: &Schema, name: &str) -> Schema {
+    let fields = schema
+        .fields()
+        .iter()
+        .map(|field| {
+            if field.name() == name {
+                cell_id_field(name, field.is_nullable())
+            } else {
+                field.as_ref().clone()
+            }
+        })
+        .collect::<Vec<_>>();
+    Schema::new(fields)
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn write_transcript_metadata(
     output_transcript_metadata: &Option<String>,
@@ -338,18 +870,22 @@ pub fn write_transcript_metadata(
     transcript_positions: &[(f32, f32, f32)],
     transcript_names: &[String],
     cell_assignments: &[(u32, f32)],
+    count_pr_cutoff: f32,
     transcript_state: &Array1<TranscriptState>,
     qvs: &[f32],
     fovs: &[u32],
     fov_names: &[String],
+    compartments: &[Compartment],
+    nucleus_distances: &[f32],
+    source_transcript_ids: &[u64],
 ) {
     if let Some(output_transcript_metadata) = output_transcript_metadata {
         // arraw_csv has no problem outputting LargeStringArray, but can't read them.
         // As a work around we always output the same schema, but change the schema
         // when reading csv.
-        let schema = transcript_metadata_schema(OutputFormat::Parquet);
+        let mut schema = with_cell_id_column(&transcript_metadata_schema(OutputFormat::Parquet), "assignment");
 
-        let columns: Vec<Arc<dyn arrow::array::Array>> = vec![
+        let mut columns: Vec<Arc<dyn arrow::array::Array>> = vec![
             Arc::new(
                 transcripts.iter().map(|t| t.transcript_id).collect::<arrow::array::UInt64Array>()
             ),
@@ -386,11 +922,22 @@ pub fn write_transcript_metadata(
                     .collect::<arrow::array::LargeStringArray>()
             ),
             Arc::new(
-                cell_assignments.iter().map(|(cell, _)| *cell).collect::<arrow::array::UInt32Array>()
+                compartments
+                    .iter()
+                    .map(|c| Some(c.as_str()))
+                    .collect::<arrow::array::LargeStringArray>()
             ),
+            cell_id_array(cell_assignments.iter().map(|(cell, _)| Some(*cell))),
             Arc::new(
                 cell_assignments.iter().map(|(_, pr)| *pr).collect::<arrow::array::Float32Array>()
             ),
+            // assigned to a cell, but not confidently enough to be in the maxpost counts
+            Arc::new(
+                cell_assignments
+                    .iter()
+                    .map(|&(cell, pr)| (cell != BACKGROUND_CELL && pr <= count_pr_cutoff) as u8)
+                    .collect::<arrow::array::UInt8Array>()
+            ),
             Arc::new(
                 transcript_state
                     .iter()
@@ -403,8 +950,23 @@ pub fn write_transcript_metadata(
                     .map(|&s| (s == TranscriptState::Confusion) as u8)
                     .collect::<arrow::array::UInt8Array>()
             ),
+            Arc::new(
+                nucleus_distances.iter().cloned().collect::<arrow::array::Float32Array>()
+            ),
+            Arc::new(
+                transcript_displacements(transcripts, transcript_positions)
+                    .into_iter()
+                    .collect::<arrow::array::Float32Array>()
+            ),
         ];
 
+        if !source_transcript_ids.is_empty() {
+            schema = with_source_transcript_id_column(&schema, false);
+            columns.push(Arc::new(
+                source_transcript_ids.iter().cloned().collect::<arrow::array::UInt64Array>()
+            ));
+        }
+
         let batch = RecordBatch::try_new(
             Arc::new(schema),
             columns
@@ -418,19 +980,50 @@ pub fn write_transcript_metadata(
     }
 }
 
+// Number of each gene's transcripts in the confusion state.
+pub fn gene_confusion_counts(params: &ModelParams, transcripts: &[Transcript], ngenes: usize) -> Vec<usize> {
+    let mut counts = vec![0; ngenes];
+    for (t, &state) in transcripts.iter().zip(&params.transcript_state) {
+        if state == TranscriptState::Confusion {
+            counts[t.gene as usize] += 1;
+        }
+    }
+    counts
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn write_gene_metadata(
     output_gene_metadata: &Option<String>,
     output_gene_metadata_fmt: OutputFormat,
     params: &ModelParams,
     transcript_names: &[String],
     expected_counts: &Array2<f32>,
+    transcripts: &[Transcript],
+    cell_assignments: &[(u32, f32)],
+    count_pr_cutoff: f32,
+    kept_genes: &[usize],
 ) {
     if let Some(output_gene_metadata) = output_gene_metadata {
+        // fraction of each gene's transcripts counted in a cell, as in the
+        // maxpost counts, rather than left in background
+        let mut ngene = vec![0_usize; transcript_names.len()];
+        let mut nassigned = vec![0_usize; transcript_names.len()];
+        for (t, &(cell, pr)) in transcripts.iter().zip(cell_assignments) {
+            ngene[t.gene as usize] += 1;
+            if cell != BACKGROUND_CELL && pr > count_pr_cutoff {
+                nassigned[t.gene as usize] += 1;
+            }
+        }
+
         let mut schema_fields = vec![
             Field::new("gene", DataType::Utf8, false),
             Field::new("total_count", DataType::UInt64, false),
             Field::new("expected_assigned_count", DataType::Float32, false),
-            // Field::new("dispersion", DataType::Float32, false),
+            Field::new("dispersion", DataType::Float32, false),
+            Field::new("diffusion", DataType::Boolean, false),
+            Field::new("assignment_rate", DataType::Float32, false),
+            Field::new("confusion_count", DataType::UInt64, false),
+            Field::new("confusion_rate", DataType::Float32, false),
         ];
 
         let mut columns: Vec<Arc<dyn arrow::array::Array>> = vec![
@@ -451,15 +1044,33 @@ pub fn write_gene_metadata(
                     .iter().cloned()
                     .collect::<arrow::array::Float32Array>()
             ),
-            // Arc::new(array::Float32Array::from_values(
-            //     params.r.iter().cloned(),
-            // ))
+            // mean over components, or the per-gene dispersion if that's being used
+            Arc::new(
+                params.r.mean_axis(Axis(0)).unwrap().iter().cloned().collect::<arrow::array::Float32Array>()
+            ),
+            Arc::new(
+                params.gene_diffusion.iter().map(|&d| Some(d)).collect::<arrow::array::BooleanArray>()
+            ),
+            Arc::new(
+                ngene
+                    .iter()
+                    .zip(&nassigned)
+                    .map(|(&n, &a)| if n > 0 { a as f32 / n as f32 } else { 0.0 })
+                    .collect::<arrow::array::Float32Array>()
+            ),
+            Arc::new(
+                gene_confusion_counts(params, transcripts, transcript_names.len())
+                    .iter()
+                    .map(|&n| n as u64)
+                    .collect::<arrow::array::UInt64Array>()
+            ),
+            Arc::new(params.λ_c.iter().cloned().collect::<arrow::array::Float32Array>()),
         ];
 
         // cell type dispersions
         for i in 0..params.ncomponents() {
             schema_fields.push(Field::new(
-                &format!("dispersion_{}", i),
+                format!("dispersion_{}", i),
                 DataType::Float32,
                 false,
             ));
@@ -470,7 +1081,7 @@ pub fn write_gene_metadata(
 
         // cell type rates
         for i in 0..params.ncomponents() {
-            schema_fields.push(Field::new(&format!("λ_{}", i), DataType::Float32, false));
+            schema_fields.push(Field::new(format!("λ_{}", i), DataType::Float32, false));
 
             let mut λ_component = Array1::<f32>::from_elem(params.ngenes(), 0_f32);
             let mut count = 0;
@@ -503,6 +1114,14 @@ pub fn write_gene_metadata(
             columns
         ).unwrap();
 
+        // genes dropped by --min-gene-total
+        let batch = if kept_genes.len() < transcript_names.len() {
+            let kept_genes = kept_genes.iter().map(|&g| g as u32).collect::<arrow::array::UInt32Array>();
+            arrow::compute::take_record_batch(&batch, &kept_genes).unwrap()
+        } else {
+            batch
+        };
+
         write_table(
             output_gene_metadata,
             output_gene_metadata_fmt,
@@ -538,7 +1157,7 @@ pub fn write_voxels(
         }
 
         let schema = Schema::new(vec![
-            Field::new("cell", DataType::UInt32, false),
+            cell_id_field("cell", false),
             Field::new("x0", DataType::Float32, false),
             Field::new("y0", DataType::Float32, false),
             Field::new("z0", DataType::Float32, false),
@@ -548,7 +1167,7 @@ pub fn write_voxels(
         ]);
 
         let columns: Vec<Arc<dyn arrow::array::Array>> = vec![
-            Arc::new(cells.iter().cloned().collect::<arrow::array::UInt32Array>()),
+            cell_id_array(cells.iter().map(|&cell| Some(cell))),
             Arc::new(x0s.iter().cloned().collect::<arrow::array::Float32Array>()),
             Arc::new(y0s.iter().cloned().collect::<arrow::array::Float32Array>()),
             Arc::new(z0s.iter().cloned().collect::<arrow::array::Float32Array>()),
@@ -566,6 +1185,388 @@ pub fn write_voxels(
     }
 }
 
+// Write the voxel assignments as a dense (nz, ny, nx) uint32 array in NPY
+// format, holding cell id + 1 for each voxel and 0 for background (ignoring
+// any --cell-id-prefix). The xy extent
+// is cropped to the assigned voxels. Alongside it, a JSON file (same path with a
+// .json extension) records the affine mapping array indices to microns.
+pub fn write_label_volume(output_label_volume: &Option<String>, sampler: &VoxelSampler) {
+    if let Some(output_label_volume) = output_label_volume {
+        let mut voxels = sampler.iter_cell_voxels().map(|(cell, voxel)| (voxel, cell)).collect::<Vec<_>>();
+        // Voxel's ordering is (k, j, i), which is exactly C order for a (z, y, x) array
+        voxels.sort_unstable();
+
+        let (imin, imax, jmin, jmax) = voxels.iter().fold(
+            (i32::MAX, i32::MIN, i32::MAX, i32::MIN),
+            |(imin, imax, jmin, jmax), (voxel, _)| {
+                (imin.min(voxel.i), imax.max(voxel.i), jmin.min(voxel.j), jmax.max(voxel.j))
+            },
+        );
+        let (imin, imax, jmin, jmax) = if voxels.is_empty() { (0, -1, 0, -1) } else { (imin, imax, jmin, jmax) };
+        let nx = (imax - imin + 1) as usize;
+        let ny = (jmax - jmin + 1) as usize;
+        let nz = sampler.voxel_layers();
+
+        let file = File::create(output_label_volume)
+            .unwrap_or_else(|_| panic!("Unable to create file: {}", output_label_volume));
+        let mut writer = BufWriter::new(file);
+
+        let mut header = format!(
+            "{{'descr': '<u4', 'fortran_order': False, 'shape': ({}, {}, {}), }}",
+            nz, ny, nx
+        );
+        // magic, version, and header length take 10 bytes, and the data should start 64-byte aligned
+        while (10 + header.len() + 1) % 64 != 0 {
+            header.push(' ');
+        }
+        header.push('\n');
+        writer.write_all(b"\x93NUMPY\x01\x00").unwrap();
+        writer.write_all(&(header.len() as u16).to_le_bytes()).unwrap();
+        writer.write_all(header.as_bytes()).unwrap();
+
+        let mut next = voxels.iter().peekable();
+        for k in 0..nz as i32 {
+            for j in jmin..=jmax {
+                for i in imin..=imax {
+                    let voxel = Voxel::new(i, j, k);
+                    let label = match next.peek() {
+                        Some((v, cell)) if *v == voxel => {
+                            next.next();
+                            cell_id_number(*cell) + 1
+                        }
+                        _ => 0,
+                    };
+                    writer.write_all(&label.to_le_bytes()).unwrap();
+                }
+            }
+        }
+        writer.flush().unwrap();
+
+        let (x0, y0, z0, x1, y1, z1) = sampler.voxel_world_coords(Voxel::new(imin, jmin, 0));
+        // go through the shortest decimal representation so e.g. 0.1 doesn't come out as 0.10000000149
+        let exact = |x: f32| x.to_string().parse::<f64>().unwrap();
+        let (x0, y0, z0) = (exact(x0), exact(y0), exact(z0));
+        let (dx, dy, dz) = (exact(x1 - x0 as f32), exact(y1 - y0 as f32), exact(z1 - z0 as f32));
+        // maps (z, y, x, 1) array indices to the (z, y, x) micron coordinates of the voxel's corner
+        let metadata = json::object! {
+            shape: [nz, ny, nx],
+            axes: ["z", "y", "x"],
+            labels: "cell id + 1, with 0 for background",
+            voxel_size: [dz, dy, dx],
+            origin: [z0, y0, x0],
+            affine: [
+                [dz, 0.0, 0.0, z0],
+                [0.0, dy, 0.0, y0],
+                [0.0, 0.0, dx, x0],
+                [0.0, 0.0, 0.0, 1.0],
+            ],
+        };
+
+        let metadata_path = std::path::Path::new(output_label_volume).with_extension("json");
+        let mut file = File::create(&metadata_path).unwrap();
+        if metadata.write_pretty(&mut file, 2).is_err() {
+            panic!("Error writing label volume metadata: {}", metadata_path.display());
+        }
+    }
+}
+
+// Counts of transcripts, cells, and genes removed at each filtering stage
+// before sampling.
+#[derive(Default)]
+pub struct FilteringReport {
+    pub transcripts_read: usize,
+    pub low_qv_transcripts: usize,
+    pub distant_transcripts: usize,
+    pub initial_cells: usize,
+    pub sparse_cells_removed: Vec<usize>,
+    pub absent_genes: usize,
+}
+
+impl FilteringReport {
+    pub fn print(&self) {
+        eprintln!("Filtering:");
+        eprintln!("  {} transcripts read", self.transcripts_read);
+        eprintln!("  {} removed with qv below --min-qv", self.low_qv_transcripts);
+        eprintln!(
+            "  {} removed as too far from any nucleus",
+            self.distant_transcripts
+        );
+        eprint!(
+            "  {} of {} cells removed as too sparse to initialize",
+            self.sparse_cells_removed.iter().sum::<usize>(),
+            self.initial_cells
+        );
+        if self.sparse_cells_removed.is_empty() {
+            eprintln!();
+        } else {
+            eprintln!(
+                " (per iteration: {})",
+                self.sparse_cells_removed.iter().join(", ")
+            );
+        }
+        eprintln!("  {} genes removed with no remaining transcripts", self.absent_genes);
+    }
+
+    fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            transcripts_read: self.transcripts_read,
+            low_qv_transcripts: self.low_qv_transcripts,
+            distant_transcripts: self.distant_transcripts,
+            initial_cells: self.initial_cells,
+            sparse_cells_removed: self.sparse_cells_removed.iter().sum::<usize>(),
+            sparse_cells_removed_per_iteration: self.sparse_cells_removed.clone(),
+            absent_genes: self.absent_genes,
+        }
+    }
+}
+
+// Git commit proseg was built from, if known.
+pub const GIT_HASH: Option<&str> = option_env!("PROSEG_GIT_HASH");
+
+// Version banner printed at startup.
+pub fn version_banner() -> String {
+    match GIT_HASH {
+        Some(hash) => format!("proseg {} (git {})", env!("CARGO_PKG_VERSION"), hash),
+        None => format!("proseg {}", env!("CARGO_PKG_VERSION")),
+    }
+}
+
+// The command line proseg was run with, quoted so it can be pasted back into a shell.
+pub fn command_line() -> String {
+    std::env::args()
+        .map(|arg| {
+            let safe = !arg.is_empty()
+                && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_.,/=:+@%".contains(c));
+            if safe {
+                arg
+            } else {
+                format!("'{}'", arg.replace('\'', "'\\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Summary of the run as a small JSON object.
+#[allow(clippy::too_many_arguments)]
+pub fn write_run_metadata(
+    output_run_metadata: &Option<String>,
+    ntranscripts: usize,
+    ngenes: usize,
+    counts: &Array2<u32>,
+    filtering: &FilteringReport,
+    min_cell_volume: f32,
+    params: &ModelParams,
+    log_likelihood: f32,
+) {
+    if let Some(output_run_metadata) = output_run_metadata {
+        // fitted log-normal volume distribution per component, with the
+        // median and mean volume it implies
+        let volume_distribution = params
+            .volume_distribution()
+            .iter()
+            .enumerate()
+            .map(|(k, &(ncells, μ, σ))| {
+                json::object! {
+                    component: k,
+                    cells: ncells,
+                    proportion: qc_value(params.π[k]),
+                    log_volume_mean: qc_value(μ),
+                    log_volume_sd: qc_value(σ),
+                    median_volume: qc_value(μ.exp()),
+                    mean_volume: qc_value((μ + σ * σ / 2.0).exp()),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let ncells = counts.shape()[1];
+        let nempty = counts.columns().into_iter().filter(|c| c.sum() == 0).count();
+        let nassigned_transcripts = counts.sum();
+
+        let metadata = json::object! {
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: GIT_HASH,
+            command_line: command_line(),
+            transcripts: ntranscripts,
+            genes: ngenes,
+            assigned_transcripts: nassigned_transcripts,
+            seeded_cells: ncells,
+            assigned_cells: ncells - nempty,
+            empty_cells: nempty,
+            min_cell_volume: qc_value(min_cell_volume),
+            log_likelihood: log_likelihood,
+            filtering: filtering.to_json(),
+            volume_distribution: volume_distribution,
+        };
+
+        let mut file = File::create(output_run_metadata).unwrap();
+        if metadata.write_pretty(&mut file, 2).is_err() {
+            panic!("Error writing run metadata: {}", output_run_metadata);
+        }
+    }
+}
+
+// Round a statistic to the output precision for the QC summary, which would
+// otherwise print f32 values with spurious digits.
+fn qc_value(x: f32) -> f64 {
+    round_to_output_precision(x as f64, OUTPUT_PRECISION.load(Ordering::Relaxed))
+}
+
+// Quantiles and mean of a distribution, for the QC summary.
+fn summarize_distribution(mut xs: Vec<f32>) -> json::JsonValue {
+    if xs.is_empty() {
+        return json::JsonValue::Null;
+    }
+    xs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let quantile = |q: f32| qc_value(xs[((q * (xs.len() - 1) as f32).round() as usize).min(xs.len() - 1)]);
+    let mean = xs.iter().sum::<f32>() / xs.len() as f32;
+
+    json::object! {
+        min: quantile(0.0),
+        q05: quantile(0.05),
+        q25: quantile(0.25),
+        median: quantile(0.5),
+        q75: quantile(0.75),
+        q95: quantile(0.95),
+        max: quantile(1.0),
+        mean: qc_value(mean),
+    }
+}
+
+// Common QC statistics computed from the final state, as a JSON object.
+pub fn write_qc_summary(
+    output_qc_summary: &Option<String>,
+    params: &ModelParams,
+    counts: &Array2<u32>,
+) {
+    if let Some(output_qc_summary) = output_qc_summary {
+        let ntranscripts = params.transcript_state.len();
+        let nbackground = params
+            .transcript_state
+            .iter()
+            .filter(|&&s| s == TranscriptState::Background)
+            .count();
+        let nconfusion = params
+            .transcript_state
+            .iter()
+            .filter(|&&s| s == TranscriptState::Confusion)
+            .count();
+
+        let transcripts_per_cell = counts
+            .columns()
+            .into_iter()
+            .map(|c| c.sum() as f32)
+            .collect::<Vec<_>>();
+        let genes_per_cell = counts
+            .columns()
+            .into_iter()
+            .map(|c| c.iter().filter(|&&x| x > 0).count() as f32)
+            .collect::<Vec<_>>();
+
+        let summary = json::object! {
+            ncells: counts.shape()[1],
+            ngenes: counts.shape()[0],
+            ntranscripts: ntranscripts,
+            assigned_fraction: qc_value(counts.sum() as f32 / ntranscripts as f32),
+            background_fraction: qc_value(nbackground as f32 / ntranscripts as f32),
+            confusion_fraction: qc_value(nconfusion as f32 / ntranscripts as f32),
+            transcripts_per_cell: summarize_distribution(transcripts_per_cell),
+            genes_per_cell: summarize_distribution(genes_per_cell),
+            cell_volume: summarize_distribution(params.cell_volume.to_vec()),
+        };
+
+        let mut file = File::create(output_qc_summary).unwrap();
+        if summary.write_pretty(&mut file, 2).is_err() {
+            panic!("Error writing QC summary: {}", output_qc_summary);
+        }
+    }
+}
+
+pub fn write_proposal_stats(
+    output_proposal_stats: &Option<String>,
+    output_proposal_stats_fmt: OutputFormat,
+    proposal_stats: &[ProposalStats],
+) {
+    if let Some(output_proposal_stats) = output_proposal_stats {
+        let schema = Schema::new(vec![
+            Field::new("iteration", DataType::UInt32, false),
+            Field::new("cell_to_cell_accept", DataType::UInt64, false),
+            Field::new("cell_to_cell_reject", DataType::UInt64, false),
+            Field::new("cell_to_cell_ignore", DataType::UInt64, false),
+            Field::new("cell_to_cell_acceptance_rate", DataType::Float32, false),
+            Field::new("background_to_cell_accept", DataType::UInt64, false),
+            Field::new("background_to_cell_reject", DataType::UInt64, false),
+            Field::new("background_to_cell_ignore", DataType::UInt64, false),
+            Field::new("background_to_cell_acceptance_rate", DataType::Float32, false),
+            Field::new("cell_to_background_accept", DataType::UInt64, false),
+            Field::new("cell_to_background_reject", DataType::UInt64, false),
+            Field::new("cell_to_background_ignore", DataType::UInt64, false),
+            Field::new("cell_to_background_acceptance_rate", DataType::Float32, false),
+            Field::new("transcript_position_accept", DataType::UInt64, false),
+            Field::new("transcript_position_reject", DataType::UInt64, false),
+            Field::new("transcript_position_acceptance_rate", DataType::Float32, false),
+            Field::new("cell_birth_accept", DataType::UInt64, false),
+            Field::new("cell_birth_reject", DataType::UInt64, false),
+            Field::new("cell_death_accept", DataType::UInt64, false),
+            Field::new("cell_death_reject", DataType::UInt64, false),
+            Field::new("nonempty_cells", DataType::UInt64, false),
+        ]);
+
+        fn count_column<F>(proposal_stats: &[ProposalStats], f: F) -> Arc<dyn arrow::array::Array>
+        where
+            F: Fn(&ProposalStats) -> usize,
+        {
+            Arc::new(proposal_stats.iter().map(|s| f(s) as u64).collect::<arrow::array::UInt64Array>())
+        }
+
+        fn rate_column<F>(proposal_stats: &[ProposalStats], f: F) -> Arc<dyn arrow::array::Array>
+        where
+            F: Fn(&ProposalStats) -> (usize, usize),
+        {
+            Arc::new(
+                proposal_stats
+                    .iter()
+                    .map(|s| {
+                        let (accept, reject) = f(s);
+                        acceptance_rate(accept, reject)
+                    })
+                    .collect::<arrow::array::Float32Array>(),
+            )
+        }
+
+        let columns: Vec<Arc<dyn arrow::array::Array>> = vec![
+            Arc::new((0..proposal_stats.len() as u32).collect::<arrow::array::UInt32Array>()),
+            count_column(proposal_stats, |s| s.cell_to_cell_accept),
+            count_column(proposal_stats, |s| s.cell_to_cell_reject),
+            count_column(proposal_stats, |s| s.cell_to_cell_ignore),
+            rate_column(proposal_stats, |s| (s.cell_to_cell_accept, s.cell_to_cell_reject)),
+            count_column(proposal_stats, |s| s.background_to_cell_accept),
+            count_column(proposal_stats, |s| s.background_to_cell_reject),
+            count_column(proposal_stats, |s| s.background_to_cell_ignore),
+            rate_column(proposal_stats, |s| (s.background_to_cell_accept, s.background_to_cell_reject)),
+            count_column(proposal_stats, |s| s.cell_to_background_accept),
+            count_column(proposal_stats, |s| s.cell_to_background_reject),
+            count_column(proposal_stats, |s| s.cell_to_background_ignore),
+            rate_column(proposal_stats, |s| (s.cell_to_background_accept, s.cell_to_background_reject)),
+            count_column(proposal_stats, |s| s.transcript_position_accept),
+            count_column(proposal_stats, |s| s.transcript_position_reject),
+            rate_column(proposal_stats, |s| (s.transcript_position_accept, s.transcript_position_reject)),
+            count_column(proposal_stats, |s| s.cell_birth_accept),
+            count_column(proposal_stats, |s| s.cell_birth_reject),
+            count_column(proposal_stats, |s| s.cell_death_accept),
+            count_column(proposal_stats, |s| s.cell_death_reject),
+            count_column(proposal_stats, |s| s.nonempty_cells),
+        ];
+
+        let batch = RecordBatch::try_new(
+            Arc::new(schema),
+            columns
+        ).unwrap();
+
+        write_table(output_proposal_stats, output_proposal_stats_fmt, &batch);
+    }
+}
+
 // TODO:
 // If we want to import things into qupath, I think we need a way to scale
 // the coordinates to pixel space. It also doesn't seem like it supports
@@ -573,144 +1574,469 @@ pub fn write_voxels(
 
 pub fn write_cell_multipolygons(
     output_cell_polygons: &Option<String>,
+    output_cell_polygons_fmt: GeometryFormat,
     polygons: Vec<MultiPolygon<f32>>,
 ) {
     if let Some(output_cell_polygons) = output_cell_polygons {
-        let file = File::create(output_cell_polygons).unwrap();
-        let mut encoder = GzEncoder::new(file, Compression::default());
+        let features = polygons
+            .into_iter()
+            .enumerate()
+            .map(|(cell, polys)| (vec![("cell", cell_id_property(cell))], Geometry::MultiPolygon(polys)));
 
-        writeln!(
-            encoder,
-            "{{\n  \"type\": \"FeatureCollection\",\n  \"features\": ["
-        )
-        .unwrap();
+        write_geometry(output_cell_polygons, output_cell_polygons_fmt, features);
+    }
+}
 
-        let ncells = polygons.len();
-        for (cell, polys) in polygons.into_iter().enumerate() {
-            writeln!(
-                encoder,
-                concat!(
-                    "    {{\n",
-                    "      \"type\": \"Feature\",\n",
-                    "      \"properties\": {{\n",
-                    "        \"cell\": {}\n",
-                    "      }},\n",
-                    "      \"geometry\": {{\n",
-                    "        \"type\": \"MultiPolygon\",\n",
-                    "        \"coordinates\": ["
-                ),
-                cell
-            )
-            .unwrap();
+// Write each component's cells to `{basename}-component{k}.geojson.gz`, keeping
+// their cell ids. Components with no cells are skipped.
+pub fn write_cell_multipolygons_by_component(
+    basename: &Option<String>,
+    polygons: &[MultiPolygon<f32>],
+    cell_components: &Array1<u32>,
+) {
+    if let Some(basename) = basename {
+        let ncomponents = cell_components.iter().max().map_or(0, |&k| k as usize + 1);
+        for k in 0..ncomponents as u32 {
+            let mut features = polygons
+                .iter()
+                .zip(cell_components)
+                .enumerate()
+                .filter(|(_, (_, &z))| z == k)
+                .map(|(cell, (polys, _))| {
+                    (
+                        vec![("cell", cell_id_property(cell)), ("component", k.to_string())],
+                        Geometry::MultiPolygon(polys.clone()),
+                    )
+                })
+                .peekable();
+
+            if features.peek().is_some() {
+                let filename = format!("{}-component{}.geojson{}", basename, k, compressed_suffix());
+                write_geometry(&filename, GeometryFormat::GeoJsonGz, features);
+            }
+        }
+    }
+}
 
-            let npolys = polys.iter().count();
-            for (i, poly) in polys.into_iter().enumerate() {
-                writeln!(encoder, concat!("          [\n", "            [")).unwrap();
+pub fn write_cell_layered_multipolygons(
+    output_cell_polygons: &Option<String>,
+    polygons: Vec<Vec<(i32, MultiPolygon<f32>)>>,
+) {
+    if let Some(output_cell_polygons) = output_cell_polygons {
+        let features = polygons
+            .into_iter()
+            .enumerate()
+            .flat_map(|(cell, cell_polys)| {
+                cell_polys.into_iter().map(move |(layer, polys)| {
+                    (
+                        vec![("cell", cell_id_property(cell)), ("layer", layer.to_string())],
+                        Geometry::MultiPolygon(polys),
+                    )
+                })
+            });
+
+        write_geometry(output_cell_polygons, GeometryFormat::GeoJsonGz, features);
+    }
+}
 
-                let ncoords = poly.exterior().coords().count();
-                for (j, coord) in poly.exterior().coords().enumerate() {
-                    write!(encoder, "              [{}, {}]", coord.x, coord.y).unwrap();
-                    if j < ncoords - 1 {
-                        writeln!(encoder, ",").unwrap();
-                    } else {
-                        writeln!(encoder).unwrap();
-                    }
-                }
+pub fn write_tissue_boundary(
+    output_tissue_boundary: &Option<String>,
+    output_tissue_boundary_fmt: GeometryFormat,
+    boundary: MultiPolygon<f32>,
+) {
+    if let Some(output_tissue_boundary) = output_tissue_boundary {
+        let area = boundary.unsigned_area();
+        let features = std::iter::once((vec![("area", area.to_string())], Geometry::MultiPolygon(boundary)));
+        write_geometry(output_tissue_boundary, output_tissue_boundary_fmt, features);
+    }
+}
+
+// Transcript density on a square grid, with one row for each bin containing
+// any transcripts.
+pub fn write_transcript_density(
+    output_density: &Option<String>,
+    output_density_fmt: OutputFormat,
+    transcripts: &[Transcript],
+    transcript_names: &[String],
+    bin_size: f32,
+    per_gene: bool,
+) {
+    if let Some(output_density) = output_density {
+        let (xmin, ymin) = transcripts
+            .iter()
+            .fold((f32::MAX, f32::MAX), |(xmin, ymin), t| (xmin.min(t.x), ymin.min(t.y)));
+        let ngenes = transcript_names.len();
+
+        let mut bins: HashMap<(i32, i32), Vec<u32>> = HashMap::new();
+        for t in transcripts {
+            let i = ((t.x - xmin) / bin_size).floor() as i32;
+            let j = ((t.y - ymin) / bin_size).floor() as i32;
+            let bin = bins.entry((i, j)).or_insert_with(|| vec![0; ngenes]);
+            bin[t.gene as usize] += 1;
+        }
+        let bins = bins.into_iter().sorted_by_key(|(ij, _)| *ij).collect::<Vec<_>>();
+        let bin_area = bin_size * bin_size;
 
-                write!(encoder, concat!("            ]\n", "          ]")).unwrap();
+        let mut schema_fields = vec![
+            Field::new("x0", DataType::Float32, false),
+            Field::new("y0", DataType::Float32, false),
+            Field::new("x1", DataType::Float32, false),
+            Field::new("y1", DataType::Float32, false),
+            Field::new("count", DataType::UInt32, false),
+            Field::new("density", DataType::Float32, false),
+        ];
 
-                if i < npolys - 1 {
-                    writeln!(encoder, ",").unwrap();
-                } else {
-                    writeln!(encoder).unwrap();
-                }
-            }
+        let mut columns: Vec<Arc<dyn arrow::array::Array>> = vec![
+            Arc::new(bins.iter().map(|((i, _), _)| xmin + *i as f32 * bin_size).collect::<arrow::array::Float32Array>()),
+            Arc::new(bins.iter().map(|((_, j), _)| ymin + *j as f32 * bin_size).collect::<arrow::array::Float32Array>()),
+            Arc::new(bins.iter().map(|((i, _), _)| xmin + (*i + 1) as f32 * bin_size).collect::<arrow::array::Float32Array>()),
+            Arc::new(bins.iter().map(|((_, j), _)| ymin + (*j + 1) as f32 * bin_size).collect::<arrow::array::Float32Array>()),
+            Arc::new(bins.iter().map(|(_, counts)| counts.iter().sum::<u32>()).collect::<arrow::array::UInt32Array>()),
+            Arc::new(bins.iter().map(|(_, counts)| counts.iter().sum::<u32>() as f32 / bin_area).collect::<arrow::array::Float32Array>()),
+        ];
 
-            write!(encoder, concat!("        ]\n", "      }}\n", "    }}")).unwrap();
-            if cell < ncells - 1 {
-                writeln!(encoder, ",").unwrap();
-            } else {
-                writeln!(encoder).unwrap();
+        if per_gene {
+            for (gene, name) in transcript_names.iter().enumerate() {
+                schema_fields.push(Field::new(name, DataType::Float32, false));
+                columns.push(Arc::new(
+                    bins.iter().map(|(_, counts)| counts[gene] as f32 / bin_area).collect::<arrow::array::Float32Array>()
+                ));
             }
         }
 
-        writeln!(encoder, "  ]\n}}").unwrap();
+        let batch = RecordBatch::try_new(
+            Arc::new(Schema::new(schema_fields)),
+            columns
+        ).unwrap();
+
+        write_table(output_density, output_density_fmt, &batch);
     }
 }
 
-pub fn write_cell_layered_multipolygons(
-    output_cell_polygons: &Option<String>,
-    polygons: Vec<Vec<(i32, MultiPolygon<f32>)>>,
+// Convex (or, if a concavity is given, concave) hulls around each cell's
+// assigned transcripts, as an alternative to the voxel derived polygons.
+pub fn write_cell_hulls(
+    output_cell_hulls: &Option<String>,
+    output_cell_hulls_fmt: GeometryFormat,
+    cell_hull_concavity: Option<f32>,
+    params: &ModelParams,
+    transcripts: &[Transcript],
+    counts: &Array2<u32>,
 ) {
-    if let Some(output_cell_polygons) = output_cell_polygons {
-        let file = File::create(output_cell_polygons).unwrap();
-        let mut encoder = GzEncoder::new(file, Compression::default());
+    if let Some(output_cell_hulls) = output_cell_hulls {
+        // We are not maintaining any kind of per-cell array, so I guess I have
+        // no choice but to compute such a thing here.
+        let mut cell_transcripts: Vec<Vec<usize>> = vec![Vec::new(); params.ncells()];
+        for (i, &cell) in params.cell_assignments.iter().enumerate() {
+            if cell != BACKGROUND_CELL {
+                cell_transcripts[cell as usize].push(i);
+            }
+        }
+
+        let mut vertices: Vec<(f32, f32)> = Vec::new();
+        let mut hull: Vec<(f32, f32)> = Vec::new();
+
+        let features = cell_transcripts.iter().enumerate().map(|(i, js)| {
+            vertices.clear();
+            for j in js {
+                let transcript = transcripts[*j];
+                vertices.push((transcript.x, transcript.y));
+            }
+
+            let (polygon, area) = match cell_hull_concavity {
+                Some(concavity) if vertices.len() >= 3 => concave_hull_area(&vertices, concavity),
+                _ => {
+                    let area = convex_hull_area(&mut vertices, &mut hull);
+                    (Polygon::new(LineString::from(hull.clone()), vec![]), area)
+                }
+            };
+            let count = counts.column(i).sum();
+
+            (
+                vec![
+                    ("cell", cell_id_property(i)),
+                    ("area", area.to_string()),
+                    ("count", count.to_string()),
+                ],
+                Geometry::Polygon(polygon),
+            )
+        });
+
+        write_geometry(output_cell_hulls, output_cell_hulls_fmt, features);
+    }
+}
+
+pub fn infer_geometry_format_from_filename(filename: &str) -> GeometryFormat {
+    if filename.ends_with(".geojson.gz") || filename.ends_with(".json.gz") {
+        GeometryFormat::GeoJsonGz
+    } else if filename.ends_with(".geojson.zst") || filename.ends_with(".json.zst") {
+        GeometryFormat::GeoJsonZst
+    } else if filename.ends_with(".geojson") || filename.ends_with(".json") {
+        GeometryFormat::GeoJson
+    } else if filename.ends_with(".csv.gz") {
+        GeometryFormat::WktGz
+    } else if filename.ends_with(".csv.zst") {
+        GeometryFormat::WktZst
+    } else if filename.ends_with(".csv") {
+        GeometryFormat::Wkt
+    } else {
+        panic!("Unknown file format for filename: {}", filename);
+    }
+}
+
+// Write features, each a list of (already formatted) numeric properties along
+// with a polygon or multipolygon, as a GeoJSON FeatureCollection or as a CSV
+// table with a column per property and a WKT geometry column.
+fn write_geometry<I>(filename: &str, fmt: GeometryFormat, features: I)
+where
+    I: Iterator<Item = (Vec<(&'static str, String)>, Geometry<f32>)>,
+{
+    let fmt = match fmt {
+        GeometryFormat::Infer => infer_geometry_format_from_filename(filename),
+        _ => fmt,
+    };
+
+    let compression = match fmt {
+        GeometryFormat::GeoJson | GeometryFormat::Wkt => OutputCompression::None,
+        GeometryFormat::GeoJsonGz | GeometryFormat::WktGz => output_compression(),
+        GeometryFormat::GeoJsonZst | GeometryFormat::WktZst => OutputCompression::Zstd,
+        GeometryFormat::Infer => {
+            panic!("Cannot infer output format for filename: {}", filename);
+        }
+    };
+
+    let mut encoder = compressed_writer(File::create(filename).unwrap(), compression);
+    let result = match fmt {
+        GeometryFormat::Wkt | GeometryFormat::WktGz | GeometryFormat::WktZst => {
+            write_wkt_features(encoder, features)
+        }
+        _ => write_geojson_features(&mut encoder, features),
+    };
+
+    if result.is_err() {
+        panic!("Error writing geometry file: {}", filename);
+    }
+}
+
+fn write_wkt_features<W, I>(encoder: W, features: I) -> std::io::Result<()>
+where
+    W: Write,
+    I: Iterator<Item = (Vec<(&'static str, String)>, Geometry<f32>)>,
+{
+    let mut features = features.peekable();
+    let mut writer = ::csv::Writer::from_writer(encoder);
+    let mut header = features
+        .peek()
+        .map(|(properties, _)| properties.iter().map(|(key, _)| *key).collect::<Vec<_>>())
+        .unwrap_or_default();
+    header.push("geometry");
+    writer.write_record(&header)?;
+
+    for (properties, geometry) in features {
+        let mut wkt = String::new();
+        match geometry {
+            Geometry::Polygon(poly) => {
+                wkt.push_str("POLYGON ");
+                write_wkt_polygon(&mut wkt, &poly);
+            }
+            Geometry::MultiPolygon(polys) if polys.0.is_empty() => wkt.push_str("MULTIPOLYGON EMPTY"),
+            Geometry::MultiPolygon(polys) => {
+                wkt.push_str("MULTIPOLYGON (");
+                for (i, poly) in polys.iter().enumerate() {
+                    if i > 0 {
+                        wkt.push_str(", ");
+                    }
+                    write_wkt_polygon(&mut wkt, poly);
+                }
+                wkt.push(')');
+            }
+            _ => panic!("Only polygons and multipolygons can be written as WKT"),
+        }
+        // properties are formatted for GeoJSON, so strings are quoted
+        let properties = properties
+            .iter()
+            .map(|(_, value)| {
+                json::parse(value)
+                    .ok()
+                    .and_then(|value| value.as_str().map(String::from))
+                    .unwrap_or_else(|| value.clone())
+            })
+            .collect::<Vec<_>>();
+        writer.write_record(properties.iter().map(String::as_str).chain([wkt.as_str()]))?;
+    }
+    writer.flush()
+}
+
+// Write a polygon's exterior ring in WKT, counter-clockwise as with GeoJSON.
+fn write_wkt_polygon(wkt: &mut String, poly: &Polygon<f32>) {
+    let coords: Box<dyn Iterator<Item = _>> = if poly.signed_area() < 0.0 {
+        Box::new(poly.exterior().coords().rev())
+    } else {
+        Box::new(poly.exterior().coords())
+    };
+    let coords = coords.map(|coord| format!("{} {}", coord.x, coord.y)).join(", ");
+    wkt.push_str(&format!("(({}))", coords));
+}
+
+fn write_geojson_features<W, I>(encoder: &mut W, features: I) -> std::io::Result<()>
+where
+    W: Write,
+    I: Iterator<Item = (Vec<(&'static str, String)>, Geometry<f32>)>,
+{
+    writeln!(
+        encoder,
+        "{{\n  \"type\": \"FeatureCollection\",\n  \"features\": ["
+    )?;
+
+    for (k, (properties, geometry)) in features.enumerate() {
+        if k > 0 {
+            writeln!(encoder, ",")?;
+        }
 
         writeln!(
             encoder,
-            "{{\n  \"type\": \"FeatureCollection\",\n  \"features\": ["
-        )
-        .unwrap();
-
-        let mut nmultipolys = 0;
-        for cell_polys in polygons.iter() {
-            nmultipolys += cell_polys.len();
+            concat!(
+                "    {{\n",
+                "      \"type\": \"Feature\",\n",
+                "      \"properties\": {{"
+            )
+        )?;
+        for (i, (key, value)) in properties.iter().enumerate() {
+            write!(encoder, "        \"{}\": {}", key, value)?;
+            if i < properties.len() - 1 {
+                writeln!(encoder, ",")?;
+            } else {
+                writeln!(encoder)?;
+            }
         }
+        writeln!(encoder, concat!("      }},\n", "      \"geometry\": {{"))?;
 
-        let mut count = 0;
-        for (cell, cell_polys) in polygons.iter().enumerate() {
-            for (layer, polys) in cell_polys.iter() {
+        match geometry {
+            Geometry::Polygon(poly) => {
+                writeln!(
+                    encoder,
+                    concat!(
+                        "        \"type\": \"Polygon\",\n",
+                        "        \"coordinates\": ["
+                    )
+                )?;
+                write_geojson_polygon(encoder, &poly)?;
+                writeln!(encoder)?;
+            }
+            Geometry::MultiPolygon(polys) => {
                 writeln!(
                     encoder,
                     concat!(
-                        "    {{\n",
-                        "      \"type\": \"Feature\",\n",
-                        "      \"properties\": {{\n",
-                        "        \"cell\": {},\n",
-                        "        \"layer\": {}\n",
-                        "      }},\n",
-                        "      \"geometry\": {{\n",
                         "        \"type\": \"MultiPolygon\",\n",
                         "        \"coordinates\": ["
-                    ),
-                    cell, layer
-                )
-                .unwrap();
-
-                let npolys = polys.iter().count();
-                for (i, poly) in polys.into_iter().enumerate() {
-                    writeln!(encoder, concat!("          [\n", "            [")).unwrap();
-
-                    let ncoords = poly.exterior().coords().count();
-                    for (j, coord) in poly.exterior().coords().enumerate() {
-                        write!(encoder, "              [{}, {}]", coord.x, coord.y).unwrap();
-                        if j < ncoords - 1 {
-                            writeln!(encoder, ",").unwrap();
-                        } else {
-                            writeln!(encoder).unwrap();
-                        }
-                    }
-
-                    write!(encoder, concat!("            ]\n", "          ]")).unwrap();
-
+                    )
+                )?;
+                let npolys = polys.0.len();
+                for (i, poly) in polys.iter().enumerate() {
+                    writeln!(encoder, "          [")?;
+                    write_geojson_polygon(encoder, poly)?;
+                    write!(encoder, "\n          ]")?;
                     if i < npolys - 1 {
-                        writeln!(encoder, ",").unwrap();
+                        writeln!(encoder, ",")?;
                     } else {
-                        writeln!(encoder).unwrap();
+                        writeln!(encoder)?;
                     }
                 }
+            }
+            _ => panic!("Only polygons and multipolygons can be written as GeoJSON"),
+        }
 
-                write!(encoder, concat!("        ]\n", "      }}\n", "    }}")).unwrap();
-                if count < nmultipolys - 1 {
-                    writeln!(encoder, ",").unwrap();
-                } else {
-                    writeln!(encoder).unwrap();
-                }
+        write!(encoder, concat!("        ]\n", "      }}\n", "    }}"))?;
+    }
 
-                count += 1;
-            }
+    writeln!(encoder, "\n  ]\n}}")?;
+    encoder.flush()
+}
+
+// Write a polygon's exterior ring as a GeoJSON linear ring, without a trailing
+// newline. The ring is written counter-clockwise, as the GeoJSON spec requires,
+// whichever way the polygon builder traced it.
+fn write_geojson_polygon<W: Write>(encoder: &mut W, poly: &Polygon<f32>) -> std::io::Result<()> {
+    writeln!(encoder, "            [")?;
+    let ncoords = poly.exterior().coords().count();
+    let coords: Box<dyn Iterator<Item = _>> = if poly.signed_area() < 0.0 {
+        Box::new(poly.exterior().coords().rev())
+    } else {
+        Box::new(poly.exterior().coords())
+    };
+    for (j, coord) in coords.enumerate() {
+        write!(encoder, "              [{}, {}]", coord.x, coord.y)?;
+        if j < ncoords - 1 {
+            writeln!(encoder, ",")?;
+        } else {
+            writeln!(encoder)?;
         }
+    }
+    write!(encoder, "            ]")
+}
 
-        writeln!(encoder, "  ]\n}}").unwrap();
+#[test]
+fn geojson_exterior_rings_counter_clockwise() {
+    let square = |coords: Vec<(f32, f32)>| Polygon::new(LineString::from(coords), vec![]);
+    let clockwise = square(vec![(0.0, 0.0), (0.0, 1.0), (1.0, 1.0), (1.0, 0.0), (0.0, 0.0)]);
+    let counter_clockwise = square(vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0), (0.0, 0.0)]);
+    assert!(clockwise.signed_area() < 0.0);
+
+    let features = [clockwise, counter_clockwise]
+        .into_iter()
+        .map(|poly| (vec![], Geometry::MultiPolygon(MultiPolygon(vec![poly]))));
+    let mut out = Vec::new();
+    write_geojson_features(&mut out, features).unwrap();
+    let geojson = json::parse(std::str::from_utf8(&out).unwrap()).unwrap();
+
+    for feature in geojson["features"].members() {
+        let ring = &feature["geometry"]["coordinates"][0][0];
+        let coords: Vec<(f64, f64)> = ring
+            .members()
+            .map(|c| (c[0].as_f64().unwrap(), c[1].as_f64().unwrap()))
+            .collect();
+        let signed_area = coords
+            .windows(2)
+            .map(|w| w[0].0 * w[1].1 - w[1].0 * w[0].1)
+            .sum::<f64>()
+            / 2.0;
+        assert_eq!(signed_area, 1.0);
     }
 }
+
+#[test]
+fn wkt_features_table() {
+    let clockwise = Polygon::new(
+        LineString::from(vec![(0.0, 0.0), (0.0, 1.0), (1.0, 1.0), (1.0, 0.0), (0.0, 0.0)]),
+        vec![],
+    );
+    let features = [MultiPolygon(vec![clockwise]), MultiPolygon(vec![])]
+        .into_iter()
+        .enumerate()
+        .map(|(cell, polys)| (vec![("cell", cell.to_string())], Geometry::MultiPolygon(polys)));
+    let mut out = Vec::new();
+    write_wkt_features(&mut out, features).unwrap();
+    assert_eq!(
+        std::str::from_utf8(&out).unwrap(),
+        "cell,geometry\n0,\"MULTIPOLYGON (((0 0, 1 0, 1 1, 0 1, 0 0)))\"\n1,MULTIPOLYGON EMPTY\n"
+    );
+}
+
+#[test]
+fn zstd_csv_table_roundtrip() {
+    let path = std::env::temp_dir().join(format!("proseg-zstd-{}.csv.zst", std::process::id()));
+    let path = path.to_str().unwrap();
+    let schema = Schema::new(vec![Field::new("n", DataType::UInt32, false)]);
+    let batch = RecordBatch::try_new(
+        Arc::new(schema),
+        vec![Arc::new(arrow::array::UInt32Array::from(vec![1, 2, 3]))],
+    )
+    .unwrap();
+    write_table(path, OutputFormat::Infer, &batch);
+
+    let mut content = String::new();
+    std::io::Read::read_to_string(&mut zstd::Decoder::new(File::open(path).unwrap()).unwrap(), &mut content)
+        .unwrap();
+    assert_eq!(content, "n\n1\n2\n3\n");
+    std::fs::remove_file(path).unwrap();
+}
@@ -8,9 +8,6 @@ mod sampleset;
 pub mod transcripts;
 
 use core::fmt::Debug;
-use flate2::write::GzEncoder;
-use flate2::Compression;
-use hull::convex_hull_area;
 use itertools::{izip, Itertools};
 use libm::{lgammaf, log1pf};
 use linfa::traits::{Fit, Predict};
@@ -20,16 +17,14 @@ use math::{
     logistic, lognormal_logpdf, negbin_logpmf_fast, normal_pdf, normal_x2_logpdf, normal_x2_pdf,
     rand_crt, LogFactorial, LogGammaPlus,
 };
-use ndarray::{Array1, Array2, Array3, Axis, Zip};
+use ndarray::{s, Array1, Array2, Array3, Axis, Zip};
 use polyagamma::PolyaGamma;
 use rand::{thread_rng, Rng};
-use rand_distr::{Dirichlet, Distribution, Gamma, Normal, StandardNormal};
+use rand_distr::{Beta, Dirichlet, Distribution, Gamma, Normal, StandardNormal};
 use rayon::prelude::*;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::f32;
-use std::fs::File;
-use std::io::Write;
 use std::iter::Iterator;
 use thread_local::ThreadLocal;
 use transcripts::{CellIndex, Transcript, BACKGROUND_CELL};
@@ -49,6 +44,31 @@ pub fn perimeter_bound(eta: f32, bound: f32, population: f32) -> f32 {
     bound * eta * (2.0 * (f32::consts::PI * population).sqrt())
 }
 
+// Background layer containing depth z. Layer k is the half-open interval
+// [z0 + k * layer_depth, z0 + (k + 1) * layer_depth), so a transcript exactly
+// on a boundary always goes to the layer above it. The division alone can
+// round either way there, so the result is checked against the boundaries.
+// Depths outside the layers are clamped to the first or last.
+pub fn zlayer(z: f32, z0: f32, layer_depth: f32, nlayers: usize) -> usize {
+    let mut layer = ((z - z0) / layer_depth).floor();
+    if z < z0 + layer * layer_depth {
+        layer -= 1.0;
+    } else if z >= z0 + (layer + 1.0) * layer_depth {
+        layer += 1.0;
+    }
+    (layer.max(0.0) as usize).min(nlayers - 1)
+}
+
+// Whether `zlayer` has to clamp depth z: it's below the first layer or above
+// the last. The top of the last layer counts as inside, give or take rounding
+// in computing it, so that transcripts at exactly zmax, which z-clamping can
+// leave there, belong to it.
+pub fn outside_zlayers(z: f32, z0: f32, layer_depth: f32, nlayers: usize) -> bool {
+    let top = z0 + nlayers as f32 * layer_depth;
+    let tolerance = 4.0 * f32::EPSILON * z0.abs().max(top.abs());
+    z < z0 - tolerance || z > top + tolerance
+}
+
 // Compute chunk and quadrant for a single a single (x,y) point.
 fn chunkquad(x: f32, y: f32, xmin: f32, ymin: f32, chunk_size: f32, nxchunks: usize) -> (u32, u32) {
     let xchunkquad = ((x - xmin) / (chunk_size / 2.0)).floor() as u32;
@@ -60,6 +80,130 @@ fn chunkquad(x: f32, y: f32, xmin: f32, ymin: f32, chunk_size: f32, nxchunks: us
     (chunk, quad)
 }
 
+// Adaptive alternative to the uniform chunk grid: a quadtree over cell
+// centroids, split until each leaf has at most a target number of cells. Each
+// leaf is a chunk, and its quadrants are found by splitting it in half along
+// each axis, so neighboring leaves never share a quadrant along an edge.
+#[derive(Clone, Debug)]
+pub struct ChunkQuadTree {
+    nodes: Vec<ChunkQuadTreeNode>,
+    nchunks: usize,
+}
+
+#[derive(Clone, Debug)]
+enum ChunkQuadTreeNode {
+    Split {
+        xmid: f32,
+        ymid: f32,
+        children: [usize; 4],
+    },
+    Leaf {
+        xmid: f32,
+        ymid: f32,
+        chunk: u32,
+    },
+}
+
+impl ChunkQuadTree {
+    // Build the tree over the given bounds. Leaves are never made smaller than
+    // `min_size` on either side, so quadrants stay wide enough that updates in
+    // the same quadrant of different chunks don't interact.
+    pub fn new(
+        centroids: &[(f32, f32)],
+        bounds: (f32, f32, f32, f32),
+        cells_per_chunk: usize,
+        min_size: f32,
+    ) -> Self {
+        let mut tree = ChunkQuadTree {
+            nodes: Vec::new(),
+            nchunks: 0,
+        };
+        let mut centroids = centroids.to_vec();
+        tree.build(&mut centroids, bounds, cells_per_chunk, min_size);
+        tree
+    }
+
+    fn build(
+        &mut self,
+        centroids: &mut [(f32, f32)],
+        (xmin, xmax, ymin, ymax): (f32, f32, f32, f32),
+        cells_per_chunk: usize,
+        min_size: f32,
+    ) -> usize {
+        let xmid = (xmin + xmax) / 2.0;
+        let ymid = (ymin + ymax) / 2.0;
+        let idx = self.nodes.len();
+
+        let splittable = (xmax - xmin) / 2.0 >= min_size && (ymax - ymin) / 2.0 >= min_size;
+        if centroids.len() <= cells_per_chunk || !splittable {
+            self.nodes.push(ChunkQuadTreeNode::Leaf {
+                xmid,
+                ymid,
+                chunk: self.nchunks as u32,
+            });
+            self.nchunks += 1;
+            return idx;
+        }
+
+        // placeholder, filled in once the children are built
+        self.nodes.push(ChunkQuadTreeNode::Split {
+            xmid,
+            ymid,
+            children: [0; 4],
+        });
+
+        centroids.sort_unstable_by_key(|&(x, y)| Self::quadrant(x, y, xmid, ymid));
+        let mut children = [0; 4];
+        let mut rest = centroids;
+        for (quad, child) in children.iter_mut().enumerate() {
+            let n = rest
+                .iter()
+                .take_while(|&&(x, y)| Self::quadrant(x, y, xmid, ymid) == quad as u32)
+                .count();
+            let (quad_centroids, tail) = rest.split_at_mut(n);
+            rest = tail;
+
+            let (x0, x1) = if quad % 2 == 0 { (xmin, xmid) } else { (xmid, xmax) };
+            let (y0, y1) = if quad / 2 == 0 { (ymin, ymid) } else { (ymid, ymax) };
+            *child = self.build(quad_centroids, (x0, x1, y0, y1), cells_per_chunk, min_size);
+        }
+
+        self.nodes[idx] = ChunkQuadTreeNode::Split {
+            xmid,
+            ymid,
+            children,
+        };
+        idx
+    }
+
+    fn quadrant(x: f32, y: f32, xmid: f32, ymid: f32) -> u32 {
+        (x >= xmid) as u32 + 2 * (y >= ymid) as u32
+    }
+
+    pub fn nchunks(&self) -> usize {
+        self.nchunks
+    }
+
+    // Compute chunk and quadrant for a single (x,y) point.
+    pub fn chunkquad(&self, x: f32, y: f32) -> (u32, u32) {
+        let mut node = &self.nodes[0];
+        loop {
+            match node {
+                ChunkQuadTreeNode::Split {
+                    xmid,
+                    ymid,
+                    children,
+                } => {
+                    node = &self.nodes[children[Self::quadrant(x, y, *xmid, *ymid) as usize]];
+                }
+                ChunkQuadTreeNode::Leaf { xmid, ymid, chunk } => {
+                    return (*chunk, Self::quadrant(x, y, *xmid, *ymid));
+                }
+            }
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum TranscriptState {
     Background,
@@ -73,8 +217,18 @@ pub struct ModelPriors {
     pub dispersion: Option<f32>,
     pub burnin_dispersion: Option<f32>,
 
+    // share one dispersion per gene across all components
+    pub per_gene_dispersion: bool,
+
+    // when set, component proportions get a truncated stick-breaking prior
+    // with this concentration rather than a symmetric Dirichlet
+    pub component_concentration: Option<f32>,
+
     pub min_cell_volume: f32,
 
+    // soft cap on cell volume, see `ModelPriors::max_cell_volume_logpdf`
+    pub max_cell_volume: Option<f32>,
+
     // params for normal prior
     pub μ_μ_volume: f32,
     pub σ_μ_volume: f32,
@@ -100,6 +254,12 @@ pub struct ModelPriors {
     pub α_c: f32,
     pub β_c: f32,
 
+    // when set, centers the confusion rate prior on this rate per unit cell
+    // volume, as though `confusion_prior_weight` times the total cell volume
+    // had been observed at it
+    pub confusion_prior_rate: Option<f32>,
+    pub confusion_prior_weight: f32,
+
     // scaling factor for circle perimeters
     pub perimeter_eta: f32,
     pub perimeter_bound: f32,
@@ -107,6 +267,10 @@ pub struct ModelPriors {
     pub nuclear_reassignment_log_prob: f32,
     pub nuclear_reassignment_1mlog_prob: f32,
 
+    // reassignment probability for nuclear transcripts of nuclear marker genes
+    pub nuclear_marker_reassignment_log_prob: f32,
+    pub nuclear_marker_reassignment_1mlog_prob: f32,
+
     pub prior_seg_reassignment_log_prob: f32,
     pub prior_seg_reassignment_1mlog_prob: f32,
 
@@ -126,6 +290,27 @@ pub struct ModelPriors {
 
     // whether to check if voxel updates break local connectivity
     pub enforce_connectivity: bool,
+
+    // whether birth and death moves may add cells from the pool of empty
+    // cells reserved by `ModelParams::reserve_birth_pool`, and remove them
+    pub allow_cell_birth: bool,
+}
+
+// Log-space scale over which the density falls off past the volume cap.
+const MAX_CELL_VOLUME_LOG_SCALE: f32 = 0.1;
+
+impl ModelPriors {
+    // Penalty for cells larger than `max_cell_volume`, falling off like a
+    // half-lognormal beyond it, so a cell 10% over the cap pays about half a
+    // nat and one 30% over about three and a half.
+    pub fn max_cell_volume_logpdf(&self, volume: f32) -> f32 {
+        match self.max_cell_volume {
+            Some(max_volume) if volume > max_volume => {
+                -((volume / max_volume).ln() / MAX_CELL_VOLUME_LOG_SCALE).powi(2) / 2.0
+            }
+            _ => 0.0,
+        }
+    }
 }
 
 // Model global parameters.
@@ -138,6 +323,21 @@ pub struct ModelParams {
     init_nuclear_cell_assignment: Vec<CellIndex>,
     prior_seg_cell_assignment: Vec<CellIndex>,
 
+    // per-transcript (log reassignment prob, log 1 - reassignment prob) for
+    // the prior segmentation, when weighted by prior cell probabilities
+    // (empty otherwise, using the priors' values for every transcript)
+    prior_seg_log_probs: Vec<(f32, f32)>,
+
+    // [ngenes] gamma prior (α, β) on each gene's background rate, when given
+    // a known ambient profile (empty otherwise, using the priors' α_bg and β_bg)
+    background_rate_priors: Vec<(f32, f32)>,
+
+    // marks transcripts of nuclear marker genes (empty if there are none)
+    pub nuclear_marker: Vec<bool>,
+
+    // per gene, whether its transcripts may diffuse
+    pub gene_diffusion: Vec<bool>,
+
     pub cell_assignments: Vec<CellIndex>,
     pub cell_assignment_time: Vec<u32>,
 
@@ -183,12 +383,17 @@ pub struct ModelParams {
 
     pub z: Array1<u32>, // assignment of cells to components
 
+    // Cells from `nseeded_cells` on are a pool for birth moves to draw from.
+    // Dormant ones are not currently part of the segmentation.
+    pub nseeded_cells: usize,
+    pub cell_dormant: Array1<bool>,
+
     component_population: Array1<u32>, // number of cells assigned to each component
 
     // thread-local space used for sampling z
     z_probs: ThreadLocal<RefCell<Vec<f64>>>,
 
-    π: Vec<f32>, // mixing proportions over components
+    pub π: Vec<f32>, // mixing proportions over components
 
     μ_volume: Array1<f32>, // volume dist mean param by component
     σ_volume: Array1<f32>, // volume dist std param by component
@@ -273,24 +478,35 @@ impl ModelParams {
         // compute initial counts
         let mut counts = Array3::<u16>::from_elem((ngenes, ncells, nlayers), 0);
         let mut total_gene_counts = Array2::<u32>::from_elem((ngenes, nlayers), 0);
+        let mut noutside = 0;
         for (i, &j) in init_cell_assignments.iter().enumerate() {
             let gene = transcripts[i].gene as usize;
-            let layer = ((transcripts[i].z - z0) / layer_depth) as usize;
+            noutside += outside_zlayers(transcripts[i].z, z0, layer_depth, nlayers) as usize;
+            let layer = zlayer(transcripts[i].z, z0, layer_depth, nlayers);
             if j != BACKGROUND_CELL {
                 counts[[gene, j as usize, layer]] += 1;
             }
             total_gene_counts[[gene, layer]] += 1;
         }
+        if noutside > 0 {
+            println!(
+                "Warning: {} transcripts fall outside the z-layers and were assigned to the nearest layer",
+                noutside
+            );
+        }
 
         // initial component assignments
         let norm_constant = 1e4;
         let mut init_samples = counts
             .sum_axis(Axis(2))
-            .map(|&x| (x as f32))
+            .map(|&x| x as f32)
             .reversed_axes();
         init_samples.rows_mut().into_iter().for_each(|mut row| {
             let rowsum = row.sum();
-            row.mapv_inplace(|x| (norm_constant * (x / rowsum)).ln_1p());
+            // cells reserved for births start empty
+            if rowsum > 0.0 {
+                row.mapv_inplace(|x| (norm_constant * (x / rowsum)).ln_1p());
+            }
         });
         let init_samples = DatasetBase::from(init_samples);
 
@@ -343,6 +559,10 @@ impl ModelParams {
             transcript_position_updates,
             init_nuclear_cell_assignment: init_cell_assignments.to_vec(),
             prior_seg_cell_assignment: prior_seg_cell_assignment.to_vec(),
+            prior_seg_log_probs: Vec::new(),
+            background_rate_priors: Vec::new(),
+            nuclear_marker: Vec::new(),
+            gene_diffusion: vec![priors.use_diffusion_model; ngenes],
             cell_assignments: init_cell_assignments.to_vec(),
             cell_assignment_time: vec![0; init_cell_assignments.len()],
             cell_population: init_cell_population.to_vec(),
@@ -362,6 +582,8 @@ impl ModelParams {
             logfactorial: LogFactorial::new(),
             loggammaplus,
             z,
+            nseeded_cells: ncells,
+            cell_dormant: Array1::<bool>::from_elem(ncells, false),
             component_population: Array1::<u32>::from_elem(ncomponents, 0),
             z_probs: ThreadLocal::new(),
             π: vec![1_f32 / (ncomponents as f32); ncomponents],
@@ -387,9 +609,28 @@ impl ModelParams {
         self.π.len()
     }
 
+    // Mark every cell from `nseeded` on as a dormant member of the birth pool.
+    pub fn reserve_birth_pool(&mut self, nseeded: usize) {
+        self.nseeded_cells = nseeded;
+        self.cell_dormant.slice_mut(s![nseeded..]).fill(true);
+    }
+
+    // number of cells drawn from the birth pool that are currently alive
+    pub fn nborn_cells(&self) -> usize {
+        self.cell_dormant
+            .slice(s![self.nseeded_cells..])
+            .iter()
+            .filter(|&&d| !d)
+            .count()
+    }
+
+    // number of components with at least one cell assigned
+    pub fn ncomponents_used(&self) -> usize {
+        self.component_population.iter().filter(|&&n| n > 0).count()
+    }
+
     fn zlayer(&self, z: f32) -> usize {
-        let layer = ((z - self.z0) / self.layer_depth).max(0.0) as usize;
-        layer.min(self.nlayers() - 1)
+        zlayer(z, self.z0, self.layer_depth, self.nlayers())
     }
 
     fn recompute_counts(&mut self, transcripts: &[Transcript]) {
@@ -431,6 +672,61 @@ impl ModelParams {
         self.cell_population.len()
     }
 
+    // Number of foreground transcripts currently in cell `j`.
+    pub fn cell_foreground_count(&self, j: usize) -> u32 {
+        self.foreground_counts
+            .slice(s![j, .., ..])
+            .iter()
+            .map(|&c| c as u32)
+            .sum()
+    }
+
+    // Fitted log-normal cell volume distribution of each component, as the
+    // number of cells it was fit to and the (mean, std. dev.) of log volume.
+    // Components without cells are just draws from the prior.
+    pub fn volume_distribution(&self) -> Vec<(u32, f32, f32)> {
+        izip!(&self.component_population, &self.μ_volume, &self.σ_volume)
+            .map(|(&pop, &μ, &σ)| (pop, μ, σ))
+            .collect()
+    }
+
+    // Inform each gene's background rate prior with a known ambient rate per
+    // unit volume, as though `weight` layers' worth of volume had been
+    // observed at that rate on top of the default prior. Genes without a
+    // rate keep the default prior.
+    pub fn set_background_rate_priors(&mut self, priors: &ModelPriors, rates: &[Option<f32>], weight: f32) {
+        let volume = weight * self.full_layer_volume;
+        self.background_rate_priors = rates
+            .iter()
+            .map(|rate| match rate {
+                Some(rate) => (priors.α_bg + rate * volume, priors.β_bg + volume),
+                None => (priors.α_bg, priors.β_bg),
+            })
+            .collect();
+    }
+
+    // Weight each transcript's prior segmentation penalty by its prior cell
+    // probability: at 1 it's reassigned with --prior-seg-reassignment-prob, at
+    // 0 the prior segmentation has no say (a reassignment probability of 1/2),
+    // and in between interpolates linearly.
+    pub fn set_prior_cell_probs(&mut self, priors: &ModelPriors, probs: &[f32]) {
+        let reassignment_prob = priors.prior_seg_reassignment_log_prob.exp();
+        self.prior_seg_log_probs = probs
+            .iter()
+            .map(|&p| {
+                let prob = p * reassignment_prob + (1.0 - p) * 0.5;
+                (prob.ln(), (1.0 - prob).ln())
+            })
+            .collect();
+    }
+
+    fn prior_seg_log_probs(&self, priors: &ModelPriors, t: usize) -> (f32, f32) {
+        self.prior_seg_log_probs.get(t).cloned().unwrap_or((
+            priors.prior_seg_reassignment_log_prob,
+            priors.prior_seg_reassignment_1mlog_prob,
+        ))
+    }
+
     pub fn ngenes(&self) -> usize {
         self.total_gene_counts.shape()[0]
     }
@@ -490,13 +786,14 @@ impl ModelParams {
             });
 
         // prior seg reassignment terms
-        ll += Zip::from(&self.cell_assignments)
+        ll += Zip::indexed(&self.cell_assignments)
             .and(&self.prior_seg_cell_assignment)
-            .fold(0_f32, |accum, &cell, &nuc_cell| {
+            .fold(0_f32, |accum, t, &cell, &nuc_cell| {
+                let (log_prob, log_1mprob) = self.prior_seg_log_probs(priors, t);
                 if cell == nuc_cell {
-                    accum + priors.prior_seg_reassignment_1mlog_prob
+                    accum + log_1mprob
                 } else {
-                    accum + priors.prior_seg_reassignment_log_prob
+                    accum + log_prob
                 }
             });
 
@@ -504,7 +801,9 @@ impl ModelParams {
         ll += Zip::from(&self.cell_volume)
             .and(&self.z)
             .fold(0_f32, |accum, &v, &z| {
-                accum + lognormal_logpdf(self.μ_volume[z as usize], self.σ_volume[z as usize], v)
+                accum
+                    + lognormal_logpdf(self.μ_volume[z as usize], self.σ_volume[z as usize], v)
+                    + priors.max_cell_volume_logpdf(v)
             });
 
         // background terms
@@ -520,98 +819,30 @@ impl ModelParams {
 
         ll
     }
-
-    pub fn write_cell_hulls(
-        &self,
-        transcripts: &[Transcript],
-        counts: &Array2<u32>,
-        filename: &str,
-    ) {
-        // We are not maintaining any kind of per-cell array, so I guess I have
-        // no choice but to compute such a thing here.
-        let mut cell_transcripts: Vec<Vec<usize>> = vec![Vec::new(); self.ncells()];
-        for (i, &cell) in self.cell_assignments.iter().enumerate() {
-            if cell != BACKGROUND_CELL {
-                cell_transcripts[cell as usize].push(i);
-            }
-        }
-
-        let file = File::create(filename).unwrap();
-        let mut encoder = GzEncoder::new(file, Compression::default());
-        writeln!(
-            encoder,
-            "{{\n  \"type\": \"FeatureCollection\",\n  \"features\": ["
-        )
-        .unwrap();
-
-        let mut vertices: Vec<(f32, f32)> = Vec::new();
-        let mut hull: Vec<(f32, f32)> = Vec::new();
-
-        for (i, js) in cell_transcripts.iter().enumerate() {
-            vertices.clear();
-            for j in js {
-                let transcript = transcripts[*j];
-                vertices.push((transcript.x, transcript.y));
-            }
-
-            let area = convex_hull_area(&mut vertices, &mut hull);
-            let count = counts.column(i).sum();
-
-            writeln!(
-                encoder,
-                concat!(
-                    "    {{\n",
-                    "      \"type\": \"Feature\",\n",
-                    "      \"properties\": {{\n",
-                    "        \"cell\": {},\n",
-                    "        \"area\": {},\n",
-                    "        \"count\": {}\n",
-                    "      }},\n",
-                    "      \"geometry\": {{\n",
-                    "        \"type\": \"Polygon\",\n",
-                    "        \"coordinates\": [",
-                    "          ["
-                ),
-                i, area, count
-            )
-            .unwrap();
-            for (i, (x, y)) in hull.iter().enumerate() {
-                writeln!(encoder, "            [{}, {}]", x, y).unwrap();
-                if i < hull.len() - 1 {
-                    write!(encoder, ",").unwrap();
-                }
-            }
-            write!(
-                encoder,
-                concat!(
-                    "          ]\n", // polygon
-                    "        ]\n",   // coordinates
-                    "      }}\n",    // geometry
-                    "    }}\n",      // feature
-                )
-            )
-            .unwrap();
-
-            if i < cell_transcripts.len() - 1 {
-                write!(encoder, ",").unwrap();
-            }
-        }
-
-        writeln!(encoder, "\n  ]\n}}").unwrap();
-    }
 }
 
+// Counts of accepted, rejected, and ignored proposals of each move type.
 #[derive(Clone, Debug)]
 pub struct ProposalStats {
-    cell_to_cell_accept: usize,
-    cell_to_cell_reject: usize,
-    cell_to_cell_ignore: usize,
-    background_to_cell_accept: usize,
-    background_to_cell_reject: usize,
-    background_to_cell_ignore: usize,
-    cell_to_background_accept: usize,
-    cell_to_background_reject: usize,
-    cell_to_background_ignore: usize,
+    pub cell_to_cell_accept: usize,
+    pub cell_to_cell_reject: usize,
+    pub cell_to_cell_ignore: usize,
+    pub background_to_cell_accept: usize,
+    pub background_to_cell_reject: usize,
+    pub background_to_cell_ignore: usize,
+    pub cell_to_background_accept: usize,
+    pub cell_to_background_reject: usize,
+    pub cell_to_background_ignore: usize,
+    pub transcript_position_accept: usize,
+    pub transcript_position_reject: usize,
+    pub cell_birth_accept: usize,
+    pub cell_birth_reject: usize,
+    pub cell_death_accept: usize,
+    pub cell_death_reject: usize,
+
+    // Not a proposal count: cells with any transcripts at the end of the
+    // iteration, kept here so it's traced alongside the proposals.
+    pub nonempty_cells: usize,
 }
 
 impl ProposalStats {
@@ -626,6 +857,13 @@ impl ProposalStats {
             cell_to_background_accept: 0,
             cell_to_background_reject: 0,
             cell_to_background_ignore: 0,
+            transcript_position_accept: 0,
+            transcript_position_reject: 0,
+            cell_birth_accept: 0,
+            cell_birth_reject: 0,
+            cell_death_accept: 0,
+            cell_death_reject: 0,
+            nonempty_cells: 0,
         }
     }
 
@@ -639,11 +877,65 @@ impl ProposalStats {
         self.cell_to_background_accept = 0;
         self.cell_to_background_reject = 0;
         self.cell_to_background_ignore = 0;
+        self.transcript_position_accept = 0;
+        self.transcript_position_reject = 0;
+        self.cell_birth_accept = 0;
+        self.cell_birth_reject = 0;
+        self.cell_death_accept = 0;
+        self.cell_death_reject = 0;
+        self.nonempty_cells = 0;
+    }
+}
+
+impl Default for ProposalStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Gene-by-cell count matrix from a cell assignment for each transcript.
+// Transcripts assigned to BACKGROUND_CELL aren't counted.
+pub fn counts_from_assignments(
+    assignments: &[CellIndex],
+    transcripts: &[Transcript],
+    ngenes: usize,
+    ncells: usize,
+) -> Array2<u32> {
+    assert_eq!(assignments.len(), transcripts.len());
+    let mut counts = Array2::<u32>::from_elem((ngenes, ncells), 0_u32);
+    for (&cell, transcript) in assignments.iter().zip(transcripts) {
+        if cell != BACKGROUND_CELL {
+            counts[[transcript.gene as usize, cell as usize]] += 1;
+        }
+    }
+    counts
+}
+
+// Acceptance rate among evaluated (i.e. not ignored) proposals.
+pub fn acceptance_rate(accept: usize, reject: usize) -> f32 {
+    if accept + reject == 0 {
+        f32::NAN
+    } else {
+        accept as f32 / (accept + reject) as f32
     }
 }
 
 pub struct UncertaintyTracker {
     cell_assignment_duration: HashMap<(usize, CellIndex), u32>,
+
+    // number of recorded samples in which each cell existed, out of
+    // `nrecorded_samples`
+    cell_existence: Vec<u32>,
+    nrecorded_samples: u32,
+
+    // set by `finish_unrecorded`, whose durations are already weights
+    unrecorded: bool,
+}
+
+impl Default for UncertaintyTracker {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl UncertaintyTracker {
@@ -652,9 +944,42 @@ impl UncertaintyTracker {
 
         UncertaintyTracker {
             cell_assignment_duration,
+            cell_existence: Vec::new(),
+            nrecorded_samples: 0,
+            unrecorded: false,
         }
     }
 
+    // Count a recorded sample towards each cell's existence probability. A
+    // cell exists in a sample if it's not dormant, has more than the minimum
+    // volume, and has at least one foreground transcript.
+    pub fn record_cell_existence(&mut self, priors: &ModelPriors, params: &ModelParams) {
+        self.cell_existence.resize(params.ncells(), 0);
+        for (j, count) in self.cell_existence.iter_mut().enumerate() {
+            if !params.cell_dormant[j]
+                && params.cell_volume[j] > priors.min_cell_volume
+                && params.cell_foreground_count(j) > 0
+            {
+                *count += 1;
+            }
+        }
+        self.nrecorded_samples += 1;
+    }
+
+    pub fn nrecorded_samples(&self) -> u32 {
+        self.nrecorded_samples
+    }
+
+    // Fraction of recorded samples in which each cell existed.
+    pub fn cell_existence_probs(&self, ncells: usize) -> Vec<f32> {
+        (0..ncells)
+            .map(|j| {
+                let count = self.cell_existence.get(j).cloned().unwrap_or(0);
+                count as f32 / self.nrecorded_samples.max(1) as f32
+            })
+            .collect()
+    }
+
     // record the duration of the current cell assignment. Called when the state
     // is about to change.
     fn update(&mut self, params: &ModelParams, i: usize) {
@@ -677,6 +1002,19 @@ impl UncertaintyTracker {
             .or_insert(duration);
     }
 
+    // When only recording some iterations, these bracket each recorded
+    // iteration, so that assignment durations only accumulate while recording.
+    pub fn start_recording(&mut self, params: &mut ModelParams) {
+        params.cell_assignment_time.fill(params.t);
+    }
+
+    pub fn stop_recording(&mut self, params: &mut ModelParams) {
+        for i in 0..params.cell_assignments.len() {
+            self.update(params, i);
+        }
+        params.cell_assignment_time.fill(params.t);
+    }
+
     pub fn finish(&mut self, params: &ModelParams) {
         for ((i, &j), &t) in params
             .cell_assignments
@@ -692,6 +1030,20 @@ impl UncertaintyTracker {
         }
     }
 
+    // Alternative to `finish` when no samples were recorded: treat the current
+    // assignment as certain, with a duration of one that's used as a weight
+    // as is, since time doesn't advance until recording starts.
+    pub fn finish_unrecorded(&mut self, priors: &ModelPriors, params: &ModelParams) {
+        self.cell_existence.clear();
+        self.nrecorded_samples = 0;
+        self.record_cell_existence(priors, params);
+        self.unrecorded = true;
+        self.cell_assignment_duration.clear();
+        for (i, &j) in params.cell_assignments.iter().enumerate() {
+            self.cell_assignment_duration.insert((i, j), 1);
+        }
+    }
+
     fn max_posterior_cell_assignments(&self, params: &ModelParams) -> Vec<(u32, f32)> {
         // sort ascending on (transcript, cell)
         let sorted_durations: Vec<(usize, u32, u32)> = self
@@ -719,6 +1071,7 @@ impl UncertaintyTracker {
             (*i_a, *d_b, *j_a).cmp(&(*i_b, *d_a, *j_b))
         });
 
+        let norm = if self.unrecorded { 1.0 } else { params.t as f32 };
         let mut maxpost_cell_assignments = Vec::new();
         let mut i_prev = usize::MAX;
         let mut j_prev = CellIndex::MAX;
@@ -729,7 +1082,7 @@ impl UncertaintyTracker {
                 assert!(d <= d_prev);
                 continue;
             } else if i_prev == usize::MAX || (i > 0 && i - 1 == i_prev) {
-                maxpost_cell_assignments.push((j, d as f32 / params.t as f32));
+                maxpost_cell_assignments.push((j, d as f32 / norm));
                 i_prev = i;
                 j_prev = j;
                 d_prev = d;
@@ -748,35 +1101,27 @@ impl UncertaintyTracker {
         count_pr_cutoff: f32,
         _foreground_pr_cutoff: f32,
     ) -> (Array2<u32>, Vec<(u32, f32)>) {
-        let mut counts = Array2::<u32>::from_elem((params.ngenes(), params.ncells()), 0_u32);
         let maxpost_assignments = self.max_posterior_cell_assignments(params);
-        for (i, (j, pr)) in maxpost_assignments.iter().enumerate() {
-            if *pr > count_pr_cutoff && *j != BACKGROUND_CELL {
-                let gene = transcripts[i].gene;
-                // let layer = params.zlayer(params.transcript_positions[i].2);
-
-                // TODO: This doesn't really make sense, because we are tracking
-                // the proportion of time a transcript is assigned to background
-                // or confusion.
-
-                // let λ_cell = params.λ[[gene as usize, *j as usize]];
-                // let λ_bg = params.λ_bg[[gene as usize, layer]];
-                // let λ_c = params.λ_c[gene as usize];
-                // let fg_pr = λ_cell / (λ_cell + λ_bg + λ_c);
-
-                // if fg_pr > foreground_pr_cutoff {
-                counts[[gene as usize, *j as usize]] += 1;
-                // }
-            }
-        }
+
+        // TODO: This doesn't really make sense, because we are tracking
+        // the proportion of time a transcript is assigned to background
+        // or confusion, so there's no foreground probability cutoff here.
+        let assignments = maxpost_assignments
+            .iter()
+            .map(|&(j, pr)| if pr > count_pr_cutoff { j } else { BACKGROUND_CELL })
+            .collect::<Vec<_>>();
+        let counts = counts_from_assignments(&assignments, transcripts, params.ngenes(), params.ncells());
 
         (counts, maxpost_assignments)
     }
 
     pub fn expected_counts(&self, params: &ModelParams, transcripts: &[Transcript]) -> Array2<f32> {
         let mut ecounts = Array2::<f32>::zeros((params.ngenes(), params.ncells()));
+        let norm = if self.unrecorded { 1.0 } else { (params.t - 1) as f32 };
 
-        for (&(i, j), &d) in self.cell_assignment_duration.iter() {
+        // summed in (transcript, cell) order rather than hash order, so the
+        // rounding doesn't differ from run to run
+        for (&(i, j), &d) in self.cell_assignment_duration.iter().sorted_by_key(|(&ij, _)| ij) {
             if j == BACKGROUND_CELL {
                 continue;
             }
@@ -784,7 +1129,7 @@ impl UncertaintyTracker {
             let gene = transcripts[i].gene;
             // let layer = params.zlayer(params.transcript_positions[i].2);
 
-            let w_d = d as f32 / (params.t - 1) as f32;
+            let w_d = d as f32 / norm;
 
             // TODO: not accounting for λ_c here!!!
 
@@ -847,32 +1192,45 @@ pub trait Proposal {
         for &t in self.transcripts() {
             let cell = params.init_nuclear_cell_assignment[t];
             if cell != BACKGROUND_CELL {
+                let (log_prob, log_1mprob) = if params.nuclear_marker.get(t) == Some(&true) {
+                    (
+                        priors.nuclear_marker_reassignment_log_prob,
+                        priors.nuclear_marker_reassignment_1mlog_prob,
+                    )
+                } else {
+                    (
+                        priors.nuclear_reassignment_log_prob,
+                        priors.nuclear_reassignment_1mlog_prob,
+                    )
+                };
+
                 if cell == old_cell {
-                    δ -= priors.nuclear_reassignment_1mlog_prob;
+                    δ -= log_1mprob;
                 } else {
-                    δ -= priors.nuclear_reassignment_log_prob;
+                    δ -= log_prob;
                 }
 
                 if cell == new_cell {
-                    δ += priors.nuclear_reassignment_1mlog_prob;
+                    δ += log_1mprob;
                 } else {
-                    δ += priors.nuclear_reassignment_log_prob;
+                    δ += log_prob;
                 }
             }
         }
 
         for &t in self.transcripts() {
             let cell = params.prior_seg_cell_assignment[t];
+            let (log_prob, log_1mprob) = params.prior_seg_log_probs(priors, t);
             if cell == old_cell {
-                δ -= priors.prior_seg_reassignment_1mlog_prob;
+                δ -= log_1mprob;
             } else {
-                δ -= priors.prior_seg_reassignment_log_prob;
+                δ -= log_prob;
             }
 
             if cell == new_cell {
-                δ += priors.prior_seg_reassignment_1mlog_prob;
+                δ += log_1mprob;
             } else {
-                δ += priors.prior_seg_reassignment_log_prob;
+                δ += log_prob;
             }
         }
 
@@ -907,6 +1265,8 @@ pub trait Proposal {
                 });
 
             let z = params.z[old_cell as usize];
+            δ -= priors.max_cell_volume_logpdf(prev_volume);
+            δ += priors.max_cell_volume_logpdf(new_volume);
             δ -= lognormal_logpdf(
                 params.μ_volume[z as usize],
                 params.σ_volume[z as usize],
@@ -951,6 +1311,8 @@ pub trait Proposal {
                 });
 
             let z = params.z[new_cell as usize];
+            δ -= priors.max_cell_volume_logpdf(prev_volume);
+            δ += priors.max_cell_volume_logpdf(new_volume);
             δ -= lognormal_logpdf(
                 params.μ_volume[z as usize],
                 params.σ_volume[z as usize],
@@ -996,6 +1358,11 @@ where
     }
 
     fn repopulate_proposals(&mut self, priors: &ModelPriors, params: &ModelParams);
+
+    // Replace the proposals with birth moves, giving a background voxel to a
+    // dormant cell, or death moves, returning a born cell's last voxel to the
+    // background. Unused proposals are ignored.
+    fn repopulate_birth_death_proposals(&mut self, priors: &ModelPriors, params: &ModelParams);
     fn proposals<'a, 'b>(&'a self) -> &'b [P]
     where
         'a: 'b;
@@ -1032,6 +1399,40 @@ where
             .par_iter_mut()
             .for_each(|p| p.evaluate(priors, params, hillclimb));
         self.apply_accepted_proposals(stats, transcripts, priors, params, uncertainty);
+
+        if priors.allow_cell_birth {
+            self.repopulate_birth_death_proposals(priors, params);
+            self.proposals_mut()
+                .par_iter_mut()
+                .for_each(|p| p.evaluate(priors, params, hillclimb));
+            self.apply_birth_death_proposals(stats, transcripts, priors, params, uncertainty);
+        }
+    }
+
+    fn apply_birth_death_proposals(
+        &mut self,
+        stats: &mut ProposalStats,
+        transcripts: &[Transcript],
+        priors: &ModelPriors,
+        params: &mut ModelParams,
+        uncertainty: &mut Option<&mut UncertaintyTracker>,
+    ) {
+        for proposal in self.proposals().iter().filter(|p| !p.ignored()) {
+            let birth = proposal.old_cell() == BACKGROUND_CELL;
+            match (birth, proposal.accepted()) {
+                (true, true) => stats.cell_birth_accept += 1,
+                (true, false) => stats.cell_birth_reject += 1,
+                (false, true) => stats.cell_death_accept += 1,
+                (false, false) => stats.cell_death_reject += 1,
+            }
+
+            if proposal.accepted() {
+                let cell = if birth { proposal.new_cell() } else { proposal.old_cell() };
+                params.cell_dormant[cell as usize] = !birth;
+            }
+        }
+
+        self.apply_proposal_updates(transcripts, priors, params, uncertainty);
     }
 
     fn apply_accepted_proposals(
@@ -1071,7 +1472,17 @@ where
             }
         }
 
-        // Update cell assignments
+        self.apply_proposal_updates(transcripts, priors, params, uncertainty);
+    }
+
+    // Move the transcripts of accepted proposals to their new cells.
+    fn apply_proposal_updates(
+        &mut self,
+        transcripts: &[Transcript],
+        priors: &ModelPriors,
+        params: &mut ModelParams,
+        uncertainty: &mut Option<&mut UncertaintyTracker>,
+    ) {
         for proposal in self
             .proposals()
             .iter()
@@ -1103,10 +1514,7 @@ where
 
                 for &i in proposal.transcripts() {
                     let gene = transcripts[i].gene;
-                    let layer = ((params.transcript_positions[i].2 - params.z0)
-                        / params.layer_depth)
-                        .max(0.0) as usize;
-                    let layer = layer.min(params.nlayers() - 1);
+                    let layer = params.zlayer(params.transcript_positions[i].2);
                     params.counts[[gene as usize, old_cell as usize, layer]] -= 1;
                 }
             }
@@ -1121,10 +1529,7 @@ where
 
                 for &i in proposal.transcripts() {
                     let gene = transcripts[i].gene;
-                    let layer = ((params.transcript_positions[i].2 - params.z0)
-                        / params.layer_depth)
-                        .max(0.0) as usize;
-                    let layer = layer.min(params.nlayers() - 1);
+                    let layer = params.zlayer(params.transcript_positions[i].2);
                     params.counts[[gene as usize, new_cell as usize, layer]] += 1;
                 }
             }
@@ -1137,7 +1542,8 @@ where
         &mut self,
         priors: &ModelPriors,
         params: &mut ModelParams,
-        transcripts: &Vec<Transcript>,
+        stats: &mut ProposalStats,
+        transcripts: &[Transcript],
         uncertainty: &mut Option<&mut UncertaintyTracker>,
         burnin: bool,
     ) {
@@ -1179,13 +1585,33 @@ where
 
         // sample π
         let mut α = vec![1_f32; params.ncomponents()];
-        for z_i in params.z.iter() {
-            α[*z_i as usize] += 1.0;
+        for (z_i, &dormant) in params.z.iter().zip(&params.cell_dormant) {
+            if !dormant {
+                α[*z_i as usize] += 1.0;
+            }
         }
 
         if α.len() == 1 {
             params.π.clear();
             params.π.push(1.0);
+        } else if let Some(concentration) = priors.component_concentration {
+            // stick-breaking: v_k ~ Beta(1 + n_k, concentration + n_{>k})
+            let mut remaining = α.iter().map(|α_k| α_k - 1.0).sum::<f32>();
+            let mut stick = 1.0_f32;
+            params.π.clear();
+            for (k, α_k) in α.iter().enumerate() {
+                let n_k = α_k - 1.0;
+                remaining -= n_k;
+                let v = if k + 1 == α.len() {
+                    1.0
+                } else {
+                    Beta::new(1.0 + n_k, concentration + remaining.max(0.0))
+                        .unwrap()
+                        .sample(&mut rng)
+                };
+                params.π.push(stick * v);
+                stick *= 1.0 - v;
+            }
         } else {
             params.π.clear();
             params
@@ -1204,7 +1630,7 @@ where
 
         // let t0 = Instant::now();
         if !burnin && priors.use_diffusion_model {
-            self.sample_transcript_positions(priors, params, transcripts, uncertainty);
+            self.sample_transcript_positions(priors, params, stats, transcripts, uncertainty);
         }
         // println!("  Sample transcript positions: {:?}", t0.elapsed());
     }
@@ -1213,13 +1639,13 @@ where
         &mut self,
         _priors: &ModelPriors,
         params: &mut ModelParams,
-        transcripts: &Vec<Transcript>,
+        transcripts: &[Transcript],
         uncertainty: &mut Option<&mut UncertaintyTracker>,
     ) {
         params
             .prev_transcript_state
             .clone_from(&params.transcript_state);
-        let nlayers = params.nlayers();
+        let (z0, layer_depth, nlayers) = (params.z0, params.layer_depth, params.nlayers());
         Zip::from(&mut params.transcript_state)
             .and(&params.cell_assignments)
             .and(&params.transcript_positions)
@@ -1231,8 +1657,7 @@ where
                     *state = TranscriptState::Background;
                 } else {
                     let gene = t.gene as usize;
-                    let layer = ((position.2 - params.z0) / params.layer_depth).max(0.0) as usize;
-                    let layer = layer.min(nlayers - 1);
+                    let layer = zlayer(position.2, z0, layer_depth, nlayers);
 
                     let λ_cell = params.λ[[gene, cell as usize]];
                     let λ_bg = params.λ_bg[[gene, layer]];
@@ -1282,9 +1707,9 @@ where
         &self,
         _priors: &ModelPriors,
         params: &mut ModelParams,
-        transcripts: &Vec<Transcript>,
+        transcripts: &[Transcript],
     ) {
-        let nlayers = params.nlayers();
+        let (z0, layer_depth, nlayers) = (params.z0, params.layer_depth, params.nlayers());
         params.confusion_counts.fill(0_u32);
         params.background_counts.fill(0_u32);
         params.foreground_counts.fill(0_u16);
@@ -1294,9 +1719,7 @@ where
             .and(&params.transcript_positions)
             .for_each(|&state, t, &cell, pos| {
                 let gene = t.gene as usize;
-                // let layer = params.zlayer(pos.2);
-                let layer = ((pos.2 - params.z0) / params.layer_depth).max(0.0) as usize;
-                let layer = layer.min(nlayers - 1);
+                let layer = zlayer(pos.2, z0, layer_depth, nlayers);
 
                 match state {
                     TranscriptState::Background => {
@@ -1450,9 +1873,38 @@ where
 
         if let Some(dispersion) = priors.dispersion {
             set_constant_dispersion(params, dispersion);
-        } else if burnin && priors.burnin_dispersion.is_some() {
-            let dispersion = priors.burnin_dispersion.unwrap();
+        } else if let (true, Some(dispersion)) = (burnin, priors.burnin_dispersion) {
             set_constant_dispersion(params, dispersion);
+        } else if priors.per_gene_dispersion {
+            // for each gene, pool u and v across every cell regardless of component
+            Zip::from(params.r.columns_mut())
+                .and(params.lgamma_r.columns_mut())
+                .and(params.loggammaplus.columns_mut())
+                .and(params.φ.columns())
+                .and(params.foreground_counts.axis_iter(Axis(1)))
+                .par_for_each(|mut rs, mut lgamma_rs, mut loggammaplus, φs, cs| {
+                    let mut rng = thread_rng();
+                    let r = rs[0];
+
+                    let (u, v) = Zip::from(&params.z)
+                        .and(cs.axis_iter(Axis(0)))
+                        .and(&params.cell_volume)
+                        .fold((0_u32, 0_f32), |(u, v), &z, c, &vol| {
+                            let ψ = φs[z as usize] + vol.ln();
+                            let δv = -ψ - log1pf((-ψ).exp());
+                            (u + rand_crt(&mut rng, c.sum() as u32, r), v + δv)
+                        });
+
+                    let r = Gamma::new(priors.e_r + u as f32, (params.h - v).recip())
+                        .unwrap()
+                        .sample(&mut rng)
+                        .max(2e-4);
+                    assert!(r.is_finite());
+
+                    rs.fill(r);
+                    lgamma_rs.fill(lgammaf(r));
+                    loggammaplus.iter_mut().for_each(|l| l.reset(r));
+                });
         } else {
             // for each gene
             params.uv.fill((0_u32, 0_f32));
@@ -1553,9 +2005,15 @@ where
         }
 
         // params.h = 0.1;
+        // with per-gene dispersion, every row of r is the same
+        let r = if priors.per_gene_dispersion {
+            params.r.slice(s![0..1, ..])
+        } else {
+            params.r.view()
+        };
         params.h = Gamma::new(
-            priors.e_h * (1_f32 + params.r.len() as f32),
-            (priors.f_h + params.r.sum()).recip(),
+            priors.e_h * (1_f32 + r.len() as f32),
+            (priors.f_h + r.sum()).recip(),
         )
         .unwrap()
         .sample(&mut thread_rng());
@@ -1606,12 +2064,17 @@ where
     fn sample_background_rates(&mut self, priors: &ModelPriors, params: &mut ModelParams) {
         let mut rng = thread_rng();
 
-        Zip::from(params.λ_bg.rows_mut())
+        Zip::indexed(params.λ_bg.rows_mut())
             .and(params.background_counts.rows())
-            .for_each(|λs, cs| {
+            .for_each(|gene, λs, cs| {
+                let (α_bg, β_bg) = params
+                    .background_rate_priors
+                    .get(gene)
+                    .cloned()
+                    .unwrap_or((priors.α_bg, priors.β_bg));
                 Zip::from(λs).and(cs).for_each(|λ, c| {
-                    let α = priors.α_bg + *c as f32;
-                    let β = priors.β_bg + params.full_layer_volume;
+                    let α = α_bg + *c as f32;
+                    let β = β_bg + params.full_layer_volume;
                     *λ = Gamma::new(α, β.recip()).unwrap().sample(&mut rng) as f32;
                 });
             });
@@ -1634,11 +2097,18 @@ where
     fn sample_confusion_rates(&mut self, priors: &ModelPriors, params: &mut ModelParams) {
         let total_cell_volume = params.cell_volume.sum();
         let mut rng = thread_rng();
+        let (α_c, β_c) = match priors.confusion_prior_rate {
+            Some(rate) => {
+                let volume = priors.confusion_prior_weight * total_cell_volume;
+                (priors.α_c + rate * volume, priors.β_c + volume)
+            }
+            None => (priors.α_c, priors.β_c),
+        };
         Zip::from(&mut params.λ_c)
             .and(&params.confusion_counts)
             .for_each(|λ, c| {
-                let α = priors.α_c + *c as f32;
-                let β = priors.β_c + total_cell_volume;
+                let α = α_c + *c as f32;
+                let β = β_c + total_cell_volume;
                 *λ = Gamma::new(α, β.recip()).unwrap().sample(&mut rng) as f32;
             });
     }
@@ -1650,7 +2120,13 @@ where
         Zip::from(params.foreground_counts.axis_iter(Axis(0)))
             .and(&mut params.z)
             .and(&params.cell_log_volume)
-            .par_for_each(|cs, z_i, cell_log_volume| {
+            .and(&params.cell_dormant)
+            .par_for_each(|cs, z_i, cell_log_volume, &dormant| {
+                // dormant cells keep their component until they're born
+                if dormant {
+                    return;
+                }
+
                 let mut z_probs = params
                     .z_probs
                     .get_or(|| RefCell::new(vec![0_f64; ncomponents]))
@@ -1729,9 +2205,12 @@ where
         params.μ_volume.fill(0_f32);
         Zip::from(&params.z)
             .and(&params.cell_log_volume)
-            .for_each(|&z, &log_volume| {
-                params.μ_volume[z as usize] += log_volume;
-                params.component_population[z as usize] += 1;
+            .and(&params.cell_dormant)
+            .for_each(|&z, &log_volume, &dormant| {
+                if !dormant {
+                    params.μ_volume[z as usize] += log_volume;
+                    params.component_population[z as usize] += 1;
+                }
             });
 
         // dbg!(&params.component_population);
@@ -1759,8 +2238,11 @@ where
         params.σ_volume.fill(0_f32);
         Zip::from(&params.z)
             .and(&params.cell_log_volume)
-            .for_each(|&z, &log_volume| {
-                params.σ_volume[z as usize] += (params.μ_volume[z as usize] - log_volume).powi(2);
+            .and(&params.cell_dormant)
+            .for_each(|&z, &log_volume, &dormant| {
+                if !dormant {
+                    params.σ_volume[z as usize] += (params.μ_volume[z as usize] - log_volume).powi(2);
+                }
             });
 
         // sample σ parameters
@@ -1783,7 +2265,7 @@ where
         &mut self,
         priors: &ModelPriors,
         params: &mut ModelParams,
-        transcripts: &Vec<Transcript>,
+        transcripts: &[Transcript],
     ) {
         // make proposals
         // let t0 = Instant::now();
@@ -1816,6 +2298,12 @@ where
 
         // accept/reject proposals
         // let t0 = Instant::now();
+        let prior_seg_log_probs = &params.prior_seg_log_probs;
+        let default_prior_seg_log_probs = (
+            priors.prior_seg_reassignment_log_prob,
+            priors.prior_seg_reassignment_1mlog_prob,
+        );
+        let (z0, layer_depth, nlayers) = (params.z0, params.layer_depth, params.nlayers());
         params
             .accept_proposed_transcript_positions
             .par_iter_mut()
@@ -1873,9 +2361,7 @@ where
 
                     let gene = transcript.gene as usize;
 
-                    let layer_prev =
-                        ((position.2 - params.z0) / params.layer_depth).max(0.0) as usize;
-                    let layer_prev = layer_prev.min(params.λ_bg.ncols() - 1);
+                    let layer_prev = zlayer(position.2, z0, layer_depth, nlayers);
                     let cell_prev = self.cell_at_position(*position);
                     let λ_prev = if cell_prev == BACKGROUND_CELL {
                         0.0
@@ -1883,9 +2369,7 @@ where
                         params.λ[[gene, cell_prev as usize]] + params.λ_c[gene]
                     } + params.λ_bg[[gene, layer_prev]];
 
-                    let layer_new =
-                        ((proposed_position.2 - params.z0) / params.layer_depth).max(0.0) as usize;
-                    let layer_new = layer_new.min(params.λ_bg.ncols() - 1);
+                    let layer_new = zlayer(proposed_position.2, z0, layer_depth, nlayers);
                     let cell_new = self.cell_at_position(*proposed_position);
                     let λ_new = if cell_new == BACKGROUND_CELL {
                         0.0
@@ -1912,16 +2396,20 @@ where
                     // }
 
                     let cell_prior = params.prior_seg_cell_assignment[i];
+                    let (log_prob, log_1mprob) = prior_seg_log_probs
+                        .get(i)
+                        .cloned()
+                        .unwrap_or(default_prior_seg_log_probs);
                     if cell_prior == cell_prev {
-                        δ -= priors.prior_seg_reassignment_1mlog_prob;
+                        δ -= log_1mprob;
                     } else {
-                        δ -= priors.prior_seg_reassignment_log_prob;
+                        δ -= log_prob;
                     }
 
                     if cell_prior == cell_new {
-                        δ += priors.prior_seg_reassignment_1mlog_prob;
+                        δ += log_1mprob;
                     } else {
-                        δ += priors.prior_seg_reassignment_log_prob;
+                        δ += log_prob;
                     }
 
                     let mut rng = thread_rng();
@@ -1940,12 +2428,38 @@ where
         &mut self,
         priors: &ModelPriors,
         params: &mut ModelParams,
-        transcripts: &Vec<Transcript>,
+        stats: &mut ProposalStats,
+        transcripts: &[Transcript],
         uncertainty: &mut Option<&mut UncertaintyTracker>,
     ) {
         self.propose_eval_transcript_positions(priors, params, transcripts);
 
+        // transcripts of genes with diffusion turned off stay put
+        let mut nfixed = 0;
+        if params.gene_diffusion.iter().any(|&d| !d) {
+            for (accept, t) in params
+                .accept_proposed_transcript_positions
+                .iter_mut()
+                .zip(transcripts)
+            {
+                if !params.gene_diffusion[t.gene as usize] {
+                    *accept = false;
+                    nfixed += 1;
+                }
+            }
+        }
+
+        let naccepted = params
+            .accept_proposed_transcript_positions
+            .iter()
+            .filter(|&&accept| accept)
+            .count();
+        stats.transcript_position_accept += naccepted;
+        stats.transcript_position_reject +=
+            params.accept_proposed_transcript_positions.len() - naccepted - nfixed;
+
         // Update position and compute cell and layer changes for updates
+        let (z0, layer_depth, nlayers) = (params.z0, params.layer_depth, params.nlayers());
         params
             .transcript_position_updates
             .par_iter_mut()
@@ -1956,13 +2470,10 @@ where
             .for_each(
                 |((((update, position), proposed_position), cell_prev), &accept)| {
                     if accept {
-                        let layer_prev = ((position.2 - params.z0) / params.layer_depth) as usize;
-                        let layer_prev = layer_prev.min(params.λ_bg.ncols() - 1);
+                        let layer_prev = zlayer(position.2, z0, layer_depth, nlayers);
 
                         let cell_new = self.cell_at_position(*proposed_position);
-                        let layer_new =
-                            ((proposed_position.2 - params.z0) / params.layer_depth) as usize;
-                        let layer_new = layer_new.min(params.λ_bg.ncols() - 1);
+                        let layer_new = zlayer(proposed_position.2, z0, layer_depth, nlayers);
 
                         // assert!(self.cell_at_position(*position) == *cell_prev);
 
@@ -2026,3 +2537,57 @@ where
         );
     }
 }
+
+#[test]
+fn counts_from_assignments_tiny() {
+    let transcript = |gene| Transcript { transcript_id: 0, x: 0.0, y: 0.0, z: 0.0, gene, fov: 0 };
+    let transcripts = vec![transcript(0), transcript(1), transcript(1), transcript(2), transcript(0)];
+    let assignments = vec![0, 1, 1, BACKGROUND_CELL, 1];
+
+    let counts = counts_from_assignments(&assignments, &transcripts, 3, 2);
+    assert_eq!(counts.shape(), &[3, 2]);
+    assert_eq!(counts.column(0).to_vec(), vec![1, 0, 0]);
+    assert_eq!(counts.column(1).to_vec(), vec![1, 2, 0]);
+    // background transcripts aren't counted
+    assert_eq!(counts.sum(), 4);
+
+    // cells with nothing assigned still get a column
+    let counts = counts_from_assignments(&[BACKGROUND_CELL; 5], &transcripts, 3, 2);
+    assert_eq!(counts.sum(), 0);
+    assert_eq!(counts.shape(), &[3, 2]);
+}
+
+#[test]
+fn zlayer_boundaries_half_open() {
+    // depths that don't divide evenly in floating point
+    let (z0, layer_depth, nlayers) = (0.1_f32, 0.7 / 3.0, 6);
+    for k in 0..nlayers {
+        let boundary = z0 + k as f32 * layer_depth;
+        assert_eq!(zlayer(boundary, z0, layer_depth, nlayers), k);
+        if k > 0 {
+            let below = f32::from_bits(boundary.to_bits() - 1);
+            assert_eq!(zlayer(below, z0, layer_depth, nlayers), k - 1);
+        }
+    }
+
+    // outside the layers is clamped
+    assert_eq!(zlayer(z0 - 1.0, z0, layer_depth, nlayers), 0);
+    assert_eq!(zlayer(z0 + nlayers as f32 * layer_depth, z0, layer_depth, nlayers), nlayers - 1);
+}
+
+#[test]
+fn zlayer_at_zmax_is_in_last_layer() {
+    // layers spanning exactly [zmin, zmax], as with --target-z-layers
+    let (zmin, zmax, nlayers) = (-2.3_f32, 7.9_f32, 3);
+    let layer_depth = (zmax - zmin) / nlayers as f32;
+    assert_eq!(zlayer(zmax, zmin, layer_depth, nlayers), nlayers - 1);
+    assert!(!outside_zlayers(zmax, zmin, layer_depth, nlayers));
+    assert!(!outside_zlayers(zmin, zmin, layer_depth, nlayers));
+
+    // anything past the ends is clamped, and counted as outside
+    let above = zmax + 0.01;
+    assert_eq!(zlayer(above, zmin, layer_depth, nlayers), nlayers - 1);
+    assert!(outside_zlayers(above, zmin, layer_depth, nlayers));
+    assert_eq!(zlayer(zmin - 0.5, zmin, layer_depth, nlayers), 0);
+    assert!(outside_zlayers(zmin - 0.5, zmin, layer_depth, nlayers));
+}
@@ -7,23 +7,6 @@ use std::collections::{HashMap, HashSet};
 // very small, so I expect this to be fast and easier to resize/reset without allocating.
 type NeighborhoodGraph = Graph<(), (), Undirected, usize>;
 
-#[derive(Copy, Clone, PartialEq)]
-struct DfsInfo {
-    parent: NodeIndex<usize>,
-    depth: u32,
-    low: u32,
-}
-
-impl Default for DfsInfo {
-    fn default() -> Self {
-        Self {
-            parent: NodeIndex::end(),
-            depth: 0,
-            low: 0,
-        }
-    }
-}
-
 pub struct ConnectivityChecker {
     subgraph: NeighborhoodGraph,
     voxel_to_subgraph: HashMap<Voxel, NodeIndex<usize>>,
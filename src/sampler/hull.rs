@@ -1,5 +1,6 @@
 use super::transcripts::{CellIndex, Transcript, BACKGROUND_CELL};
 
+use geo::{Area, ConcaveHull, MultiPoint, Point, Polygon};
 use std::cmp::Ordering;
 use std::fmt::Debug;
 
@@ -34,6 +35,9 @@ impl QuickhullSide for QuickhullBelow {
 //     return convex_hull_area(&mut vertices, &mut hull);
 // }
 
+// Convex hull area of each cell's transcripts in the xy plane. This is only used
+// for rough nucleus size estimates before sampling; reported cell volumes and
+// polygons are based on voxels instead.
 pub fn compute_cell_areas(
     ncells: usize,
     transcripts: &[Transcript],
@@ -109,6 +113,16 @@ pub fn convex_hull_area(vertices: &mut [(f32, f32)], hull: &mut Vec<(f32, f32)>)
     polygon_area(hull)
 }
 
+/// Compute a concave hull and return it along with its area. Smaller
+/// `concavity` values follow the points more closely, while large values
+/// approach the convex hull.
+pub fn concave_hull_area(vertices: &[(f32, f32)], concavity: f32) -> (Polygon<f32>, f32) {
+    let points = MultiPoint::from_iter(vertices.iter().map(|&(x, y)| Point::new(x, y)));
+    let hull = points.concave_hull(concavity);
+    let area = hull.unsigned_area();
+    (hull, area)
+}
+
 pub fn polygon_area(vertices: &mut [(f32, f32)]) -> f32 {
     let c = center(vertices);
     vertices.sort_unstable_by(|a, b| clockwise_cmp(c, *a, *b));
@@ -254,3 +268,24 @@ fn linedist(u: (f32, f32), v: (f32, f32), w: (f32, f32)) -> f32 {
 fn isabove(u: (f32, f32), v: (f32, f32), w: (f32, f32)) -> bool {
     (v.0 - u.0) * (w.1 - u.1) - (v.1 - u.1) * (w.0 - u.0) > 0.0
 }
+
+#[test]
+fn concave_hull_is_tighter_than_convex() {
+    // Grid of points covering an L shape made of three unit squares.
+    let mut vertices = Vec::new();
+    for i in 0..=20 {
+        for j in 0..=20 {
+            let (x, y) = (i as f32 / 10.0, j as f32 / 10.0);
+            if x <= 1.0 || y <= 1.0 {
+                vertices.push((x, y));
+            }
+        }
+    }
+
+    let (_, concave_area) = concave_hull_area(&vertices, 1.0);
+    let mut hull = Vec::new();
+    let convex_area = convex_hull_area(&mut vertices.clone(), &mut hull);
+
+    assert!(concave_area < convex_area);
+    assert!((concave_area - 3.0).abs() < 0.25);
+}
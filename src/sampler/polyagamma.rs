@@ -13,6 +13,10 @@ use saddlepoint::sample_polyagamma_saddlepoint;
 mod alternate;
 use alternate::sample_polyagamma_alternate;
 
+// Crossovers between samplers, see `PolyaGamma::sample`.
+const NORMAL_MIN_H: f32 = 50.0;
+const SADDLEPOINT_MIN_H: f32 = 8.0;
+
 pub struct PolyaGamma<T: Float> {
     h: T,
     z: T,
@@ -66,31 +70,23 @@ where
         }
     }
 
+    // Exact sampling with the alternating series method gets slow as h grows,
+    // which it does with high-count genes, but the distribution is a sum of h
+    // independent PG(1, z) draws, so it also gets closer to normal. Above
+    // NORMAL_MIN_H a normal with matching mean and variance is used, and in
+    // between the saddlepoint approximation (Windle et al. 2014), which is
+    // accurate down to about SADDLEPOINT_MIN_H, or lower when z is small.
     #[replace_float_literals(T::from(literal).unwrap())]
     pub fn sample<R: Rng>(&self, rng: &mut R) -> T {
         assert!(self.h > T::zero(), "h must be non-negative");
 
-        // if self.h >= 50.0 {
-        //     return self.sample_normal(rng);
-        // } else {
-        //     return self.sample_saddlepoint(rng);
-        // };
-
-        if self.h >= 50.0 {
+        if self.h >= T::from(NORMAL_MIN_H).unwrap() {
             self.sample_normal(rng)
-        } else if self.h >= 8.0 || (self.h > 4.0 && self.z <= 4.0) {
+        } else if self.h >= T::from(SADDLEPOINT_MIN_H).unwrap() || (self.h > 4.0 && self.z <= 4.0) {
             self.sample_saddlepoint(rng)
         } else {
             self.sample_alternate(rng)
         }
-
-        // } else if self.h >= 8.0 || (self.h > 4.0 && self.z <= 4.0) {
-        //     return self.sample_saddlepoint(rng);
-        // } else if self.h == 1.0 || (self.h == self.h.floor() && self.z <= 1.0) {
-        //     return self.sample_devroye(rng);
-        // } else {
-        //     return self.sample_alternate(rng);
-        // }
     }
 
     fn sample_normal<R: Rng>(&self, rng: &mut R) -> T {
@@ -123,3 +119,32 @@ fn try_pg_sampler() {
     }
     dbg!(rs);
 }
+
+// Every sampler, and the crossovers between them, should match the exact
+// mean and variance.
+#[test]
+fn pg_sample_moments() {
+    use rand::SeedableRng;
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+    let n = 20000;
+    for &h in &[1.0_f64, 3.0, 5.0, 7.9, 8.0, 20.0, 49.9, 50.0, 200.0, 1000.0] {
+        for &z in &[0.0_f64, 1.0, 3.0, 10.0, -6.0] {
+            let pg = PolyaGamma::new(h, z);
+            let rs = (0..n).map(|_| pg.sample(&mut rng)).collect::<Vec<_>>();
+            let mean = rs.iter().sum::<f64>() / n as f64;
+            let var = rs.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / (n - 1) as f64;
+
+            // within a few standard errors
+            let mean_se = (pg.var() / n as f64).sqrt();
+            assert!(
+                (mean - pg.mean()).abs() < 5.0 * mean_se,
+                "PG({}, {}): sample mean {} vs {}", h, z, mean, pg.mean()
+            );
+            assert!(
+                (var / pg.var() - 1.0).abs() < 0.1,
+                "PG({}, {}): sample variance {} vs {}", h, z, var, pg.var()
+            );
+        }
+    }
+}
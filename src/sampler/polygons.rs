@@ -19,11 +19,11 @@ use itertools::Itertools;
 //   To reduce the jagged edges:
 
 fn drop_interiors(multipoly: MultiPolygon<f32>) -> MultiPolygon<f32> {
-    return MultiPolygon::from_iter(
+    MultiPolygon::from_iter(
         multipoly
             .iter()
             .map(|poly| Polygon::new(poly.exterior().clone(), vec![])),
-    );
+    )
 }
 
 // taken from: https://github.com/a-b-street/abstreet
@@ -166,7 +166,7 @@ fn remove_polygon_loops(polygon: Vec<VoxelIJ>) -> Vec<VoxelIJ> {
         }
     }
 
-    return loopless_polygon;
+    loopless_polygon
 }
 
 
@@ -357,3 +357,65 @@ impl PolygonBuilder {
         multipolygons
     }
 }
+
+#[cfg(test)]
+use geo::Area;
+
+// Polygons traced from a single layer of voxels at the given (i, j).
+#[cfg(test)]
+fn trace_voxels(ijs: &[(i32, i32)]) -> MultiPolygon<f32> {
+    let layout = VoxelLayout::new((0.0, 0.0, 0.0), (1.0, 1.0, 1.0));
+    let voxels = ijs.iter().map(|&(i, j)| Voxel::new(i, j, 0)).collect::<HashSet<_>>();
+    let mut polygons = PolygonBuilder::new().cell_voxels_to_polygons(&layout, &voxels);
+    assert_eq!(polygons.len(), 1);
+    polygons.pop().unwrap().1
+}
+
+#[test]
+fn polygons_single_voxel() {
+    let polys = trace_voxels(&[(0, 0)]);
+    assert_eq!(polys.0.len(), 1);
+    assert_eq!(polys.unsigned_area(), 1.0);
+    // four corners, closed
+    assert_eq!(polys.0[0].exterior().0.len(), 5);
+}
+
+#[test]
+fn polygons_l_shape() {
+    let polys = trace_voxels(&[(0, 0), (1, 0), (2, 0), (0, 1), (0, 2)]);
+    assert_eq!(polys.0.len(), 1);
+    assert_eq!(polys.unsigned_area(), 5.0);
+    // collinear segments are merged, leaving the six corners
+    assert_eq!(polys.0[0].exterior().0.len(), 7);
+}
+
+#[test]
+fn polygons_donut_hole_filled() {
+    let ring = (0..3)
+        .cartesian_product(0..3)
+        .filter(|&ij| ij != (1, 1))
+        .collect::<Vec<_>>();
+    let polys = trace_voxels(&ring);
+    assert_eq!(polys.0.len(), 1);
+    assert!(polys.0[0].interiors().is_empty());
+    assert_eq!(polys.unsigned_area(), 9.0);
+}
+
+#[test]
+fn polygons_corner_touching_voxels() {
+    // the shared corner has four adjacent edges
+    for ijs in [[(0, 0), (1, 1)], [(1, 0), (0, 1)]] {
+        let polys = trace_voxels(&ijs);
+        assert_eq!(polys.unsigned_area(), 2.0);
+    }
+}
+
+#[test]
+fn polygons_checkerboard() {
+    let board = (0..4)
+        .cartesian_product(0..4)
+        .filter(|&(i, j)| (i + j) % 2 == 0)
+        .collect::<Vec<_>>();
+    let polys = trace_voxels(&board);
+    assert_eq!(polys.unsigned_area(), 8.0);
+}
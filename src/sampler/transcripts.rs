@@ -3,22 +3,29 @@ use flate2::read::MultiGzDecoder;
 use kiddo::SquaredEuclidean;
 use kiddo::float::kdtree::KdTree;
 use ndarray::Array2;
-use std::collections::HashMap;
-use std::fs::File;
+use std::collections::{HashMap, HashSet};
+use memmap2::Mmap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
 use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
 use arrow;
 use itertools::izip;
+use rayon::prelude::*;
 use std::str;
 
 pub type CellIndex = u32;
-pub const BACKGROUND_CELL: CellIndex = std::u32::MAX;
+pub const BACKGROUND_CELL: CellIndex = u32::MAX;
 
 
 // Should probably rearrange this...
 use super::super::output::infer_format_from_filename;
-use crate::schemas::OutputFormat;
+use crate::schemas::{DuplicateGenes, NucleusDistanceMetric, OutputFormat};
 
+// repr(C) so transcripts can be stored in, and read back from, a
+// memory-mapped file (see `MappedTranscripts`).
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[repr(C)]
 pub struct Transcript {
     pub transcript_id: u64,
     pub x: f32,
@@ -28,6 +35,121 @@ pub struct Transcript {
     pub fov: u32,
 }
 
+// Transcripts stored in a memory-mapped scratch file, so the OS can page them
+// out of memory on datasets too large to hold in RAM. The file is removed
+// when this is dropped.
+pub struct MappedTranscripts {
+    path: String,
+    mmap: Option<Mmap>,
+    len: usize,
+}
+
+impl MappedTranscripts {
+    pub fn new(path: &str, transcripts: Vec<Transcript>) -> MappedTranscripts {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .unwrap_or_else(|err| panic!("Unable to create '{}': {}", path, err));
+
+        // Written field by field, zeroing the struct's trailing padding.
+        let padding = [0_u8; size_of::<Transcript>() - 28];
+        let mut writer = BufWriter::new(&file);
+        for t in &transcripts {
+            writer.write_all(&t.transcript_id.to_ne_bytes()).unwrap();
+            writer.write_all(&t.x.to_ne_bytes()).unwrap();
+            writer.write_all(&t.y.to_ne_bytes()).unwrap();
+            writer.write_all(&t.z.to_ne_bytes()).unwrap();
+            writer.write_all(&t.gene.to_ne_bytes()).unwrap();
+            writer.write_all(&t.fov.to_ne_bytes()).unwrap();
+            writer.write_all(&padding).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+
+        let len = transcripts.len();
+        drop(transcripts);
+
+        // Mapping an empty file fails on some platforms.
+        let mmap = (len > 0).then(|| {
+            // Safety: the scratch file is ours and isn't modified while mapped.
+            unsafe { Mmap::map(&file) }.unwrap_or_else(|err| panic!("Unable to map '{}': {}", path, err))
+        });
+
+        MappedTranscripts { path: path.to_string(), mmap, len }
+    }
+
+    pub fn as_slice(&self) -> &[Transcript] {
+        match &self.mmap {
+            // Safety: the file holds `len` transcripts in Transcript's repr(C)
+            // layout, and the mapping is page aligned.
+            Some(mmap) => unsafe { std::slice::from_raw_parts(mmap.as_ptr() as *const Transcript, self.len) },
+            None => &[],
+        }
+    }
+}
+
+impl Drop for MappedTranscripts {
+    fn drop(&mut self) {
+        self.mmap = None;
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            eprintln!("Warning: unable to remove '{}': {}", self.path, err);
+        }
+    }
+}
+
+// Cellular compartment a transcript was reported in. Only the nucleus is used
+// to initialize cells, the others are carried through to the transcript
+// metadata and may weight the prior segmentation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compartment {
+    Nuclear,
+    Cytoplasm,
+    Membrane,
+    Extracellular,
+    Unknown,
+}
+
+impl Compartment {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Compartment::Nuclear => "nuclear",
+            Compartment::Cytoplasm => "cytoplasm",
+            Compartment::Membrane => "membrane",
+            Compartment::Extracellular => "extracellular",
+            Compartment::Unknown => "unknown",
+        }
+    }
+}
+
+// Values of the compartment column, besides the nuclear one, indicating the
+// other compartments.
+#[derive(Clone, Debug, Default)]
+pub struct CompartmentValues {
+    pub cytoplasm: Option<String>,
+    pub membrane: Option<String>,
+    pub extracellular: Option<String>,
+}
+
+impl CompartmentValues {
+    fn classify(&self, value: &str, nuclear: &str) -> Compartment {
+        let is = |v: &Option<String>| v.as_deref() == Some(value);
+        if value == nuclear {
+            Compartment::Nuclear
+        } else if is(&self.cytoplasm) {
+            Compartment::Cytoplasm
+        } else if is(&self.membrane) {
+            Compartment::Membrane
+        } else if is(&self.extracellular) {
+            Compartment::Extracellular
+        } else {
+            Compartment::Unknown
+        }
+    }
+}
+
 pub struct TranscriptDataset {
     pub transcript_names: Vec<String>,
     pub transcripts: Vec<Transcript>,
@@ -36,7 +158,20 @@ pub struct TranscriptDataset {
     pub nucleus_population: Vec<usize>,
     pub fovs: Vec<u32>,
     pub qvs: Vec<f32>,
+    pub compartments: Vec<Compartment>,
+    // per-transcript confidence in the prior cell assignment, if read from
+    // --prior-cell-prob-column, otherwise empty
+    pub prior_cell_probs: Vec<f32>,
+    // id (or row number, without an id column) of the input row each
+    // transcript was expanded from with --count-column, otherwise empty
+    pub source_transcript_ids: Vec<u64>,
+    // (fov, cell id) of each cell in the input, and the index of each
+    // transcript's input cell, before any cells are renumbered or filtered
+    pub original_cell_ids: Vec<(u32, String)>,
+    pub original_cell_assignments: Vec<CellIndex>,
     pub fov_names: Vec<String>,
+    // rows dropped for having a qv below min_qv
+    pub nfiltered_qv: usize,
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -46,65 +181,95 @@ pub fn read_transcripts_csv(
     id_column: Option<String>,
     compartment_column: Option<String>,
     compartment_nuclear: Option<String>,
+    compartment_values: CompartmentValues,
     fov_column: Option<String>,
     cell_assignment_column: Option<String>,
     cell_assignment_unassigned: Option<String>,
     cell_id_column: &str,
     cell_id_unassigned: &str,
     qv_column: Option<String>,
+    count_column: Option<String>,
+    prior_cell_prob_column: Option<String>,
     x_column: &str,
     y_column: &str,
     z_column: &str,
     min_qv: f32,
     ignore_z_column: bool,
     coordinate_scale: f32,
+    z_scale: f32,
+    coord_affine: [f32; 6],
 ) -> TranscriptDataset {
     let fmt = infer_format_from_filename(path);
 
     match fmt {
         OutputFormat::Csv => {
             let mut rdr = csv::Reader::from_path(path).unwrap();
-            read_transcripts_csv_xyz(
-                &mut rdr,
+            let columns = TranscriptCsvColumns::new(
+                rdr.headers().unwrap(),
                 transcript_column,
                 id_column,
                 compartment_column,
                 compartment_nuclear,
+                compartment_values.clone(),
                 fov_column,
                 cell_assignment_column,
                 cell_assignment_unassigned,
                 cell_id_column,
                 cell_id_unassigned,
                 qv_column,
+                count_column,
+                prior_cell_prob_column,
                 x_column,
                 y_column,
                 z_column,
                 min_qv,
                 ignore_z_column,
                 coordinate_scale,
-            )
+                z_scale,
+                coord_affine,
+            );
+            let len = std::fs::metadata(path).unwrap().len();
+            let nchunks = rayon::current_num_threads().min((len / MIN_CSV_CHUNK_BYTES) as usize);
+            let parts = if nchunks > 1 {
+                parse_csv_chunks(path, rdr.position().byte(), nchunks, &columns)
+            } else {
+                columns.parse(rdr.records())
+            };
+            parts.finish(cell_id_column, cell_id_unassigned)
         }
-        OutputFormat::CsvGz => {
-            let mut rdr = csv::Reader::from_reader(MultiGzDecoder::new(File::open(path).unwrap()));
-            read_transcripts_csv_xyz(
-                &mut rdr,
+        OutputFormat::CsvGz | OutputFormat::CsvZst => {
+            let mut rdr = csv::Reader::from_reader(decompressed_reader(path, fmt));
+            let columns = TranscriptCsvColumns::new(
+                rdr.headers().unwrap(),
                 transcript_column,
                 id_column,
                 compartment_column,
                 compartment_nuclear,
+                compartment_values,
                 fov_column,
                 cell_assignment_column,
                 cell_assignment_unassigned,
                 cell_id_column,
                 cell_id_unassigned,
                 qv_column,
+                count_column,
+                prior_cell_prob_column,
                 x_column,
                 y_column,
                 z_column,
                 min_qv,
                 ignore_z_column,
                 coordinate_scale,
-            )
+                z_scale,
+                coord_affine,
+            );
+            columns.parse(rdr.records()).finish(cell_id_column, cell_id_unassigned)
+        }
+        OutputFormat::Parquet if count_column.is_some() => {
+            panic!("--count-column is not supported for parquet input")
+        }
+        OutputFormat::Parquet if prior_cell_prob_column.is_some() => {
+            panic!("--prior-cell-prob-column is not supported for parquet input")
         }
         OutputFormat::Parquet => read_xenium_transcripts_parquet(
             path,
@@ -121,11 +286,186 @@ pub fn read_transcripts_csv(
             z_column,
             min_qv,
             ignore_z_column,
-            coordinate_scale),
+            coordinate_scale,
+            z_scale,
+            coord_affine),
         OutputFormat::Infer => panic!("Could not infer format of file '{}'", path),
     }
 }
 
+// Map (x, y) through the affine transform [a, b, c, d, e, f], giving
+// (a*x + b*y + c, d*x + e*y + f).
+pub fn apply_affine(affine: &[f32; 6], x: f32, y: f32) -> (f32, f32) {
+    let [a, b, c, d, e, f] = *affine;
+    (a * x + b * y + c, d * x + e * y + f)
+}
+
+// Open a csv, decompressing it if it's gzip or zstd compressed.
+pub fn decompressed_reader(path: &str, fmt: OutputFormat) -> Box<dyn Read> {
+    let file = File::open(path).unwrap_or_else(|_| panic!("Unable to open '{}'.", path));
+    match fmt {
+        OutputFormat::Csv => Box::new(file),
+        OutputFormat::CsvZst => Box::new(zstd::Decoder::new(file).unwrap()),
+        _ => Box::new(MultiGzDecoder::new(file)),
+    }
+}
+
+// Read just the column names from a transcript table.
+pub fn read_transcripts_header(path: &str) -> Vec<String> {
+    let fmt = infer_format_from_filename(path);
+
+    match fmt {
+        OutputFormat::Csv => {
+            let mut rdr = csv::Reader::from_path(path).unwrap();
+            rdr.headers().unwrap().iter().map(String::from).collect()
+        }
+        OutputFormat::CsvGz | OutputFormat::CsvZst => {
+            let mut rdr = csv::Reader::from_reader(decompressed_reader(path, fmt));
+            rdr.headers().unwrap().iter().map(String::from).collect()
+        }
+        OutputFormat::Parquet => {
+            let input_file = File::open(path).unwrap_or_else(|_| panic!("Unable to open '{}'.", &path));
+            let builder = ParquetRecordBatchReaderBuilder::try_new(input_file).unwrap();
+            builder.schema().fields().iter().map(|field| field.name().clone()).collect()
+        }
+        OutputFormat::Infer => panic!("Could not infer format of file '{}'", path),
+    }
+}
+
+// Column names of a transcript table with a type and the values from its first
+// `nrows` rows, for --list-columns. CSV types are inferred from those values.
+pub fn read_transcripts_sample(path: &str, nrows: usize) -> Vec<(String, String, Vec<String>)> {
+    let fmt = infer_format_from_filename(path);
+
+    let read_csv = |mut rdr: csv::Reader<Box<dyn Read>>| {
+        let headers = rdr.headers().unwrap().clone();
+        let rows = rdr
+            .records()
+            .take(nrows)
+            .map(|row| row.unwrap())
+            .collect::<Vec<_>>();
+        headers
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let values = rows.iter().map(|row| row[i].to_string()).collect::<Vec<_>>();
+                let nonempty = values.iter().filter(|v| !v.is_empty());
+                let inferred_type = if values.iter().all(|v| v.is_empty()) {
+                    "empty"
+                } else if nonempty.clone().all(|v| v.parse::<i64>().is_ok()) {
+                    "integer"
+                } else if nonempty.clone().all(|v| v.parse::<f64>().is_ok()) {
+                    "float"
+                } else {
+                    "string"
+                };
+                (name.to_string(), inferred_type.to_string(), values)
+            })
+            .collect()
+    };
+
+    match fmt {
+        OutputFormat::Csv => {
+            let file = File::open(path).unwrap_or_else(|_| panic!("Unable to open '{}'.", &path));
+            read_csv(csv::Reader::from_reader(Box::new(file)))
+        }
+        OutputFormat::CsvGz | OutputFormat::CsvZst => {
+            read_csv(csv::Reader::from_reader(decompressed_reader(path, fmt)))
+        }
+        OutputFormat::Parquet => {
+            let input_file = File::open(path).unwrap_or_else(|_| panic!("Unable to open '{}'.", &path));
+            let builder = ParquetRecordBatchReaderBuilder::try_new(input_file).unwrap();
+            let schema = builder.schema().clone();
+            let batch = builder.with_batch_size(nrows.max(1)).build().unwrap().next().transpose().unwrap();
+            schema
+                .fields()
+                .iter()
+                .enumerate()
+                .map(|(i, field)| {
+                    let values = batch
+                        .iter()
+                        .flat_map(|batch| {
+                            let column = batch.column(i);
+                            (0..column.len().min(nrows))
+                                .map(|j| arrow::util::display::array_value_to_string(column, j).unwrap())
+                        })
+                        .collect();
+                    (field.name().clone(), field.data_type().to_string(), values)
+                })
+                .collect()
+        }
+        OutputFormat::Infer => panic!("Could not infer format of file '{}'", path),
+    }
+}
+
+// Tracks how often the cell id column matches the unassigned value, since a
+// mistyped --cell-id-unassigned otherwise silently produces nonsense.
+struct CellIdUnassignedCheck {
+    nrows: usize,
+    nunassigned: usize,
+    examples: Vec<String>,
+}
+
+impl CellIdUnassignedCheck {
+    const MAX_EXAMPLES: usize = 10;
+
+    fn new() -> Self {
+        CellIdUnassignedCheck {
+            nrows: 0,
+            nunassigned: 0,
+            examples: Vec::new(),
+        }
+    }
+
+    fn observe(&mut self, cell_id: &str, unassigned: bool) {
+        self.nrows += 1;
+        if unassigned {
+            self.nunassigned += 1;
+        }
+        if self.examples.len() < Self::MAX_EXAMPLES && !self.examples.iter().any(|e| e == cell_id) {
+            self.examples.push(cell_id.to_string());
+        }
+    }
+
+    fn report(&self, cell_id_column: &str, cell_id_unassigned: &str) {
+        if self.nrows == 0 {
+            return;
+        }
+
+        let problem = if self.nunassigned == 0 {
+            "matched no transcripts"
+        } else if self.nunassigned == self.nrows {
+            "matched every transcript"
+        } else {
+            return;
+        };
+
+        eprintln!(
+            "Warning: cell id unassigned value '{}' {}. Check --cell-id-unassigned. Values seen in column '{}' include: {}",
+            cell_id_unassigned,
+            problem,
+            cell_id_column,
+            self.examples.iter().map(|e| format!("'{}'", e)).collect::<Vec<_>>().join(", ")
+        );
+    }
+}
+
+// Check if a cell id is the value indicating no cell. Ids are compared as
+// numbers when both are numeric, so "0", "0.0", and "00" are all treated
+// the same.
+fn is_unassigned_cell_id(cell_id: &str, unassigned: &str) -> bool {
+    let cell_id = cell_id.trim();
+    let unassigned = unassigned.trim();
+    if cell_id == unassigned {
+        return true;
+    }
+
+    match (cell_id.parse::<f64>(), unassigned.parse::<f64>()) {
+        (Ok(a), Ok(b)) => b.is_finite() && a == b,
+        _ => false,
+    }
+}
+
 fn find_column(headers: &csv::StringRecord, column: &str) -> usize {
     let col = headers.iter().position(|x| x == column);
     match col {
@@ -185,197 +525,529 @@ fn postprocess_cell_assignments(
     nucleus_population
 }
 
-#[allow(clippy::too_many_arguments)]
-fn read_transcripts_csv_xyz<T>(
-    rdr: &mut csv::Reader<T>,
-
-    transcript_column: &str,
-    id_column: Option<String>,
-    compartment_column: Option<String>,
-    compartment_nuclear: Option<String>,
-    fov_column: Option<String>,
-    cell_assignment_column: Option<String>,
-    cell_assignment_unassigned: Option<String>,
-    cell_id_column: &str,
-    cell_id_unassigned: &str,
-    qv_column: Option<String>,
-    x_column: &str,
-    y_column: &str,
-    z_column: &str,
+// Column indices and settings needed to parse transcript rows, resolved once
+// from the header so that chunks of a file can be parsed independently.
+struct TranscriptCsvColumns {
+    transcript_col: usize,
+    x_col: usize,
+    y_col: usize,
+    z_col: usize,
+    id_col: Option<usize>,
+    cell_id_col: usize,
+    compartment_col: Option<usize>,
+    compartment_nuclear: String,
+    compartment_values: CompartmentValues,
+    qv_col: Option<usize>,
+    fov_col: Option<usize>,
+    cell_assignment_col: Option<usize>,
+    cell_assignment_unassigned: String,
+    cell_id_unassigned: String,
+    count_col: Option<usize>,
+    prior_cell_prob_col: Option<usize>,
     min_qv: f32,
     ignore_z_column: bool,
     coordinate_scale: f32,
-) -> TranscriptDataset
-where
-    T: std::io::Read,
-{
-    // Find the column we need
-    let headers = rdr.headers().unwrap();
-    let transcript_col = find_column(headers, transcript_column);
-    let x_col = find_column(headers, x_column);
-    let y_col = find_column(headers, y_column);
-    let z_col = find_column(headers, z_column);
-    let id_col = id_column.map(|id_column| find_column(headers, &id_column));
-
-    let cell_id_col = find_column(headers, cell_id_column);
-    let compartment_col =
-        compartment_column.map(|compartment_column| find_column(headers, &compartment_column));
-    let has_compartment = compartment_col.is_some();
-
-    let compartment_nuclear = if has_compartment {
-        compartment_nuclear.unwrap()
-    } else {
-        String::new()
-    };
-
-    let qv_col = find_optional_column(headers, &qv_column);
-    let fov_col = find_optional_column(headers, &fov_column);
-    let cell_assignment_col = find_optional_column(headers, &cell_assignment_column);
-    let cell_assignment_unassigned = cell_assignment_unassigned.unwrap_or(String::from(""));
-
-    let mut transcripts = Vec::new();
-    let mut transcript_name_map: HashMap<String, usize> = HashMap::new();
-    let mut transcript_names = Vec::new();
-    let mut nucleus_assignments = Vec::new();
-    let mut cell_assignments = Vec::new();
-    let mut qvs = Vec::new();
-    let mut fovs = Vec::new();
-
-    let mut fov_map: HashMap<String, u32> = HashMap::new();
-    let mut cell_id_map: HashMap<(u32, String), CellIndex> = HashMap::new();
+    z_scale: f32,
+    coord_affine: [f32; 6],
+}
 
-    for result in rdr.records() {
-        let row = result.unwrap();
+// Transcripts parsed from a run of rows, with gene, fov, and cell ids numbered
+// in order of first appearance within those rows.
+struct PartialTranscripts {
+    transcripts: Vec<Transcript>,
+    transcript_names: Vec<String>,
+    fov_names: Vec<String>,
+    cell_keys: Vec<(u32, String)>,
+    nucleus_assignments: Vec<CellIndex>,
+    cell_assignments: Vec<CellIndex>,
+    qvs: Vec<f32>,
+    compartments: Vec<Compartment>,
+    prior_cell_probs: Vec<f32>,
+    source_transcript_ids: Vec<u64>,
+    fovs: Vec<u32>,
+    cell_id_check: CellIdUnassignedCheck,
+    nfiltered_qv: usize,
+    // rows read, including those filtered out
+    nrows: u64,
+}
 
-        let qv = if let Some(qv_col) = qv_col {
-            row[qv_col].parse::<f32>().unwrap()
+impl TranscriptCsvColumns {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        headers: &csv::StringRecord,
+        transcript_column: &str,
+        id_column: Option<String>,
+        compartment_column: Option<String>,
+        compartment_nuclear: Option<String>,
+        compartment_values: CompartmentValues,
+        fov_column: Option<String>,
+        cell_assignment_column: Option<String>,
+        cell_assignment_unassigned: Option<String>,
+        cell_id_column: &str,
+        cell_id_unassigned: &str,
+        qv_column: Option<String>,
+        count_column: Option<String>,
+        prior_cell_prob_column: Option<String>,
+        x_column: &str,
+        y_column: &str,
+        z_column: &str,
+        min_qv: f32,
+        ignore_z_column: bool,
+        coordinate_scale: f32,
+        z_scale: f32,
+        coord_affine: [f32; 6],
+    ) -> TranscriptCsvColumns {
+        // Find the column we need
+        let compartment_col =
+            compartment_column.map(|compartment_column| find_column(headers, &compartment_column));
+
+        let compartment_nuclear = if compartment_col.is_some() {
+            compartment_nuclear.unwrap()
         } else {
-            f32::INFINITY
+            String::new()
         };
 
-        if qv < min_qv {
-            continue;
+        TranscriptCsvColumns {
+            transcript_col: find_column(headers, transcript_column),
+            x_col: find_column(headers, x_column),
+            y_col: find_column(headers, y_column),
+            z_col: find_column(headers, z_column),
+            id_col: id_column.map(|id_column| find_column(headers, &id_column)),
+            cell_id_col: find_column(headers, cell_id_column),
+            compartment_col,
+            compartment_nuclear,
+            compartment_values,
+            qv_col: find_optional_column(headers, &qv_column),
+            fov_col: find_optional_column(headers, &fov_column),
+            cell_assignment_col: find_optional_column(headers, &cell_assignment_column),
+            cell_assignment_unassigned: cell_assignment_unassigned.unwrap_or(String::from("")),
+            cell_id_unassigned: cell_id_unassigned.to_string(),
+            count_col: count_column.map(|count_column| find_column(headers, &count_column)),
+            prior_cell_prob_col: prior_cell_prob_column.map(|column| find_column(headers, &column)),
+            min_qv,
+            ignore_z_column,
+            coordinate_scale,
+            z_scale,
+            coord_affine,
         }
+    }
 
-        let fov = if let Some(fov_col) = fov_col {
-            match fov_map.get(&row[fov_col]) {
-                Some(fov) => *fov,
-                None => {
-                    let next_fov = fov_map.len();
-                    fov_map.insert(row[fov_col].to_string(), next_fov as u32);
-                    next_fov as u32
-                }
-            }
-        } else {
-            0
-        };
-
-        let transcript_name = &row[transcript_col];
-
-        let gene = if let Some(gene) = transcript_name_map.get(transcript_name) {
-            *gene
-        } else {
-            transcript_names.push(transcript_name.to_string());
-            transcript_name_map.insert(transcript_name.to_string(), transcript_names.len() - 1);
-            transcript_names.len() - 1
-        };
+    fn parse<I>(&self, records: I) -> PartialTranscripts
+    where
+        I: Iterator<Item = csv::Result<csv::StringRecord>>,
+    {
+        let mut transcripts = Vec::new();
+        let mut transcript_name_map: HashMap<String, usize> = HashMap::new();
+        let mut transcript_names = Vec::new();
+        let mut nucleus_assignments = Vec::new();
+        let mut cell_assignments = Vec::new();
+        let mut qvs = Vec::new();
+        let mut compartments = Vec::new();
+        let mut prior_cell_probs = Vec::new();
+        let mut source_transcript_ids = Vec::new();
+        let mut fovs = Vec::new();
+
+        let mut fov_map: HashMap<String, u32> = HashMap::new();
+        let mut fov_names = Vec::new();
+        let mut cell_id_map: HashMap<(u32, String), CellIndex> = HashMap::new();
+        let mut cell_keys = Vec::new();
+        let mut cell_id_check = CellIdUnassignedCheck::new();
+        let mut nfiltered_qv = 0;
+        let mut nrows = 0;
+
+        for result in records {
+            let row = result.unwrap();
+            let row_number = nrows;
+            nrows += 1;
+
+            let qv = if let Some(qv_col) = self.qv_col {
+                row[qv_col].parse::<f32>().unwrap()
+            } else {
+                f32::INFINITY
+            };
 
-        let x = coordinate_scale * row[x_col].parse::<f32>().unwrap();
-        let y = coordinate_scale * row[y_col].parse::<f32>().unwrap();
-        let z = row[z_col].parse::<f32>().unwrap();
-        let transcript_id = if let Some(id_col) = id_col {
-            row[id_col]
-                .parse::<u64>()
-                .unwrap_or_else(|_| panic!("Transcript ID must be an integer: {}", &row[id_col]))
-        } else {
-            transcripts.len() as u64
-        };
+            if qv < self.min_qv {
+                nfiltered_qv += 1;
+                continue;
+            }
 
-        transcripts.push(Transcript {
-            transcript_id,
-            x,
-            y,
-            z: if ignore_z_column { 0.0 } else { z },
-            gene: gene as u32,
-            fov,
-        });
+            let fov = if let Some(fov_col) = self.fov_col {
+                match fov_map.get(&row[fov_col]) {
+                    Some(fov) => *fov,
+                    None => {
+                        let next_fov = fov_map.len();
+                        fov_map.insert(row[fov_col].to_string(), next_fov as u32);
+                        fov_names.push(row[fov_col].to_string());
+                        next_fov as u32
+                    }
+                }
+            } else {
+                0
+            };
 
-        qvs.push(qv);
-        fovs.push(fov);
+            let transcript_name = &row[self.transcript_col];
 
-        if let Some(cell_assignment_col) = cell_assignment_col {
-            if row[cell_assignment_col] == cell_assignment_unassigned {
-                nucleus_assignments.push(BACKGROUND_CELL);
-                cell_assignments.push(BACKGROUND_CELL);
-                continue;
-            }
-        };
+            let gene = if let Some(gene) = transcript_name_map.get(transcript_name) {
+                *gene
+            } else {
+                transcript_names.push(transcript_name.to_string());
+                transcript_name_map.insert(transcript_name.to_string(), transcript_names.len() - 1);
+                transcript_names.len() - 1
+            };
 
-        let cell_id_str = &row[cell_id_col];
-        // let overlaps_nucleus = row[overlaps_nucleus_col].parse::<i32>().unwrap();
+            let (x, y) = apply_affine(
+                &self.coord_affine,
+                self.coordinate_scale * row[self.x_col].parse::<f32>().unwrap(),
+                self.coordinate_scale * row[self.y_col].parse::<f32>().unwrap(),
+            );
+            let z = self.z_scale * row[self.z_col].parse::<f32>().unwrap();
+            let row_id = if let Some(id_col) = self.id_col {
+                row[id_col]
+                    .parse::<u64>()
+                    .unwrap_or_else(|_| panic!("Transcript ID must be an integer: {}", &row[id_col]))
+            } else {
+                row_number
+            };
 
-        // Earlier version of Xenium used numeric cell ids and -1 for unassigned.
-        // Newer versions use alphanumeric hash codes and "UNASSIGNED" for unasssigned.
-        if cell_id_str == cell_id_unassigned {
-            nucleus_assignments.push(BACKGROUND_CELL);
-            cell_assignments.push(BACKGROUND_CELL);
-        } else {
-            let next_cell_id = cell_id_map.len() as CellIndex;
-            let cell_id = *cell_id_map
-                .entry((fov, cell_id_str.to_string()))
-                .or_insert_with(|| next_cell_id);
+            let compartment = if let Some(compartment_col) = self.compartment_col {
+                self.compartment_values.classify(&row[compartment_col], &self.compartment_nuclear)
+            } else {
+                Compartment::Unknown
+            };
 
-            let is_nuclear = if let Some(compartment_col) = compartment_col {
-                row[compartment_col] == compartment_nuclear
+            let (nucleus_assignment, cell_assignment) = if self.cell_assignment_col
+                .is_some_and(|cell_assignment_col| is_unassigned_cell_id(&row[cell_assignment_col], &self.cell_assignment_unassigned))
+            {
+                (BACKGROUND_CELL, BACKGROUND_CELL)
             } else {
-                // If we have no compartment information, use anything assigned to the cell.
-                true
+                let cell_id_str = &row[self.cell_id_col];
+                // let overlaps_nucleus = row[overlaps_nucleus_col].parse::<i32>().unwrap();
+
+                // Earlier version of Xenium used numeric cell ids and -1 for unassigned.
+                // Newer versions use alphanumeric hash codes and "UNASSIGNED" for unasssigned.
+                let unassigned = is_unassigned_cell_id(cell_id_str, &self.cell_id_unassigned);
+                cell_id_check.observe(cell_id_str, unassigned);
+                if unassigned {
+                    (BACKGROUND_CELL, BACKGROUND_CELL)
+                } else {
+                    let next_cell_id = cell_id_map.len() as CellIndex;
+                    let cell_id = *cell_id_map
+                        .entry((fov, cell_id_str.to_string()))
+                        .or_insert_with(|| {
+                            cell_keys.push((fov, cell_id_str.to_string()));
+                            next_cell_id
+                        });
+
+                    let is_nuclear = if self.compartment_col.is_some() {
+                        compartment == Compartment::Nuclear
+                    } else {
+                        // If we have no compartment information, use anything assigned to the cell.
+                        true
+                    };
+
+                    if is_nuclear {
+                        (cell_id, cell_id)
+                    } else {
+                        (BACKGROUND_CELL, cell_id)
+                    }
+                }
             };
 
-            if is_nuclear {
-                nucleus_assignments.push(cell_id);
+            // Pre-aggregated rows are expanded into one transcript per count, all
+            // sharing the same position. Each gets its own id, numbered in order,
+            // and the row's id is kept in `source_transcript_ids`.
+            let count = if let Some(count_col) = self.count_col {
+                row[count_col]
+                    .parse::<usize>()
+                    .unwrap_or_else(|_| panic!("Transcript count must be a non-negative integer: {}", &row[count_col]))
             } else {
-                nucleus_assignments.push(BACKGROUND_CELL);
+                1
+            };
+
+            let prior_cell_prob = self.prior_cell_prob_col.map(|col| {
+                let prob = row[col]
+                    .parse::<f32>()
+                    .unwrap_or_else(|_| panic!("Prior cell probability must be a number: {}", &row[col]));
+                if !(0.0..=1.0).contains(&prob) {
+                    panic!("Prior cell probability must be between 0 and 1: {}", prob);
+                }
+                prob
+            });
+
+            for _ in 0..count {
+                let transcript_id = if self.ids_are_indices() { transcripts.len() as u64 } else { row_id };
+                transcripts.push(Transcript {
+                    transcript_id,
+                    x,
+                    y,
+                    z: if self.ignore_z_column { 0.0 } else { z },
+                    gene: gene as u32,
+                    fov,
+                });
+
+                qvs.push(qv);
+                compartments.push(compartment);
+                if let Some(prob) = prior_cell_prob {
+                    prior_cell_probs.push(prob);
+                }
+                if self.count_col.is_some() {
+                    source_transcript_ids.push(row_id);
+                }
+                fovs.push(fov);
+                nucleus_assignments.push(nucleus_assignment);
+                cell_assignments.push(cell_assignment);
             }
-            cell_assignments.push(cell_id);
+        }
+
+        PartialTranscripts {
+            transcripts,
+            transcript_names,
+            fov_names,
+            cell_keys,
+            nucleus_assignments,
+            cell_assignments,
+            qvs,
+            compartments,
+            prior_cell_probs,
+            source_transcript_ids,
+            fovs,
+            cell_id_check,
+            nfiltered_qv,
+            nrows,
         }
     }
 
-    // per-fov zscore normalization of z coordinate.
-    // TODO: make this an option
-    // normalize_z_coord(&mut transcripts, fovs);
+    // Whether transcript ids are numbered in order rather than read from the
+    // id column.
+    fn ids_are_indices(&self) -> bool {
+        self.id_col.is_none() || self.count_col.is_some()
+    }
+}
 
-    // Sort on x for better memory locality (This doesn't actually seem to make any difference)
-    // let mut ord = (0..transcripts.len())
-    //     .collect::<Vec<_>>();
-    // ord.sort_unstable_by(|&i, &j| transcripts[i].x.partial_cmp(&transcripts[j].x).unwrap());
-    // let transcripts = ord.iter().map(|&i| transcripts[i]).collect::<Vec<_>>();
-    // let mut cell_assignments = ord.iter().map(|&i| cell_assignments[i]).collect::<Vec<_>>();
+impl PartialTranscripts {
+    fn finish(self, cell_id_column: &str, cell_id_unassigned: &str) -> TranscriptDataset {
+        // per-fov zscore normalization of z coordinate.
+        // TODO: make this an option
+        // normalize_z_coord(&mut transcripts, fovs);
 
-    let mut fov_names = vec![String::new(); fov_map.len().max(1)];
-    if fov_map.is_empty() {
-        fov_names[0] = String::from("0");
-    } else {
-        for (fov_name, fov) in fov_map {
-            fov_names[fov as usize] = fov_name;
+        // Sort on x for better memory locality (This doesn't actually seem to make any difference)
+        // let mut ord = (0..transcripts.len())
+        //     .collect::<Vec<_>>();
+        // ord.sort_unstable_by(|&i, &j| transcripts[i].x.partial_cmp(&transcripts[j].x).unwrap());
+        // let transcripts = ord.iter().map(|&i| transcripts[i]).collect::<Vec<_>>();
+        // let mut cell_assignments = ord.iter().map(|&i| cell_assignments[i]).collect::<Vec<_>>();
+
+        self.cell_id_check.report(cell_id_column, cell_id_unassigned);
+
+        let fov_names = if self.fov_names.is_empty() {
+            vec![String::from("0")]
+        } else {
+            self.fov_names
+        };
+
+        let mut nucleus_assignments = self.nucleus_assignments;
+        let mut cell_assignments = self.cell_assignments;
+        let original_cell_assignments = cell_assignments.clone();
+        let nucleus_population =
+            postprocess_cell_assignments(&mut nucleus_assignments, &mut cell_assignments);
+
+        TranscriptDataset {
+            transcript_names: self.transcript_names,
+            transcripts: self.transcripts,
+            nucleus_assignments,
+            cell_assignments,
+            nucleus_population,
+            qvs: self.qvs,
+            compartments: self.compartments,
+            prior_cell_probs: self.prior_cell_probs,
+            source_transcript_ids: self.source_transcript_ids,
+            original_cell_ids: self.cell_keys,
+            original_cell_assignments,
+            fovs: self.fovs,
+            fov_names,
+            nfiltered_qv: self.nfiltered_qv,
         }
     }
+}
 
-    let nucleus_population =
-        postprocess_cell_assignments(&mut nucleus_assignments, &mut cell_assignments);
+// Combine chunks parsed in file order, renumbering ids to match what a single
+// pass over the whole file would have assigned.
+fn merge_partial_transcripts(
+    mut parts: Vec<PartialTranscripts>,
+    ids_are_indices: bool,
+    has_id_col: bool,
+) -> PartialTranscripts {
+    let mut transcript_name_map: HashMap<String, u32> = HashMap::new();
+    let mut transcript_names = Vec::new();
+    let mut fov_map: HashMap<String, u32> = HashMap::new();
+    let mut fov_names = Vec::new();
+    let mut cell_id_map: HashMap<(u32, String), CellIndex> = HashMap::new();
+    let mut cell_keys = Vec::new();
+    let mut cell_id_check = CellIdUnassignedCheck::new();
+
+    let mut nfiltered_qv = 0;
+    let mut remaps = Vec::with_capacity(parts.len());
+    let mut offset = 0;
+    let mut row_offset = 0;
+    for part in &parts {
+        let gene_remap = part
+            .transcript_names
+            .iter()
+            .map(|name| {
+                *transcript_name_map.entry(name.clone()).or_insert_with(|| {
+                    transcript_names.push(name.clone());
+                    transcript_names.len() as u32 - 1
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let fov_remap = part
+            .fov_names
+            .iter()
+            .map(|name| {
+                *fov_map.entry(name.clone()).or_insert_with(|| {
+                    fov_names.push(name.clone());
+                    fov_names.len() as u32 - 1
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let cell_remap = part
+            .cell_keys
+            .iter()
+            .map(|(fov, cell_id)| {
+                // without a fov column every fov is 0 and there's nothing to remap
+                let fov = fov_remap.get(*fov as usize).cloned().unwrap_or(*fov);
+                let key = (fov, cell_id.clone());
+                let next_cell_id = cell_id_map.len() as CellIndex;
+                *cell_id_map.entry(key.clone()).or_insert_with(|| {
+                    cell_keys.push(key);
+                    next_cell_id
+                })
+            })
+            .collect::<Vec<_>>();
+
+        cell_id_check.nrows += part.cell_id_check.nrows;
+        cell_id_check.nunassigned += part.cell_id_check.nunassigned;
+        for example in &part.cell_id_check.examples {
+            if cell_id_check.examples.len() < CellIdUnassignedCheck::MAX_EXAMPLES
+                && !cell_id_check.examples.contains(example)
+            {
+                cell_id_check.examples.push(example.clone());
+            }
+        }
 
-    TranscriptDataset {
+        nfiltered_qv += part.nfiltered_qv;
+        remaps.push((gene_remap, fov_remap, cell_remap, offset, row_offset));
+        offset += part.transcripts.len() as u64;
+        row_offset += part.nrows;
+    }
+
+    parts
+        .par_iter_mut()
+        .zip(remaps.par_iter())
+        .for_each(|(part, (gene_remap, fov_remap, cell_remap, offset, row_offset))| {
+            let remap_fov = |fov: u32| fov_remap.get(fov as usize).cloned().unwrap_or(fov);
+            for t in part.transcripts.iter_mut() {
+                t.gene = gene_remap[t.gene as usize];
+                t.fov = remap_fov(t.fov);
+                if ids_are_indices {
+                    t.transcript_id += offset;
+                }
+            }
+            if !has_id_col {
+                for id in part.source_transcript_ids.iter_mut() {
+                    *id += row_offset;
+                }
+            }
+            for fov in part.fovs.iter_mut() {
+                *fov = remap_fov(*fov);
+            }
+            for cell_id in part
+                .nucleus_assignments
+                .iter_mut()
+                .chain(part.cell_assignments.iter_mut())
+            {
+                if *cell_id != BACKGROUND_CELL {
+                    *cell_id = cell_remap[*cell_id as usize];
+                }
+            }
+        });
+
+    let mut merged = PartialTranscripts {
+        transcripts: Vec::with_capacity(offset as usize),
         transcript_names,
-        transcripts,
-        nucleus_assignments,
-        cell_assignments,
-        nucleus_population,
-        qvs,
-        fovs,
         fov_names,
+        cell_keys,
+        nucleus_assignments: Vec::with_capacity(offset as usize),
+        cell_assignments: Vec::with_capacity(offset as usize),
+        qvs: Vec::with_capacity(offset as usize),
+        compartments: Vec::with_capacity(offset as usize),
+        prior_cell_probs: Vec::new(),
+        source_transcript_ids: Vec::new(),
+        fovs: Vec::with_capacity(offset as usize),
+        cell_id_check,
+        nfiltered_qv,
+        nrows: row_offset,
+    };
+    for part in parts {
+        merged.transcripts.extend(part.transcripts);
+        merged.nucleus_assignments.extend(part.nucleus_assignments);
+        merged.cell_assignments.extend(part.cell_assignments);
+        merged.qvs.extend(part.qvs);
+        merged.compartments.extend(part.compartments);
+        merged.prior_cell_probs.extend(part.prior_cell_probs);
+        merged.source_transcript_ids.extend(part.source_transcript_ids);
+        merged.fovs.extend(part.fovs);
     }
+
+    merged
+}
+
+// Files smaller than this per thread aren't worth splitting.
+const MIN_CSV_CHUNK_BYTES: u64 = 64 << 20;
+
+// Byte offset of the first line starting at or after `pos`.
+fn next_line_start(file: &mut BufReader<File>, pos: u64) -> u64 {
+    file.seek(SeekFrom::Start(pos - 1)).unwrap();
+    let mut line = Vec::new();
+    let nread = file.read_until(b'\n', &mut line).unwrap();
+    pos - 1 + nread as u64
 }
 
+// Parse an uncompressed csv in parallel by splitting the rows after the header
+// (which ends at byte `start`) into chunks at line boundaries. This assumes
+// quoted fields don't contain newlines.
+fn parse_csv_chunks(
+    path: &str,
+    start: u64,
+    nchunks: usize,
+    columns: &TranscriptCsvColumns,
+) -> PartialTranscripts {
+    let len = std::fs::metadata(path).unwrap().len();
+    let mut file = BufReader::new(File::open(path).unwrap());
+    let mut bounds = vec![start];
+    for i in 1..nchunks {
+        let target = start + (len - start) * i as u64 / nchunks as u64;
+        let prev = *bounds.last().unwrap();
+        bounds.push(if target <= prev { prev } else { next_line_start(&mut file, target) });
+    }
+    bounds.push(len);
+
+    let parts = bounds
+        .par_windows(2)
+        .map(|bound| {
+            let mut file = File::open(path).unwrap();
+            file.seek(SeekFrom::Start(bound[0])).unwrap();
+            let rdr = csv::ReaderBuilder::new()
+                .has_headers(false)
+                .from_reader(BufReader::new(file.take(bound[1] - bound[0])));
+            columns.parse(rdr.into_records())
+        })
+        .collect::<Vec<_>>();
+
+    merge_partial_transcripts(parts, columns.ids_are_indices(), columns.id_col.is_some())
+}
 
 #[allow(clippy::too_many_arguments)]
 fn read_xenium_transcripts_parquet(
@@ -394,14 +1066,16 @@ fn read_xenium_transcripts_parquet(
     min_qv: f32,
     ignore_z_column: bool,
     coordinate_scale: f32,
+    z_scale: f32,
+    coord_affine: [f32; 6],
 ) -> TranscriptDataset
 {
-    let input_file = File::open(&filename).expect(&format!("Unable to open '{}'.", &filename));
+    let input_file = File::open(filename).unwrap_or_else(|_| panic!("Unable to open '{}'.", &filename));
     let builder = ParquetRecordBatchReaderBuilder::try_new(input_file)
         .unwrap();
     let schema = builder.schema().as_ref().clone();
     let rdr = builder.build()
-        .expect(&format!("Unable to read parquet data from frobm {}", filename));
+        .unwrap_or_else(|_| panic!("Unable to read parquet data from frobm {}", filename));
 
     let transcript_col_idx = schema.index_of(transcript_col_name).unwrap();
     let id_col_idx = schema.index_of(id_col_name).unwrap();
@@ -419,10 +1093,14 @@ fn read_xenium_transcripts_parquet(
     let mut nucleus_assignments = Vec::new();
     let mut cell_assignments = Vec::new();
     let mut qvs = Vec::new();
+    let mut compartments = Vec::new();
     let mut fovs = Vec::new();
 
     let mut fov_map: HashMap<String, u32> = HashMap::new();
     let mut cell_id_map: HashMap<(u32, String), CellIndex> = HashMap::new();
+    let mut cell_keys = Vec::new();
+    let mut cell_id_check = CellIdUnassignedCheck::new();
+    let mut nfiltered_qv = 0;
 
     for rec_batch in rdr {
         let rec_batch = rec_batch.expect("Unable to read record batch.");
@@ -495,6 +1173,7 @@ fn read_xenium_transcripts_parquet(
             let qv = qv.unwrap();
 
             if qv < min_qv {
+                nfiltered_qv += 1;
                 continue;
             }
 
@@ -515,8 +1194,8 @@ fn read_xenium_transcripts_parquet(
                 transcript_names.len() - 1
             };
 
-            let x = coordinate_scale * x;
-            let y = coordinate_scale * y;
+            let (x, y) = apply_affine(&coord_affine, coordinate_scale * x, coordinate_scale * y);
+            let z = z_scale * z;
 
             transcripts.push(Transcript {
                 transcript_id,
@@ -529,15 +1208,25 @@ fn read_xenium_transcripts_parquet(
 
             qvs.push(qv);
             fovs.push(fov);
+            compartments.push(if compartment == compartment_nuclear {
+                Compartment::Nuclear
+            } else {
+                Compartment::Unknown
+            });
 
-            if cell_id == cell_id_unassigned {
+            let unassigned = is_unassigned_cell_id(cell_id, cell_id_unassigned);
+            cell_id_check.observe(cell_id, unassigned);
+            if unassigned {
                 nucleus_assignments.push(BACKGROUND_CELL);
                 cell_assignments.push(BACKGROUND_CELL);
             } else {
                 let next_cell_id = cell_id_map.len() as CellIndex;
                 let cell_id = *cell_id_map
                     .entry((fov, cell_id.to_string()))
-                    .or_insert_with(|| next_cell_id);
+                    .or_insert_with(|| {
+                        cell_keys.push((fov, cell_id.to_string()));
+                        next_cell_id
+                    });
 
                 let is_nuclear = compartment == compartment_nuclear;
 
@@ -551,6 +1240,8 @@ fn read_xenium_transcripts_parquet(
         }
     }
 
+    cell_id_check.report(cell_id_col_name, cell_id_unassigned);
+
     let mut fov_names = vec![String::new(); fov_map.len().max(1)];
     if fov_map.is_empty() {
         fov_names[0] = String::from("0");
@@ -560,6 +1251,7 @@ fn read_xenium_transcripts_parquet(
         }
     }
 
+    let original_cell_assignments = cell_assignments.clone();
     let nucleus_population =
         postprocess_cell_assignments(&mut nucleus_assignments, &mut cell_assignments);
 
@@ -570,8 +1262,14 @@ fn read_xenium_transcripts_parquet(
         cell_assignments,
         nucleus_population,
         qvs,
+        compartments,
+        prior_cell_probs: Vec::new(),
+        source_transcript_ids: Vec::new(),
+        original_cell_ids: cell_keys,
+        original_cell_assignments,
         fovs,
         fov_names,
+        nfiltered_qv,
     }
 }
 
@@ -606,13 +1304,13 @@ fn read_xenium_transcripts_parquet(
 //     }
 // }
 
-pub fn coordinate_span(transcripts: &Vec<Transcript>) -> (f32, f32, f32, f32, f32, f32) {
-    let mut min_x = std::f32::MAX;
-    let mut max_x = std::f32::MIN;
-    let mut min_y = std::f32::MAX;
-    let mut max_y = std::f32::MIN;
-    let mut min_z = std::f32::MAX;
-    let mut max_z = std::f32::MIN;
+pub fn coordinate_span(transcripts: &[Transcript]) -> (f32, f32, f32, f32, f32, f32) {
+    let mut min_x = f32::MAX;
+    let mut max_x = f32::MIN;
+    let mut min_y = f32::MAX;
+    let mut max_y = f32::MIN;
+    let mut min_z = f32::MAX;
+    let mut max_z = f32::MIN;
 
     for t in transcripts {
         min_x = min_x.min(t.x);
@@ -626,8 +1324,109 @@ pub fn coordinate_span(transcripts: &Vec<Transcript>) -> (f32, f32, f32, f32, f3
     (min_x, max_x, min_y, max_y, min_z, max_z)
 }
 
+// Typical distance from a transcript to its nearest neighbor, estimated from an
+// evenly spaced sample and rescaled to the full density, since nearest neighbor
+// distance goes roughly as one over the square root of density.
+pub fn estimate_transcript_spacing(transcripts: &[Transcript]) -> Option<f32> {
+    const MAX_SAMPLE: usize = 20000;
+    let stride = transcripts.len().div_ceil(MAX_SAMPLE).max(1);
+    let sample = transcripts.iter().step_by(stride).collect::<Vec<_>>();
+    if sample.len() < 2 {
+        return None;
+    }
+
+    let mut kdtree: KdTree<f32, u32, 2, 32, u32> = KdTree::with_capacity(sample.len());
+    for (i, t) in sample.iter().enumerate() {
+        kdtree.add(&[t.x, t.y], i as u32);
+    }
+
+    let mut distances = sample
+        .iter()
+        .filter_map(|t| {
+            let d = kdtree.nearest_n::<SquaredEuclidean>(&[t.x, t.y], 2)[1].distance.sqrt();
+            // duplicate positions (e.g. expanded counts) tell us nothing about units
+            if d > 0.0 { Some(d) } else { None }
+        })
+        .collect::<Vec<_>>();
+    if distances.is_empty() {
+        return None;
+    }
+    distances.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let median = distances[distances.len() / 2];
+
+    Some(median * (sample.len() as f32 / transcripts.len() as f32).sqrt())
+}
+
+// Coordinates are assumed to be in microns, but pixel or millimeter coordinates
+// are easy to pass in by mistake and lead to hopeless runs. Warn (but carry on)
+// when the data looks implausible in microns.
+pub fn check_coordinate_units(transcripts: &[Transcript]) {
+    if transcripts.is_empty() {
+        return;
+    }
+
+    let (xmin, xmax, ymin, ymax, _, _) = coordinate_span(transcripts);
+    let span = (xmax - xmin).max(ymax - ymin);
+    let spacing = estimate_transcript_spacing(transcripts);
+
+    let mut problems = Vec::new();
+    if span > 1e5 {
+        problems.push(format!("the coordinates span {:.0} units, over 10cm if these are microns", span));
+    } else if span < 1.0 {
+        problems.push(format!("the coordinates span only {} units, under 1µm if these are microns", span));
+    }
+    if let Some(spacing) = spacing {
+        if spacing > 20.0 {
+            problems.push(format!("transcripts are typically {:.1} units apart, which is very sparse for microns", spacing));
+        } else if spacing < 0.01 {
+            problems.push(format!("transcripts are typically {} units apart, which is very dense for microns", spacing));
+        }
+    }
+
+    if !problems.is_empty() {
+        eprintln!();
+        eprintln!("**********************************************************************");
+        eprintln!("Warning: transcript coordinates may not be in microns: {}.", problems.join("; "));
+        eprintln!("If coordinates are in pixels or another unit, set --coordinate-scale to");
+        eprintln!("the number of microns per unit.");
+        eprintln!("**********************************************************************");
+        eprintln!();
+    }
+}
+
+// Tissue sections are thin, so a z span (after clamping) larger than the xy
+// span and over 100 units almost certainly means z isn't in microns, e.g.
+// nanometers. Warn, and return the power of ten that would bring the span
+// under 100.
+pub fn check_z_span(transcripts: &[Transcript], zmin: f32, zmax: f32) -> Option<f32> {
+    if transcripts.is_empty() {
+        return None;
+    }
+
+    let (xmin, xmax, ymin, ymax, _, _) = coordinate_span(transcripts);
+    let xyspan = (xmax - xmin).max(ymax - ymin);
+    let zspan = zmax - zmin;
+    if zspan <= 100.0 || zspan <= xyspan {
+        return None;
+    }
+
+    let factor = 10.0_f32.powi(-(zspan / 100.0).log10().ceil() as i32);
+    eprintln!();
+    eprintln!("**********************************************************************");
+    eprintln!(
+        "Warning: z-coordinates span {:.0} units, more than the {:.0} units spanned in x and y.",
+        zspan, xyspan
+    );
+    eprintln!("They may not be in microns. If z is in another unit, set --z-scale to");
+    eprintln!("the number of microns per unit.");
+    eprintln!("**********************************************************************");
+    eprintln!();
+
+    Some(factor)
+}
+
 // Estimate what region of the slide to model by counting the number of occupied bins.
-pub fn estimate_full_area(transcripts: &Vec<Transcript>, mean_nucleus_area: f32) -> f32 {
+pub fn estimate_full_area(transcripts: &[Transcript], mean_nucleus_area: f32) -> f32 {
     let (xmin, xmax, ymin, ymax, _, _) = coordinate_span(transcripts);
 
     const SCALE: f32 = 2.0;
@@ -692,6 +1491,100 @@ pub fn estimate_cell_centroids(
     centroids
 }
 
+// Read the assignments from a prior run's transcript metadata table, keyed by
+// transcript id, as (assignment, probability, observed x, observed y). The
+// position is kept so transcripts can be matched up.
+pub fn read_prior_transcript_assignments(path: &str) -> HashMap<u64, (CellIndex, f32, f32, f32)> {
+    let mut prior = HashMap::new();
+
+    let fmt = infer_format_from_filename(path);
+    match fmt {
+        OutputFormat::Csv => {
+            let mut rdr = csv::Reader::from_path(path).unwrap();
+            read_prior_transcript_assignments_csv(&mut rdr, &mut prior);
+        }
+        OutputFormat::CsvGz | OutputFormat::CsvZst => {
+            let mut rdr = csv::Reader::from_reader(decompressed_reader(path, fmt));
+            read_prior_transcript_assignments_csv(&mut rdr, &mut prior);
+        }
+        OutputFormat::Parquet => {
+            let input_file = File::open(path).unwrap_or_else(|_| panic!("Unable to open '{}'.", &path));
+            let builder = ParquetRecordBatchReaderBuilder::try_new(input_file).unwrap();
+            let schema = builder.schema().as_ref().clone();
+            let rdr = builder.build()
+                .unwrap_or_else(|_| panic!("Unable to read parquet data from {}", path));
+
+            let id_col_idx = schema.index_of("transcript_id").unwrap();
+            let assignment_col_idx = schema.index_of("assignment").unwrap();
+            let probability_col_idx = schema.index_of("probability").unwrap();
+            let x_col_idx = schema.index_of("observed_x").unwrap();
+            let y_col_idx = schema.index_of("observed_y").unwrap();
+
+            for rec_batch in rdr {
+                let rec_batch = rec_batch.expect("Unable to read record batch.");
+                let id_col = rec_batch.column(id_col_idx).as_any()
+                    .downcast_ref::<arrow::array::UInt64Array>().unwrap();
+                let assignment_col = rec_batch.column(assignment_col_idx).as_any()
+                    .downcast_ref::<arrow::array::UInt32Array>().unwrap();
+                let probability_col = rec_batch.column(probability_col_idx).as_any()
+                    .downcast_ref::<arrow::array::Float32Array>().unwrap();
+                let x_col = rec_batch.column(x_col_idx).as_any()
+                    .downcast_ref::<arrow::array::Float32Array>().unwrap();
+                let y_col = rec_batch.column(y_col_idx).as_any()
+                    .downcast_ref::<arrow::array::Float32Array>().unwrap();
+
+                for (id, assignment, probability, x, y) in
+                    izip!(id_col, assignment_col, probability_col, x_col, y_col)
+                {
+                    prior.insert(
+                        id.unwrap(),
+                        (assignment.unwrap(), probability.unwrap(), x.unwrap(), y.unwrap()),
+                    );
+                }
+            }
+        }
+        OutputFormat::Infer => panic!("Could not infer format of file '{}'", path),
+    }
+
+    prior
+}
+
+fn read_prior_transcript_assignments_csv<T>(
+    rdr: &mut csv::Reader<T>,
+    prior: &mut HashMap<u64, (CellIndex, f32, f32, f32)>,
+) where
+    T: std::io::Read,
+{
+    let headers = rdr.headers().unwrap();
+    let id_col = find_column(headers, "transcript_id");
+    let assignment_col = find_column(headers, "assignment");
+    let probability_col = find_column(headers, "probability");
+    let x_col = find_column(headers, "observed_x");
+    let y_col = find_column(headers, "observed_y");
+
+    for result in rdr.records() {
+        let row = result.unwrap();
+        prior.insert(
+            row[id_col].parse::<u64>().unwrap(),
+            (
+                row[assignment_col].parse::<CellIndex>().unwrap_or_else(|_| {
+                    panic!(
+                        "Cell assignment must be an integer: {} (was the run written with --cell-id-prefix?)",
+                        &row[assignment_col]
+                    )
+                }),
+                row[probability_col].parse::<f32>().unwrap(),
+                row[x_col].parse::<f32>().unwrap(),
+                row[y_col].parse::<f32>().unwrap(),
+            ),
+        );
+    }
+}
+
+// Remove transcripts further than `max_distance` from any nucleus centroid.
+// Returns the distance to the nearest nucleus centroid for each remaining
+// transcript. With `NucleusDistanceMetric::Xyz` the z offset counts too, so
+// z should already be in the same units as x and y.
 pub fn filter_cellfree_transcripts(
     // transcripts: &[Transcript],
     // nucleus_assignments: &[CellIndex],
@@ -699,26 +1592,46 @@ pub fn filter_cellfree_transcripts(
     dataset: &mut TranscriptDataset,
     ncells: usize,
     max_distance: f32,
-) {
+    metric: NucleusDistanceMetric,
+) -> Vec<f32> {
     let max_distance_squared = max_distance * max_distance;
 
     let centroids = estimate_cell_centroids(
         &dataset.transcripts, &dataset.nucleus_assignments, ncells);
 
-    let mut kdtree: KdTree<f32, u32, 2, 32, u32> = KdTree::with_capacity(centroids.len());
-    for (i, (x, y)) in centroids.iter().enumerate() {
+    // z is flattened to zero for plain xy distances
+    let use_z = metric == NucleusDistanceMetric::Xyz;
+    let mut centroid_zs = vec![0.0; ncells];
+    if use_z {
+        let mut populations = vec![0; ncells];
+        for (t, &cell) in dataset.transcripts.iter().zip(&dataset.nucleus_assignments) {
+            if cell != BACKGROUND_CELL {
+                centroid_zs[cell as usize] += t.z;
+                populations[cell as usize] += 1;
+            }
+        }
+        for (z, &population) in centroid_zs.iter_mut().zip(&populations) {
+            *z /= population.max(1) as f32;
+        }
+    }
+
+    let mut kdtree: KdTree<f32, u32, 3, 32, u32> = KdTree::with_capacity(centroids.len());
+    for (i, ((x, y), z)) in centroids.iter().zip(&centroid_zs).enumerate() {
         if !x.is_finite() || !y.is_finite() {
             continue;
         }
-        kdtree.add(&[*x, *y], i as u32);
+        kdtree.add(&[*x, *y, *z], i as u32);
     }
 
     let mut mask = vec![false; dataset.transcripts.len()];
+    let mut distances = Vec::with_capacity(dataset.transcripts.len());
     for (i, t) in dataset.transcripts.iter().enumerate() {
-        let d = kdtree.nearest_one::<SquaredEuclidean>(&[t.x, t.y]).distance;
+        let z = if use_z { t.z } else { 0.0 };
+        let d = kdtree.nearest_one::<SquaredEuclidean>(&[t.x, t.y, z]).distance;
 
         if d <= max_distance_squared {
             mask[i] = true;
+            distances.push(d.sqrt());
         }
     }
 
@@ -766,4 +1679,320 @@ pub fn filter_cellfree_transcripts(
             .map(|(t, _)| t)
             .cloned()
             .collect::<Vec<_>>());
+
+    dataset.compartments.clone_from(
+        &dataset.compartments
+            .iter()
+            .zip(mask.iter())
+            .filter(|(_, &m)| m)
+            .map(|(t, _)| t)
+            .cloned()
+            .collect::<Vec<_>>());
+
+    dataset.original_cell_assignments.clone_from(
+        &dataset.original_cell_assignments
+            .iter()
+            .zip(mask.iter())
+            .filter(|(_, &m)| m)
+            .map(|(t, _)| t)
+            .cloned()
+            .collect::<Vec<_>>());
+
+    dataset.prior_cell_probs.clone_from(
+        &dataset.prior_cell_probs
+            .iter()
+            .zip(mask.iter())
+            .filter(|(_, &m)| m)
+            .map(|(t, _)| t)
+            .cloned()
+            .collect::<Vec<_>>());
+
+    dataset.source_transcript_ids.clone_from(
+        &dataset.source_transcript_ids
+            .iter()
+            .zip(mask.iter())
+            .filter(|(_, &m)| m)
+            .map(|(t, _)| t)
+            .cloned()
+            .collect::<Vec<_>>());
+
+    distances
+}
+
+// Trim whitespace from gene names, and optionally uppercase them. Genes whose
+// names then coincide are merged, given distinct names by suffixing them
+// (GENE.1, GENE.2, ...), or rejected, according to `duplicates`. Returns the
+// normalized name and original names of each set of coinciding genes.
+pub fn normalize_gene_names(
+    dataset: &mut TranscriptDataset,
+    uppercase: bool,
+    duplicates: DuplicateGenes,
+) -> Vec<(String, Vec<String>)> {
+    let mut gene_map = Vec::with_capacity(dataset.transcript_names.len());
+    let mut transcript_name_map: HashMap<String, usize> = HashMap::new();
+    let mut transcript_names = Vec::new();
+    let mut original_names: Vec<Vec<String>> = Vec::new();
+    for name in &dataset.transcript_names {
+        let normalized = if uppercase {
+            name.trim().to_uppercase()
+        } else {
+            name.trim().to_string()
+        };
+
+        let gene = *transcript_name_map.entry(normalized.clone()).or_insert_with(|| {
+            transcript_names.push(normalized);
+            original_names.push(Vec::new());
+            transcript_names.len() - 1
+        });
+        original_names[gene].push(name.clone());
+        gene_map.push(gene as u32);
+    }
+
+    let duplicated: Vec<(String, Vec<String>)> = transcript_names
+        .iter()
+        .zip(&original_names)
+        .filter(|(_, names)| names.len() > 1)
+        .map(|(name, names)| (name.clone(), names.clone()))
+        .collect();
+
+    match duplicates {
+        DuplicateGenes::Merge => {
+            for t in &mut dataset.transcripts {
+                t.gene = gene_map[t.gene as usize];
+            }
+            dataset.transcript_names = transcript_names;
+        }
+        DuplicateGenes::Suffix => {
+            // every gene stays distinct, so only the names change
+            let mut seen = vec![0; transcript_names.len()];
+            let mut used: HashSet<String> = transcript_names.iter().cloned().collect();
+            for (name, &gene) in dataset.transcript_names.iter_mut().zip(&gene_map) {
+                let gene = gene as usize;
+                *name = if original_names[gene].len() > 1 {
+                    loop {
+                        seen[gene] += 1;
+                        let suffixed = format!("{}.{}", transcript_names[gene], seen[gene]);
+                        if used.insert(suffixed.clone()) {
+                            break suffixed;
+                        }
+                    }
+                } else {
+                    transcript_names[gene].clone()
+                };
+            }
+        }
+        DuplicateGenes::Error => {
+            if !duplicated.is_empty() {
+                panic!(
+                    "Distinct genes have the same normalized name: {}. Use --duplicate-genes merge or suffix.",
+                    duplicated
+                        .iter()
+                        .map(|(name, names)| format!(
+                            "{} ({})",
+                            name,
+                            names.iter().map(|n| format!("'{}'", n)).collect::<Vec<_>>().join(", ")
+                        ))
+                        .collect::<Vec<_>>()
+                        .join("; ")
+                );
+            }
+            dataset.transcript_names = transcript_names;
+        }
+    }
+
+    duplicated
+}
+
+// Remove genes that have no transcripts left after filtering, renumbering the
+// remaining genes. Returns the names of the removed genes.
+pub fn filter_absent_genes(dataset: &mut TranscriptDataset) -> Vec<String> {
+    let ngenes = dataset.transcript_names.len();
+    let mut gene_counts = vec![0; ngenes];
+    for t in &dataset.transcripts {
+        gene_counts[t.gene as usize] += 1;
+    }
+
+    let mut gene_map = vec![u32::MAX; ngenes];
+    let mut transcript_names = Vec::new();
+    let mut removed_names = Vec::new();
+    for (i, (name, &count)) in dataset.transcript_names.iter().zip(gene_counts.iter()).enumerate() {
+        if count > 0 {
+            gene_map[i] = transcript_names.len() as u32;
+            transcript_names.push(name.clone());
+        } else {
+            removed_names.push(name.clone());
+        }
+    }
+
+    if removed_names.is_empty() {
+        return removed_names;
+    }
+
+    for t in &mut dataset.transcripts {
+        t.gene = gene_map[t.gene as usize];
+    }
+    dataset.transcript_names = transcript_names;
+
+    removed_names
+}
+
+#[test]
+fn unassigned_cell_id_numeric_variants() {
+    assert!(is_unassigned_cell_id("0", "0"));
+    assert!(is_unassigned_cell_id("0.0", "0"));
+    assert!(is_unassigned_cell_id("00", "0"));
+    assert!(is_unassigned_cell_id(" 0", "0"));
+    assert!(is_unassigned_cell_id("-1.0", "-1"));
+    assert!(is_unassigned_cell_id("UNASSIGNED", "UNASSIGNED"));
+
+    assert!(!is_unassigned_cell_id("007", "0"));
+    assert!(!is_unassigned_cell_id("10", "0"));
+    assert!(!is_unassigned_cell_id("NA", "0"));
+    assert!(!is_unassigned_cell_id("aaabbbcc-1", "UNASSIGNED"));
+}
+
+#[test]
+fn parallel_csv_parse_matches_serial() {
+    let path = std::env::temp_dir().join(format!("proseg-parallel-csv-{}.csv", std::process::id()));
+    let mut csv = String::from("transcript_id,feature_name,x_location,y_location,z_location,overlaps_nucleus,cell_id,qv,fov_name,count\n");
+    for i in 0..500 {
+        let cell_id = if i % 7 == 0 { String::from("UNASSIGNED") } else { format!("c{}", (i * 13) % 37) };
+        csv.push_str(&format!(
+            "{},G{},{},{},{},{},{},{},fov{},{}\n",
+            i,
+            (i * 31) % 17,
+            (i % 23) as f32 * 1.5,
+            (i % 19) as f32 * 2.5,
+            i % 3,
+            i % 2,
+            cell_id,
+            10 + (i % 30),
+            (i / 120) % 3,
+            1 + i % 3,
+        ));
+    }
+    std::fs::write(&path, csv).unwrap();
+    let path = path.to_str().unwrap();
+
+    let read = |nchunks: usize, with_id: bool| {
+        let mut rdr = csv::Reader::from_path(path).unwrap();
+        let columns = TranscriptCsvColumns::new(
+            rdr.headers().unwrap(),
+            "feature_name",
+            if with_id { Some(String::from("transcript_id")) } else { None },
+            Some(String::from("overlaps_nucleus")),
+            Some(String::from("1")),
+            CompartmentValues::default(),
+            Some(String::from("fov_name")),
+            None,
+            None,
+            "cell_id",
+            "UNASSIGNED",
+            Some(String::from("qv")),
+            Some(String::from("count")),
+            None,
+            "x_location",
+            "y_location",
+            "z_location",
+            20.0,
+            false,
+            1.0,
+            1.0,
+            [1.0, 0.0, 0.0, 0.0, 1.0, 0.0],
+        );
+        let parts = if nchunks > 1 {
+            parse_csv_chunks(path, rdr.position().byte(), nchunks, &columns)
+        } else {
+            columns.parse(rdr.records())
+        };
+        parts.finish("cell_id", "UNASSIGNED")
+    };
+
+    for with_id in [false, true] {
+        let serial = read(1, with_id);
+        for nchunks in [2, 3, 7, 64] {
+            let parallel = read(nchunks, with_id);
+            assert_eq!(serial.transcript_names, parallel.transcript_names);
+            assert_eq!(serial.transcripts, parallel.transcripts);
+            assert_eq!(serial.nucleus_assignments, parallel.nucleus_assignments);
+            assert_eq!(serial.cell_assignments, parallel.cell_assignments);
+            assert_eq!(serial.nucleus_population, parallel.nucleus_population);
+            assert_eq!(serial.qvs, parallel.qvs);
+            assert_eq!(serial.compartments, parallel.compartments);
+            assert_eq!(serial.fovs, parallel.fovs);
+            assert_eq!(serial.fov_names, parallel.fov_names);
+            assert_eq!(serial.source_transcript_ids, parallel.source_transcript_ids);
+        }
+    }
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn compartment_values_classify() {
+    let values = CompartmentValues {
+        cytoplasm: Some(String::from("Cytoplasm")),
+        membrane: Some(String::from("Membrane")),
+        extracellular: Some(String::from("None")),
+    };
+    assert_eq!(values.classify("Nuclear", "Nuclear"), Compartment::Nuclear);
+    assert_eq!(values.classify("Cytoplasm", "Nuclear"), Compartment::Cytoplasm);
+    assert_eq!(values.classify("Membrane", "Nuclear"), Compartment::Membrane);
+    assert_eq!(values.classify("None", "Nuclear"), Compartment::Extracellular);
+    assert_eq!(values.classify("0", "Nuclear"), Compartment::Unknown);
+    assert_eq!(CompartmentValues::default().classify("Membrane", "Nuclear"), Compartment::Unknown);
+}
+
+#[test]
+fn mapped_transcripts_roundtrip() {
+    let path = std::env::temp_dir().join(format!("proseg-mapped-{}.bin", std::process::id()));
+    let path = path.to_str().unwrap();
+    let transcripts = (0..100)
+        .map(|i| Transcript { transcript_id: i, x: i as f32, y: -(i as f32), z: 0.5, gene: i as u32 % 7, fov: 3 })
+        .collect::<Vec<_>>();
+    let mapped = MappedTranscripts::new(path, transcripts.clone());
+    assert_eq!(mapped.as_slice(), &transcripts[..]);
+    drop(mapped);
+    assert!(!std::path::Path::new(path).exists());
+}
+
+#[test]
+fn apply_affine_maps_xy() {
+    let identity = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0];
+    assert_eq!(apply_affine(&identity, 3.0, 4.0), (3.0, 4.0));
+    // rotate a quarter turn and shift
+    let rotate = [0.0, -1.0, 10.0, 1.0, 0.0, 20.0];
+    assert_eq!(apply_affine(&rotate, 3.0, 4.0), (6.0, 23.0));
+}
+
+#[test]
+fn nucleus_distance_metric_counts_z_offset() {
+    let transcript = |transcript_id, z| Transcript { transcript_id, x: 0.0, y: 0.0, z, gene: 0, fov: 0 };
+    let dataset = || TranscriptDataset {
+        transcript_names: vec![String::from("G")],
+        transcripts: vec![transcript(0, 0.0), transcript(1, 0.0), transcript(2, 50.0)],
+        nucleus_assignments: vec![0, 0, BACKGROUND_CELL],
+        cell_assignments: vec![0, 0, BACKGROUND_CELL],
+        nucleus_population: vec![2],
+        fovs: vec![0; 3],
+        qvs: vec![0.0; 3],
+        compartments: vec![Compartment::Unknown; 3],
+        prior_cell_probs: Vec::new(),
+        source_transcript_ids: Vec::new(),
+        original_cell_ids: vec![(0, String::from("c0"))],
+        original_cell_assignments: vec![0, 0, BACKGROUND_CELL],
+        fov_names: vec![String::from("fov")],
+        nfiltered_qv: 0,
+    };
+
+    let mut xy = dataset();
+    let distances = filter_cellfree_transcripts(&mut xy, 1, 10.0, NucleusDistanceMetric::Xy);
+    assert_eq!(xy.transcripts.len(), 3);
+    assert_eq!(distances, vec![0.0, 0.0, 0.0]);
+
+    let mut xyz = dataset();
+    let distances = filter_cellfree_transcripts(&mut xyz, 1, 10.0, NucleusDistanceMetric::Xyz);
+    assert_eq!(xyz.transcripts.iter().map(|t| t.transcript_id).collect::<Vec<_>>(), vec![0, 1]);
+    assert_eq!(xyz.cell_assignments.len(), 2);
+    assert_eq!(distances, vec![0.0, 0.0]);
 }
@@ -1,15 +1,18 @@
 use super::connectivity::ConnectivityChecker;
-use super::math::relerr;
+use super::math::{lognormal_logpdf, relerr};
 use super::polygons::{PolygonBuilder, union_all_into_multipolygon};
 use super::sampleset::SampleSet;
 use super::transcripts::{coordinate_span, CellIndex, Transcript, BACKGROUND_CELL};
-use super::{chunkquad, perimeter_bound, ModelParams, ModelPriors, Proposal, Sampler};
+use super::{
+    chunkquad, perimeter_bound, zlayer, ChunkQuadTree, ModelParams, ModelPriors, Proposal, Sampler,
+};
 
 // use hexx::{Hex, HexLayout, HexOrientation, Vec2};
 // use arrow;
 use geo::geometry::{MultiPolygon, Polygon};
+use geo::Area;
 use itertools::Itertools;
-use ndarray::Array2;
+use ndarray::{Array2, Zip};
 use rand::{thread_rng, Rng};
 use rayon::prelude::*;
 use std::cell::RefCell;
@@ -195,6 +198,10 @@ pub struct VoxelLayout {
 }
 
 impl VoxelLayout {
+    pub fn new(origin: (f32, f32, f32), size: (f32, f32, f32)) -> VoxelLayout {
+        VoxelLayout { origin, size }
+    }
+
     fn double_resolution(&self) -> VoxelLayout {
         VoxelLayout {
             origin: (self.origin.0, self.origin.1, self.origin.2),
@@ -263,11 +270,15 @@ struct ChunkQuadMap {
     ymin: f32,
     chunk_size: f32,
     nxchunks: usize,
+    tree: Option<Arc<ChunkQuadTree>>,
 }
 
 impl ChunkQuadMap {
     fn get(&self, voxel: Voxel) -> (u32, u32) {
         let voxel_xyz = self.layout.voxel_to_world_pos(voxel);
+        if let Some(tree) = &self.tree {
+            return tree.chunkquad(voxel_xyz.0, voxel_xyz.1);
+        }
         chunkquad(
             voxel_xyz.0,
             voxel_xyz.1,
@@ -313,14 +324,27 @@ impl VoxelCellMap {
     //     return self.index.values().filter(|&&c| c == cell).count();
     // }
 
-    fn iter(&self) -> std::collections::hash_map::Iter<Voxel, CellIndex> {
-        return self.index.iter();
+    fn iter(&self) -> std::collections::hash_map::Iter<'_, Voxel, CellIndex> {
+        self.index.iter()
+    }
+
+    // Voxels assigned to cells, ordered by cell then voxel, so anything written
+    // out doesn't depend on hash map iteration order.
+    fn sorted_cell_voxels(&self) -> Vec<(CellIndex, Voxel)> {
+        let mut cell_voxels = self
+            .index
+            .iter()
+            .filter(|(_, &cell)| cell != BACKGROUND_CELL)
+            .map(|(voxel, &cell)| (cell, *voxel))
+            .collect::<Vec<_>>();
+        cell_voxels.par_sort_unstable();
+        cell_voxels
     }
 }
 
 // Initial binning of the transcripts
 fn bin_transcripts(
-    transcripts: &Vec<Transcript>,
+    transcripts: &[Transcript],
     scale: f32,
     zlayers: usize,
 ) -> (VoxelLayout, Vec<VoxelBin>) {
@@ -428,6 +452,11 @@ pub struct VoxelSampler {
 
     voxel_volume: f32,
     quad: usize,
+
+    // voxels of each cell in the birth pool (cells from `nseeded_cells` on),
+    // so death moves can find a born cell's last voxel
+    nseeded_cells: usize,
+    pool_cell_voxels: Vec<HashSet<Voxel>>,
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -435,7 +464,7 @@ impl VoxelSampler {
     pub fn new(
         priors: &ModelPriors,
         params: &mut ModelParams,
-        transcripts: &Vec<Transcript>,
+        transcripts: &[Transcript],
         ngenes: usize,
         voxellayers: usize,
         nlayers: usize,
@@ -443,18 +472,51 @@ impl VoxelSampler {
         layer_depth: f32,
         scale: f32,
         chunk_size: f32,
+        adaptive_cells_per_chunk: Option<usize>,
     ) -> Self {
         let (xmin, xmax, ymin, ymax, zmin, zmax) = coordinate_span(transcripts);
         let nxchunks = ((xmax - xmin) / chunk_size).ceil() as usize;
         let nychunks = ((ymax - ymin) / chunk_size).ceil() as usize;
-        let nchunks = nxchunks * nychunks;
+
+        // Optionally replace the uniform grid with a quadtree over the initial
+        // cell centroids, so dense regions are split into more chunks.
+        let tree = adaptive_cells_per_chunk.map(|cells_per_chunk| {
+            let ncells = params.ncells();
+            let mut centroid_sums = vec![(0.0_f32, 0.0_f32, 0_u32); ncells];
+            for (t, &cell) in transcripts.iter().zip(params.cell_assignments.iter()) {
+                if cell != BACKGROUND_CELL {
+                    let c = &mut centroid_sums[cell as usize];
+                    c.0 += t.x;
+                    c.1 += t.y;
+                    c.2 += 1;
+                }
+            }
+            let centroids = centroid_sums
+                .iter()
+                .filter(|c| c.2 > 0)
+                .map(|c| (c.0 / c.2 as f32, c.1 / c.2 as f32))
+                .collect::<Vec<_>>();
+
+            // Keep quadrants at least a few voxels wide.
+            Arc::new(ChunkQuadTree::new(
+                &centroids,
+                (xmin, xmax, ymin, ymax),
+                cells_per_chunk,
+                8.0 * scale,
+            ))
+        });
+
+        let nchunks = match &tree {
+            Some(tree) => tree.nchunks(),
+            None => nxchunks * nychunks,
+        };
 
         let (layout, voxel_bins) = bin_transcripts(transcripts, scale, voxellayers);
 
         let transcript_genes = transcripts.iter().map(|t| t.gene).collect::<Vec<_>>();
         let transcript_layers = transcripts
             .iter()
-            .map(|t| ((t.z - z0) / layer_depth) as u32)
+            .map(|t| zlayer(t.z, z0, layer_depth, nlayers) as u32)
             .collect::<Vec<_>>();
 
         assert!(layout.size.0 == layout.size.1);
@@ -527,6 +589,7 @@ impl VoxelSampler {
                 ymin,
                 chunk_size,
                 nxchunks,
+                tree,
             },
             transcript_genes,
             transcript_voxels,
@@ -545,11 +608,14 @@ impl VoxelSampler {
             zmax,
             voxel_volume,
             quad: 0,
+            nseeded_cells: params.nseeded_cells,
+            pool_cell_voxels: Vec::new(),
         };
 
         sampler.recompute_cell_population();
         sampler.recompute_cell_perimeter();
         sampler.recompute_cell_volume(priors, params);
+        sampler.recompute_pool_cell_voxels();
         sampler.populate_mismatches();
         sampler.update_transcript_positions(
             &vec![true; transcripts.len()],
@@ -563,6 +629,14 @@ impl VoxelSampler {
         self.cell_population.shape()[1]
     }
 
+    pub fn nchunks(&self) -> usize {
+        self.mismatch_edges[0].len()
+    }
+
+    pub fn voxel_layers(&self) -> usize {
+        self.voxel_layers
+    }
+
     // Allocate a new RectBinSampler with the same state as this one, but
     // grid resolution doubled (i.e. rect size halved).
     pub fn double_resolution(&self, params: &ModelParams, double_z_layers: bool) -> VoxelSampler {
@@ -648,6 +722,7 @@ impl VoxelSampler {
                 ymin: self.chunkquad.ymin,
                 chunk_size: self.chunkquad.chunk_size,
                 nxchunks: self.chunkquad.nxchunks,
+                tree: self.chunkquad.tree.clone(),
             },
             transcript_genes: self.transcript_genes.clone(),
             transcript_voxels: self.transcript_voxels.clone(),
@@ -666,6 +741,8 @@ impl VoxelSampler {
             zmax: self.zmax,
             voxel_volume,
             quad: 0,
+            nseeded_cells: self.nseeded_cells,
+            pool_cell_voxels: Vec::new(),
         };
 
         // 11.3s
@@ -683,6 +760,8 @@ impl VoxelSampler {
         sampler.recompute_cell_perimeter();
         // println!("recompute_cell_perimeter: {:?}", t0.elapsed());
 
+        sampler.recompute_pool_cell_voxels();
+
         sampler.update_transcript_positions(
             &vec![true; params.transcript_positions.len()],
             &params.transcript_positions,
@@ -701,12 +780,156 @@ impl VoxelSampler {
             params.cell_volume[cell as usize] += self.voxel_volume;
         }
 
-        for cell_volume in params.cell_volume.iter_mut() {
-            assert!(*cell_volume > 0.0);
+        for (cell_volume, &dormant) in params.cell_volume.iter_mut().zip(&params.cell_dormant) {
+            assert!(*cell_volume > 0.0 || dormant);
             *cell_volume = cell_volume.max(priors.min_cell_volume);
         }
     }
 
+    // Give each cell's voxels to `destination[cell]`, to merge cells once
+    // sampling is done. Cells merged away are left empty and dormant.
+    pub fn merge_cells(&mut self, priors: &ModelPriors, params: &mut ModelParams, destination: &[CellIndex]) {
+        for cell in self.voxel_cells.index.values_mut() {
+            if *cell != BACKGROUND_CELL {
+                *cell = destination[*cell as usize];
+            }
+        }
+        for (i, &dest) in destination.iter().enumerate() {
+            if dest as usize != i {
+                params.cell_dormant[i] = true;
+            }
+        }
+
+        self.recompute_cell_population();
+        self.recompute_cell_perimeter();
+        self.recompute_cell_volume(priors, params);
+        self.recompute_pool_cell_voxels();
+    }
+
+    fn recompute_pool_cell_voxels(&mut self) {
+        let npool = self.ncells() - self.nseeded_cells;
+        self.pool_cell_voxels = vec![HashSet::new(); npool];
+        if npool == 0 {
+            return;
+        }
+
+        for (&voxel, &cell) in self.voxel_cells.iter() {
+            if cell != BACKGROUND_CELL && cell as usize >= self.nseeded_cells {
+                self.pool_cell_voxels[cell as usize - self.nseeded_cells].insert(voxel);
+            }
+        }
+    }
+
+    // Fill in the transcripts in `voxel` and their gene counts.
+    fn set_proposal_transcripts(&self, proposal: &mut VoxelProposal, voxel: Voxel) {
+        let start = self
+            .transcript_voxel_ord
+            .partition_point(|&t| self.transcript_voxels[t] < voxel);
+
+        proposal.genepop.fill(0);
+        proposal.transcripts.clear();
+        for &t in self.transcript_voxel_ord[start..].iter() {
+            if self.transcript_voxels[t] != voxel {
+                break;
+            }
+            let layer = self.transcript_layers[t] as usize;
+            proposal.genepop[[self.transcript_genes[t] as usize, layer]] += 1;
+            proposal.transcripts.push(t);
+        }
+    }
+
+    // Set up a single birth or death move in `proposal`, or leave it ignored.
+    //
+    // A birth gives the voxel of a random transcript, if it's background, to a
+    // dormant cell, which favors dense clusters of background transcripts. A
+    // death returns the only voxel of a random single-voxel born cell to the
+    // background. Each is the other's reverse move. Dormant cells aren't part
+    // of the model, so the volume prior term evaluated for them is cancelled.
+    fn birth_death_proposal(
+        &self,
+        priors: &ModelPriors,
+        params: &ModelParams,
+        proposal: &mut VoxelProposal,
+    ) {
+        let ntranscripts = self.transcript_voxels.len();
+        if ntranscripts == 0 {
+            return;
+        }
+
+        let singletons = self
+            .pool_cell_voxels
+            .iter()
+            .enumerate()
+            .filter(|(_, voxels)| voxels.len() == 1)
+            .map(|(i, _)| self.nseeded_cells + i)
+            .collect::<Vec<_>>();
+
+        // none of a single voxel cell's neighbors are in the cell
+        let perimeter = Voxel::default().radius2_xy_neighborhood().len() as f32;
+
+        let mut rng = thread_rng();
+        if rng.gen::<bool>() {
+            let cell = match (params.nseeded_cells..params.ncells()).find(|&c| params.cell_dormant[c]) {
+                Some(cell) => cell,
+                None => return,
+            };
+
+            let voxel = self.transcript_voxels[rng.gen_range(0..ntranscripts)];
+            if self.voxel_cells.get(voxel) != BACKGROUND_CELL {
+                return;
+            }
+            let (_, _, z0, _, _, z1) = self.chunkquad.layout.voxel_to_world_coords(voxel);
+            if z1 < self.zmin || z0 > self.zmax {
+                return;
+            }
+
+            self.set_proposal_transcripts(proposal, voxel);
+            let z = params.z[cell] as usize;
+            let volume = params.cell_volume[cell];
+
+            proposal.voxel = voxel;
+            proposal.old_cell = BACKGROUND_CELL;
+            proposal.new_cell = cell as CellIndex;
+            proposal.old_cell_volume_delta = 0.0;
+            proposal.new_cell_volume_delta = self.voxel_volume - volume;
+            proposal.old_cell_perimeter_delta = 0.0;
+            proposal.new_cell_perimeter_delta = perimeter;
+            proposal.log_weight = (ntranscripts as f32 / proposal.transcripts.len() as f32).ln()
+                - ((singletons.len() + 1) as f32).ln()
+                + lognormal_logpdf(params.μ_volume[z], params.σ_volume[z], volume);
+        } else {
+            if singletons.is_empty() {
+                return;
+            }
+            let cell = singletons[rng.gen_range(0..singletons.len())];
+            let voxel = *self.pool_cell_voxels[cell - self.nseeded_cells]
+                .iter()
+                .next()
+                .unwrap();
+
+            // the reverse birth could only have picked a voxel with transcripts
+            self.set_proposal_transcripts(proposal, voxel);
+            if proposal.transcripts.is_empty() {
+                return;
+            }
+            let z = params.z[cell] as usize;
+            let volume = params.cell_volume[cell];
+
+            proposal.voxel = voxel;
+            proposal.old_cell = cell as CellIndex;
+            proposal.new_cell = BACKGROUND_CELL;
+            proposal.old_cell_volume_delta = priors.min_cell_volume - volume;
+            proposal.new_cell_volume_delta = 0.0;
+            proposal.old_cell_perimeter_delta = -perimeter;
+            proposal.new_cell_perimeter_delta = 0.0;
+            proposal.log_weight = (proposal.transcripts.len() as f32 / ntranscripts as f32).ln()
+                + (singletons.len() as f32).ln()
+                - lognormal_logpdf(params.μ_volume[z], params.σ_volume[z], priors.min_cell_volume);
+        }
+
+        proposal.ignore = false;
+    }
+
     fn recompute_cell_population(&mut self) {
         self.cell_population.fill(0.0_f32);
         for (&voxel, &cell) in self.voxel_cells.iter() {
@@ -771,24 +994,30 @@ impl VoxelSampler {
     }
 
     pub fn voxels(&self) -> impl Iterator<Item = (CellIndex, (f32, f32, f32, f32, f32, f32))> + '_ {
-        return self
-            .voxel_cells
-            .iter()
-            .filter(|(_, &cell)| cell != BACKGROUND_CELL)
-            .map(|(voxel, cell)| (*cell, self.chunkquad.layout.voxel_to_world_coords(*voxel)));
+        self.iter_cell_voxels()
+            .map(|(cell, voxel)| (cell, self.voxel_world_coords(voxel)))
+    }
+
+    // Iterate over every voxel assigned to a cell, ordered by cell then voxel.
+    pub fn iter_cell_voxels(&self) -> impl Iterator<Item = (CellIndex, Voxel)> + '_ {
+        self.voxel_cells.sorted_cell_voxels().into_iter()
+    }
+
+    // World coordinates of a voxel's bounds as (x0, y0, z0, x1, y1, z1).
+    pub fn voxel_world_coords(&self, voxel: Voxel) -> (f32, f32, f32, f32, f32, f32) {
+        self.chunkquad.layout.voxel_to_world_coords(voxel)
     }
 
     pub fn cell_centroids(&self) -> Vec<(f32, f32, f32)> {
         let mut centroids = vec![(0.0, 0.0, 0.0); self.ncells()];
         let mut counts = vec![0; self.ncells()];
-        for (&voxel, &cell) in self.voxel_cells.iter() {
-            if cell != BACKGROUND_CELL {
-                let (x0, y0, z0, x1, y1, z1) = self.chunkquad.layout.voxel_to_world_coords(voxel);
-                centroids[cell as usize].0 += (x0 + x1) / 2.0;
-                centroids[cell as usize].1 += (y0 + y1) / 2.0;
-                centroids[cell as usize].2 += (z0 + z1) / 2.0;
-                counts[cell as usize] += 1;
-            }
+        // summing in a fixed order keeps the rounding the same from run to run
+        for (cell, voxel) in self.iter_cell_voxels() {
+            let (x0, y0, z0, x1, y1, z1) = self.chunkquad.layout.voxel_to_world_coords(voxel);
+            centroids[cell as usize].0 += (x0 + x1) / 2.0;
+            centroids[cell as usize].1 += (y0 + y1) / 2.0;
+            centroids[cell as usize].2 += (z0 + z1) / 2.0;
+            counts[cell as usize] += 1;
         }
 
         for (i, count) in counts.iter().enumerate() {
@@ -802,6 +1031,15 @@ impl VoxelSampler {
         centroids
     }
 
+    // Number of voxels in each cell, across all layers.
+    pub fn cell_voxel_counts(&self) -> Vec<u32> {
+        self.cell_population
+            .columns()
+            .into_iter()
+            .map(|voxels| voxels.sum() as u32)
+            .collect()
+    }
+
     pub fn cell_polygons(&self) -> (Vec<CellPolygonLayers>, Vec<CellPolygon>) {
         // Build sets of voxels for each cell
         let mut cell_voxels = vec![HashSet::new(); self.ncells()];
@@ -916,10 +1154,13 @@ impl VoxelSampler {
         // Issues arise with some downstream tools (e.g. xeniumranger) if there
         // are empty cell polygons, which can happen with this consensus approach.
         // Here we try to fix those cases by including at least on voxel.
+        // Cells reserved for births that were never born have no voxels at all.
         for (cell, voxels) in cell_voxels.iter_mut().enumerate() {
             if voxels.is_empty() {
                 let cell =  cell as u32;
-                voxels.insert(top_voxel[&cell].0);
+                if let Some((voxel, _)) = top_voxel.get(&cell) {
+                    voxels.insert(*voxel);
+                }
             }
         }
 
@@ -945,7 +1186,7 @@ impl VoxelSampler {
             .collect();
         // println!("build polygons: {:?}", t0.elapsed());
 
-        return cell_polygons;
+        cell_polygons
     }
 
     // pub fn mismatch_edge_stats(&self) -> (usize, usize) {
@@ -969,23 +1210,99 @@ impl VoxelSampler {
     //     return (num_cell_cell_edges, num_cell_bg_edges);
     // }
 
-    // pub fn check_perimeter_bounds(&self, priors: &ModelPriors) {
-    //     let mut count = 0;
-    //     Zip::from(&self.cell_perimeter)
-    //         .and(&self.cell_population)
-    //         .for_each(|&perimiter, &pop| {
-    //             let bound = perimeter_bound(priors.perimeter_eta, priors.perimeter_bound, pop);
-    //             if perimiter > bound {
-    //                 count += 1;
-    //             }
-    //         });
-    //     println!("perimeter bound violations: {}", count);
-    // }
+    // For each cell, the (perimeter, bound) pair from the z-layer where the
+    // perimeter is closest to (or furthest beyond) its bound. Perimeters are
+    // in units of mismatching voxel neighbors.
+    pub fn cell_perimeters(&self, priors: &ModelPriors) -> Vec<(f32, f32)> {
+        let mut perimeters = vec![(0.0, 0.0); self.ncells()];
+        let mut max_ratios = vec![f32::NEG_INFINITY; self.ncells()];
+        Zip::indexed(&self.cell_perimeter)
+            .and(&self.cell_population)
+            .for_each(|(_, cell), &perimeter, &pop| {
+                if pop == 0.0 {
+                    return;
+                }
+                let bound = perimeter_bound(priors.perimeter_eta, priors.perimeter_bound, pop);
+                let ratio = perimeter / bound;
+                if ratio > max_ratios[cell] {
+                    max_ratios[cell] = ratio;
+                    perimeters[cell] = (perimeter, bound);
+                }
+            });
+        perimeters
+    }
+
+    // Count voxel faces in the xy-plane on each cell's boundary, and how many
+    // of those are shared with each neighboring cell, keyed by (lower, higher)
+    // cell index.
+    pub fn cell_contacts(&self) -> (Vec<u32>, HashMap<(CellIndex, CellIndex), u32>) {
+        let mut boundary = vec![0; self.ncells()];
+        let mut contacts = HashMap::new();
+        for (&voxel, &cell) in self.voxel_cells.iter() {
+            if cell == BACKGROUND_CELL {
+                continue;
+            }
+
+            for neighbor in voxel.von_neumann_neighborhood_xy() {
+                let neighbor_cell = self.voxel_cells.get(neighbor);
+                if neighbor_cell == cell {
+                    continue;
+                }
+                boundary[cell as usize] += 1;
+                if neighbor_cell != BACKGROUND_CELL && cell < neighbor_cell {
+                    *contacts.entry((cell, neighbor_cell)).or_insert(0) += 1;
+                }
+            }
+        }
+        (boundary, contacts)
+    }
 
     pub fn check_consistency(&mut self, priors: &ModelPriors, params: &mut ModelParams) {
         self.check_cell_volume(priors, params);
         self.check_cell_perimeter();
         self.check_cell_population();
+        self.check_cell_polygon_areas();
+    }
+
+    // Polygons are traced from voxels, so the polygon area in each layer should
+    // match the cell's voxel count in that layer, to within ε either way.
+    // Polygons are written without holes though, so the upper bound gets the
+    // extra slack of every enclosed voxel not belonging to the cell, counting
+    // gaps that only open diagonally to the outside as enclosed.
+    fn check_cell_polygon_areas(&self) {
+        let voxel_area = self.chunkquad.layout.size.0 * self.chunkquad.layout.size.1;
+        let (cell_polygons, _) = self.cell_polygons();
+
+        let mut cell_layer_voxels: HashMap<(CellIndex, i32), HashSet<Voxel>> = HashMap::new();
+        for (cell, voxel) in self.iter_cell_voxels() {
+            cell_layer_voxels.entry((cell, voxel.k)).or_default().insert(voxel);
+        }
+        let mut filled_counts = vec![0; cell_polygons.len()];
+        for ((cell, _), voxels) in &cell_layer_voxels {
+            filled_counts[*cell as usize] += fill_xy_holes(voxels).len();
+        }
+
+        let ε = 1e-3_f32;
+        let mut nfilled = 0;
+        for (cell, polygons) in cell_polygons.iter().enumerate() {
+            let polygon_area: f32 = polygons.iter().map(|(_k, poly)| poly.unsigned_area()).sum();
+            let voxel_count: f32 = self.cell_population.column(cell).sum();
+            let voxel_cells_area = voxel_count * voxel_area;
+            let filled_area = filled_counts[cell] as f32 * voxel_area;
+            let consistent =
+                polygon_area >= voxel_cells_area * (1.0 - ε) && polygon_area <= filled_area * (1.0 + ε);
+            if !consistent {
+                dbg!(cell, polygon_area, voxel_cells_area, filled_area);
+            }
+            assert!(consistent);
+            if polygon_area > voxel_cells_area * (1.0 + ε) {
+                nfilled += 1;
+            }
+        }
+
+        if nfilled > 0 {
+            println!("{} cell polygons have filled holes", nfilled);
+        }
     }
 
     // Since we have to update various values as we sample, here we are checking to
@@ -1315,6 +1632,16 @@ impl Sampler<VoxelProposal> for VoxelSampler {
         self.quad = (self.quad + 1) % 4;
     }
 
+    fn repopulate_birth_death_proposals(&mut self, priors: &ModelPriors, params: &ModelParams) {
+        let mut proposals = std::mem::take(&mut self.proposals);
+        for proposal in proposals.iter_mut() {
+            proposal.ignore = true;
+            proposal.accept = false;
+        }
+        self.birth_death_proposal(priors, params, &mut proposals[0]);
+        self.proposals = proposals;
+    }
+
     fn proposals<'a, 'b>(&'a self) -> &'b [VoxelProposal]
     where
         'a: 'b,
@@ -1333,6 +1660,15 @@ impl Sampler<VoxelProposal> for VoxelSampler {
         for proposal in self.proposals.iter().filter(|p| !p.ignore && p.accept) {
             self.voxel_cells.set(proposal.voxel, proposal.new_cell);
 
+            if proposal.old_cell != BACKGROUND_CELL && proposal.old_cell as usize >= self.nseeded_cells {
+                self.pool_cell_voxels[proposal.old_cell as usize - self.nseeded_cells]
+                    .remove(&proposal.voxel);
+            }
+            if proposal.new_cell != BACKGROUND_CELL && proposal.new_cell as usize >= self.nseeded_cells {
+                self.pool_cell_voxels[proposal.new_cell as usize - self.nseeded_cells]
+                    .insert(proposal.voxel);
+            }
+
             // update cell population and perimeter
             if proposal.old_cell != BACKGROUND_CELL {
                 self.cell_population[[proposal.voxel.k as usize, proposal.old_cell as usize]] -=
@@ -1624,7 +1960,7 @@ impl Proposal for VoxelProposal {
 pub fn filter_sparse_cells(
     scale: f32,
     voxellayers: usize,
-    transcripts: &Vec<Transcript>,
+    transcripts: &[Transcript],
     nucleus_assignments: &mut [CellIndex],
     cell_assignments: &mut [CellIndex],
     nucleus_population: &mut Vec<usize>,
@@ -1676,3 +2012,267 @@ pub fn filter_sparse_cells(
         }
     }
 }
+
+// Number of distinct z values when every transcript has an integer z and there
+// are at most `max` of them, as with z-stacks imaged in discrete slices.
+pub fn discrete_z_levels(transcripts: &[Transcript], max: usize) -> Option<usize> {
+    let mut zlevels = HashSet::new();
+    for t in transcripts {
+        if t.z.round() != t.z {
+            return None;
+        }
+        zlevels.insert(t.z as i32);
+    }
+    if zlevels.len() <= max {
+        Some(zlevels.len())
+    } else {
+        None
+    }
+}
+
+// Voxel layers and background layers are independent: a transcript's
+// background layer comes from its own z, so either can be larger. With discrete
+// z, though, voxel layers beyond the number of z levels can only be empty
+// slices, so refuse those up front rather than letting them starve cells of
+// voxels. When z-layers are doubled with resolution, the final phase uses
+// voxel_layers * 2^ndoublings layers, or fewer with a target number of z-layers.
+pub fn check_layer_counts(
+    voxel_layers: usize,
+    nbglayers: usize,
+    ndoublings: usize,
+    double_z_layers: bool,
+    zlevels: Option<usize>,
+    target_z_layers: Option<usize>,
+) {
+    if voxel_layers == 0 {
+        panic!("--voxel-layers must be at least 1");
+    }
+    if nbglayers == 0 {
+        panic!("--nbglayers must be at least 1");
+    }
+
+    if let Some(target_z_layers) = target_z_layers {
+        if voxel_layers > target_z_layers {
+            panic!(
+                "--voxel-layers {} exceeds --target-z-layers {}. Use --voxel-layers {} or fewer.",
+                voxel_layers, target_z_layers, target_z_layers
+            );
+        }
+    }
+
+    let final_voxel_layers = final_voxel_layers(voxel_layers, ndoublings, double_z_layers, target_z_layers);
+
+    if let Some(zlevels) = zlevels {
+        if voxel_layers > zlevels {
+            panic!(
+                "--voxel-layers {} exceeds the {} distinct z values in the data, which would leave empty z-slices. Use --voxel-layers {} or fewer.",
+                voxel_layers, zlevels, zlevels
+            );
+        }
+        if final_voxel_layers > zlevels {
+            eprintln!(
+                "Warning: doubling resolution reaches {} voxel layers, more than the {} distinct z values.",
+                final_voxel_layers, zlevels
+            );
+        }
+    }
+}
+
+// Whether doubling resolution from `voxel_layers` layers doubles them too,
+// which stops short of exceeding a target number of z-layers.
+pub fn doubles_z_layers(voxel_layers: usize, double_z_layers: bool, target_z_layers: Option<usize>) -> bool {
+    double_z_layers && target_z_layers.is_none_or(|target| 2 * voxel_layers <= target)
+}
+
+// Voxel layers after `ndoublings` resolution doublings.
+pub fn final_voxel_layers(
+    voxel_layers: usize,
+    ndoublings: usize,
+    double_z_layers: bool,
+    target_z_layers: Option<usize>,
+) -> usize {
+    (0..ndoublings).fold(voxel_layers, |nlayers, _| {
+        if doubles_z_layers(nlayers, double_z_layers, target_z_layers) {
+            2 * nlayers
+        } else {
+            nlayers
+        }
+    })
+}
+
+// Outline of the region covered by transcripts, tracing the boundary of every
+// xy voxel of the given size that contains at least one transcript.
+pub fn tissue_boundary(transcripts: &[Transcript], voxel_size: f32) -> MultiPolygon<f32> {
+    let (layout, occupied) = occupied_xy_voxels(transcripts, voxel_size);
+    let mut polygon_builder = PolygonBuilder::new();
+    polygon_builder
+        .cell_voxels_to_polygons(&layout, &occupied)
+        .pop()
+        .map(|(_k, polys)| polys)
+        .unwrap_or(MultiPolygon(Vec::new()))
+}
+
+// xy voxels of the given size containing at least one transcript, flattened
+// onto the lowest z.
+fn occupied_xy_voxels(transcripts: &[Transcript], voxel_size: f32) -> (VoxelLayout, HashSet<Voxel>) {
+    let (_, _, _, _, zmin, _) = coordinate_span(transcripts);
+    let layout = VoxelLayout {
+        origin: (0.0, 0.0, zmin),
+        size: (voxel_size, voxel_size, 1.0),
+    };
+
+    let occupied: HashSet<Voxel> = transcripts
+        .iter()
+        .map(|t| layout.world_pos_to_voxel((t.x, t.y, zmin)))
+        .collect();
+
+    (layout, occupied)
+}
+
+// Flag cells that reach the tissue boundary traced with the same voxel size:
+// those with a voxel that, grown by half its width, overlaps a voxel outside
+// it. As with the traced boundary, holes inside the tissue don't count, so
+// only cells likely truncated by the edge of the imaged region are flagged.
+pub fn edge_cells(
+    sampler: &VoxelSampler,
+    transcripts: &[Transcript],
+    voxel_size: f32,
+    ncells: usize,
+) -> Vec<bool> {
+    edge_cells_from_voxels(sampler.voxels(), transcripts, voxel_size, ncells)
+}
+
+fn edge_cells_from_voxels(
+    voxels: impl Iterator<Item = (CellIndex, (f32, f32, f32, f32, f32, f32))>,
+    transcripts: &[Transcript],
+    voxel_size: f32,
+    ncells: usize,
+) -> Vec<bool> {
+    let (layout, occupied) = occupied_xy_voxels(transcripts, voxel_size);
+    let tissue = fill_xy_holes(&occupied);
+    let zmin = layout.origin.2;
+    let outside = |x: f32, y: f32| !tissue.contains(&layout.world_pos_to_voxel((x, y, zmin)));
+
+    let mut edge = vec![false; ncells];
+    for (cell, (x0, y0, _, x1, y1, _)) in voxels {
+        if edge[cell as usize] {
+            continue;
+        }
+        let (dx, dy) = ((x1 - x0) / 2.0, (y1 - y0) / 2.0);
+        let (x0, y0, x1, y1) = (x0 - dx, y0 - dy, x1 + dx, y1 + dy);
+        if outside(x0, y0) || outside(x0, y1) || outside(x1, y0) || outside(x1, y1) {
+            edge[cell as usize] = true;
+        }
+    }
+    edge
+}
+
+// The voxels along with any holes they enclose: everything in their bounding
+// box that can't be reached from outside it through unoccupied voxels.
+fn fill_xy_holes(occupied: &HashSet<Voxel>) -> HashSet<Voxel> {
+    let Some(k) = occupied.iter().next().map(|v| v.k) else {
+        return HashSet::new();
+    };
+    let (i0, i1) = occupied.iter().map(|v| v.i).minmax().into_option().unwrap();
+    let (j0, j1) = occupied.iter().map(|v| v.j).minmax().into_option().unwrap();
+    let (i0, i1, j0, j1) = (i0 - 1, i1 + 1, j0 - 1, j1 + 1);
+
+    let mut exterior = HashSet::new();
+    let mut stack = vec![Voxel::new(i0, j0, k)];
+    while let Some(v) = stack.pop() {
+        if v.i < i0 || v.i > i1 || v.j < j0 || v.j > j1 || occupied.contains(&v) || !exterior.insert(v) {
+            continue;
+        }
+        stack.extend([
+            Voxel::new(v.i - 1, v.j, k),
+            Voxel::new(v.i + 1, v.j, k),
+            Voxel::new(v.i, v.j - 1, k),
+            Voxel::new(v.i, v.j + 1, k),
+        ]);
+    }
+
+    (i0..=i1)
+        .cartesian_product(j0..=j1)
+        .map(|(i, j)| Voxel::new(i, j, k))
+        .filter(|v| !exterior.contains(v))
+        .collect()
+}
+
+#[test]
+#[should_panic(expected = "--voxel-layers 8 exceeds the 3 distinct z values")]
+fn voxel_layers_exceeding_z_levels_rejected() {
+    let transcripts = (0..30)
+        .map(|i| Transcript {
+            transcript_id: i as u64,
+            x: (i % 5) as f32,
+            y: (i / 5) as f32,
+            z: (i % 3) as f32,
+            gene: 0,
+            fov: 0,
+        })
+        .collect::<Vec<_>>();
+    let zlevels = discrete_z_levels(&transcripts, 30);
+    assert_eq!(zlevels, Some(3));
+    check_layer_counts(8, 4, 2, true, zlevels, None);
+}
+
+#[test]
+fn z_layer_doubling_stops_at_target() {
+    assert_eq!(final_voxel_layers(2, 3, true, None), 16);
+    assert_eq!(final_voxel_layers(2, 3, true, Some(6)), 4);
+    assert_eq!(final_voxel_layers(2, 3, false, Some(6)), 2);
+    assert_eq!(final_voxel_layers(3, 3, true, Some(3)), 3);
+}
+
+#[test]
+fn cell_voxel_order_independent_of_insertion() {
+    let voxels = (0..200)
+        .map(|i| (Voxel::new(i % 7, (i * 3) % 11, i % 2), if i % 9 == 0 { BACKGROUND_CELL } else { (i % 5) as CellIndex }))
+        .collect::<Vec<_>>();
+
+    let mut forward = VoxelCellMap::new();
+    for &(voxel, cell) in &voxels {
+        forward.insert(voxel, cell);
+    }
+    let mut backward = VoxelCellMap::new();
+    for &(voxel, cell) in voxels.iter().rev() {
+        backward.insert(voxel, cell);
+    }
+    // later inserts win, so make both maps agree on the assignments
+    for (&voxel, &cell) in forward.iter() {
+        backward.set(voxel, cell);
+    }
+
+    let a = forward.sorted_cell_voxels();
+    let b = backward.sorted_cell_voxels();
+    assert_eq!(a, b);
+    assert!(a.windows(2).all(|w| w[0] < w[1]));
+    assert!(a.iter().all(|(cell, _)| *cell != BACKGROUND_CELL));
+}
+
+#[test]
+fn edge_cells_ignore_interior_holes() {
+    // a 10x10 section with no transcripts in the 2x2 block at (4, 4)
+    let transcripts = (0..10)
+        .cartesian_product(0..10)
+        .filter(|&(i, j)| !((4..6).contains(&i) && (4..6).contains(&j)))
+        .enumerate()
+        .map(|(id, (i, j))| Transcript {
+            transcript_id: id as u64,
+            x: i as f32 + 0.5,
+            y: j as f32 + 0.5,
+            z: 0.0,
+            gene: 0,
+            fov: 0,
+        })
+        .collect::<Vec<_>>();
+
+    // next to the hole, on the section's edge, and in the interior
+    let voxels = [
+        (0, (3.0, 4.0, 0.0, 4.0, 5.0, 1.0)),
+        (1, (9.0, 5.0, 0.0, 10.0, 6.0, 1.0)),
+        (2, (2.0, 2.0, 0.0, 3.0, 3.0, 1.0)),
+    ];
+    let edge = edge_cells_from_voxels(voxels.into_iter(), &transcripts, 1.0, 3);
+    assert_eq!(edge, vec![false, true, false]);
+}
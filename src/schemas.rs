@@ -9,9 +9,52 @@ pub enum OutputFormat {
     Infer,
     Csv,
     CsvGz,
+    CsvZst,
     Parquet,
 }
 
+// Compression for outputs that are compressed by default.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum OutputCompression {
+    Gzip,
+    Zstd,
+    None,
+}
+
+// Wide writes the cell-by-gene matrix, tidy one row per cell and gene.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum RatesLayout {
+    Wide,
+    Tidy,
+}
+
+// Whether distance to the nearest nucleus counts only xy offsets or z too.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum NucleusDistanceMetric {
+    Xy,
+    Xyz,
+}
+
+// What to do with distinct genes whose names coincide after normalization.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum DuplicateGenes {
+    Merge,
+    Suffix,
+    Error,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum GeometryFormat {
+    Infer,
+    GeoJson,
+    GeoJsonGz,
+    GeoJsonZst,
+    // CSV with a WKT geometry column
+    Wkt,
+    WktGz,
+    WktZst,
+}
+
 pub fn large_utf8_if_parquet(fmt: OutputFormat) -> DataType {
     match fmt {
         OutputFormat::Parquet => DataType::LargeUtf8,
@@ -31,9 +74,21 @@ pub fn transcript_metadata_schema(fmt: OutputFormat) -> Schema {
         Field::new("gene", large_utf8_if_parquet(fmt), false),
         Field::new("qv", DataType::Float32, false),
         Field::new("fov", large_utf8_if_parquet(fmt), false),
+        Field::new("compartment", large_utf8_if_parquet(fmt), false),
         Field::new("assignment", DataType::UInt32, false),
         Field::new("probability", DataType::Float32, false),
+        Field::new("low_confidence", DataType::UInt8, false),
         Field::new("background", DataType::UInt8, false),
         Field::new("confusion", DataType::UInt8, false),
+        Field::new("nucleus_distance", DataType::Float32, false),
+        Field::new("displacement", DataType::Float32, false),
     ])
+}
+
+// Transcript metadata read with --count-column ends with the id of the input
+// row each transcript was expanded from.
+pub fn with_source_transcript_id_column(schema: &Schema, nullable: bool) -> Schema {
+    let mut fields = schema.fields().iter().map(|field| field.as_ref().clone()).collect::<Vec<_>>();
+    fields.push(Field::new("source_transcript_id", DataType::UInt64, nullable));
+    Schema::new(fields)
 }
\ No newline at end of file
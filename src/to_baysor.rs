@@ -9,7 +9,7 @@ use clap::Parser;
 // use csv::StringRecord;
 
 mod schemas;
-use crate::schemas::{transcript_metadata_schema, OutputFormat};
+use crate::schemas::{transcript_metadata_schema, with_source_transcript_id_column, OutputFormat};
 
 use arrow::array::RecordBatch;
 use arrow::datatypes::{Schema, Field, DataType};
@@ -25,7 +25,7 @@ use std::io::{Read, Write};
 use std::sync::Arc;
 use std::collections::HashSet;
 
-pub const BACKGROUND_CELL: u32 = std::u32::MAX;
+pub const BACKGROUND_CELL: u32 = u32::MAX;
 
 #[derive(Parser, Debug)]
 #[command(name = "proseg-to-baysor")]
@@ -62,6 +62,8 @@ fn determine_format(filename: &str, fmtstr: &Option<String>) -> OutputFormat {
     if let Some(fmtstr) = fmtstr {
         if fmtstr == "csv.gz" {
             return OutputFormat::CsvGz;
+        } else if fmtstr == "csv.zst" {
+            return OutputFormat::CsvZst;
         } else if fmtstr == "csv" {
             return OutputFormat::Csv;
         } else if fmtstr == "parquet" {
@@ -73,6 +75,8 @@ fn determine_format(filename: &str, fmtstr: &Option<String>) -> OutputFormat {
 
     if filename.ends_with(".csv.gz") {
         OutputFormat::CsvGz
+    } else if filename.ends_with(".csv.zst") {
+        OutputFormat::CsvZst
     } else if filename.ends_with(".csv") {
         OutputFormat::Csv
     } else if filename.ends_with(".parquet") {
@@ -103,29 +107,43 @@ fn read_proseg_transcript_metadata(filename: String) -> TranscriptMetadata {
     let fmt = determine_format(&filename, &None);
 
     let schema = transcript_metadata_schema(fmt);
-    let input_file = File::open(&filename).expect(&format!("Unable to open '{}'.", &filename));
+    // csv from runs with --count-column has a trailing source_transcript_id
+    // column, which we allow to be missing.
+    let csv_schema = Arc::new(with_source_transcript_id_column(&schema, true));
+    let input_file = File::open(&filename).unwrap_or_else(|_| panic!("Unable to open '{}'.", &filename));
 
     match fmt {
         OutputFormat::Csv => {
-            let rdr = csv::ReaderBuilder::new(Arc::new(schema.clone()))
+            let rdr = csv::ReaderBuilder::new(csv_schema.clone())
                 .with_header(true)
+                .with_truncated_rows(true)
                 .build(input_file)
-                .expect(&format!("Unable to construct CSV reader for '{}'", filename));
+                .unwrap_or_else(|_| panic!("Unable to construct CSV reader for '{}'", filename));
             read_proseg_transcript_metadata_from_reader(rdr, &schema)
         }
         OutputFormat::CsvGz => {
             let input_decoder = GzDecoder::new(input_file);
-            let rdr = csv::ReaderBuilder::new(Arc::new(schema.clone()))
+            let rdr = csv::ReaderBuilder::new(csv_schema.clone())
                 .with_header(true)
+                .with_truncated_rows(true)
                 .build(input_decoder)
-                .expect(&format!("Unable to construct CSV reader for '{}'", filename));
+                .unwrap_or_else(|_| panic!("Unable to construct CSV reader for '{}'", filename));
+            read_proseg_transcript_metadata_from_reader(rdr, &schema)
+        }
+        OutputFormat::CsvZst => {
+            let input_decoder = zstd::Decoder::new(input_file).unwrap();
+            let rdr = csv::ReaderBuilder::new(csv_schema.clone())
+                .with_header(true)
+                .with_truncated_rows(true)
+                .build(input_decoder)
+                .unwrap_or_else(|_| panic!("Unable to construct CSV reader for '{}'", filename));
             read_proseg_transcript_metadata_from_reader(rdr, &schema)
         }
         OutputFormat::Parquet => {
             let rdr = ParquetRecordBatchReaderBuilder::try_new(input_file)
                 .unwrap()
                 .build()
-                .expect(&format!("Unable to read parquet data from frobm {}", filename));
+                .unwrap_or_else(|_| panic!("Unable to read parquet data from frobm {}", filename));
 
             read_proseg_transcript_metadata_from_reader(rdr, &schema)
         },
@@ -344,8 +362,12 @@ fn polygon_area(vertices: &mut [(f32, f32)]) -> f32 {
 
 fn read_cell_polygons_geojson(input_filename: String) -> (JsonValue, Vec<JsonValue>) {
     let input =
-        File::open(input_filename).expect("Unable to open input cell polygon geojson file.");
-    let mut input = GzDecoder::new(input);
+        File::open(&input_filename).expect("Unable to open input cell polygon geojson file.");
+    let mut input: Box<dyn Read> = if input_filename.ends_with(".zst") {
+        Box::new(zstd::Decoder::new(input).unwrap())
+    } else {
+        Box::new(GzDecoder::new(input))
+    };
 
     let mut content = String::new();
     input
@@ -390,7 +412,7 @@ fn read_cell_polygons_geojson(input_filename: String) -> (JsonValue, Vec<JsonVal
         geometry
     }).collect();
 
-    return (data, geometries);
+    (data, geometries)
 }
 
 // We need to rename
@@ -0,0 +1,152 @@
+// End-to-end smoke test: run the proseg binary on a tiny synthetic dataset of
+// well-separated cells and check that the outputs parse and recover the cells.
+//
+// Only the synthetic data is generated from a fixed seed. Sampling itself isn't
+// seeded, so the run differs each time and this only checks properties that
+// should hold for any chain: the blobs are far enough apart that each must end
+// up as one cell.
+
+use flate2::read::GzDecoder;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Distribution, Normal};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::Command;
+
+const CENTERS: [(f32, f32); 4] = [(20.0, 20.0), (80.0, 20.0), (20.0, 80.0), (80.0, 80.0)];
+const TRANSCRIPTS_PER_CELL: usize = 300;
+const NGENES: usize = 8;
+const NUCLEUS_RADIUS: f32 = 3.0;
+
+// Write a Xenium-style transcript csv, returning each transcript's true blob.
+fn write_synthetic_transcripts(path: &Path) -> Vec<usize> {
+    let mut rng = StdRng::seed_from_u64(1);
+    let spread = Normal::new(0.0_f32, 4.0).unwrap();
+    let mut file = File::create(path).unwrap();
+    writeln!(
+        file,
+        "transcript_id,feature_name,x_location,y_location,z_location,overlaps_nucleus,cell_id,qv,fov_name"
+    )
+    .unwrap();
+
+    let mut blobs = Vec::new();
+    for (i, (cx, cy)) in CENTERS.iter().enumerate() {
+        for _ in 0..TRANSCRIPTS_PER_CELL {
+            let dx = spread.sample(&mut rng);
+            let dy = spread.sample(&mut rng);
+            let z = rng.gen_range(0.0_f32..3.0);
+            // each cell is biased towards its own pair of genes
+            let gene = if rng.gen_bool(0.7) {
+                2 * i + rng.gen_range(0..2)
+            } else {
+                rng.gen_range(0..NGENES)
+            };
+            let in_nucleus = (dx * dx + dy * dy).sqrt() < NUCLEUS_RADIUS;
+            writeln!(
+                file,
+                "{},G{},{},{},{},{},{},30.0,fov1",
+                blobs.len(),
+                gene,
+                cx + dx,
+                cy + dy,
+                z,
+                in_nucleus as u8,
+                if in_nucleus { format!("c{}", i) } else { "UNASSIGNED".to_string() },
+            )
+            .unwrap();
+            blobs.push(i);
+        }
+    }
+    blobs
+}
+
+fn read_csv(path: &Path) -> (csv::StringRecord, Vec<csv::StringRecord>) {
+    let mut rdr = csv::Reader::from_path(path)
+        .unwrap_or_else(|err| panic!("Unable to read {}: {}", path.display(), err));
+    let headers = rdr.headers().unwrap().clone();
+    let records = rdr.records().map(|r| r.unwrap()).collect();
+    (headers, records)
+}
+
+fn column(headers: &csv::StringRecord, name: &str) -> usize {
+    headers
+        .iter()
+        .position(|h| h == name)
+        .unwrap_or_else(|| panic!("Missing column: {}", name))
+}
+
+#[test]
+fn smoke_synthetic_blobs() {
+    let dir = std::env::temp_dir().join(format!("proseg-smoke-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("transcripts.csv");
+    let blobs = write_synthetic_transcripts(&input);
+
+    let status = Command::new(env!("CARGO_BIN_EXE_proseg"))
+        .arg("--xenium")
+        .arg(&input)
+        .args(["--schedule", "30,30", "--recorded-samples", "10", "--nthreads", "2"])
+        .arg("--check-final-summaries")
+        .arg("--out-dir")
+        .arg(&dir)
+        .args([
+            "--output-expected-counts", "expected-counts.csv",
+            "--output-cell-metadata", "cell-metadata.csv",
+            "--output-transcript-metadata", "transcript-metadata.csv",
+            "--output-cell-polygons", "cell-polygons.geojson.gz",
+        ])
+        .status()
+        .expect("Unable to run proseg");
+    assert!(status.success());
+
+    // one cell per nucleus
+    let (headers, cells) = read_csv(&dir.join("cell-metadata.csv"));
+    assert_eq!(cells.len(), CENTERS.len());
+    let existence_col = column(&headers, "existence_probability");
+    for cell in &cells {
+        let existence: f32 = cell[existence_col].parse().unwrap();
+        assert!(existence > 0.5, "cell {} rarely exists: {}", &cell[0], existence);
+    }
+
+    let (headers, counts) = read_csv(&dir.join("expected-counts.csv"));
+    assert_eq!(counts.len(), CENTERS.len());
+    assert_eq!(headers.len(), NGENES);
+
+    // the bulk of each blob goes to a single cell, distinct from the others
+    let (headers, transcripts) = read_csv(&dir.join("transcript-metadata.csv"));
+    assert_eq!(transcripts.len(), blobs.len());
+    let id_col = column(&headers, "transcript_id");
+    let assignment_col = column(&headers, "assignment");
+    let mut votes: Vec<HashMap<u32, usize>> = vec![HashMap::new(); CENTERS.len()];
+    for t in &transcripts {
+        let id: usize = t[id_col].parse().unwrap();
+        let cell: u32 = t[assignment_col].parse().unwrap();
+        *votes[blobs[id]].entry(cell).or_insert(0) += 1;
+    }
+    let mut majority_cells = Vec::new();
+    for (i, blob_votes) in votes.iter().enumerate() {
+        let (&cell, &count) = blob_votes.iter().max_by_key(|(_, &count)| count).unwrap();
+        assert_ne!(cell, u32::MAX, "blob {} is mostly unassigned", i);
+        assert!(
+            count * 2 > TRANSCRIPTS_PER_CELL,
+            "blob {} split: only {} of {} transcripts in cell {}",
+            i, count, TRANSCRIPTS_PER_CELL, cell
+        );
+        majority_cells.push(cell);
+    }
+    majority_cells.sort();
+    majority_cells.dedup();
+    assert_eq!(majority_cells.len(), CENTERS.len());
+
+    let mut geojson = String::new();
+    GzDecoder::new(File::open(dir.join("cell-polygons.geojson.gz")).unwrap())
+        .read_to_string(&mut geojson)
+        .unwrap();
+    let polygons = json::parse(&geojson).expect("Unable to parse cell polygons");
+    assert_eq!(polygons["features"].len(), CENTERS.len());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}